@@ -1,8 +1,9 @@
+use crate::bus::{Bus, Bus16};
 use crate::gates::{
-    and16_gate, and_gate, mux16_gate, not16_gate, not_gate, or8way_gate, or_gate, xor_gate,
+    and_gate, and_n_gate, mux_n_gate, not_gate, not_n_gate, or_gate, or_nway_gate, xor_gate,
 };
 
-fn half_adder(a: bool, b: bool) -> (bool, bool) {
+pub fn half_adder(a: bool, b: bool) -> (bool, bool) {
     //println!("half_adder => a: {}, b: {}", a, b);
     let sum = xor_gate(a, b);
     let carry = and_gate(a, b);
@@ -10,7 +11,7 @@ fn half_adder(a: bool, b: bool) -> (bool, bool) {
     (sum, carry)
 }
 
-fn full_adder(a: bool, b: bool, c: bool) -> (bool, bool) {
+pub fn full_adder(a: bool, b: bool, c: bool) -> (bool, bool) {
     //println!("full_adder => a: {}, b: {}, c: {}", a, b, c);
     let (sum_1, carry_1) = half_adder(a, b);
     let (sum, carry_2) = half_adder(sum_1, c);
@@ -19,46 +20,35 @@ fn full_adder(a: bool, b: bool, c: bool) -> (bool, bool) {
     (sum, carry)
 }
 
-fn add_16(a: u16, b: u16) -> u16 {
-    //println!("Starting add_16 a: {}, b: {}", a, b);
-    let a_bit = (a >> 0) & 1 != 0;
-    let b_bit = (b >> 0) & 1 != 0;
-    let (out_bool, mut c) = half_adder(a_bit, b_bit);
-    let mut out: u16 = 0;
-
-    match out_bool {
-        true => {
-            out |= 1;
-        }
-        false => {
-            out |= 0;
-        }
-    };
-
-    for i in 1..16 {
-        let a_bit = (a >> i) & 1 != 0;
-        let b_bit = (b >> i) & 1 != 0;
+/**
+ * N-bit ripple carry adder:
+ * out = a + b, the carry out of the most significant bit is dropped.
+ */
+pub fn add_n<const N: usize>(a: Bus<N>, b: Bus<N>) -> Bus<N> {
+    let (out_bool, mut c) = half_adder(a.bit(0), b.bit(0));
+    let mut out = Bus::zero().with_bit(0, out_bool);
 
-        let (out_bool, c_t) = full_adder(a_bit, b_bit, c);
+    for i in 1..N {
+        let (out_bool, c_t) = full_adder(a.bit(i), b.bit(i), c);
 
-        //println!("i: {} a: {} b: {} out_bool: {}", i, a_bit, b_bit, out_bool);
+        //println!("i: {} a: {} b: {} out_bool: {}", i, a.bit(i), b.bit(i), out_bool);
         c = c_t;
-
-        match out_bool {
-            true => {
-                out |= 1 << i;
-            }
-            false => {
-                out |= 0 << i;
-            }
-        };
+        out = out.with_bit(i, out_bool);
     }
 
     out
 }
 
-fn inc_16(a: u16) -> u16 {
-    add_16(a, 1)
+pub fn inc_n<const N: usize>(a: Bus<N>) -> Bus<N> {
+    add_n(a, Bus::new(1))
+}
+
+pub fn add_16(a: u16, b: u16) -> u16 {
+    add_n(Bus16::from(a), Bus16::from(b)).into()
+}
+
+pub fn inc_16(a: u16) -> u16 {
+    inc_n(Bus16::from(a)).into()
 }
 
 /**
@@ -98,7 +88,8 @@ fn inc_16(a: u16) -> u16 {
 //        out[16], // 16-bit output
 //        zr,      // if (out == 0) equals 1, else 0
 //        ng;      // if (out < 0)  equals 1, else 0
-fn alu(
+#[allow(clippy::too_many_arguments)]
+pub fn alu(
     x: u16,
     y: u16,
     zx: bool,
@@ -108,28 +99,41 @@ fn alu(
     f: bool,
     no: bool,
 ) -> (u16, bool, bool) {
-    let zx_out = mux16_gate(x, 0, zx);
-    let not_zx_out = not16_gate(zx_out);
-    let xout_1 = mux16_gate(zx_out, not_zx_out, nx);
+    let (out, zr, ng) = alu_n(Bus16::from(x), Bus16::from(y), zx, nx, zy, ny, f, no);
+    (out.into(), zr, ng)
+}
 
-    let zy_out = mux16_gate(y, 0, zy);
-    let not_zy_out = not16_gate(zy_out);
-    let yout_1 = mux16_gate(zy_out, not_zy_out, ny);
+/**
+ * N-bit ALU:
+ * Same control bits and flags as the 16-bit ALU above, for any bus width.
+ */
+#[allow(clippy::too_many_arguments)]
+pub fn alu_n<const N: usize>(
+    x: Bus<N>,
+    y: Bus<N>,
+    zx: bool,
+    nx: bool,
+    zy: bool,
+    ny: bool,
+    f: bool,
+    no: bool,
+) -> (Bus<N>, bool, bool) {
+    let zx_out = mux_n_gate(x, Bus::zero(), zx);
+    let not_zx_out = not_n_gate(zx_out);
+    let xout_1 = mux_n_gate(zx_out, not_zx_out, nx);
 
-    let xy_sum = add_16(xout_1, yout_1);
-    let xy_and = and16_gate(xout_1, yout_1);
-    let out_f = mux16_gate(xy_and, xy_sum, f);
+    let zy_out = mux_n_gate(y, Bus::zero(), zy);
+    let not_zy_out = not_n_gate(zy_out);
+    let yout_1 = mux_n_gate(zy_out, not_zy_out, ny);
 
-    let not_out_f = not16_gate(out_f);
-    let out = mux16_gate(out_f, not_out_f, no);
-    let ng = (out >> 15) & 1 != 0;
-    let tmp_out_1 = (out & 0b0000000011111111) as u8;
-    let tmp_out_2 = ((out & 0b1111111100000000) >> 8) as u8;
+    let xy_sum = add_n(xout_1, yout_1);
+    let xy_and = and_n_gate(xout_1, yout_1);
+    let out_f = mux_n_gate(xy_and, xy_sum, f);
 
-    let tout_1 = or8way_gate(tmp_out_1);
-    let tout_2 = or8way_gate(tmp_out_2);
-    let tout = or_gate(tout_1, tout_2);
-    let zr = not_gate(tout);
+    let not_out_f = not_n_gate(out_f);
+    let out = mux_n_gate(out_f, not_out_f, no);
+    let ng = out.msb();
+    let zr = not_gate(or_nway_gate(out));
 
     (out, zr, ng)
 }
@@ -137,6 +141,7 @@ fn alu(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::verify::Harness;
 
     #[test]
     fn test_half_adder() {
@@ -216,6 +221,49 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_add_n() {
+        let res = add_n(Bus::<8>::new(0xFF), Bus::<8>::new(0x01));
+        assert_eq!(res.value(), 0);
+
+        let res = add_n(Bus::<32>::new(0x0001_FFFF), Bus::<32>::new(0x0000_0001));
+        assert_eq!(res.value(), 0x0002_0000);
+
+        let res = add_n(Bus::<64>::new(u64::MAX), Bus::<64>::new(2));
+        assert_eq!(res.value(), 1);
+
+        assert_eq!(inc_n(Bus::<4>::new(0b0111)).value(), 0b1000);
+    }
+
+    #[test]
+    fn test_alu_n() {
+        let x = Bus::<8>::new(12);
+        let y = Bus::<8>::new(20);
+
+        // x - y
+        let (out, zr, ng) = alu_n(x, y, false, true, false, false, true, true);
+        assert_eq!(out.value() as u8 as i8, -8);
+        assert_eq!((zr, ng), (false, true));
+
+        // x + y
+        let (out, _, _) = alu_n(x, y, false, false, false, false, true, false);
+        assert_eq!(out.value(), 32);
+
+        // 0
+        let (out, zr, ng) = alu_n(
+            Bus::<32>::new(0xFFFF_FFFF),
+            Bus::<32>::new(7),
+            true,
+            false,
+            true,
+            false,
+            true,
+            false,
+        );
+        assert_eq!(out.value(), 0);
+        assert_eq!((zr, ng), (true, false));
+    }
+
     #[test]
     fn test_alu() {
         let test_data = [
@@ -632,4 +680,91 @@ mod tests {
             assert_eq!(res, exp_data);
         }
     }
+
+    // reference ALU written with plain integer arithmetic on `width` bits
+    fn alu_model(x: u64, y: u64, control: u64, width: u32) -> (u64, bool, bool) {
+        let mask = u64::MAX >> (64 - width);
+        let bit = |i: u32| (control >> (5 - i)) & 1 != 0;
+        let (zx, nx, zy, ny, f, no) = (bit(0), bit(1), bit(2), bit(3), bit(4), bit(5));
+
+        let x = if zx { 0 } else { x };
+        let x = if nx { !x & mask } else { x };
+        let y = if zy { 0 } else { y };
+        let y = if ny { !y & mask } else { y };
+        let out = if f { x.wrapping_add(y) & mask } else { x & y };
+        let out = if no { !out & mask } else { out };
+
+        (out, out == 0, (out >> (width - 1)) & 1 != 0)
+    }
+
+    #[test]
+    fn test_harness_adders() {
+        let harness = Harness::new();
+
+        harness.assert_equivalent(
+            "half_adder",
+            &["a", "b"],
+            |(a, b)| half_adder(a, b),
+            |(a, b): (bool, bool)| (a ^ b, a & b),
+        );
+        harness.assert_equivalent(
+            "full_adder",
+            &["a", "b", "c"],
+            |(a, b, c)| full_adder(a, b, c),
+            |(a, b, c): (bool, bool, bool)| {
+                let sum = a as u8 + b as u8 + c as u8;
+                (sum & 1 != 0, sum >= 2)
+            },
+        );
+        harness.assert_equivalent(
+            "add16",
+            &["a", "b"],
+            |(a, b)| add_16(a, b),
+            |(a, b): (u16, u16)| a.wrapping_add(b),
+        );
+        harness.assert_equivalent("inc16", &["in"], inc_16, |a: u16| a.wrapping_add(1));
+        harness.assert_equivalent(
+            "add8",
+            &["a", "b"],
+            |(a, b)| add_n::<8>(a, b),
+            |(a, b)| Bus::new(a.value().wrapping_add(b.value())),
+        );
+        harness.assert_equivalent(
+            "add64",
+            &["a", "b"],
+            |(a, b)| add_n::<64>(a, b),
+            |(a, b)| Bus::new(a.value().wrapping_add(b.value())),
+        );
+    }
+
+    #[test]
+    fn test_harness_alu() {
+        let harness = Harness::new().samples(5000);
+
+        harness.assert_equivalent(
+            "alu",
+            &["x", "y", "zx nx zy ny f no"],
+            |(x, y, control): (u16, u16, Bus<6>)| {
+                let bit = |i: usize| control.bit(5 - i);
+                alu(x, y, bit(0), bit(1), bit(2), bit(3), bit(4), bit(5))
+            },
+            |(x, y, control)| {
+                let (out, zr, ng) = alu_model(x as u64, y as u64, control.value(), 16);
+                (out as u16, zr, ng)
+            },
+        );
+
+        // the 4-bit ALU is small enough to check exhaustively
+        let report = harness.assert_equivalent(
+            "alu4",
+            &["x", "y", "zx nx zy ny f no"],
+            |(x, y, control): (Bus<4>, Bus<4>, Bus<6>)| {
+                let bit = |i: usize| control.bit(5 - i);
+                let (out, zr, ng) = alu_n(x, y, bit(0), bit(1), bit(2), bit(3), bit(4), bit(5));
+                (out.value(), zr, ng)
+            },
+            |(x, y, control)| alu_model(x.value(), y.value(), control.value(), 4),
+        );
+        assert!(report.exhaustive);
+    }
 }
@@ -0,0 +1,548 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fmt::Write as _;
+
+use crate::asm::{disassemble, SymbolTable};
+use crate::keyboard::KEYBOARD_ADDRESS;
+
+/**
+ * A Hack instruction decoded: the value of an A-instruction, or the comp
+ * (the a-bit then c1..c6), dest and jump bits of a C-instruction.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    A(u16),
+    C { comp: u16, dest: u16, jump: u16 },
+}
+
+impl Instruction {
+    pub fn decode(word: u16) -> Self {
+        if word & 0x8000 == 0 {
+            Instruction::A(word)
+        } else {
+            Instruction::C {
+                comp: (word >> 6) & 0x7F,
+                dest: (word >> 3) & 0b111,
+                jump: word & 0b111,
+            }
+        }
+    }
+
+    // the comp has x = D unless zx zeroes it
+    pub fn reads_d(&self) -> bool {
+        matches!(self, Instruction::C { comp, .. } if comp & 0b100000 == 0)
+    }
+
+    // the comp has y = A, not M, unless zy zeroes it
+    pub fn reads_a(&self) -> bool {
+        matches!(self, Instruction::C { comp, .. } if comp & 0b1001000 == 0)
+    }
+
+    pub fn writes(&self, register: char) -> bool {
+        let bit = match register {
+            'A' => 0b100,
+            'D' => 0b010,
+            'M' => 0b001,
+            _ => return false,
+        };
+        matches!(self, Instruction::C { dest, .. } if dest & bit != 0)
+    }
+
+    pub fn jumps(&self) -> bool {
+        matches!(self, Instruction::C { jump, .. } if *jump != 0)
+    }
+}
+
+/**
+ * A problem found in a program, at the ROM address of the instruction
+ * it is about.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    // start..end, end excluded
+    Unreachable { start: u16, end: u16 },
+    JumpOutside { addr: u16, target: u16 },
+    ReadsUnwrittenD { addr: u16 },
+    WritesKeyboard { addr: u16 },
+    RunsOffEnd { addr: u16 },
+}
+
+impl Warning {
+    pub fn addr(&self) -> u16 {
+        match *self {
+            Warning::Unreachable { start, .. } => start,
+            Warning::JumpOutside { addr, .. }
+            | Warning::ReadsUnwrittenD { addr }
+            | Warning::WritesKeyboard { addr }
+            | Warning::RunsOffEnd { addr } => addr,
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::Unreachable { start, end } => {
+                write!(f, "unreachable code at {}..{}", start, end)
+            }
+            Warning::JumpOutside { target, .. } => {
+                write!(f, "jump to {}, past the end of the program", target)
+            }
+            Warning::ReadsUnwrittenD { .. } => write!(f, "D is read before it is written"),
+            Warning::WritesKeyboard { .. } => write!(f, "write to the keyboard register KBD"),
+            Warning::RunsOffEnd { .. } => {
+                write!(f, "no halt loop, execution runs off the end of the program")
+            }
+        }
+    }
+}
+
+/**
+ * A basic block: the instructions start..end, entered only at start and
+ * left only after the last, and the starts of the blocks it goes to.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub start: u16,
+    pub end: u16,
+    pub next: Vec<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Analysis {
+    code: Vec<u16>,
+    pub blocks: Vec<Block>,
+    pub warnings: Vec<Warning>,
+}
+
+impl Analysis {
+    /**
+     * The control-flow graph in Graphviz, a box per block listing its
+     * instructions, named by the labels at its start when there are any.
+     */
+    pub fn to_dot(&self, symbols: &SymbolTable) -> String {
+        let labels = symbols.labels_by_address();
+        let mut dot = String::new();
+        writeln!(dot, "digraph program {{").unwrap();
+        writeln!(dot, "  node [shape=box, fontname=monospace];").unwrap();
+        for block in &self.blocks {
+            let mut label = String::new();
+            if let Some(names) = labels.get(&block.start) {
+                write!(label, "({})\\l", names.join(", ")).unwrap();
+            }
+            for addr in block.start..block.end {
+                let word = self.code[addr as usize];
+                let text = disassemble(word).unwrap_or_else(|| format!("{:#06x}", word));
+                write!(label, "{}: {}\\l", addr, text).unwrap();
+            }
+            writeln!(dot, "  b{} [label=\"{}\"];", block.start, label).unwrap();
+        }
+        for block in &self.blocks {
+            for next in &block.next {
+                writeln!(dot, "  b{} -> b{};", block.start, next).unwrap();
+            }
+        }
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+}
+
+// what is known before an instruction, on every path to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct State {
+    a: Option<u16>,
+    d_written: bool,
+}
+
+impl State {
+    fn join(self, other: State) -> State {
+        State {
+            a: if self.a == other.a { self.a } else { None },
+            d_written: self.d_written && other.d_written,
+        }
+    }
+}
+
+struct Edge {
+    target: u16,
+    state: State,
+    jump: bool,
+}
+
+/**
+ * Analyzes a program from its ROM words, following the value of A to
+ * find where jumps go. Jumps to a computed A, such as the returns of VM
+ * code, may go to any label the program loads into A and uses as data,
+ * e.g. `@ret / D=A`; with no labels, as for a .hack file, to any address
+ * used that way.
+ */
+pub fn analyze(code: &[u16], symbols: &SymbolTable) -> Analysis {
+    let instructions: Vec<Instruction> = code.iter().map(|&w| Instruction::decode(w)).collect();
+    let len = code.len();
+
+    let labels = symbols.labels_by_address();
+    let mut taken = BTreeSet::new();
+    for pair in instructions.windows(2) {
+        // an @x whose value the next instruction computes with, not jumps to
+        if let [Instruction::A(value), next] = *pair {
+            let label = labels.is_empty() || labels.contains_key(&value);
+            if (value as usize) < len && label && next.reads_a() && !next.jumps() {
+                taken.insert(value);
+            }
+        }
+    }
+
+    let mut states: Vec<Option<State>> = vec![None; len];
+    let mut pending = Vec::new();
+    if len > 0 {
+        states[0] = Some(State {
+            a: None,
+            d_written: false,
+        });
+        pending.push(0);
+    }
+    while let Some(pc) = pending.pop() {
+        let state = states[pc].unwrap();
+        for edge in successors(pc as u16, instructions[pc], state, &taken) {
+            let Some(target) = states.get_mut(edge.target as usize) else {
+                continue;
+            };
+            let joined = match *target {
+                Some(old) => old.join(edge.state),
+                None => edge.state,
+            };
+            if *target != Some(joined) {
+                *target = Some(joined);
+                pending.push(edge.target as usize);
+            }
+        }
+    }
+
+    let mut warnings = Vec::new();
+    let mut edges: BTreeMap<u16, Vec<Edge>> = BTreeMap::new();
+    for (pc, instruction) in instructions.iter().enumerate() {
+        let addr = pc as u16;
+        let Some(state) = states[pc] else {
+            if pc == 0 || states[pc - 1].is_some() {
+                let end = (pc..len).find(|&i| states[i].is_some()).unwrap_or(len);
+                warnings.push(Warning::Unreachable {
+                    start: addr,
+                    end: end as u16,
+                });
+            }
+            continue;
+        };
+        if instruction.reads_d() && !state.d_written {
+            warnings.push(Warning::ReadsUnwrittenD { addr });
+        }
+        if instruction.writes('M') && state.a == Some(KEYBOARD_ADDRESS) {
+            warnings.push(Warning::WritesKeyboard { addr });
+        }
+        let next = successors(addr, *instruction, state, &taken);
+        for edge in next.iter().filter(|edge| edge.target as usize >= len) {
+            warnings.push(match edge.jump {
+                true => Warning::JumpOutside {
+                    addr,
+                    target: edge.target,
+                },
+                false => Warning::RunsOffEnd { addr },
+            });
+        }
+        edges.insert(addr, next);
+    }
+
+    let blocks = blocks(&instructions, &edges);
+    Analysis {
+        code: code.to_vec(),
+        blocks,
+        warnings,
+    }
+}
+
+// where the instruction at pc goes, with what is known there
+fn successors(pc: u16, instruction: Instruction, state: State, taken: &BTreeSet<u16>) -> Vec<Edge> {
+    let (comp, jump) = match instruction {
+        Instruction::A(value) => {
+            let state = State {
+                a: Some(value),
+                ..state
+            };
+            return vec![fall_through(pc, state)];
+        }
+        Instruction::C { comp, jump, .. } => (comp, jump),
+    };
+
+    // the comp, when it depends on nothing but constants and a known A
+    let x = if instruction.reads_d() { None } else { Some(0) };
+    let y = match comp & 0b1001000 {
+        0 => state.a,
+        0b1000000 => None,
+        _ => Some(0),
+    };
+    let out = x.zip(y).map(|(x, y)| alu(comp, x, y));
+
+    let after = State {
+        a: match instruction.writes('A') {
+            true => out,
+            false => state.a,
+        },
+        d_written: state.d_written || instruction.writes('D'),
+    };
+
+    let (taken_always, taken_maybe) = match (jump, out) {
+        (0, _) => (false, false),
+        (0b111, _) => (true, true),
+        (_, Some(out)) => (jumps(jump, out), jumps(jump, out)),
+        (_, None) => (false, true),
+    };
+    let mut res = Vec::new();
+    if taken_maybe {
+        // A before the instruction, its dest only set after the jump
+        let targets: Vec<u16> = match state.a {
+            Some(target) => vec![target],
+            None => taken.iter().copied().collect(),
+        };
+        for target in targets {
+            let state = State {
+                a: match instruction.writes('A') {
+                    true => after.a,
+                    false => Some(target),
+                },
+                ..after
+            };
+            res.push(Edge {
+                target,
+                state,
+                jump: true,
+            });
+        }
+    }
+    if !taken_always {
+        res.push(fall_through(pc, after));
+    }
+    res
+}
+
+fn fall_through(pc: u16, state: State) -> Edge {
+    Edge {
+        target: pc + 1,
+        state,
+        jump: false,
+    }
+}
+
+// the Hack ALU on the c1..c6 bits of comp
+fn alu(comp: u16, x: u16, y: u16) -> u16 {
+    let bit = |c: u16| comp & (1 << (6 - c)) != 0;
+    let mut x = if bit(1) { 0 } else { x };
+    if bit(2) {
+        x = !x;
+    }
+    let mut y = if bit(3) { 0 } else { y };
+    if bit(4) {
+        y = !y;
+    }
+    let out = if bit(5) { x.wrapping_add(y) } else { x & y };
+    if bit(6) {
+        !out
+    } else {
+        out
+    }
+}
+
+fn jumps(jump: u16, out: u16) -> bool {
+    let out = out as i16;
+    (jump & 0b100 != 0 && out < 0)
+        || (jump & 0b010 != 0 && out == 0)
+        || (jump & 0b001 != 0 && out > 0)
+}
+
+// the reachable code cut where jumps go to and come from
+fn blocks(instructions: &[Instruction], edges: &BTreeMap<u16, Vec<Edge>>) -> Vec<Block> {
+    let len = instructions.len();
+    let mut leaders = BTreeSet::from([0]);
+    for (&pc, next) in edges {
+        for edge in next.iter().filter(|edge| edge.jump) {
+            leaders.insert(edge.target);
+            leaders.insert(pc + 1);
+        }
+    }
+
+    let mut blocks: Vec<Block> = Vec::new();
+    for (&pc, next) in edges {
+        let after_gap = pc == 0 || !edges.contains_key(&(pc - 1));
+        if leaders.contains(&pc) || after_gap {
+            blocks.push(Block {
+                start: pc,
+                end: pc,
+                next: Vec::new(),
+            });
+        }
+        let block = blocks.last_mut().unwrap();
+        block.end = pc + 1;
+        if leaders.contains(&(pc + 1)) || !edges.contains_key(&(pc + 1)) {
+            let mut targets: Vec<u16> = next
+                .iter()
+                .map(|edge| edge.target)
+                .filter(|&target| (target as usize) < len)
+                .collect();
+            targets.sort();
+            targets.dedup();
+            block.next = targets;
+        }
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    fn analyze_source(source: &str) -> Analysis {
+        let program = assemble(source).unwrap();
+        analyze(&program.code, &program.symbols)
+    }
+
+    fn warnings(source: &str) -> Vec<Warning> {
+        analyze_source(source).warnings
+    }
+
+    #[test]
+    fn test_clean() {
+        let source = "
+            @R0
+            D=M
+            @R1
+            D=D-M
+            @FIRST
+            D;JGT
+            @R1
+            D=M
+            @OUT
+            0;JMP
+        (FIRST)
+            @R0
+            D=M
+        (OUT)
+            @R2
+            M=D
+        (END)
+            @END
+            0;JMP
+        ";
+        let analysis = analyze_source(source);
+        assert_eq!(analysis.warnings, []);
+        let blocks: Vec<(u16, u16, Vec<u16>)> = analysis
+            .blocks
+            .iter()
+            .map(|b| (b.start, b.end, b.next.clone()))
+            .collect();
+        assert_eq!(
+            blocks,
+            [
+                (0, 6, vec![6, 10]),
+                (6, 10, vec![12]),
+                (10, 12, vec![12]),
+                (12, 14, vec![14]),
+                (14, 16, vec![14]),
+            ]
+        );
+        let dot = analysis.to_dot(&assemble(source).unwrap().symbols);
+        assert!(dot.contains("  b14 [label=\"(END)\\l14: @14\\l15: 0;JMP\\l\"];"));
+        assert!(dot.contains("  b0 -> b10;"));
+    }
+
+    #[test]
+    fn test_warnings() {
+        assert_eq!(
+            warnings("@5\nM=D\n@END\n(END)\n0;JMP"),
+            [Warning::ReadsUnwrittenD { addr: 1 }]
+        );
+        // the first D-read warns, D is written on every path after
+        assert_eq!(
+            warnings("@2\nD;JEQ\nD=1\nD=D+1\n(END)\n@END\n0;JMP"),
+            [Warning::ReadsUnwrittenD { addr: 1 }]
+        );
+        assert_eq!(
+            warnings("@KBD\nM=0\n(END)\n@END\n0;JMP"),
+            [Warning::WritesKeyboard { addr: 1 }]
+        );
+        assert_eq!(
+            warnings("@END\n0;JMP\nD=1\nD=0\n(END)\n@END\n0;JMP"),
+            [Warning::Unreachable { start: 2, end: 4 }]
+        );
+        assert_eq!(
+            warnings("@100\n0;JMP"),
+            [Warning::JumpOutside {
+                addr: 1,
+                target: 100
+            }]
+        );
+        assert_eq!(warnings("D=1\n@R0\nM=D"), [Warning::RunsOffEnd { addr: 2 }]);
+        // a constant comp decides the jump
+        assert_eq!(
+            warnings("@3\n0;JEQ\nD=D+1\n(END)\n@END\n0;JMP"),
+            [Warning::Unreachable { start: 2, end: 3 }]
+        );
+    }
+
+    #[test]
+    fn test_computed_jumps() {
+        // a call and return through the stack, the return address as data
+        let source = "
+            @256
+            D=A
+            @SP
+            M=D
+            @RET
+            D=A
+            @SP
+            M=M+1
+            A=M-1
+            M=D
+            @F
+            0;JMP
+        (RET)
+            @RET
+            0;JMP
+        (F)
+            @SP
+            AM=M-1
+            A=M
+            0;JMP
+        ";
+        let analysis = analyze_source(source);
+        assert_eq!(analysis.warnings, []);
+        let f = analysis.blocks.iter().find(|b| b.start == 14).unwrap();
+        assert!(f.next.contains(&12));
+    }
+
+    #[test]
+    fn test_vm_code() {
+        use crate::vm::{Translator, VmFile};
+
+        let sys = "
+            function Sys.init 0
+            push constant 3
+            call Sys.double 1
+            lt
+            if-goto END
+            push constant 1
+            pop temp 0
+            label END
+            goto END
+            function Sys.double 0
+            push argument 0
+            push argument 0
+            add
+            return
+        ";
+        let asm = Translator::new()
+            .translate(&[VmFile::new("Sys", sys)])
+            .unwrap();
+        // the runtime routines it does not use are left out, nothing is dead
+        let analysis = analyze_source(&asm);
+        assert_eq!(analysis.warnings, []);
+    }
+}
@@ -0,0 +1,676 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::keyboard::KEYBOARD_ADDRESS;
+use crate::screen::SCREEN_ADDRESS;
+use crate::srcmap::{Location, SourceMap};
+
+mod optimize;
+mod preprocess;
+
+pub use optimize::{optimize, optimize_with_map, verify_optimized, Optimizer, Verification};
+
+// comp mnemonics with their a-bit and c1..c6 bits, canonical spelling first
+const COMP: [(&str, u16); 37] = [
+    ("0", 0b0_101010),
+    ("1", 0b0_111111),
+    ("-1", 0b0_111010),
+    ("D", 0b0_001100),
+    ("A", 0b0_110000),
+    ("!D", 0b0_001101),
+    ("!A", 0b0_110001),
+    ("-D", 0b0_001111),
+    ("-A", 0b0_110011),
+    ("D+1", 0b0_011111),
+    ("A+1", 0b0_110111),
+    ("D-1", 0b0_001110),
+    ("A-1", 0b0_110010),
+    ("D+A", 0b0_000010),
+    ("D-A", 0b0_010011),
+    ("A-D", 0b0_000111),
+    ("D&A", 0b0_000000),
+    ("D|A", 0b0_010101),
+    ("M", 0b1_110000),
+    ("!M", 0b1_110001),
+    ("-M", 0b1_110011),
+    ("M+1", 0b1_110111),
+    ("M-1", 0b1_110010),
+    ("D+M", 0b1_000010),
+    ("D-M", 0b1_010011),
+    ("M-D", 0b1_000111),
+    ("D&M", 0b1_000000),
+    ("D|M", 0b1_010101),
+    // commuted forms
+    ("1+D", 0b0_011111),
+    ("1+A", 0b0_110111),
+    ("1+M", 0b1_110111),
+    ("A+D", 0b0_000010),
+    ("A&D", 0b0_000000),
+    ("A|D", 0b0_010101),
+    ("M+D", 0b1_000010),
+    ("M&D", 0b1_000000),
+    ("M|D", 0b1_010101),
+];
+
+const JUMP: [&str; 8] = ["", "JGT", "JEQ", "JGE", "JLT", "JNE", "JLE", "JMP"];
+
+/**
+ * Names of the Hack assembly: the predefined symbols, (LABEL)s which are
+ * ROM addresses, and variables, given RAM addresses from 16 on.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolTable {
+    predefined: HashMap<String, u16>,
+    labels: HashMap<String, u16>,
+    variables: HashMap<String, u16>,
+    next_variable: u16,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        let mut predefined: HashMap<String, u16> =
+            (0..16).map(|i| (format!("R{}", i), i)).collect();
+        for (name, addr) in [
+            ("SP", 0),
+            ("LCL", 1),
+            ("ARG", 2),
+            ("THIS", 3),
+            ("THAT", 4),
+            ("SCREEN", SCREEN_ADDRESS),
+            ("KBD", KEYBOARD_ADDRESS),
+        ] {
+            predefined.insert(name.to_string(), addr);
+        }
+
+        SymbolTable {
+            predefined,
+            labels: HashMap::new(),
+            variables: HashMap::new(),
+            next_variable: 16,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<u16> {
+        self.predefined
+            .get(name)
+            .or_else(|| self.labels.get(name))
+            .or_else(|| self.variables.get(name))
+            .copied()
+    }
+
+    pub fn label(&self, name: &str) -> Option<u16> {
+        self.labels.get(name).copied()
+    }
+
+    // RAM address of a predefined symbol or variable
+    pub fn variable(&self, name: &str) -> Option<u16> {
+        self.predefined
+            .get(name)
+            .or_else(|| self.variables.get(name))
+            .copied()
+    }
+
+    pub fn add_label(&mut self, name: &str, addr: u16) -> bool {
+        if self.get(name).is_some() {
+            return false;
+        }
+        self.labels.insert(name.to_string(), addr);
+        true
+    }
+
+    // address of the variable, allocating the next free one if it is new
+    pub fn variable_or_insert(&mut self, name: &str) -> u16 {
+        if let Some(addr) = self.get(name) {
+            return addr;
+        }
+        let addr = self.next_variable;
+        self.variables.insert(name.to_string(), addr);
+        self.next_variable += 1;
+        addr
+    }
+
+    // labels by ROM address, several labels may share one
+    pub fn labels_by_address(&self) -> BTreeMap<u16, Vec<&str>> {
+        let mut res: BTreeMap<u16, Vec<&str>> = BTreeMap::new();
+        for (name, &addr) in &self.labels {
+            res.entry(addr).or_default().push(name);
+        }
+        for names in res.values_mut() {
+            names.sort();
+        }
+        res
+    }
+
+    pub fn variables_by_address(&self) -> BTreeMap<u16, &str> {
+        self.variables
+            .iter()
+            .map(|(name, &addr)| (addr, name.as_str()))
+            .collect()
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/**
+ * An error at a line of the source, with the column and width of the text
+ * it is about, in chars counted from 1: the comp, dest, constant or label
+ * that is wrong, or the first word of the line for errors not about one
+ * piece of it, such as those in a macro or an included file.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub width: usize,
+    pub message: String,
+}
+
+impl AsmError {
+    // the span of token in the line, found in its code before any comment
+    // from the given byte of that code with its spaces taken out
+    fn at(source: &str, line: usize, token: Option<(usize, &str)>, message: String) -> Self {
+        let text = source_line(source, line);
+        let code = text.split("//").next().unwrap();
+        // C-instructions are read with their spaces taken out, so is the token
+        let chars: Vec<(usize, char)> = code
+            .char_indices()
+            .filter(|(_, c)| !c.is_whitespace())
+            .collect();
+        let stripped: String = chars.iter().map(|&(_, c)| c).collect();
+        let (start, end) = token
+            .map(|(from, token)| (from, token.split_whitespace().collect::<String>()))
+            .filter(|(_, token)| !token.is_empty())
+            .and_then(|(from, token)| {
+                let found = from + stripped.get(from..)?.find(&token)?;
+                let first = stripped[..found].chars().count();
+                let (last, c) = chars[first + token.chars().count() - 1];
+                Some((chars[first].0, last + c.len_utf8()))
+            })
+            .unwrap_or_else(|| {
+                let start = code.len() - code.trim_start().len();
+                let word = code[start..].split_whitespace().next().unwrap_or("");
+                (start, start + word.len())
+            });
+        AsmError {
+            line,
+            column: code[..start].chars().count() + 1,
+            width: code[start..end].chars().count().max(1),
+            message,
+        }
+    }
+
+    /**
+     * The error as file:line:column: message, then the line of the source
+     * with a caret under the text it is about.
+     */
+    pub fn report(&self, file: &str, source: &str) -> String {
+        let text = source_line(source, self.line);
+        // tabs kept so the caret lines up however they are shown
+        let pad: String = text
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        format!(
+            "{}:{}:{}: {}\n{}\n{}{}",
+            file,
+            self.line,
+            self.column,
+            self.message,
+            text,
+            pad,
+            "^".repeat(self.width)
+        )
+    }
+}
+
+fn source_line(source: &str, line: usize) -> &str {
+    let text = source.lines().nth(line.saturating_sub(1)).unwrap_or("");
+    text.trim_end_matches('\r')
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/**
+ * An assembled program: the ROM words, the symbols it defined and the
+ * source line of each instruction.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub code: Vec<u16>,
+    pub symbols: SymbolTable,
+    pub lines: Vec<usize>,
+}
+
+impl Program {
+    // the .hack text, one binary word per line
+    pub fn to_hack(&self) -> String {
+        self.code
+            .iter()
+            .map(|word| format!("{:016b}\n", word))
+            .collect()
+    }
+
+    /**
+     * Each line of the source next to the ROM address and binary of the
+     * instructions it became; those after the first, from macros and
+     * pseudo-instructions, disassembled on the lines below it.
+     */
+    pub fn listing(&self, source: &str) -> String {
+        let mut by_line: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (addr, &line) in self.lines.iter().enumerate() {
+            by_line.entry(line).or_default().push(addr);
+        }
+
+        let mut out = String::new();
+        for (i, text) in source.lines().enumerate() {
+            let addrs = by_line.get(&(i + 1)).map(Vec::as_slice).unwrap_or(&[]);
+            let row = match addrs.first() {
+                Some(&addr) => format!("{:5}  {:016b}", addr, self.code[addr]),
+                None => String::new(),
+            };
+            let row = format!("{:23}  {:4}  {}", row, i + 1, text.trim_end_matches('\r'));
+            out.push_str(row.trim_end());
+            out.push('\n');
+            for &addr in addrs.iter().skip(1) {
+                let word = self.code[addr];
+                let text = disassemble(word).unwrap_or_else(|| format!("{:#06x}", word));
+                let row = format!("{:5}  {:016b}        {}", addr, word, text);
+                out.push_str(&row);
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    // each ROM address to its line of the source, named file
+    pub fn source_map(&self, file: &str) -> SourceMap {
+        let mut map = SourceMap::new();
+        for (addr, &line) in self.lines.iter().enumerate() {
+            map.add(addr, Location::new(file, line));
+        }
+        map
+    }
+}
+
+enum Line<'a> {
+    Label(&'a str),
+    A(&'a str),
+    C(&'a str),
+}
+
+fn is_symbol(name: &str) -> bool {
+    let mut chars = name.chars();
+    let valid = |c: char| c.is_ascii_alphanumeric() || "_.$:".contains(c);
+    matches!(chars.next(), Some(c) if valid(c) && !c.is_ascii_digit()) && chars.all(valid)
+}
+
+fn parse_line(text: &str) -> Option<Line<'_>> {
+    let text = text.split("//").next().unwrap().trim();
+    if text.is_empty() {
+        None
+    } else if let Some(label) = text.strip_prefix('(') {
+        Some(Line::Label(label))
+    } else if let Some(value) = text.strip_prefix('@') {
+        Some(Line::A(value))
+    } else {
+        Some(Line::C(text))
+    }
+}
+
+// errors with the part of the instruction that is wrong and where it
+// starts, spaces removed
+fn encode_c(text: &str) -> Result<u16, (usize, String, String)> {
+    let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let (dest, rest) = match text.split_once('=') {
+        Some((dest, rest)) => (dest, rest),
+        None => ("", text.as_str()),
+    };
+    let (comp, jump) = match rest.split_once(';') {
+        Some((comp, jump)) => (comp, jump),
+        None => (rest, ""),
+    };
+
+    let mut dest_bits = 0;
+    for c in dest.chars() {
+        let bit = match c {
+            'A' => 0b100,
+            'D' => 0b010,
+            'M' => 0b001,
+            _ => return Err((0, dest.to_string(), format!("invalid dest '{}'", dest))),
+        };
+        if dest_bits & bit != 0 {
+            return Err((0, dest.to_string(), format!("invalid dest '{}'", dest)));
+        }
+        dest_bits |= bit;
+    }
+
+    let comp_bits = COMP
+        .iter()
+        .find(|(name, _)| *name == comp)
+        .map(|(_, bits)| *bits)
+        .ok_or_else(|| {
+            let at = text.len() - rest.len();
+            (at, comp.to_string(), format!("unknown comp '{}'", comp))
+        })?;
+
+    let jump_bits = JUMP.iter().position(|&name| name == jump).ok_or_else(|| {
+        let at = text.len() - jump.len();
+        (at, jump.to_string(), format!("unknown jump '{}'", jump))
+    })? as u16;
+
+    Ok(0b111 << 13 | comp_bits << 6 | dest_bits << 3 | jump_bits)
+}
+
+/**
+ * Translates Hack assembly to machine code, in the two passes of the
+ * Nand2Tetris assembler: labels first, then instructions and variables.
+ * Macros, pseudo-instructions and directives are expanded before, see
+ * Assembler; .include reads from the current directory.
+ */
+pub fn assemble(source: &str) -> Result<Program, AsmError> {
+    Assembler::new().assemble(source)
+}
+
+/**
+ * The assembler, with the extensions to Hack assembly:
+ *
+ * ```text
+ * .equ NAME VALUE              a constant, for @NAME and the operands below
+ * .include "file.asm"          the lines of the file, from the include directory
+ * .macro NAME [p1, p2...]      a macro, its body using %p1 for the arguments
+ * .endm                        and %@ for a number unique to each expansion
+ * NAME a1, a2                  the body of a macro with the arguments
+ * .data ADDR v1, v2...         RAM[ADDR..] preloaded with the values
+ * .fill ADDR N VALUE           N words from ADDR preloaded with VALUE
+ * MOV dst, src                 copy through D; D, A, M, #value or RAM[x]
+ * PUSH src / POP dst           on the stack at SP
+ * CALL label / RET             return address pushed on that stack
+ * JMP label / JEQ label...     @label then 0;JMP or D;JEQ...
+ * ```
+ *
+ * RAM is preloaded by code put before everything else, so the program
+ * still starts at ROM address 0 and the output is standard .hack.
+ */
+pub struct Assembler {
+    include_dir: PathBuf,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Assembler {
+            include_dir: PathBuf::new(),
+        }
+    }
+
+    // where .include finds its files, usually the directory of the source
+    pub fn include_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.include_dir = dir.as_ref().to_path_buf();
+        self
+    }
+
+    pub fn assemble(&self, source: &str) -> Result<Program, AsmError> {
+        let lines = preprocess::expand(source, &self.include_dir)
+            .map_err(|(line, message)| AsmError::at(source, line, None, message))?;
+        assemble_lines(source, &lines)
+    }
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// the two passes, errors and lines given as the source's
+fn assemble_lines(source: &str, lines: &[preprocess::Line]) -> Result<Program, AsmError> {
+    let mut symbols = SymbolTable::new();
+
+    let mut rom_addr: u16 = 0;
+    for preprocess::Line { line, text } in lines {
+        let error =
+            |token: &str, message: String| AsmError::at(source, *line, Some((0, token)), message);
+        match parse_line(text) {
+            Some(Line::Label(label)) => {
+                let Some(name) = label.strip_suffix(')') else {
+                    return Err(error(label, format!("missing ')' after label '{}'", label)));
+                };
+                if !is_symbol(name) {
+                    return Err(error(name, format!("invalid label '{}'", name)));
+                }
+                if !symbols.add_label(name, rom_addr) {
+                    let message = format!("symbol '{}' is already defined", name);
+                    return Err(error(name, message));
+                }
+            }
+            Some(_) => {
+                rom_addr = rom_addr
+                    .checked_add(1)
+                    .filter(|&addr| addr <= 0x8000)
+                    .ok_or_else(|| error("", "program does not fit in 32K of ROM".to_string()))?;
+            }
+            None => {}
+        }
+    }
+
+    let mut code = Vec::new();
+    let mut rom_lines = Vec::new();
+    for preprocess::Line { line, text } in lines {
+        let error =
+            |token: &str, message: String| AsmError::at(source, *line, Some((0, token)), message);
+        let word = match parse_line(text) {
+            Some(Line::A(value)) if value.starts_with(|c: char| c.is_ascii_digit()) => {
+                match value.parse::<u16>() {
+                    Ok(n) if n <= 0x7FFF => n,
+                    _ => {
+                        let message = format!("constant '{}' is not in 0..32767", value);
+                        return Err(error(value, message));
+                    }
+                }
+            }
+            Some(Line::A(name)) if is_symbol(name) => symbols.variable_or_insert(name),
+            Some(Line::A(name)) => return Err(error(name, format!("invalid symbol '{}'", name))),
+            Some(Line::C(text)) => encode_c(text).map_err(|(from, token, message)| {
+                AsmError::at(source, *line, Some((from, &token)), message)
+            })?,
+            Some(Line::Label(_)) | None => continue,
+        };
+        code.push(word);
+        rom_lines.push(*line);
+    }
+
+    Ok(Program {
+        code,
+        symbols,
+        lines: rom_lines,
+    })
+}
+
+/**
+ * Assembly for one instruction, None for C-instructions that do not use
+ * a documented comp.
+ */
+pub fn disassemble(word: u16) -> Option<String> {
+    if word & 0x8000 == 0 {
+        return Some(format!("@{}", word));
+    }
+    if word & 0xE000 != 0xE000 {
+        return None;
+    }
+
+    let comp_bits = (word >> 6) & 0x7F;
+    let (comp, _) = COMP.iter().find(|(_, bits)| *bits == comp_bits)?;
+
+    let dest: String = [('A', 0b100), ('D', 0b010), ('M', 0b001)]
+        .iter()
+        .filter(|(_, bit)| (word >> 3) & bit != 0)
+        .map(|(c, _)| c)
+        .collect();
+    let jump = JUMP[(word & 0b111) as usize];
+
+    let mut res = String::new();
+    if !dest.is_empty() {
+        res.push_str(&dest);
+        res.push('=');
+    }
+    res.push_str(comp);
+    if !jump.is_empty() {
+        res.push(';');
+        res.push_str(jump);
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: &str = "
+        // Computes R2 = max(R0, R1)
+        @R0
+        D=M              // D = first number
+        @R1
+        D=D-M            // D = first number - second number
+        @OUTPUT_FIRST
+        D;JGT            // if D>0 (first is greater) goto output_first
+        @R1
+        D=M              // D = second number
+        @OUTPUT_D
+        0;JMP            // goto output_d
+    (OUTPUT_FIRST)
+        @R0
+        D=M              // D = first number
+    (OUTPUT_D)
+        @R2
+        M=D              // M[2] = D (greatest number)
+    (INFINITE_LOOP)
+        @INFINITE_LOOP
+        0;JMP            // infinite loop
+    ";
+
+    #[test]
+    fn test_assemble_max() {
+        let program = assemble(MAX).unwrap();
+        let expected = [
+            0x0000, 0xFC10, 0x0001, 0xF4D0, 0x000A, 0xE301, 0x0001, 0xFC10, 0x000C, 0xEA87, 0x0000,
+            0xFC10, 0x0002, 0xE308, 0x000E, 0xEA87,
+        ];
+        assert_eq!(program.code, expected);
+        assert_eq!(program.symbols.label("OUTPUT_D"), Some(12));
+        assert_eq!(program.lines[..2], [3, 4]);
+        assert!(program
+            .to_hack()
+            .starts_with("0000000000000000\n1111110000010000\n"));
+    }
+
+    #[test]
+    fn test_variables() {
+        let program = assemble("@i\nM=1\n@sum\nM=0\n@i\nD=M\n@SCREEN\n@KBD\n").unwrap();
+        assert_eq!(program.code[0], 16);
+        assert_eq!(program.code[2], 17);
+        assert_eq!(program.code[4], 16);
+        assert_eq!(program.code[6..], [0x4000, 0x6000]);
+        assert_eq!(program.symbols.variable("sum"), Some(17));
+        assert_eq!(program.symbols.variables_by_address()[&16], "i");
+    }
+
+    #[test]
+    fn test_encode_c() {
+        assert_eq!(encode_c("AMD=M+1;JMP"), Ok(0b1111_1101_1111_1111));
+        assert_eq!(encode_c("DM=D"), encode_c("MD=D"));
+        assert_eq!(encode_c("D = D + A"), Ok(0xE090));
+        assert_eq!(encode_c("A+D"), encode_c("D+A"));
+        let error = |text| encode_c(text).unwrap_err().2;
+        assert_eq!(error("D=D*A"), "unknown comp 'D*A'");
+        assert_eq!(error("X=D"), "invalid dest 'X'");
+        assert_eq!(error("DD=D"), "invalid dest 'DD'");
+        let part = |text| {
+            let (at, token, _) = encode_c(text).unwrap_err();
+            (at, token)
+        };
+        assert_eq!(part("0;JMPP"), (2, "JMPP".to_string()));
+        assert_eq!(part("AMD=AM"), (4, "AM".to_string()));
+        assert_eq!(error("0;JMPP"), "unknown jump 'JMPP'");
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = assemble("@1\n(LOOP)\n(LOOP)\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 3: symbol 'LOOP' is already defined");
+        assert_eq!(assemble("@32768").unwrap_err().line, 1);
+        assert_eq!(assemble("\n@1x").unwrap_err().line, 2);
+        assert!(assemble("(SP)").is_err());
+        let err = assemble("(LOOP\n@LOOP\n0;JMP").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: missing ')' after label 'LOOP'");
+    }
+
+    #[test]
+    fn test_error_report() {
+        let report = |source| assemble(source).unwrap_err().report("Prog.asm", source);
+        assert_eq!(
+            report("@1\n  D=D*A // no\n"),
+            "Prog.asm:2:5: unknown comp 'D*A'\n  D=D*A // no\n    ^^^"
+        );
+        assert_eq!(
+            report("\t@40000"),
+            "Prog.asm:1:3: constant '40000' is not in 0..32767\n\t@40000\n\t ^^^^^"
+        );
+        let span = |source| {
+            let err = assemble(source).unwrap_err();
+            (err.line, err.column, err.width)
+        };
+        assert_eq!(span("(LOOP)\n(LOOP)"), (2, 2, 4));
+        assert_eq!(span("@1\n  (LOOP // no\n"), (2, 4, 4));
+        assert_eq!(span(" X=D"), (1, 2, 1));
+        assert_eq!(span("0;JMPP"), (1, 3, 4));
+        assert_eq!(span("D = D * A"), (1, 5, 5));
+        // the comp after the dest it shares its letters with
+        assert_eq!(span("AMD=AM"), (1, 5, 2));
+        assert_eq!(span("M = M ; M"), (1, 9, 1));
+        assert_eq!(span("  0 ; JNO"), (1, 7, 3));
+        // macro errors point at the use of the macro
+        assert_eq!(span(".macro M\nD=X\n.endm\n   M"), (4, 4, 1));
+        assert_eq!(span("  .equ N"), (1, 3, 4));
+    }
+
+    #[test]
+    fn test_listing() {
+        let source = "// max\n@R0\nD=M\n(END)\nJMP END\n";
+        let listing = assemble(source).unwrap().listing(source);
+        let expected = [
+            "                            1  // max",
+            "    0  0000000000000000     2  @R0",
+            "    1  1111110000010000     3  D=M",
+            "                            4  (END)",
+            "    2  0000000000000010     5  JMP END",
+            "    3  1110101010000111        0;JMP",
+        ];
+        assert_eq!(listing.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_disassemble_round_trip() {
+        for (name, bits) in COMP.iter().take(28) {
+            let word = 0xE000 | bits << 6 | 0b010_000;
+            assert_eq!(disassemble(word), Some(format!("D={}", name)));
+        }
+        for word in 0xE000..=0xFFFF {
+            if let Some(text) = disassemble(word) {
+                assert_eq!(encode_c(&text).ok(), Some(word), "{}", text);
+            }
+        }
+        assert_eq!(disassemble(0xEA87).as_deref(), Some("0;JMP"));
+        assert_eq!(disassemble(0x8000), None);
+    }
+}
@@ -0,0 +1,701 @@
+use crate::runner::Runner;
+use crate::srcmap::{Location, SourceMap};
+
+use super::{encode_c, parse_line, Program};
+
+// push D then pop D, as the VM translator writes them
+const PUSH_POP: [&str; 7] = ["@SP", "M=M+1", "A=M-1", "M=D", "@SP", "AM=M-1", "D=M"];
+
+// the RAM compared by verify_optimized, up to and with the keyboard
+const RAM_END: u16 = 0x6001;
+
+// the words of the VM stack, above SP are those popped off
+const STACK: std::ops::Range<u16> = 256..2048;
+
+/*
+ * A line of the assembly being optimized and the line of the source it
+ * comes from, comments kept as they are.
+ */
+struct Line {
+    text: String,
+    source: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Kind {
+    Label,
+    A(String),
+    C(C),
+    // blank lines and comments
+    Nothing,
+    // what the optimizer does not know, e.g. directives
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct C {
+    dest: String,
+    comp: String,
+    jump: String,
+}
+
+impl C {
+    fn reads(&self, register: char) -> bool {
+        match register {
+            // M is RAM[A], A is read to find it
+            'A' => self.comp.contains(['A', 'M']) || self.dest.contains('M') || self.jumps(),
+            'D' => self.comp.contains('D') || self.jumps(),
+            _ => self.comp.contains(register),
+        }
+    }
+
+    fn writes(&self, register: char) -> bool {
+        self.dest.contains(register)
+    }
+
+    fn jumps(&self) -> bool {
+        !self.jump.is_empty()
+    }
+
+    fn is(&self, text: &str) -> bool {
+        kind(text) == Kind::C(self.clone())
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = String::new();
+        if !self.dest.is_empty() {
+            text.push_str(&self.dest);
+            text.push('=');
+        }
+        text.push_str(&self.comp);
+        if self.jumps() {
+            text.push(';');
+            text.push_str(&self.jump);
+        }
+        text
+    }
+}
+
+fn kind(text: &str) -> Kind {
+    match parse_line(text) {
+        None => Kind::Nothing,
+        Some(super::Line::Label(_)) => Kind::Label,
+        Some(super::Line::A(value)) => Kind::A(value.to_string()),
+        Some(super::Line::C(text)) if encode_c(text).is_ok() => {
+            let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+            let (dest, rest) = text.split_once('=').unwrap_or(("", &text));
+            let (comp, jump) = rest.split_once(';').unwrap_or((rest, ""));
+            // the dest in one order, so the same dests compare equal
+            let dest = ['A', 'M', 'D']
+                .iter()
+                .filter(|&&r| dest.contains(r))
+                .collect();
+            Kind::C(C {
+                dest,
+                comp: comp.to_string(),
+                jump: jump.to_string(),
+            })
+        }
+        Some(super::Line::C(_)) => Kind::Unknown,
+    }
+}
+
+/**
+ * Optimizes Hack assembly with peephole rewrites, within the code between
+ * two labels, until none applies:
+ *
+ * - loads of A or D with the value they already have are removed, and
+ *   `A=M / A=A-1` is `A=M-1`;
+ * - writes to A, D or RAM[x] overwritten before being read are removed.
+ *
+ * The code after a label may be jumped to from anywhere, so nothing is
+ * known there; a jump reads A and D, as the code it goes to may. Comments
+ * and lines the optimizer does not know, such as directives, are kept.
+ *
+ * A constant loaded right before a jump is a ROM address: the instruction
+ * there is given a label, `($ROM.n)` loaded instead of `@n`, so the jump
+ * follows it when the code before moves. A program with such jumps and
+ * lines the optimizer does not know, whose addresses it cannot tell, is
+ * left as it is.
+ */
+pub fn optimize(source: &str) -> String {
+    Optimizer::new().optimize(source)
+}
+
+/**
+ * optimize, with the map of the optimized lines to the lines of the
+ * source, named file.
+ */
+pub fn optimize_with_map(source: &str, file: &str) -> (String, SourceMap) {
+    Optimizer::new().optimize_with_map(source, file)
+}
+
+/**
+ * The optimizer of optimize, with the rewrites that only hold for the VM
+ * translator's output turned on by vm_stack: there SP points at the top
+ * of a stack, and a push of D followed by a pop into D does neither, the
+ * word above the stack being left as it was. Elsewhere RAM[SP] may be
+ * anything, e.g. a variable.
+ */
+pub struct Optimizer {
+    vm_stack: bool,
+}
+
+impl Optimizer {
+    pub fn new() -> Self {
+        Optimizer { vm_stack: false }
+    }
+
+    pub fn vm_stack(mut self, vm_stack: bool) -> Self {
+        self.vm_stack = vm_stack;
+        self
+    }
+
+    pub fn optimize(&self, source: &str) -> String {
+        self.optimize_with_map(source, "").0
+    }
+
+    pub fn optimize_with_map(&self, source: &str, file: &str) -> (String, SourceMap) {
+        let mut lines: Vec<Line> = source
+            .lines()
+            .enumerate()
+            .map(|(i, text)| Line {
+                text: text.to_string(),
+                source: i + 1,
+            })
+            .collect();
+
+        if label_jump_targets(&mut lines) {
+            while (self.vm_stack && fold_push_pop(&mut lines))
+                | combine(&mut lines)
+                | remove_redundant(&mut lines)
+                | remove_dead(&mut lines)
+            {}
+        }
+
+        let mut text = String::new();
+        let mut map = SourceMap::new();
+        for (i, line) in lines.iter().enumerate() {
+            text.push_str(&line.text);
+            text.push('\n');
+            map.add(i + 1, Location::new(file, line.source));
+        }
+        (text, map)
+    }
+}
+
+impl Default for Optimizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// the indices of the instructions from start, None at a label or unknown line
+fn instructions(lines: &[Line], start: usize, count: usize) -> Option<Vec<usize>> {
+    let mut res = Vec::new();
+    for (i, line) in lines.iter().enumerate().skip(start) {
+        if res.len() == count {
+            break;
+        }
+        match kind(&line.text) {
+            Kind::Nothing => {}
+            Kind::A(_) | Kind::C(_) => res.push(i),
+            Kind::Label | Kind::Unknown => return None,
+        }
+    }
+    (res.len() == count).then_some(res)
+}
+
+// @n then a jump to @$ROM.n, the label before the instruction at ROM n;
+// false when unknown lines leave the addresses unknown
+fn label_jump_targets(lines: &mut Vec<Line>) -> bool {
+    let at: Vec<usize> = (0..lines.len())
+        .filter(|&i| matches!(kind(&lines[i].text), Kind::A(_) | Kind::C(_)))
+        .collect();
+    let targets: Vec<(usize, String, usize)> = at
+        .windows(2)
+        .filter_map(|pair| {
+            let (Kind::A(value), Kind::C(c)) =
+                (kind(&lines[pair[0]].text), kind(&lines[pair[1]].text))
+            else {
+                return None;
+            };
+            let n = value.parse::<u16>().ok()? as usize;
+            // past the end, it stays past the end
+            (c.jumps() && n <= at.len()).then_some((pair[0], value, n))
+        })
+        .collect();
+    if targets.is_empty() {
+        return true;
+    }
+    if lines.iter().any(|line| kind(&line.text) == Kind::Unknown) {
+        return false;
+    }
+
+    let label = |n: usize| format!("$ROM.{}", n);
+    for (i, value, n) in &targets {
+        let text = &lines[*i].text;
+        lines[*i].text = text.replacen(&format!("@{}", value), &format!("@{}", label(*n)), 1);
+    }
+    let mut addrs: Vec<usize> = targets.iter().map(|&(_, _, n)| n).collect();
+    addrs.sort_unstable();
+    addrs.dedup();
+    for &n in addrs.iter().rev() {
+        let i = at.get(n).copied().unwrap_or(lines.len());
+        let source = lines.get(i).or(lines.last()).map_or(1, |line| line.source);
+        let text = format!("({})", label(n));
+        lines.insert(i, Line { text, source });
+    }
+    true
+}
+
+// removed in increasing order
+fn remove(lines: &mut Vec<Line>, removed: &[usize]) {
+    let mut i = 0;
+    lines.retain(|_| {
+        i += 1;
+        removed.binary_search(&(i - 1)).is_err()
+    });
+}
+
+fn fold_push_pop(lines: &mut Vec<Line>) -> bool {
+    let mut removed = Vec::new();
+    let mut start = 0;
+    while start < lines.len() {
+        let at = instructions(lines, start, PUSH_POP.len()).filter(|at| {
+            at.iter()
+                .zip(PUSH_POP)
+                .all(|(&i, text)| kind(&lines[i].text) == kind(text))
+        });
+        let Some(at) = at else {
+            start += 1;
+            continue;
+        };
+        // A is left as the pop leaves it, on the word above the stack
+        lines[at[0]].text = "@SP".to_string();
+        lines[at[1]].text = "A=M".to_string();
+        removed.extend_from_slice(&at[2..]);
+        start = at[PUSH_POP.len() - 1] + 1;
+    }
+    remove(lines, &removed);
+    !removed.is_empty()
+}
+
+// A=M / A=A-1 to A=M-1, and the same for +1
+fn combine(lines: &mut Vec<Line>) -> bool {
+    let mut removed = Vec::new();
+    let mut start = 0;
+    while start < lines.len() {
+        let combined = instructions(lines, start, 2).and_then(|at| {
+            let (Kind::C(first), Kind::C(second)) =
+                (kind(&lines[at[0]].text), kind(&lines[at[1]].text))
+            else {
+                return None;
+            };
+            let n = ["-1", "+1"]
+                .into_iter()
+                .find(|n| first.is("A=M") && second.is(&format!("A=A{}", n)))?;
+            Some((at, n))
+        });
+        let Some((at, n)) = combined else {
+            start += 1;
+            continue;
+        };
+        lines[at[0]].text = format!("A=M{}", n);
+        removed.push(at[1]);
+        start = at[1] + 1;
+    }
+    remove(lines, &removed);
+    !removed.is_empty()
+}
+
+// what is known of A and D before each line, None after a label
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Known {
+    a: Option<String>,
+    // D holds RAM[A]
+    d_is_m: bool,
+}
+
+impl Known {
+    fn after(&self, kind: &Kind) -> Known {
+        match kind {
+            Kind::Nothing => self.clone(),
+            Kind::Label | Kind::Unknown => Known::default(),
+            Kind::A(value) if self.a.as_ref() == Some(value) => self.clone(),
+            Kind::A(value) => Known {
+                a: Some(value.clone()),
+                d_is_m: false,
+            },
+            Kind::C(c) if c.writes('A') => Known::default(),
+            Kind::C(c) => Known {
+                a: self.a.clone(),
+                d_is_m: match (c.writes('D'), c.writes('M')) {
+                    (true, true) => true,
+                    (true, false) => c.comp == "M",
+                    (false, true) => c.comp == "D",
+                    (false, false) => self.d_is_m,
+                },
+            },
+        }
+    }
+}
+
+fn known(lines: &[Line]) -> Vec<Known> {
+    let mut res = Vec::with_capacity(lines.len());
+    let mut known = Known::default();
+    for line in lines {
+        res.push(known.clone());
+        known = known.after(&kind(&line.text));
+    }
+    res
+}
+
+// @x when A is x, and D=M when D is RAM[A]
+fn remove_redundant(lines: &mut Vec<Line>) -> bool {
+    let known = known(lines);
+    let removed: Vec<usize> = lines
+        .iter()
+        .enumerate()
+        .filter(|(i, line)| match kind(&line.text) {
+            Kind::A(value) => known[*i].a.as_ref() == Some(&value),
+            Kind::C(c) => c.is("D=M") && known[*i].d_is_m,
+            _ => false,
+        })
+        .map(|(i, _)| i)
+        .collect();
+    remove(lines, &removed);
+    !removed.is_empty()
+}
+
+// whether the register is written after line i before it is read
+fn is_dead(lines: &[Line], i: usize, register: char) -> bool {
+    for line in &lines[i + 1..] {
+        match kind(&line.text) {
+            Kind::Nothing => {}
+            Kind::Label | Kind::Unknown => return false,
+            Kind::A(_) => {
+                if register == 'A' {
+                    return true;
+                }
+            }
+            Kind::C(c) => {
+                if c.reads(register) {
+                    return false;
+                }
+                if c.writes(register) {
+                    return true;
+                }
+            }
+        }
+    }
+    // the registers the program ends with
+    false
+}
+
+// whether RAM[x], x as A is before line i, is written again before any read
+fn is_dead_store(lines: &[Line], known: &[Known], i: usize) -> bool {
+    let Some(x) = &known[i].a else {
+        return false;
+    };
+    for (j, line) in lines.iter().enumerate().skip(i + 1) {
+        match kind(&line.text) {
+            Kind::Nothing | Kind::A(_) => {}
+            Kind::Label | Kind::Unknown => return false,
+            Kind::C(c) => {
+                if c.reads('M') || c.jumps() {
+                    return false;
+                }
+                if c.writes('M') && known[j].a.as_ref() == Some(x) {
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+fn remove_dead(lines: &mut Vec<Line>) -> bool {
+    let known = known(lines);
+    let mut removed = Vec::new();
+    let mut changed = false;
+    for i in 0..lines.len() {
+        match kind(&lines[i].text) {
+            Kind::A(_) if is_dead(lines, i, 'A') => removed.push(i),
+            Kind::C(mut c) if !c.jumps() => {
+                let dead: Vec<char> = c
+                    .dest
+                    .chars()
+                    .filter(|&r| match r {
+                        'M' => is_dead_store(lines, &known, i),
+                        r => is_dead(lines, i, r),
+                    })
+                    .collect();
+                if dead.is_empty() {
+                    continue;
+                }
+                c.dest.retain(|r| !dead.contains(&r));
+                if c.dest.is_empty() {
+                    removed.push(i);
+                } else {
+                    lines[i].text = c.to_text();
+                    changed = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    remove(lines, &removed);
+    changed || !removed.is_empty()
+}
+
+/**
+ * The runs of a program and its optimized version, and the words of RAM
+ * they ended with that differ: address, original, optimized.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    pub original_cycles: u64,
+    pub optimized_cycles: u64,
+    pub differences: Vec<(u16, u16, u16)>,
+}
+
+/**
+ * Runs a program and its optimized version on the simulator until they
+ * halt, every instruction of both, and compares the RAM up to the keyboard
+ * they end with. The stack above SP is left out, the words popped off it
+ * are not kept by the optimized code. The return addresses in the frames
+ * the VM calls left on the stack, and R13..R15 where the VM runtime keeps
+ * the addresses it jumps to, are the same when they are the address of
+ * the same label. Errors if either does not halt within max_cycles.
+ */
+pub fn verify_optimized(
+    original: &Program,
+    optimized: &Program,
+    max_cycles: u64,
+) -> Result<Verification, String> {
+    let run = |program: &Program, name: &str| {
+        let report = Runner::new()
+            .max_cycles(max_cycles)
+            .dump(0..RAM_END)
+            .run(&program.code);
+        match report.halted {
+            true => Ok(report),
+            false => Err(format!(
+                "the {} program did not halt in {} cycles",
+                name, max_cycles
+            )),
+        }
+    };
+    let before = run(original, "original")?;
+    let after = run(optimized, "optimized")?;
+
+    let labels = original.symbols.labels_by_address();
+    let same_label = |before: u16, after: u16| {
+        labels.get(&before).is_some_and(|names| {
+            let label = |name: &&str| optimized.symbols.label(name);
+            names.iter().any(|name| label(name) == Some(after))
+        })
+    };
+
+    let (ram, optimized_ram) = (&before.ram[0].1, &after.ram[0].1);
+    let sp = ram[0].max(STACK.start);
+    let mut label_slots = return_addresses(ram, sp);
+    label_slots.extend(13..16);
+    let differences = (0..RAM_END)
+        .filter(|addr| !(sp..STACK.end).contains(addr))
+        .map(|addr| (addr, ram[addr as usize], optimized_ram[addr as usize]))
+        .filter(|&(addr, before, after)| {
+            before != after && !(label_slots.contains(&addr) && same_label(before, after))
+        })
+        .collect();
+    Ok(Verification {
+        original_cycles: before.cycles,
+        optimized_cycles: after.cycles,
+        differences,
+    })
+}
+
+/*
+ * Where the frames below SP keep their return addresses: $CALL leaves LCL
+ * just above the return address and the caller's LCL, ARG, THIS and THAT,
+ * so the frames are found from LCL through the saved LCLs.
+ */
+fn return_addresses(ram: &[u16], sp: u16) -> Vec<u16> {
+    let mut res = Vec::new();
+    let mut lcl = ram[1];
+    while lcl >= STACK.start + 5 && lcl <= sp && !res.contains(&(lcl - 5)) {
+        res.push(lcl - 5);
+        lcl = ram[lcl as usize - 4];
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::vm::{Translator, VmFile};
+
+    fn code(source: &str) -> Vec<String> {
+        lines(&optimize(source))
+    }
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines()
+            .filter(|line| !line.trim().is_empty() && !line.trim().starts_with("//"))
+            .map(|line| line.trim().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_push_pop() {
+        // push constant 7, pop local 1
+        let source = "
+            @7
+            D=A
+            @SP
+            M=M+1
+            A=M-1
+            M=D
+            // pop
+            @SP
+            AM=M-1
+            D=M
+            @LCL
+            A=M+1
+            M=D
+        ";
+        let vm = |source| lines(&Optimizer::new().vm_stack(true).optimize(source));
+        assert_eq!(vm(source), ["@7", "D=A", "@LCL", "A=M+1", "M=D"]);
+        // push constant 1, add
+        let source = "@1\nD=A\n@SP\nM=M+1\nA=M-1\nM=D\n@SP\nAM=M-1\nD=M\nA=A-1\nM=D+M\n";
+        assert_eq!(vm(source), ["@1", "D=A", "@SP", "A=M-1", "M=D+M"]);
+
+        // SP may be anything in other code, here RAM[0] is changed
+        let source = "@SP\nM=M+1\nA=M-1\nM=D\n@SP\nAM=M-1\nD=M\n(END)\n@END\n0;JMP\n";
+        assert_eq!(code(source).len(), 10);
+        let original = assemble(source).unwrap();
+        let verify = |optimized: &str| {
+            let optimized = assemble(optimized).unwrap();
+            verify_optimized(&original, &optimized, 100)
+                .unwrap()
+                .differences
+        };
+        assert_eq!(verify(&optimize(source)), []);
+        assert_eq!(
+            verify(&Optimizer::new().vm_stack(true).optimize(source)),
+            [(0, 0xFFFF, 0)]
+        );
+    }
+
+    #[test]
+    fn test_redundant_and_dead() {
+        assert_eq!(
+            code("@R5\nM=D\n@R5\nD=M\n@R6\nM=D"),
+            ["@R5", "M=D", "@R6", "M=D"]
+        );
+        assert_eq!(code("@R1\nD=M\nD=A\nM=D"), ["@R1", "D=A", "M=D"]);
+        assert_eq!(code("@R1\nM=D\nM=0\nD=1"), ["@R1", "M=0", "D=1"]);
+        // a read of any RAM keeps the store, as A may be anything
+        assert_eq!(
+            code("@R1\nM=D\n@R2\nA=M\nD=M\n@R1\nM=D"),
+            ["@R1", "M=D", "@R2", "A=M", "D=M", "@R1", "M=D"]
+        );
+        // MD=... keeps D when only RAM is overwritten
+        assert_eq!(
+            code("@R1\nMD=M+1\nM=0\nD=D+1"),
+            ["@R1", "D=M+1", "M=0", "D=D+1"]
+        );
+        // nothing is known after a label, which may be jumped to
+        assert_eq!(
+            code("@R1\nD=M\n(L)\n@R1\nD=M\n@L\nD;JGT"),
+            ["@R1", "D=M", "(L)", "@R1", "D=M", "@L", "D;JGT"]
+        );
+        // the end of the program reads everything
+        assert_eq!(code("@R1\nD=M"), ["@R1", "D=M"]);
+        assert_eq!(code(".equ X 1\n@X"), [".equ X 1", "@X"]);
+    }
+
+    #[test]
+    fn test_numeric_jump_targets() {
+        // counts RAM[0] to 10 in a loop at ROM 4, after code that shrinks
+        let source =
+            "@R1\nM=1\n@R1\nM=1\n@R0\nM=M+1\nD=M\n@10\nD=D-A\n@4\nD;JLT\n(END)\n@END\n0;JMP\n";
+        let optimized = optimize(source);
+        assert_eq!(
+            lines(&optimized)[..5],
+            ["@R1", "M=1", "($ROM.4)", "@R0", "M=M+1"]
+        );
+        let (original, optimized) = (assemble(source).unwrap(), assemble(&optimized).unwrap());
+        assert!(optimized.code.len() < original.code.len());
+        let verification = verify_optimized(&original, &optimized, 1000).unwrap();
+        assert_eq!(verification.differences, []);
+        let report = Runner::new()
+            .max_cycles(1000)
+            .dump(0..1)
+            .run(&optimized.code);
+        assert!(report.halted);
+        assert_eq!(report.ram[0].1, [10]);
+
+        // with lines of unknown length before, the addresses are not known
+        let source = "MOV R1, #1\n@R2\n@R2\nD=M\n@2\nD;JGT\n";
+        assert_eq!(optimize(source), source);
+    }
+
+    #[test]
+    fn test_map() {
+        let (text, map) = optimize_with_map("// a\n@R1\n@R2\nM=D\n", "Prog.asm");
+        assert_eq!(text, "// a\n@R2\nM=D\n");
+        assert_eq!(map.get(2), Some(&Location::new("Prog.asm", 3)));
+    }
+
+    #[test]
+    fn test_verify() {
+        let sys = "
+            function Sys.init 2
+            push constant 10
+            pop local 0
+            label LOOP
+            push local 1
+            push local 0
+            add
+            pop local 1
+            push local 0
+            push constant 1
+            sub
+            pop local 0
+            push local 0
+            if-goto LOOP
+            push local 1
+            pop static 0
+            label END
+            goto END
+        ";
+        let asm = Translator::new()
+            .translate(&[VmFile::new("Sys", sys)])
+            .unwrap();
+        let original = assemble(&asm).unwrap();
+        let optimized = Optimizer::new().vm_stack(true).optimize(&asm);
+        let optimized = assemble(&optimized).unwrap();
+        assert!(optimized.code.len() < original.code.len());
+
+        let verification = verify_optimized(&original, &optimized, 100_000).unwrap();
+        assert_eq!(verification.differences, []);
+        assert!(verification.optimized_cycles < verification.original_cycles);
+        let sum = optimized.symbols.variable("Sys.0").unwrap();
+        let report = Runner::new().dump(sum..sum + 1).run(&optimized.code);
+        assert_eq!(report.ram[0].1, [55]);
+
+        // a wrong optimization shows
+        let broken = assemble(&asm.replace("M=D+M", "M=D")).unwrap();
+        let verification = verify_optimized(&original, &broken, 100_000).unwrap();
+        assert!(!verification.differences.is_empty());
+
+        // only return addresses in frames may differ for being labels
+        let source = "@R1\n@L\nD=A\n@R5\nM=D\n(L)\n@L\n0;JMP\n";
+        let (original, optimized) = (assemble(source), assemble(&optimize(source)));
+        let verification = verify_optimized(&original.unwrap(), &optimized.unwrap(), 100);
+        assert_eq!(verification.unwrap().differences, [(5, 5, 4)]);
+    }
+}
@@ -0,0 +1,588 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::is_symbol;
+
+// how deep macros may expand inside macros, and files include files
+const MAX_DEPTH: usize = 32;
+
+const JUMPS: [&str; 7] = ["JMP", "JGT", "JEQ", "JGE", "JLT", "JNE", "JLE"];
+
+const PSEUDO: [&str; 5] = ["MOV", "PUSH", "POP", "CALL", "RET"];
+
+/*
+ * A line of plain Hack assembly, with the line of the source it comes
+ * from: the line itself, the macro use or pseudo-instruction it was
+ * expanded from, or the .include of the file it is in.
+ */
+pub(super) struct Line {
+    pub line: usize,
+    pub text: String,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+}
+
+// a MOV, PUSH or POP operand
+enum Operand {
+    D,
+    A,
+    M,           // RAM[A]
+    Imm(u16),    // #value
+    Mem(String), // RAM[x], as the @x to load it
+}
+
+/*
+ * Expands the extensions of Assembler to plain Hack assembly, the code
+ * preloading RAM first. Errors give the line of the source.
+ */
+pub(super) fn expand(source: &str, include_dir: &Path) -> Result<Vec<Line>, (usize, String)> {
+    let mut preprocessor = Preprocessor {
+        include_dir,
+        constants: HashMap::new(),
+        macros: HashMap::new(),
+        expansions: 0,
+        includes: Vec::new(),
+        out: Vec::new(),
+        data: Vec::new(),
+    };
+    preprocessor.source(source, None)?;
+
+    let mut lines = std::mem::take(&mut preprocessor.data);
+    lines.append(&mut preprocessor.out);
+    Ok(lines)
+}
+
+struct Preprocessor<'a> {
+    include_dir: &'a Path,
+    constants: HashMap<String, u16>,
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    includes: Vec<PathBuf>,
+    out: Vec<Line>,
+    // the code preloading RAM, which runs first
+    data: Vec<Line>,
+}
+
+impl Preprocessor<'_> {
+    /*
+     * The lines of a file: the main one, or an included one whose lines
+     * all come from the .include at the given line of the main file.
+     */
+    fn source(&mut self, source: &str, included_at: Option<usize>) -> Result<(), (usize, String)> {
+        let mut defining: Option<(usize, String, Macro)> = None;
+
+        for (i, text) in source.lines().enumerate() {
+            let text = text.split("//").next().unwrap().trim();
+            let at = included_at.unwrap_or(i + 1);
+            let error = |message: String| (i + 1, message);
+
+            if let Some((start, name, mut body)) = defining.take() {
+                match text.split_whitespace().next() {
+                    Some(".endm") => {
+                        self.macros.insert(name, body);
+                    }
+                    Some(".macro") => return Err(error("a .macro inside a .macro".to_string())),
+                    _ => {
+                        body.body.push(text.to_string());
+                        defining = Some((start, name, body));
+                    }
+                }
+                continue;
+            }
+
+            match text.split_once(char::is_whitespace) {
+                Some((".macro", rest)) => {
+                    let (name, params) = rest
+                        .trim()
+                        .split_once(char::is_whitespace)
+                        .unwrap_or((rest.trim(), ""));
+                    self.check_new(name).map_err(error)?;
+                    let params = split_args(params);
+                    if params.iter().any(|p| !is_symbol(p)) {
+                        return Err(error(format!("invalid parameters '{}'", rest.trim())));
+                    }
+                    let body = Macro {
+                        params: params.iter().map(|p| p.to_string()).collect(),
+                        body: Vec::new(),
+                    };
+                    defining = Some((i + 1, name.to_string(), body));
+                }
+                _ if text == ".macro" => return Err(error(".macro needs a name".to_string())),
+                _ if text == ".endm" => return Err(error(".endm without a .macro".to_string())),
+                _ => self.line(text, at, 0).map_err(error)?,
+            }
+        }
+
+        match defining {
+            Some((line, name, _)) => Err((line, format!("macro '{}' has no .endm", name))),
+            None => Ok(()),
+        }
+    }
+
+    fn emit(&mut self, at: usize, lines: &[&str]) {
+        for text in lines {
+            self.out.push(Line {
+                line: at,
+                text: text.to_string(),
+            });
+        }
+    }
+
+    // a name for a constant or macro, which must not be taken
+    fn check_new(&self, name: &str) -> Result<(), String> {
+        if !is_symbol(name) {
+            return Err(format!("invalid name '{}'", name));
+        }
+        let predefined = super::SymbolTable::new().variable(name).is_some()
+            || PSEUDO.contains(&name)
+            || JUMPS.contains(&name);
+        if predefined || self.constants.contains_key(name) || self.macros.contains_key(name) {
+            return Err(format!("'{}' is already defined", name));
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, text: &str, at: usize, depth: usize) -> Result<(), String> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        let (word, rest) = match text.split_once(char::is_whitespace) {
+            Some((word, rest)) => (word, rest.trim()),
+            None => (text, ""),
+        };
+
+        match (word, rest) {
+            (".equ", rest) => {
+                let (name, value) = rest
+                    .split_once(char::is_whitespace)
+                    .ok_or(".equ takes a name and a value")?;
+                self.check_new(name)?;
+                let value = self.value(value.trim())?;
+                self.constants.insert(name.to_string(), value);
+            }
+            (".include", rest) => self.include(rest, at)?,
+            (".data", rest) => {
+                let (addr, values) = rest
+                    .split_once(char::is_whitespace)
+                    .ok_or(".data takes an address and values")?;
+                let addr = self.address(addr)?;
+                let values = split_args(values)
+                    .iter()
+                    .map(|v| self.value(v))
+                    .collect::<Result<Vec<u16>, String>>()?;
+                self.preload(addr, &values, at)?;
+            }
+            (".fill", rest) => {
+                let args: Vec<&str> = rest.split_whitespace().collect();
+                let [addr, count, value] = args[..] else {
+                    return Err(".fill takes an address, a count and a value".to_string());
+                };
+                let addr = self.address(addr)?;
+                let count = self.value(count)?;
+                let value = self.value(value)?;
+                self.preload(addr, &vec![value; count as usize], at)?;
+            }
+            (directive, _) if directive.starts_with('.') => {
+                return Err(format!("unknown directive '{}'", directive))
+            }
+            (name, args) if self.macros.contains_key(name) => self.expand(name, args, at, depth)?,
+            ("MOV", args) => match split_args(args)[..] {
+                [dst, src] => {
+                    let (dst, src) = (self.operand(dst)?, self.operand(src)?);
+                    if matches!(dst, Operand::M) && matches!(src, Operand::Imm(_) | Operand::Mem(_))
+                    {
+                        return Err("MOV into M loses A to its source".to_string());
+                    }
+                    self.load(&src, at);
+                    self.store(&dst, at)?;
+                }
+                _ => return Err("MOV takes a destination and a source".to_string()),
+            },
+            ("PUSH", src) if !src.is_empty() => {
+                let src = self.operand(src)?;
+                self.load(&src, at);
+                self.emit(at, &["@SP", "M=M+1", "A=M-1", "M=D"]);
+            }
+            ("POP", dst) if !dst.is_empty() => {
+                let dst = self.operand(dst)?;
+                if matches!(dst, Operand::M) {
+                    return Err("POP into M loses A to the stack".to_string());
+                }
+                self.emit(at, &["@SP", "AM=M-1", "D=M"]);
+                self.store(&dst, at)?;
+            }
+            ("CALL", target) if !target.is_empty() => {
+                self.expansions += 1;
+                let ret = format!("$RET.{}", self.expansions);
+                self.emit(at, &[&format!("@{}", ret), "D=A"]);
+                self.emit(at, &["@SP", "M=M+1", "A=M-1", "M=D"]);
+                let target = self.symbol(target)?;
+                self.emit(at, &[&target, "0;JMP", &format!("({})", ret)]);
+            }
+            ("RET", "") => self.emit(at, &["@SP", "AM=M-1", "A=M", "0;JMP"]),
+            (jump, target) if JUMPS.contains(&jump) && !target.is_empty() => {
+                let target = self.symbol(target)?;
+                let comp = if jump == "JMP" { "0" } else { "D" };
+                self.emit(at, &[&target, &format!("{};{}", comp, jump)]);
+            }
+            _ => {
+                if let Some(value) = text.strip_prefix('@') {
+                    let value = self.symbol(value)?;
+                    self.emit(at, &[&value]);
+                    return Ok(());
+                }
+                let label = text.strip_prefix('(').and_then(|l| l.strip_suffix(')'));
+                if label.is_some_and(|label| self.constants.contains_key(label)) {
+                    return Err(format!("'{}' is already defined", label.unwrap()));
+                }
+                self.emit(at, &[text]);
+            }
+        }
+        Ok(())
+    }
+
+    fn include(&mut self, name: &str, at: usize) -> Result<(), String> {
+        let name = name
+            .strip_prefix('"')
+            .and_then(|n| n.strip_suffix('"'))
+            .ok_or(".include takes a \"file\"")?;
+        let path = self.include_dir.join(name);
+        if self.includes.contains(&path) {
+            return Err(format!("'{}' includes itself", name));
+        }
+        if self.includes.len() >= MAX_DEPTH {
+            return Err(format!("'{}' is included too deep", name));
+        }
+        let source = fs::read_to_string(&path).map_err(|e| format!("{}: {}", name, e))?;
+
+        self.includes.push(path);
+        let res = self.source(&source, Some(at));
+        self.includes.pop();
+        res.map_err(|(line, message)| format!("{}:{}: {}", name, line, message))
+    }
+
+    fn expand(&mut self, name: &str, args: &str, at: usize, depth: usize) -> Result<(), String> {
+        if depth >= MAX_DEPTH {
+            return Err(format!("macro '{}' expands too deep", name));
+        }
+        let args = split_args(args);
+        let Macro { params, body } = &self.macros[name];
+        if args.len() != params.len() {
+            let plural = if params.len() == 1 { "" } else { "s" };
+            return Err(format!(
+                "macro '{}' takes {} argument{}, not {}",
+                name,
+                params.len(),
+                plural,
+                args.len()
+            ));
+        }
+
+        let expansion = (self.expansions + 1).to_string();
+        // longer names first, so %ab is not taken for %a followed by b
+        let mut params: Vec<(&String, &str)> = params.iter().zip(args).collect();
+        params.sort_by_key(|(param, _)| std::cmp::Reverse(param.len()));
+        let lines: Vec<String> = body
+            .iter()
+            .map(|line| {
+                let mut line = line.replace("%@", &expansion);
+                for (param, arg) in &params {
+                    line = line.replace(&format!("%{}", param), arg);
+                }
+                line
+            })
+            .collect();
+
+        self.expansions += 1;
+        // errors name the macro used, not the ones it uses
+        for line in lines {
+            self.line(&line, at, depth + 1).map_err(|e| match depth {
+                0 => format!("in macro '{}': {}", name, e),
+                _ => e,
+            })?;
+        }
+        Ok(())
+    }
+
+    // a number, decimal, 0x hex or negative, or a constant, as a 16-bit word
+    fn value(&self, text: &str) -> Result<u16, String> {
+        if let Some(&value) = self.constants.get(text) {
+            return Ok(value);
+        }
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text),
+        };
+        let value = match digits.strip_prefix("0x") {
+            Some(hex) => i32::from_str_radix(hex, 16),
+            None => digits.parse(),
+        }
+        .ok()
+        .filter(|&value| value <= 0xFFFF)
+        .ok_or_else(|| format!("'{}' is not a number or constant", text))?;
+        match negative {
+            true if value <= 0x8000 => Ok((value as u16).wrapping_neg()),
+            true => Err(format!("'{}' is not a 16-bit number", text)),
+            false => Ok(value as u16),
+        }
+    }
+
+    fn address(&self, text: &str) -> Result<u16, String> {
+        match self.value(text)? {
+            addr if addr <= 0x7FFF => Ok(addr),
+            _ => Err(format!("address '{}' is not in 0..32767", text)),
+        }
+    }
+
+    // @x for a constant, number or symbol, the assembler checking the last two
+    fn symbol(&self, text: &str) -> Result<String, String> {
+        match self.constants.get(text) {
+            Some(&value) if value > 0x7FFF => Err(format!(
+                "constant '{}' = {} does not fit an A-instruction",
+                text, value as i16
+            )),
+            Some(value) => Ok(format!("@{}", value)),
+            None => Ok(format!("@{}", text)),
+        }
+    }
+
+    fn operand(&self, text: &str) -> Result<Operand, String> {
+        let operand = match text {
+            "D" => Operand::D,
+            "A" => Operand::A,
+            "M" => Operand::M,
+            _ => match text.strip_prefix('#') {
+                Some(value) => Operand::Imm(self.value(value)?),
+                None => Operand::Mem(self.symbol(text)?),
+            },
+        };
+        Ok(operand)
+    }
+
+    // D = the operand
+    fn load(&mut self, src: &Operand, at: usize) {
+        match src {
+            Operand::D => {}
+            Operand::A => self.emit(at, &["D=A"]),
+            Operand::M => self.emit(at, &["D=M"]),
+            // values with the top bit set as the complement of one without
+            Operand::Imm(value) if *value <= 0x7FFF => {
+                self.emit(at, &[&format!("@{}", value), "D=A"])
+            }
+            Operand::Imm(value) => self.emit(at, &[&format!("@{}", !value), "D=!A"]),
+            Operand::Mem(a) => self.emit(at, &[a, "D=M"]),
+        }
+    }
+
+    // the operand = D
+    fn store(&mut self, dst: &Operand, at: usize) -> Result<(), String> {
+        match dst {
+            Operand::D => {}
+            Operand::A => self.emit(at, &["A=D"]),
+            Operand::M => self.emit(at, &["M=D"]),
+            Operand::Imm(_) => return Err("cannot move into a #value".to_string()),
+            Operand::Mem(a) => self.emit(at, &[a, "M=D"]),
+        }
+        Ok(())
+    }
+
+    fn preload(&mut self, addr: u16, values: &[u16], at: usize) -> Result<(), String> {
+        if addr as usize + values.len() > 0x8000 {
+            return Err("data runs past the end of RAM".to_string());
+        }
+        // generated into out, then moved to the code that runs first
+        let start = self.out.len();
+        for (i, value) in values.iter().enumerate() {
+            self.load(&Operand::Imm(*value), at);
+            self.emit(at, &[&format!("@{}", addr as usize + i), "M=D"]);
+        }
+        let lines = self.out.split_off(start);
+        self.data.extend(lines);
+        Ok(())
+    }
+}
+
+// a, b, c: empty for nothing
+fn split_args(text: &str) -> Vec<&str> {
+    if text.trim().is_empty() {
+        return Vec::new();
+    }
+    text.split(',').map(|arg| arg.trim()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::{assemble, Assembler};
+    use crate::runner::Runner;
+
+    fn error(source: &str) -> String {
+        assemble(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_macros() {
+        let extended = "
+.equ COUNT 3
+.macro INC x
+    @%x
+    M=M+1
+.endm
+.macro WAIT n
+    MOV R13, #%n
+(WAIT%@)
+    @R13
+    MD=M-1
+    JGT WAIT%@
+.endm
+    INC R0
+    WAIT COUNT
+    WAIT 0x2
+    JMP 0
+";
+        let plain = "
+    @R0
+    M=M+1
+    @3
+    D=A
+    @R13
+    M=D
+(WAIT2)
+    @R13
+    MD=M-1
+    @WAIT2
+    D;JGT
+    @2
+    D=A
+    @R13
+    M=D
+(WAIT3)
+    @R13
+    MD=M-1
+    @WAIT3
+    D;JGT
+    @0
+    0;JMP
+";
+        let program = assemble(extended).unwrap();
+        assert_eq!(program.code, assemble(plain).unwrap().code);
+        assert_eq!(program.lines[..3], [14, 14, 15]);
+        assert_eq!(program.symbols.label("WAIT3"), Some(14));
+    }
+
+    #[test]
+    fn test_stack_and_data() {
+        let source = "
+.equ STACK 256
+.data 100 5, -1, 0x10
+.fill 200 3 7
+    MOV SP, #STACK
+    PUSH 100
+    CALL DOUBLE
+    POP 300
+    MOV 301, 101
+    MOV D, #-2
+    MOV 302, D
+(END)
+    JMP END
+// doubles the word under the return address
+(DOUBLE)
+    POP R14
+    POP R15
+    MOV D, R15
+    @R15
+    D=D+M
+    PUSH D
+    PUSH R14
+    RET
+";
+        let program = assemble(source).unwrap();
+        // the preloading runs first
+        assert_eq!(program.lines[0], 3);
+        let report = Runner::new()
+            .max_cycles(1000)
+            .dump(0..1)
+            .dump(100..103)
+            .dump(200..203)
+            .dump(300..303)
+            .run(&program.code);
+        assert!(report.halted);
+        let words: Vec<&[u16]> = report.ram.iter().map(|(_, w)| w.as_slice()).collect();
+        assert_eq!(
+            words,
+            [
+                &[256][..],
+                &[5, 0xFFFF, 16],
+                &[7, 7, 7],
+                &[10, 0xFFFF, 0xFFFE]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("rcpu-include-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("lib.asm"),
+            ".macro ZERO x\n    MOV %x, #0\n.endm\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("loop.asm"), "@1\n.include \"loop.asm\"\n").unwrap();
+
+        let assembler = Assembler::new().include_dir(&dir);
+        let program = assembler
+            .assemble("@5\n.include \"lib.asm\"\nZERO R1\n")
+            .unwrap();
+        assert_eq!(
+            program.code,
+            assemble("@5\n@0\nD=A\n@R1\nM=D").unwrap().code
+        );
+        let err = assembler.assemble("\n.include \"loop.asm\"").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: loop.asm:2: 'loop.asm' includes itself"
+        );
+        let err = assembler.assemble(".include \"none.asm\"").unwrap_err();
+        assert!(err.message.starts_with("none.asm: "), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error(".endm"), "line 1: .endm without a .macro");
+        assert_eq!(error("\n.macro M\n@1\n"), "line 2: macro 'M' has no .endm");
+        assert_eq!(
+            error(".equ X 1\n.equ X 2"),
+            "line 2: 'X' is already defined"
+        );
+        assert_eq!(error(".equ SP 1"), "line 1: 'SP' is already defined");
+        assert_eq!(
+            error(".equ N -1\n@N"),
+            "line 2: constant 'N' = -1 does not fit an A-instruction"
+        );
+        assert_eq!(
+            error(".macro M a\n.endm\nM 1, 2"),
+            "line 3: macro 'M' takes 1 argument, not 2"
+        );
+        assert_eq!(
+            error(".macro R\nR\n.endm\nR"),
+            "line 4: in macro 'R': macro 'R' expands too deep"
+        );
+        assert_eq!(error(".macro M\nD=X\n.endm\nM"), "line 4: unknown comp 'X'");
+        assert_eq!(error("MOV #1, D"), "line 1: cannot move into a #value");
+        assert_eq!(error("POP M"), "line 1: POP into M loses A to the stack");
+        assert_eq!(error(".bogus 1"), "line 1: unknown directive '.bogus'");
+        assert_eq!(
+            error(".data 32767 1, 2"),
+            "line 1: data runs past the end of RAM"
+        );
+        assert_eq!(
+            error(".data 0 70000"),
+            "line 1: '70000' is not a number or constant"
+        );
+    }
+}
@@ -0,0 +1,535 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::OnceLock;
+
+use crate::asm::{assemble, Program, SymbolTable};
+use crate::computer::Computer;
+use crate::jack::os::{screen_color, vm_files};
+use crate::screen::SCREEN_ADDRESS;
+use crate::vm::{Translator, VmFile};
+
+const SP: u16 = 0;
+const LCL: u16 = 1;
+const ARG: u16 = 2;
+const THIS: u16 = 3;
+const THAT: u16 = 4;
+
+/**
+ * Data memory a builtin reads and writes, the screen included.
+ */
+pub trait Ram {
+    fn peek(&self, addr: u16) -> u16;
+    fn poke(&mut self, addr: u16, value: u16);
+}
+
+impl Ram for Computer {
+    fn peek(&self, addr: u16) -> u16 {
+        self.ram(addr)
+    }
+
+    fn poke(&mut self, addr: u16, value: u16) {
+        Computer::poke(self, addr, value);
+    }
+}
+
+/**
+ * OS functions that can run natively instead of through their Jack code.
+ * Each leaves the RAM as the Jack code does, apart from the stack above SP
+ * and the temp segment, and returns what it returns.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    Multiply,
+    Divide,
+    Sqrt,
+    ClearScreen,
+    DrawPixel,
+    DrawLine,
+    DrawRectangle,
+    DrawCircle,
+}
+
+const BUILTINS: [(&str, Builtin, usize); 8] = [
+    ("Math.multiply", Builtin::Multiply, 2),
+    ("Math.divide", Builtin::Divide, 2),
+    ("Math.sqrt", Builtin::Sqrt, 1),
+    ("Screen.clearScreen", Builtin::ClearScreen, 0),
+    ("Screen.drawPixel", Builtin::DrawPixel, 2),
+    ("Screen.drawLine", Builtin::DrawLine, 4),
+    ("Screen.drawRectangle", Builtin::DrawRectangle, 4),
+    ("Screen.drawCircle", Builtin::DrawCircle, 3),
+];
+
+impl Builtin {
+    pub fn from_name(name: &str) -> Option<Self> {
+        BUILTINS
+            .iter()
+            .find(|(n, ..)| *n == name)
+            .map(|(_, b, _)| *b)
+    }
+
+    pub fn name(self) -> &'static str {
+        BUILTINS.iter().find(|(_, b, _)| *b == self).unwrap().0
+    }
+
+    pub fn args(self) -> usize {
+        BUILTINS.iter().find(|(_, b, _)| *b == self).unwrap().2
+    }
+
+    /**
+     * Runs the function on its arguments. color is the value of Screen's
+     * color static, None if there is none. Returns None, leaving the RAM
+     * alone, where the Jack code has to run instead: for the arguments it
+     * reports with Sys.error, the Math quirks of -32768, and drawing
+     * without a known color.
+     */
+    pub fn call(self, args: &[u16], ram: &mut impl Ram, color: Option<u16>) -> Option<u16> {
+        assert_eq!(args.len(), self.args());
+        let arg = |i: usize| args[i] as i16 as i32;
+        let on_screen = |x: i32, y: i32| (0..512).contains(&x) && (0..256).contains(&y);
+
+        match self {
+            Builtin::Multiply => return Some(args[0].wrapping_mul(args[1])),
+            Builtin::Divide => {
+                let (x, y) = (arg(0), arg(1));
+                if y == 0 || x == -32768 || y == -32768 {
+                    return None;
+                }
+                return Some((x / y) as u16);
+            }
+            Builtin::Sqrt => {
+                let x = arg(0);
+                if x < 0 {
+                    return None;
+                }
+                return Some(isqrt(x) as u16);
+            }
+            _ => {}
+        }
+
+        // if (color) takes the then branch for true, -1, only
+        let mut screen = Screen {
+            ram,
+            color: color? == 0xFFFF,
+        };
+        match self {
+            Builtin::ClearScreen => {
+                for i in 0..8192 {
+                    screen.ram.poke(SCREEN_ADDRESS + i, 0);
+                }
+            }
+            Builtin::DrawPixel => {
+                if !on_screen(arg(0), arg(1)) {
+                    return None;
+                }
+                screen.pixel(arg(0), arg(1));
+            }
+            Builtin::DrawLine => {
+                if !on_screen(arg(0), arg(1)) || !on_screen(arg(2), arg(3)) {
+                    return None;
+                }
+                screen.line(arg(0), arg(1), arg(2), arg(3));
+            }
+            Builtin::DrawRectangle => {
+                let (x1, y1, x2, y2) = (arg(0), arg(1), arg(2), arg(3));
+                if x1 > x2 || y1 > y2 || !on_screen(x1, y1) || !on_screen(x2, y2) {
+                    return None;
+                }
+                for y in y1..=y2 {
+                    screen.horizontal(x1, x2, y);
+                }
+            }
+            Builtin::DrawCircle => {
+                let (x, y, r) = (arg(0), arg(1), arg(2));
+                if !on_screen(x, y) || !(0..=181).contains(&r) {
+                    return None;
+                }
+                for dy in -r..=r {
+                    if (0..256).contains(&(y + dy)) {
+                        let half = isqrt(r * r - dy * dy);
+                        screen.horizontal((x - half).max(0), (x + half).min(511), y + dy);
+                    }
+                }
+            }
+            Builtin::Multiply | Builtin::Divide | Builtin::Sqrt => unreachable!(),
+        }
+        // the drawing functions are void
+        Some(0)
+    }
+}
+
+fn isqrt(x: i32) -> i32 {
+    let mut y = 0;
+    while (y + 1) * (y + 1) <= x {
+        y += 1;
+    }
+    y
+}
+
+// the pixels Screen.jack draws, in the same order
+struct Screen<'a, R: Ram> {
+    ram: &'a mut R,
+    color: bool,
+}
+
+impl<R: Ram> Screen<'_, R> {
+    fn pixel(&mut self, x: i32, y: i32) {
+        let addr = SCREEN_ADDRESS + (y * 32 + x / 16) as u16;
+        let mask = 1 << (x & 15);
+        let word = self.ram.peek(addr);
+        let word = if self.color {
+            word | mask
+        } else {
+            word & !mask
+        };
+        self.ram.poke(addr, word);
+    }
+
+    fn horizontal(&mut self, x1: i32, x2: i32, y: i32) {
+        for x in x1..=x2 {
+            self.pixel(x, y);
+        }
+    }
+
+    fn line(&mut self, mut x1: i32, mut y1: i32, mut x2: i32, mut y2: i32) {
+        if y1 == y2 {
+            self.horizontal(x1.min(x2), x1.max(x2), y1);
+            return;
+        }
+        if x1 > x2 {
+            (x1, y1, x2, y2) = (x2, y2, x1, y1);
+        }
+        let dx = x2 - x1;
+        let (dy, step) = if y2 < y1 { (y1 - y2, -1) } else { (y2 - y1, 1) };
+
+        let (mut a, mut b, mut diff) = (0, 0, 0);
+        while a <= dx && b <= dy {
+            self.pixel(x1 + a, y1 + b * step);
+            if diff < 0 {
+                a += 1;
+                diff += dy;
+            } else {
+                b += 1;
+                diff -= dx;
+            }
+        }
+    }
+}
+
+/**
+ * Intercepts calls of the builtins in a translated and assembled program:
+ * when the PC reaches the ROM address of an accelerated function, the
+ * function runs natively and returns to its caller as $RETURN would,
+ * without the CPU executing a single instruction of it. Only functions
+ * of the bundled OS are accelerated, a program's own versions run as
+ * they are.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Accelerator {
+    entries: HashMap<u16, Builtin>,
+    color: Option<u16>,
+}
+
+impl Accelerator {
+    // the builtins whose function labels the program has, with the OS code
+    pub fn new(code: &[u16], symbols: &SymbolTable) -> Self {
+        let os = bundled_os();
+        let (os_symbols, program) = (
+            Symbols::new(&os.code, &os.symbols),
+            Symbols::new(code, symbols),
+        );
+        let entries: HashMap<u16, Builtin> = BUILTINS
+            .iter()
+            .filter_map(|(name, builtin, _)| {
+                let start = symbols.label(name)?;
+                let bundled = Function::new(os, os.symbols.label(name)?);
+                bundled
+                    .is_at(&os_symbols, &program, start)
+                    .then_some((start, *builtin))
+            })
+            .collect();
+        let draws = entries.values().any(|b| b.name().starts_with("Screen."));
+        Accelerator {
+            entries,
+            color: symbols.variable(&screen_color()).filter(|_| draws),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /**
+     * Runs the function the PC is at the entry of, if it is accelerated,
+     * and returns whether it did.
+     */
+    pub fn intercept(&self, computer: &mut Computer) -> bool {
+        let Some(&builtin) = self.entries.get(&computer.cpu().pc()) else {
+            return false;
+        };
+
+        // the frame $CALL built: the arguments from ARG, LCL just above
+        // the return address and the caller's LCL, ARG, THIS and THAT
+        let (arg, frame) = (computer.ram(ARG), computer.ram(LCL));
+        let args: Vec<u16> = (0..builtin.args() as u16)
+            .map(|i| computer.ram(arg.wrapping_add(i)))
+            .collect();
+        let color = self.color.map(|addr| computer.ram(addr));
+        let Some(result) = builtin.call(&args, computer, color) else {
+            return false;
+        };
+
+        let saved = |i: u16| computer.ram(frame.wrapping_sub(i));
+        let (return_address, lcl, arg_saved, this, that) =
+            (saved(5), saved(4), saved(3), saved(2), saved(1));
+        computer.poke(arg, result);
+        computer.poke(SP, arg.wrapping_add(1));
+        computer.poke(THAT, that);
+        computer.poke(THIS, this);
+        computer.poke(ARG, arg_saved);
+        computer.poke(LCL, lcl);
+        computer.poke_pc(return_address);
+        true
+    }
+}
+
+// the OS translated and assembled on its own, what its functions look like
+fn bundled_os() -> &'static Program {
+    static PROGRAM: OnceLock<Program> = OnceLock::new();
+    PROGRAM.get_or_init(|| {
+        let mut files = vm_files();
+        files.push(VmFile::new(
+            "Main",
+            "function Main.main 0\npush constant 0\nreturn\n",
+        ));
+        let asm = Translator::new().translate(&files).unwrap();
+        assemble(&asm).unwrap()
+    })
+}
+
+// the ROM of a function of the bundled OS, up to the next function
+struct Function {
+    start: u16,
+    end: u16,
+}
+
+impl Function {
+    fn new(os: &Program, start: u16) -> Self {
+        // function labels are the ones without a '$'
+        let end = os
+            .symbols
+            .labels_by_address()
+            .range(start + 1..)
+            .find(|(_, names)| names.iter().any(|name| !name.contains('$')))
+            .map_or(os.code.len() as u16, |(&addr, _)| addr);
+        Function { start, end }
+    }
+
+    /*
+     * Whether the program has the function at start: the same words, but
+     * for addresses, which may be of the same symbols or of the same place
+     * in the function.
+     */
+    fn is_at(&self, os: &Symbols, program: &Symbols, start: u16) -> bool {
+        let len = (self.end - self.start) as usize;
+        let Some(words) = program.code.get(start as usize..start as usize + len) else {
+            return false;
+        };
+        let body = self.start..self.end;
+        (self.start..self.end).zip(words).all(|(addr, &word)| {
+            let expected = os.code[addr as usize];
+            if word == expected {
+                return true;
+            }
+            if expected & 0x8000 != 0 || word & 0x8000 != 0 {
+                return false;
+            }
+            let same_place =
+                body.contains(&expected) && expected - self.start == word.wrapping_sub(start);
+            let same_name = match (os.names.get(&expected), program.names.get(&word)) {
+                (Some(expected), Some(found)) => found.iter().any(|name| expected.contains(name)),
+                _ => false,
+            };
+            same_place || same_name
+        })
+    }
+}
+
+// a program's code with the labels and variables at each address
+struct Symbols<'a> {
+    code: &'a [u16],
+    names: BTreeMap<u16, Vec<&'a str>>,
+}
+
+impl<'a> Symbols<'a> {
+    fn new(code: &'a [u16], symbols: &'a SymbolTable) -> Self {
+        let mut names = symbols.labels_by_address();
+        for (addr, name) in symbols.variables_by_address() {
+            names.entry(addr).or_default().push(name);
+        }
+        Symbols { code, names }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::emulator::VmEmulator;
+    use crate::jack::os::link;
+    use crate::jack::{compile, parse};
+    use crate::runner::Runner;
+    use crate::vm::{Translator, VmFile};
+
+    struct Words(Vec<u16>);
+
+    impl Ram for Words {
+        fn peek(&self, addr: u16) -> u16 {
+            self.0[addr as usize]
+        }
+
+        fn poke(&mut self, addr: u16, value: u16) {
+            self.0[addr as usize] = value;
+        }
+    }
+
+    #[test]
+    fn test_math() {
+        let mut ram = Words(vec![0; 0x6000]);
+        let mut call = |builtin: Builtin, args: &[i16]| {
+            let args: Vec<u16> = args.iter().map(|&a| a as u16).collect();
+            builtin.call(&args, &mut ram, None).map(|r| r as i16)
+        };
+        assert_eq!(call(Builtin::Multiply, &[-300, 7]), Some(-2100));
+        assert_eq!(call(Builtin::Multiply, &[300, 300]), Some(90000u32 as i16));
+        assert_eq!(call(Builtin::Divide, &[-7, 2]), Some(-3));
+        assert_eq!(call(Builtin::Divide, &[1, 0]), None);
+        assert_eq!(call(Builtin::Divide, &[-32768, 2]), None);
+        assert_eq!(call(Builtin::Sqrt, &[32767]), Some(181));
+        assert_eq!(call(Builtin::Sqrt, &[-1]), None);
+        // no color, no drawing
+        assert_eq!(call(Builtin::DrawPixel, &[0, 0]), None);
+        assert_eq!(
+            Builtin::from_name("Screen.drawLine"),
+            Some(Builtin::DrawLine)
+        );
+        assert_eq!(Builtin::DrawLine.name(), "Screen.drawLine");
+    }
+
+    #[test]
+    fn test_same_as_os() {
+        let main = "
+class Main {
+    function void main() {
+        do Screen.drawLine(10, 200, 90, 170);
+        do Screen.drawLine(300, 10, 280, 60);
+        do Screen.drawLine(0, 5, 40, 5);
+        do Screen.drawRectangle(100, 100, 130, 110);
+        do Screen.drawCircle(250, 128, 12);
+        do Screen.setColor(false);
+        do Screen.drawCircle(250, 128, 6);
+        do Screen.drawPixel(120, 110);
+        do Screen.setColor(true);
+        do Screen.drawCircle(508, 252, 9);
+        do Memory.poke(8000, 123 * -45);
+        do Memory.poke(8001, -5535 / 7);
+        do Memory.poke(8002, Math.sqrt(12345));
+        do Memory.poke(8003, 5 / 0);
+        return;
+    }
+}";
+        let mut files = vec![VmFile::new(
+            "Main",
+            &compile(&parse(main).unwrap()).unwrap(),
+        )];
+        link(&mut files);
+
+        let run = |accelerate: bool| {
+            let mut emulator = VmEmulator::new(&files, true).unwrap();
+            emulator.accelerate(accelerate);
+            emulator.run(10_000_000).unwrap();
+            assert!(emulator.halted());
+            (
+                emulator.ram()[0x4000..].to_vec(),
+                emulator.peek(8000),
+                emulator.steps(),
+            )
+        };
+        let (exact, fast) = (run(false), run(true));
+        assert!(fast.0.iter().any(|&w| w != 0));
+        assert_eq!(exact.0, fast.0);
+        assert_eq!(exact.1, fast.1);
+        assert_eq!(fast.1, (123 * -45i16) as u16);
+        assert!(fast.2 * 10 < exact.2);
+    }
+
+    #[test]
+    fn test_accelerator() {
+        // the bundled Math with the program's own Screen, which draws nothing
+        let sys = "
+function Sys.init 0
+call Math.init 0
+pop temp 0
+call Screen.init 0
+pop temp 0
+push constant 300
+push constant 7
+call Math.multiply 2
+pop static 0
+push constant 0
+push constant 0
+push constant 31
+push constant 1
+call Screen.drawRectangle 4
+pop static 1
+label END
+goto END
+function Sys.error 0
+label ERROR
+goto ERROR
+";
+        let array = "
+function Array.new 0
+push constant 3000
+return
+";
+        let screen = "
+function Screen.init 0
+push constant 0
+return
+function Screen.drawRectangle 0
+push constant 5
+return
+";
+        let math = vm_files().into_iter().find(|f| f.name == "Math").unwrap();
+        let files = [
+            VmFile::new("Sys", sys),
+            VmFile::new("Array", array),
+            math,
+            VmFile::new("Screen", screen),
+        ];
+        let program = assemble(&Translator::new().translate(&files).unwrap()).unwrap();
+        let results = program.symbols.variable("Sys.0").unwrap();
+
+        let runner = Runner::new()
+            .max_cycles(100_000)
+            .dump(results..results + 2)
+            .dump(0x4000..0x4022);
+        let exact = runner.run(&program.code);
+        let fast = runner
+            .accelerate(&program.code, &program.symbols)
+            .run(&program.code);
+        assert!(exact.halted && fast.halted);
+        assert!(fast.cycles < exact.cycles);
+        assert_eq!(exact.ram[0].1, [2100, 5]);
+        assert_eq!(fast.ram[0].1, [2100, 5]);
+        assert!(fast.ram[1].1.iter().all(|&w| w == 0));
+
+        // the same in the emulator
+        let mut emulator = VmEmulator::new(&files, true).unwrap();
+        emulator.accelerate(true);
+        emulator.run(100_000).unwrap();
+        assert!(emulator.halted());
+        assert_eq!((emulator.peek(16), emulator.peek(17)), (2100, 5));
+        assert!(emulator.ram()[0x4000..0x4022].iter().all(|&w| w == 0));
+    }
+}
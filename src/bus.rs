@@ -0,0 +1,158 @@
+use std::fmt;
+
+/**
+ * N-bit bus:
+ * A group of N wires carried together, bit 0 being the least
+ * significant one. Widths from 1 up to 64 are supported so the same
+ * chips can be instantiated for 8-, 16-, 32- and 64-bit machines.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Bus<const N: usize>(u64);
+
+pub type Bus8 = Bus<8>;
+pub type Bus16 = Bus<16>;
+pub type Bus32 = Bus<32>;
+pub type Bus64 = Bus<64>;
+
+impl<const N: usize> Bus<N> {
+    pub const WIDTH: usize = N;
+
+    const MASK: u64 = {
+        assert!(N >= 1 && N <= 64, "bus width must be between 1 and 64");
+        if N == 64 {
+            u64::MAX
+        } else {
+            (1 << N) - 1
+        }
+    };
+
+    // keeps only the low N bits of value
+    pub fn new(value: u64) -> Self {
+        Bus(value & Self::MASK)
+    }
+
+    pub fn zero() -> Self {
+        Bus(0)
+    }
+
+    pub fn ones() -> Self {
+        Bus(Self::MASK)
+    }
+
+    pub fn from_fn(mut f: impl FnMut(usize) -> bool) -> Self {
+        let mut value = 0;
+        for i in 0..N {
+            value |= (f(i) as u64) << i;
+        }
+        Bus::new(value)
+    }
+
+    pub fn value(self) -> u64 {
+        self.0
+    }
+
+    pub fn bit(self, i: usize) -> bool {
+        assert!(i < N, "bit {} out of range for a {}-bit bus", i, N);
+        (self.0 >> i) & 1 != 0
+    }
+
+    pub fn with_bit(self, i: usize, bit: bool) -> Self {
+        assert!(i < N, "bit {} out of range for a {}-bit bus", i, N);
+        Bus((self.0 & !(1 << i)) | ((bit as u64) << i))
+    }
+
+    // most significant bit, the sign bit in 2's complement
+    pub fn msb(self) -> bool {
+        self.bit(N - 1)
+    }
+}
+
+impl<const N: usize> fmt::Debug for Bus<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Bus<{}>({:0width$b})", N, self.0, width = N)
+    }
+}
+
+impl From<u8> for Bus8 {
+    fn from(value: u8) -> Self {
+        Bus::new(value as u64)
+    }
+}
+
+impl From<Bus8> for u8 {
+    fn from(value: Bus8) -> Self {
+        value.0 as u8
+    }
+}
+
+impl From<u16> for Bus16 {
+    fn from(value: u16) -> Self {
+        Bus::new(value as u64)
+    }
+}
+
+impl From<Bus16> for u16 {
+    fn from(value: Bus16) -> Self {
+        value.0 as u16
+    }
+}
+
+impl From<u32> for Bus32 {
+    fn from(value: u32) -> Self {
+        Bus::new(value as u64)
+    }
+}
+
+impl From<Bus32> for u32 {
+    fn from(value: Bus32) -> Self {
+        value.0 as u32
+    }
+}
+
+impl From<u64> for Bus64 {
+    fn from(value: u64) -> Self {
+        Bus::new(value)
+    }
+}
+
+impl From<Bus64> for u64 {
+    fn from(value: Bus64) -> Self {
+        value.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_masks_width() {
+        let bus = Bus::<4>::new(0b1111_0101);
+        assert_eq!(bus.value(), 0b0101);
+
+        let bus = Bus::<64>::new(u64::MAX);
+        assert_eq!(bus.value(), u64::MAX);
+        assert_eq!(Bus::<64>::ones().value(), u64::MAX);
+        assert_eq!(Bus::<3>::ones().value(), 0b111);
+    }
+
+    #[test]
+    fn test_bits() {
+        let bus = Bus16::from(0b1000_0000_0000_0010u16);
+        assert!(!bus.bit(0));
+        assert!(bus.bit(1));
+        assert!(bus.msb());
+
+        let bus = bus.with_bit(0, true).with_bit(15, false);
+        assert_eq!(u16::from(bus), 0b11);
+
+        let bus = Bus8::from_fn(|i| i % 2 == 0);
+        assert_eq!(u8::from(bus), 0b0101_0101);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_bit_out_of_range() {
+        Bus8::zero().bit(8);
+    }
+}
@@ -0,0 +1,1130 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/**
+ * Gate-level structure of the chips in gates.rs, alu.rs and
+ * sequential.rs, described the same way the Rust functions wire their
+ * sub-gates. A circuit is a list of part instances (graph nodes) whose
+ * input pins are fed by wires from the circuit inputs, constants or
+ * outputs of other parts (graph edges).
+ *
+ * The primitives are the gates the Rust code treats as given:
+ * and, or, not and the dff.
+ */
+pub const PRIMITIVES: [&str; 4] = ["and", "or", "not", "dff"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Node {
+    Input(usize),
+    Part(usize),
+    Const(u64),
+}
+
+/**
+ * `width` bits starting at bit `lo` of output `pin` of `node`.
+ * For constants, pin is unused and the bits are taken from the value.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signal {
+    pub node: Node,
+    pub pin: usize,
+    pub lo: usize,
+    pub width: usize,
+}
+
+/**
+ * Concatenation of signals, least significant first.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Wire(pub Vec<Signal>);
+
+impl Wire {
+    pub fn constant(value: u64, width: usize) -> Wire {
+        Wire(vec![Signal {
+            node: Node::Const(value),
+            pin: 0,
+            lo: 0,
+            width,
+        }])
+    }
+
+    pub fn width(&self) -> usize {
+        self.0.iter().map(|s| s.width).sum()
+    }
+
+    pub fn bit(&self, i: usize) -> Wire {
+        self.slice(i, i + 1)
+    }
+
+    // bits lo..hi of the wire
+    pub fn slice(&self, lo: usize, hi: usize) -> Wire {
+        assert!(lo <= hi && hi <= self.width(), "slice out of range");
+        let mut res = Vec::new();
+        let mut offset = 0;
+
+        for signal in &self.0 {
+            let start = lo.max(offset);
+            let end = hi.min(offset + signal.width);
+            if start < end {
+                res.push(Signal {
+                    lo: signal.lo + start - offset,
+                    width: end - start,
+                    ..*signal
+                });
+            }
+            offset += signal.width;
+        }
+
+        Wire(res)
+    }
+
+    // self in the low bits, high in the bits above
+    pub fn concat(mut self, high: Wire) -> Wire {
+        self.0.extend(high.0);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pin {
+    pub name: String,
+    pub width: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Part {
+    pub chip: String,
+    pub inputs: Vec<(String, Wire)>,
+    pub outputs: Vec<Pin>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Circuit {
+    pub name: String,
+    pub inputs: Vec<Pin>,
+    pub outputs: Vec<(Pin, Wire)>,
+    pub parts: Vec<Part>,
+}
+
+pub struct CircuitBuilder {
+    circuit: Circuit,
+}
+
+impl CircuitBuilder {
+    pub fn new(name: &str) -> Self {
+        CircuitBuilder {
+            circuit: Circuit {
+                name: name.to_string(),
+                inputs: Vec::new(),
+                outputs: Vec::new(),
+                parts: Vec::new(),
+            },
+        }
+    }
+
+    pub fn input(&mut self, name: &str, width: usize) -> Wire {
+        self.circuit.inputs.push(Pin {
+            name: name.to_string(),
+            width,
+        });
+        Wire(vec![Signal {
+            node: Node::Input(self.circuit.inputs.len() - 1),
+            pin: 0,
+            lo: 0,
+            width,
+        }])
+    }
+
+    // adds a part instance and returns one wire per output pin
+    pub fn part(
+        &mut self,
+        chip: &str,
+        inputs: &[(&str, Wire)],
+        outputs: &[(&str, usize)],
+    ) -> Vec<Wire> {
+        let index = self.circuit.parts.len();
+        self.circuit.parts.push(Part {
+            chip: chip.to_string(),
+            inputs: inputs
+                .iter()
+                .map(|(name, wire)| (name.to_string(), wire.clone()))
+                .collect(),
+            outputs: outputs
+                .iter()
+                .map(|(name, width)| Pin {
+                    name: name.to_string(),
+                    width: *width,
+                })
+                .collect(),
+        });
+
+        outputs
+            .iter()
+            .enumerate()
+            .map(|(pin, (_, width))| {
+                Wire(vec![Signal {
+                    node: Node::Part(index),
+                    pin,
+                    lo: 0,
+                    width: *width,
+                }])
+            })
+            .collect()
+    }
+
+    // wires an input pin of an existing part, for feedback loops
+    pub fn connect(&mut self, part: usize, pin: &str, wire: Wire) {
+        let inputs = &mut self.circuit.parts[part].inputs;
+        match inputs.iter_mut().find(|(name, _)| name == pin) {
+            Some((_, existing)) => *existing = wire,
+            None => inputs.push((pin.to_string(), wire)),
+        }
+    }
+
+    pub fn parts(&self) -> usize {
+        self.circuit.parts.len()
+    }
+
+    pub fn output(&mut self, name: &str, wire: Wire) {
+        let width = wire.width();
+        self.circuit.outputs.push((
+            Pin {
+                name: name.to_string(),
+                width,
+            },
+            wire,
+        ));
+    }
+
+    pub fn build(self) -> Circuit {
+        self.circuit
+    }
+
+    fn gate1(&mut self, chip: &str, x: Wire) -> Wire {
+        self.part(chip, &[("in", x)], &[("out", 1)]).remove(0)
+    }
+
+    fn not16(&mut self, x: Wire) -> Wire {
+        self.part("not16", &[("in", x)], &[("out", 16)]).remove(0)
+    }
+
+    fn gate2(&mut self, chip: &str, a: Wire, b: Wire) -> Wire {
+        self.part(chip, &[("a", a), ("b", b)], &[("out", 1)])
+            .remove(0)
+    }
+
+    fn mux(&mut self, chip: &str, a: Wire, b: Wire, sel: Wire) -> Wire {
+        let width = a.width();
+        self.part(chip, &[("a", a), ("b", b), ("sel", sel)], &[("out", width)])
+            .remove(0)
+    }
+
+    fn chip2(&mut self, chip: &str, inputs: &[(&str, Wire)], outputs: [&str; 2]) -> (Wire, Wire) {
+        let mut res = self.part(chip, inputs, &[(outputs[0], 1), (outputs[1], 1)]);
+        let second = res.remove(1);
+        (res.remove(0), second)
+    }
+}
+
+fn nand() -> Circuit {
+    let mut b = CircuitBuilder::new("nand");
+    let x = b.input("a", 1);
+    let y = b.input("b", 1);
+    let and = b.gate2("and", x, y);
+    let out = b.gate1("not", and);
+    b.output("out", out);
+    b.build()
+}
+
+fn xor() -> Circuit {
+    let mut b = CircuitBuilder::new("xor");
+    let x = b.input("a", 1);
+    let y = b.input("b", 1);
+    let out1 = b.gate2("nand", x.clone(), x.clone());
+    let out2 = b.gate2("nand", y.clone(), y.clone());
+    let out3 = b.gate2("nand", out1, y);
+    let out4 = b.gate2("nand", out2, x);
+    let out = b.gate2("nand", out3, out4);
+    b.output("out", out);
+    b.build()
+}
+
+fn mux() -> Circuit {
+    let mut b = CircuitBuilder::new("mux");
+    let x = b.input("a", 1);
+    let y = b.input("b", 1);
+    let sel = b.input("sel", 1);
+    let out2 = b.gate1("not", sel.clone());
+    let out3 = b.gate2("and", x, out2);
+    let out4 = b.gate2("and", y, sel);
+    let out = b.gate2("or", out3, out4);
+    b.output("out", out);
+    b.build()
+}
+
+fn dmux() -> Circuit {
+    let mut b = CircuitBuilder::new("dmux");
+    let x = b.input("in", 1);
+    let sel = b.input("sel", 1);
+    let out1 = b.gate1("not", sel.clone());
+    let a = b.gate2("and", x.clone(), out1);
+    let c = b.gate2("and", x, sel);
+    b.output("a", a);
+    b.output("b", c);
+    b.build()
+}
+
+// one 1-bit gate per bit, as not_n_gate / and_n_gate / or_n_gate / mux_n_gate
+fn bitwise16(name: &str, gate: &str) -> Circuit {
+    let mut b = CircuitBuilder::new(name);
+    let mut out = Wire::default();
+
+    match gate {
+        "not" => {
+            let x = b.input("in", 16);
+            for i in 0..16 {
+                out = out.concat(b.gate1(gate, x.bit(i)));
+            }
+        }
+        "mux" => {
+            let x = b.input("a", 16);
+            let y = b.input("b", 16);
+            let sel = b.input("sel", 1);
+            for i in 0..16 {
+                out = out.concat(b.mux(gate, x.bit(i), y.bit(i), sel.clone()));
+            }
+        }
+        _ => {
+            let x = b.input("a", 16);
+            let y = b.input("b", 16);
+            for i in 0..16 {
+                out = out.concat(b.gate2(gate, x.bit(i), y.bit(i)));
+            }
+        }
+    }
+
+    b.output("out", out);
+    b.build()
+}
+
+// balanced tree of or gates, as or_nway_gate
+fn or8way() -> Circuit {
+    let mut b = CircuitBuilder::new("or8way");
+    let x = b.input("in", 8);
+    let mut level: Vec<Wire> = (0..8).map(|i| x.bit(i)).collect();
+
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| b.gate2("or", pair[0].clone(), pair[1].clone()))
+            .collect();
+    }
+
+    b.output("out", level.remove(0));
+    b.build()
+}
+
+fn mux4way16() -> Circuit {
+    let mut b = CircuitBuilder::new("mux4way16");
+    let ins: Vec<Wire> = ["a", "b", "c", "d"]
+        .iter()
+        .map(|name| b.input(name, 16))
+        .collect();
+    let sel = b.input("sel", 2);
+    let out_1 = b.mux("mux16", ins[0].clone(), ins[1].clone(), sel.bit(0));
+    let out_2 = b.mux("mux16", ins[2].clone(), ins[3].clone(), sel.bit(0));
+    let out = b.mux("mux16", out_1, out_2, sel.bit(1));
+    b.output("out", out);
+    b.build()
+}
+
+fn mux8way16() -> Circuit {
+    let mut b = CircuitBuilder::new("mux8way16");
+    let ins: Vec<Wire> = ["a", "b", "c", "d", "e", "f", "g", "h"]
+        .iter()
+        .map(|name| b.input(name, 16))
+        .collect();
+    let sel = b.input("sel", 3);
+
+    let mut level = ins;
+    for i in 0..3 {
+        level = level
+            .chunks(2)
+            .map(|pair| b.mux("mux16", pair[0].clone(), pair[1].clone(), sel.bit(i)))
+            .collect();
+    }
+
+    b.output("out", level.remove(0));
+    b.build()
+}
+
+fn dmux4way() -> Circuit {
+    let mut b = CircuitBuilder::new("dmux4way");
+    let x = b.input("in", 1);
+    let sel = b.input("sel", 2);
+    let (out_1, out_2) = b.chip2("dmux", &[("in", x), ("sel", sel.bit(1))], ["a", "b"]);
+    let (o_a, o_b) = b.chip2("dmux", &[("in", out_1), ("sel", sel.bit(0))], ["a", "b"]);
+    let (o_c, o_d) = b.chip2("dmux", &[("in", out_2), ("sel", sel.bit(0))], ["a", "b"]);
+    for (name, wire) in [("a", o_a), ("b", o_b), ("c", o_c), ("d", o_d)] {
+        b.output(name, wire);
+    }
+    b.build()
+}
+
+fn dmux8way() -> Circuit {
+    let mut b = CircuitBuilder::new("dmux8way");
+    let x = b.input("in", 1);
+    let sel = b.input("sel", 3);
+
+    let mut level = vec![x];
+    for i in (0..3).rev() {
+        level = level
+            .into_iter()
+            .flat_map(|wire| {
+                let (lo, hi) = b.chip2("dmux", &[("in", wire), ("sel", sel.bit(i))], ["a", "b"]);
+                [lo, hi]
+            })
+            .collect();
+    }
+
+    for (name, wire) in ["a", "b", "c", "d", "e", "f", "g", "h"].iter().zip(level) {
+        b.output(name, wire);
+    }
+    b.build()
+}
+
+fn half_adder() -> Circuit {
+    let mut b = CircuitBuilder::new("half_adder");
+    let x = b.input("a", 1);
+    let y = b.input("b", 1);
+    let sum = b.gate2("xor", x.clone(), y.clone());
+    let carry = b.gate2("and", x, y);
+    b.output("sum", sum);
+    b.output("carry", carry);
+    b.build()
+}
+
+fn full_adder() -> Circuit {
+    let mut b = CircuitBuilder::new("full_adder");
+    let x = b.input("a", 1);
+    let y = b.input("b", 1);
+    let c = b.input("c", 1);
+    let (sum_1, carry_1) = b.chip2("half_adder", &[("a", x), ("b", y)], ["sum", "carry"]);
+    let (sum, carry_2) = b.chip2("half_adder", &[("a", sum_1), ("b", c)], ["sum", "carry"]);
+    let carry = b.gate2("or", carry_1, carry_2);
+    b.output("sum", sum);
+    b.output("carry", carry);
+    b.build()
+}
+
+fn add16() -> Circuit {
+    let mut b = CircuitBuilder::new("add16");
+    let x = b.input("a", 16);
+    let y = b.input("b", 16);
+    let (mut out, mut c) = b.chip2(
+        "half_adder",
+        &[("a", x.bit(0)), ("b", y.bit(0))],
+        ["sum", "carry"],
+    );
+
+    for i in 1..16 {
+        let (sum, carry) = b.chip2(
+            "full_adder",
+            &[("a", x.bit(i)), ("b", y.bit(i)), ("c", c)],
+            ["sum", "carry"],
+        );
+        out = out.concat(sum);
+        c = carry;
+    }
+
+    b.output("out", out);
+    b.build()
+}
+
+fn inc16() -> Circuit {
+    let mut b = CircuitBuilder::new("inc16");
+    let x = b.input("in", 16);
+    let out = b
+        .part(
+            "add16",
+            &[("a", x), ("b", Wire::constant(1, 16))],
+            &[("out", 16)],
+        )
+        .remove(0);
+    b.output("out", out);
+    b.build()
+}
+
+fn alu() -> Circuit {
+    let mut b = CircuitBuilder::new("alu");
+    let x = b.input("x", 16);
+    let y = b.input("y", 16);
+    let zx = b.input("zx", 1);
+    let nx = b.input("nx", 1);
+    let zy = b.input("zy", 1);
+    let ny = b.input("ny", 1);
+    let f = b.input("f", 1);
+    let no = b.input("no", 1);
+    let zero = Wire::constant(0, 16);
+
+    let zx_out = b.mux("mux16", x, zero.clone(), zx);
+    let not_zx_out = b.not16(zx_out.clone());
+    let xout_1 = b.mux("mux16", zx_out, not_zx_out, nx);
+
+    let zy_out = b.mux("mux16", y, zero, zy);
+    let not_zy_out = b.not16(zy_out.clone());
+    let yout_1 = b.mux("mux16", zy_out, not_zy_out, ny);
+
+    let xy_sum = b.part(
+        "add16",
+        &[("a", xout_1.clone()), ("b", yout_1.clone())],
+        &[("out", 16)],
+    );
+    let xy_and = b.part("and16", &[("a", xout_1), ("b", yout_1)], &[("out", 16)]);
+    let out_f = b.mux("mux16", xy_and[0].clone(), xy_sum[0].clone(), f);
+
+    let not_out_f = b.not16(out_f.clone());
+    let out = b.mux("mux16", out_f, not_out_f, no);
+
+    let tout_1 = b.gate1("or8way", out.slice(0, 8));
+    let tout_2 = b.gate1("or8way", out.slice(8, 16));
+    let tout = b.gate2("or", tout_1, tout_2);
+    let zr = b.gate1("not", tout);
+
+    b.output("out", out.clone());
+    b.output("zr", zr);
+    b.output("ng", out.bit(15));
+    b.build()
+}
+
+fn bit() -> Circuit {
+    let mut b = CircuitBuilder::new("bit");
+    let x = b.input("in", 1);
+    let load = b.input("load", 1);
+    let dff_index = b.parts();
+    let dff_out = b.part("dff", &[], &[("out", 1)]).remove(0);
+    let out_mux = b.mux("mux", dff_out.clone(), x, load);
+    b.connect(dff_index, "in", out_mux);
+    b.output("out", dff_out);
+    b.build()
+}
+
+fn register() -> Circuit {
+    let mut b = CircuitBuilder::new("register");
+    let x = b.input("in", 16);
+    let load = b.input("load", 1);
+    let mut out = Wire::default();
+
+    for i in 0..16 {
+        let bit_out = b
+            .part(
+                "bit",
+                &[("in", x.bit(i)), ("load", load.clone())],
+                &[("out", 1)],
+            )
+            .remove(0);
+        out = out.concat(bit_out);
+    }
+
+    b.output("out", out);
+    b.build()
+}
+
+// eight children selected by the high address bits, as RAM8 ... RAM4K
+fn ram(name: &str, child: &str, addr_bits: usize) -> Circuit {
+    let child_addr_bits = addr_bits - 3;
+    let mut b = CircuitBuilder::new(name);
+    let x = b.input("in", 16);
+    let load = b.input("load", 1);
+    let address = b.input("address", addr_bits);
+    let sel = address.slice(child_addr_bits, addr_bits);
+
+    let loads = b.part(
+        "dmux8way",
+        &[("in", load), ("sel", sel.clone())],
+        &[
+            ("a", 1),
+            ("b", 1),
+            ("c", 1),
+            ("d", 1),
+            ("e", 1),
+            ("f", 1),
+            ("g", 1),
+            ("h", 1),
+        ],
+    );
+
+    let mut outs = Vec::new();
+    for child_load in loads {
+        let mut inputs = vec![("in", x.clone()), ("load", child_load)];
+        if child_addr_bits > 0 {
+            inputs.push(("address", address.slice(0, child_addr_bits)));
+        }
+        outs.push(b.part(child, &inputs, &[("out", 16)]).remove(0));
+    }
+
+    let names = ["a", "b", "c", "d", "e", "f", "g", "h"];
+    let mut inputs: Vec<(&str, Wire)> = names.iter().copied().zip(outs).collect();
+    inputs.push(("sel", sel));
+    let out = b.part("mux8way16", &inputs, &[("out", 16)]).remove(0);
+
+    b.output("out", out);
+    b.build()
+}
+
+pub struct Library {
+    circuits: HashMap<String, Circuit>,
+}
+
+impl Library {
+    pub fn new() -> Self {
+        let circuits = [
+            nand(),
+            xor(),
+            mux(),
+            dmux(),
+            bitwise16("not16", "not"),
+            bitwise16("and16", "and"),
+            bitwise16("or16", "or"),
+            bitwise16("mux16", "mux"),
+            or8way(),
+            mux4way16(),
+            mux8way16(),
+            dmux4way(),
+            dmux8way(),
+            half_adder(),
+            full_adder(),
+            add16(),
+            inc16(),
+            alu(),
+            bit(),
+            register(),
+            ram("ram8", "register", 3),
+            ram("ram64", "ram8", 6),
+            ram("ram512", "ram64", 9),
+            ram("ram4k", "ram512", 12),
+        ];
+
+        Library {
+            circuits: circuits.into_iter().map(|c| (c.name.clone(), c)).collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Circuit> {
+        self.circuits.get(name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.circuits.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+
+    /**
+     * Evaluates a combinational chip, one value per output pin.
+     * Sequential chips (anything containing a dff) cannot be evaluated.
+     */
+    pub fn eval(&self, chip: &str, inputs: &[u64]) -> Result<Vec<u64>, String> {
+        match chip {
+            "and" => return Ok(vec![inputs[0] & inputs[1]]),
+            "or" => return Ok(vec![inputs[0] | inputs[1]]),
+            "not" => return Ok(vec![!inputs[0] & 1]),
+            "dff" => return Err("dff is sequential and cannot be evaluated".to_string()),
+            _ => {}
+        }
+
+        let circuit = self
+            .get(chip)
+            .ok_or_else(|| format!("unknown chip '{}'", chip))?;
+        let mut part_outputs: Vec<Vec<u64>> = Vec::new();
+
+        let read = |wire: &Wire, part_outputs: &Vec<Vec<u64>>| -> u64 {
+            let mut value = 0;
+            let mut offset = 0;
+            for signal in &wire.0 {
+                let source = match signal.node {
+                    Node::Input(i) => inputs[i],
+                    Node::Part(p) => part_outputs[p][signal.pin],
+                    Node::Const(c) => c,
+                };
+                let bits = (source >> signal.lo) & ((1 << signal.width) - 1);
+                value |= bits << offset;
+                offset += signal.width;
+            }
+            value
+        };
+
+        for part in &circuit.parts {
+            if part.chip == "dff" {
+                return Err(format!("{} contains a dff and cannot be evaluated", chip));
+            }
+
+            let child = self.get(&part.chip);
+            let values: Vec<u64> = match child {
+                // follow the pin order of the child's definition
+                Some(child) => child
+                    .inputs
+                    .iter()
+                    .map(|pin| {
+                        let (_, wire) = part
+                            .inputs
+                            .iter()
+                            .find(|(name, _)| *name == pin.name)
+                            .expect("every input pin is wired");
+                        read(wire, &part_outputs)
+                    })
+                    .collect(),
+                None => part
+                    .inputs
+                    .iter()
+                    .map(|(_, wire)| read(wire, &part_outputs))
+                    .collect(),
+            };
+            part_outputs.push(self.eval(&part.chip, &values)?);
+        }
+
+        Ok(circuit
+            .outputs
+            .iter()
+            .map(|(_, wire)| read(wire, &part_outputs))
+            .collect())
+    }
+
+    /**
+     * Graphviz rendering of one level of a chip: its parts are boxes,
+     * wires are edges labelled with the pin they feed.
+     */
+    pub fn to_dot(&self, chip: &str) -> Option<String> {
+        let circuit = self.get(chip)?;
+        let mut dot = String::new();
+
+        writeln!(dot, "digraph {} {{", circuit.name).unwrap();
+        writeln!(dot, "  rankdir=LR;").unwrap();
+        writeln!(dot, "  node [shape=box];").unwrap();
+
+        for (i, pin) in circuit.inputs.iter().enumerate() {
+            writeln!(
+                dot,
+                "  in{} [shape=circle, label=\"{}\"];",
+                i,
+                pin_label(pin)
+            )
+            .unwrap();
+        }
+        for (i, (pin, _)) in circuit.outputs.iter().enumerate() {
+            writeln!(
+                dot,
+                "  out{} [shape=doublecircle, label=\"{}\"];",
+                i,
+                pin_label(pin)
+            )
+            .unwrap();
+        }
+        for (i, part) in circuit.parts.iter().enumerate() {
+            writeln!(dot, "  p{} [label=\"{}\"];", i, part.chip).unwrap();
+        }
+
+        let mut edges = Vec::new();
+        for (i, part) in circuit.parts.iter().enumerate() {
+            for (pin, wire) in &part.inputs {
+                edges.extend(wire_edges(circuit, wire, &format!("p{}", i), pin));
+            }
+        }
+        for (i, (pin, wire)) in circuit.outputs.iter().enumerate() {
+            edges.extend(wire_edges(circuit, wire, &format!("out{}", i), &pin.name));
+        }
+
+        let mut constants = 0;
+        for (from, to, label) in edges {
+            let from = match from {
+                Source::Node(name) => name,
+                Source::Const(value) => {
+                    let name = format!("const{}", constants);
+                    constants += 1;
+                    writeln!(dot, "  {} [shape=plaintext, label=\"{}\"];", name, value).unwrap();
+                    name
+                }
+            };
+            writeln!(dot, "  {} -> {} [label=\"{}\"];", from, to, label).unwrap();
+        }
+
+        writeln!(dot, "}}").unwrap();
+        Some(dot)
+    }
+
+    /**
+     * Graphviz rendering of a chip expanded down to primitives.
+     * Nested part instances are drawn as clusters.
+     */
+    pub fn to_dot_flat(&self, chip: &str) -> Option<String> {
+        let circuit = self.get(chip)?;
+        let mut dot = String::new();
+        let mut edges = Vec::new();
+
+        writeln!(dot, "digraph {} {{", circuit.name).unwrap();
+        writeln!(dot, "  rankdir=LR;").unwrap();
+        writeln!(dot, "  node [shape=box];").unwrap();
+
+        for (i, pin) in circuit.inputs.iter().enumerate() {
+            writeln!(
+                dot,
+                "  in{} [shape=circle, label=\"{}\"];",
+                i,
+                pin_label(pin)
+            )
+            .unwrap();
+        }
+        for (i, (pin, _)) in circuit.outputs.iter().enumerate() {
+            writeln!(
+                dot,
+                "  out{} [shape=doublecircle, label=\"{}\"];",
+                i,
+                pin_label(pin)
+            )
+            .unwrap();
+        }
+
+        let top = Instance {
+            circuit,
+            path: String::new(),
+            parent: None,
+        };
+        self.flatten_into(&top, &mut dot, &mut edges, 1);
+
+        for (i, (pin, wire)) in circuit.outputs.iter().enumerate() {
+            for (from, bits) in self.resolve(&top, wire) {
+                edges.push((from, format!("out{}", i), format!("{}{}", pin.name, bits)));
+            }
+        }
+
+        for (from, to, label) in edges {
+            writeln!(dot, "  {} -> {} [label=\"{}\"];", from, to, label).unwrap();
+        }
+
+        writeln!(dot, "}}").unwrap();
+        Some(dot)
+    }
+
+    fn flatten_into(
+        &self,
+        instance: &Instance,
+        dot: &mut String,
+        edges: &mut Vec<(String, String, String)>,
+        depth: usize,
+    ) {
+        let indent = "  ".repeat(depth);
+
+        for (i, part) in instance.circuit.parts.iter().enumerate() {
+            let path = format!("{}_{}", instance.path, i);
+
+            match self.get(&part.chip) {
+                Some(child) => {
+                    writeln!(dot, "{}subgraph cluster{} {{", indent, path).unwrap();
+                    writeln!(dot, "{}  label=\"{}\";", indent, part.chip).unwrap();
+                    let inner = Instance {
+                        circuit: child,
+                        path,
+                        parent: Some((instance, i)),
+                    };
+                    self.flatten_into(&inner, dot, edges, depth + 1);
+                    writeln!(dot, "{}}}", indent).unwrap();
+                }
+                None => {
+                    writeln!(dot, "{}n{} [label=\"{}\"];", indent, path, part.chip).unwrap();
+                    for (pin, wire) in &part.inputs {
+                        for (from, bits) in self.resolve(instance, wire) {
+                            edges.push((from, format!("n{}", path), format!("{}{}", pin, bits)));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // primitive outputs, chip inputs or constants driving a wire, with the
+    // bit range they drive when the wire is wider than one bit
+    fn resolve(&self, instance: &Instance, wire: &Wire) -> Vec<(String, String)> {
+        let mut res = Vec::new();
+
+        for signal in &wire.0 {
+            let bits = if wire.width() > 1 {
+                bit_range(signal.lo, signal.width)
+            } else {
+                String::new()
+            };
+
+            match signal.node {
+                Node::Const(value) => {
+                    let value = (value >> signal.lo) & ((1 << signal.width) - 1);
+                    res.push((format!("\"{}\"", value), bits));
+                }
+                Node::Input(pin) => match instance.parent {
+                    None => res.push((format!("in{}", pin), bits)),
+                    Some((parent, part)) => {
+                        let pin_name = &instance.circuit.inputs[pin].name;
+                        let (_, outer) = parent.circuit.parts[part]
+                            .inputs
+                            .iter()
+                            .find(|(name, _)| name == pin_name)
+                            .expect("every input pin is wired");
+                        let outer = outer.slice(signal.lo, signal.lo + signal.width);
+                        res.extend(self.resolve(parent, &outer));
+                    }
+                },
+                Node::Part(p) => {
+                    let part = &instance.circuit.parts[p];
+                    let path = format!("{}_{}", instance.path, p);
+                    match self.get(&part.chip) {
+                        None => res.push((format!("n{}", path), bits)),
+                        Some(child) => {
+                            let inner = Instance {
+                                circuit: child,
+                                path,
+                                parent: Some((instance, p)),
+                            };
+                            let (_, out) = &child.outputs[signal.pin];
+                            let out = out.slice(signal.lo, signal.lo + signal.width);
+                            res.extend(self.resolve(&inner, &out));
+                        }
+                    }
+                }
+            }
+        }
+
+        res
+    }
+}
+
+impl Default for Library {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// a part instance inside the chip being flattened
+struct Instance<'a> {
+    circuit: &'a Circuit,
+    path: String,
+    parent: Option<(&'a Instance<'a>, usize)>,
+}
+
+fn pin_label(pin: &Pin) -> String {
+    if pin.width > 1 {
+        format!("{}[{}]", pin.name, pin.width)
+    } else {
+        pin.name.clone()
+    }
+}
+
+fn bit_range(lo: usize, width: usize) -> String {
+    if width == 1 {
+        format!("[{}]", lo)
+    } else {
+        format!("[{}..{}]", lo, lo + width - 1)
+    }
+}
+
+enum Source {
+    Node(String),
+    Const(u64),
+}
+
+// (source, destination node, label of the pin being fed)
+fn wire_edges(
+    circuit: &Circuit,
+    wire: &Wire,
+    to: &str,
+    pin: &str,
+) -> Vec<(Source, String, String)> {
+    wire.0
+        .iter()
+        .map(|signal| {
+            let (source, source_width) = match signal.node {
+                Node::Input(i) => (Source::Node(format!("in{}", i)), circuit.inputs[i].width),
+                Node::Part(p) => (
+                    Source::Node(format!("p{}", p)),
+                    circuit.parts[p].outputs[signal.pin].width,
+                ),
+                Node::Const(value) => (
+                    Source::Const((value >> signal.lo) & ((1 << signal.width) - 1)),
+                    signal.width,
+                ),
+            };
+            let bits = if source_width > signal.width {
+                bit_range(signal.lo, signal.width)
+            } else {
+                String::new()
+            };
+
+            (source, to.to_string(), format!("{}{}", pin, bits))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alu::{add_16, alu, full_adder, half_adder, inc_16};
+    use crate::bus::Bus;
+    use crate::gates::{dmux8way_gate, mux, mux4way16_gate, or8way_gate, xor_gate};
+    use crate::verify::Harness;
+
+    #[test]
+    fn test_wire_slicing() {
+        let a = Wire::constant(0b1010, 4);
+        let b = Wire::constant(0b11, 2);
+        let wire = a.concat(b);
+        assert_eq!(wire.width(), 6);
+
+        let slice = wire.slice(3, 5);
+        assert_eq!(slice.width(), 2);
+        assert_eq!(slice.0.len(), 2);
+        assert_eq!(slice.0[0].lo, 3);
+        assert_eq!(slice.0[1].lo, 0);
+    }
+
+    #[test]
+    fn test_netlists_match_chips() {
+        let lib = Library::new();
+        let harness = Harness::new().exhaustive_limit(12).samples(300);
+        let eval = |chip: &str, inputs: &[u64]| lib.eval(chip, inputs).unwrap();
+
+        harness.assert_equivalent(
+            "xor",
+            &["a", "b"],
+            |(a, b): (bool, bool)| eval("xor", &[a as u64, b as u64])[0] != 0,
+            |(a, b)| xor_gate(a, b),
+        );
+        harness.assert_equivalent(
+            "mux",
+            &["a", "b", "sel"],
+            |(a, b, sel): (bool, bool, bool)| {
+                eval("mux", &[a as u64, b as u64, sel as u64])[0] != 0
+            },
+            |(a, b, sel)| mux(a, b, sel),
+        );
+        harness.assert_equivalent(
+            "or8way",
+            &["in"],
+            |x: u8| eval("or8way", &[x as u64])[0] != 0,
+            or8way_gate,
+        );
+        harness.assert_equivalent(
+            "dmux8way",
+            &["in", "sel"],
+            |(x, sel): (bool, Bus<3>)| {
+                let out = eval("dmux8way", &[x as u64, sel.value()]);
+                out.iter().fold(0u8, |acc, bit| (acc << 1) | *bit as u8)
+            },
+            |(x, sel)| dmux8way_gate(x, sel.value() as u8),
+        );
+        harness.assert_equivalent(
+            "mux4way16",
+            &["a", "b", "c", "d", "sel"],
+            |(a, b, c, d, sel): (u16, u16, u16, u16, Bus<2>)| {
+                let ins = [a, b, c, d].map(|x| x as u64);
+                eval("mux4way16", &[ins[0], ins[1], ins[2], ins[3], sel.value()])[0] as u16
+            },
+            |(a, b, c, d, sel)| mux4way16_gate(a, b, c, d, sel.value() as u8),
+        );
+        harness.assert_equivalent(
+            "half_adder",
+            &["a", "b"],
+            |(a, b): (bool, bool)| {
+                let out = eval("half_adder", &[a as u64, b as u64]);
+                (out[0] != 0, out[1] != 0)
+            },
+            |(a, b)| half_adder(a, b),
+        );
+        harness.assert_equivalent(
+            "full_adder",
+            &["a", "b", "c"],
+            |(a, b, c): (bool, bool, bool)| {
+                let out = eval("full_adder", &[a as u64, b as u64, c as u64]);
+                (out[0] != 0, out[1] != 0)
+            },
+            |(a, b, c)| full_adder(a, b, c),
+        );
+        harness.assert_equivalent(
+            "add16",
+            &["a", "b"],
+            |(a, b): (u16, u16)| eval("add16", &[a as u64, b as u64])[0] as u16,
+            |(a, b)| add_16(a, b),
+        );
+        harness.samples(30).assert_equivalent(
+            "inc16",
+            &["in"],
+            |a: u16| eval("inc16", &[a as u64])[0] as u16,
+            inc_16,
+        );
+        harness.samples(50).assert_equivalent(
+            "alu",
+            &["x", "y", "zx nx zy ny f no"],
+            |(x, y, control): (u16, u16, Bus<6>)| {
+                let mut inputs = vec![x as u64, y as u64];
+                inputs.extend((0..6).map(|i| control.bit(5 - i) as u64));
+                let out = eval("alu", &inputs);
+                (out[0] as u16, out[1] != 0, out[2] != 0)
+            },
+            |(x, y, control)| {
+                let bit = |i: usize| control.bit(5 - i);
+                alu(x, y, bit(0), bit(1), bit(2), bit(3), bit(4), bit(5))
+            },
+        );
+    }
+
+    #[test]
+    fn test_sequential_chips_do_not_evaluate() {
+        let lib = Library::new();
+        assert!(lib.eval("bit", &[1, 1]).is_err());
+        assert!(lib.eval("nope", &[]).is_err());
+    }
+
+    #[test]
+    fn test_dot_export() {
+        let lib = Library::new();
+
+        let dot = lib.to_dot("half_adder").unwrap();
+        assert!(dot.starts_with("digraph half_adder {"));
+        assert!(dot.contains("p0 [label=\"xor\"];"));
+        assert!(dot.contains("p1 [label=\"and\"];"));
+        assert!(dot.contains("in0 -> p0 [label=\"a\"];"));
+        assert!(dot.contains("p1 -> out1 [label=\"carry\"];"));
+
+        let dot = lib.to_dot("add16").unwrap();
+        assert_eq!(dot.matches("[label=\"full_adder\"]").count(), 15);
+        assert!(dot.contains("in0 -> p15 [label=\"a[15]\"];"));
+
+        let dot = lib.to_dot("inc16").unwrap();
+        assert!(dot.contains("const0 [shape=plaintext, label=\"1\"];"));
+
+        let dot = lib.to_dot("ram64").unwrap();
+        assert_eq!(dot.matches("[label=\"ram8\"]").count(), 8);
+        assert!(dot.contains("in2 -> p0 [label=\"sel[3..5]\"];"));
+
+        assert!(lib.to_dot("and").is_none());
+    }
+
+    #[test]
+    fn test_flat_dot_export() {
+        let lib = Library::new();
+
+        // xor = 5 nands, each an and followed by a not
+        let dot = lib.to_dot_flat("xor").unwrap();
+        assert_eq!(dot.matches("[label=\"and\"]").count(), 5);
+        assert_eq!(dot.matches("[label=\"not\"]").count(), 5);
+        assert_eq!(dot.matches("subgraph cluster").count(), 5);
+
+        // full adder: 2 half adders (xor + and) and an or
+        let dot = lib.to_dot_flat("full_adder").unwrap();
+        assert_eq!(dot.matches("[label=\"or\"]").count(), 1);
+        assert!(dot.contains("in2 -> n_1_0_2_0 [label=\"b\"];"));
+        assert!(dot.contains("n_2 -> out1 [label=\"carry\"];"));
+
+        // the dff inside a bit feeds back into its mux
+        let dot = lib.to_dot_flat("bit").unwrap();
+        assert!(dot.contains("n_0 [label=\"dff\"];"));
+        assert!(dot.contains("n_1_3 -> n_0 [label=\"in\"];"));
+        assert!(dot.contains("n_0 -> n_1_1 [label=\"a\"];"));
+    }
+}
@@ -0,0 +1,248 @@
+use crate::cpu::CPU;
+use crate::keyboard::Keyboard;
+use crate::mem::{Memory, ROM32K};
+use crate::sequential::{Addressable, Tick};
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::utils::Bits;
+
+/**
+ * The Hack computer: CPU, data Memory and instruction ROM32K.
+ * Pins: reset. One tick executes one instruction.
+ */
+pub struct Computer {
+    cpu: CPU,
+    memory: Memory,
+    rom: ROM32K,
+    reset: bool,
+}
+
+impl Computer {
+    pub fn new(program: &[u16]) -> Self {
+        let mut rom = ROM32K::new();
+        rom.load(program);
+        Computer {
+            cpu: CPU::new(),
+            memory: Memory::new(),
+            rom,
+            reset: false,
+        }
+    }
+
+    pub fn with_keyboard(program: &[u16], keyboard: Keyboard) -> Self {
+        let mut computer = Self::new(program);
+        computer.memory.set_keyboard(keyboard);
+        computer
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn rom(&self) -> &ROM32K {
+        &self.rom
+    }
+
+    pub fn ram(&self, addr: u16) -> u16 {
+        self.memory.peek(addr as usize)
+    }
+
+    // backdoor write into the data memory, as Memory::poke
+    pub fn poke(&mut self, addr: u16, value: u16) {
+        self.memory.poke(addr as usize, value);
+    }
+
+    // backdoor jump: the next tick executes the instruction at pc
+    pub fn poke_pc(&mut self, pc: u16) {
+        self.cpu.poke_pc(pc);
+    }
+
+    // settles the wires between the chips for the current instruction
+    fn settle(&mut self) {
+        let reset = Bits::from(self.reset);
+
+        self.rom.set_state(Bits::new(self.cpu.pc() as u64, 15));
+        let instruction = self.rom.get_state();
+
+        // addressM only depends on A, so read M first and then feed it back
+        let address_m = Bits::new(self.cpu.a() as u64 & 0x7FFF, 15);
+        self.memory
+            .set_state(Bits::from(0u16).concat(false.into()).concat(address_m));
+        let in_m = self.memory.get_state();
+
+        self.cpu.set_state(in_m.concat(instruction).concat(reset));
+        let out = self.cpu.get_state();
+
+        let out_m = out.slice_msb(0..16);
+        let write_m = out.msb(16);
+        let address_m = out.slice_msb(17..32);
+        self.memory
+            .set_state(out_m.concat(write_m.into()).concat(address_m));
+    }
+}
+
+impl Tick for Computer {
+    fn tick(&mut self) {
+        self.settle();
+        self.cpu.tick();
+        self.memory.tick();
+    }
+
+    // the computer has no outputs, inspect it through cpu() and memory()
+    fn get_state(&self) -> Bits {
+        Bits::zero(0)
+    }
+
+    fn set_state(&mut self, inputs: Bits) {
+        assert_eq!(inputs.len(), 1);
+        self.reset = inputs.msb(0);
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        self.cpu.save(out);
+        self.memory.save(out);
+        self.rom.save(out);
+        out.bool(self.reset);
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.cpu.restore(input)?;
+        self.memory.restore(input)?;
+        self.rom.restore(input)?;
+        self.reset = input.bool()?;
+        Ok(())
+    }
+
+    // the ROM never changes and the wires are settled again by the next tick
+    fn tick_recorded(&mut self, undo: &mut StateWriter) {
+        self.settle();
+        self.cpu.tick_recorded(undo);
+        self.memory.tick_recorded(undo);
+    }
+
+    fn undo(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.cpu.undo(input)?;
+        self.memory.undo(input)
+    }
+
+    fn kind(&self) -> &'static str {
+        "Computer"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::KeyEvent;
+    use crate::mem::parse_hack;
+
+    // Add.hack from the Nand2Tetris course: RAM[0] = 2 + 3
+    const ADD: &str = "
+        0000000000000010
+        1110110000010000
+        0000000000000011
+        1110000010010000
+        0000000000000000
+        1110001100001000
+    ";
+
+    #[test]
+    fn test_add() {
+        let mut computer = Computer::new(&parse_hack(ADD).unwrap());
+        for _ in 0..6 {
+            computer.tick();
+        }
+        assert_eq!(computer.ram(0), 5);
+        assert_eq!(computer.cpu().pc(), 6);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut computer = Computer::new(&parse_hack(ADD).unwrap());
+        for _ in 0..3 {
+            computer.tick();
+        }
+
+        computer.set_state(true.into());
+        computer.tick();
+        assert_eq!(computer.cpu().pc(), 0);
+    }
+
+    #[test]
+    fn test_keyboard_to_screen() {
+        // @KBD, D=M, @SCREEN, M=D
+        let program = [0x6000, 0b1111_1100_0001_0000, 0x4000, 0b1110_0011_0000_1000];
+        let keyboard = Keyboard::scripted([KeyEvent { cycle: 0, key: 131 }]);
+        let mut computer = Computer::with_keyboard(&program, keyboard);
+        for _ in 0..4 {
+            computer.tick();
+        }
+        assert_eq!(computer.memory().screen().peek(0), 131);
+        assert_eq!(computer.ram(0x4000), 131);
+    }
+
+    #[test]
+    fn test_step_back() {
+        use crate::sequential::Clock;
+
+        // the key is read into D and written to RAM[0] on every pass
+        let program = parse_hack(
+            "
+            0110000000000000
+            1111110000010000
+            0000000000000000
+            1110001100001000
+            0000000000000000
+            1110101010000111
+        ",
+        )
+        .unwrap();
+        let keys = [
+            KeyEvent { cycle: 2, key: 65 },
+            KeyEvent { cycle: 9, key: 0 },
+        ];
+
+        let mut clock = Clock::new();
+        clock.register(Box::new(Computer::with_keyboard(
+            &program,
+            Keyboard::scripted(keys),
+        )));
+        clock.record_history(100);
+
+        let machine = |clock: &Clock| {
+            let computer = clock.component::<Computer>(0).unwrap();
+            let cpu = computer.cpu();
+            (
+                cpu.a(),
+                cpu.d(),
+                cpu.pc(),
+                computer.ram(0),
+                computer.ram(0x6000),
+            )
+        };
+
+        let mut seen = vec![machine(&clock)];
+        for _ in 0..20 {
+            clock.tick();
+            seen.push(machine(&clock));
+        }
+        assert_eq!(seen[10].3, 65);
+
+        while let Some(expected) = seen.pop() {
+            assert_eq!(machine(&clock), expected, "cycle {}", seen.len());
+            clock.step_back(1);
+        }
+        assert_eq!(clock.cycles(), 0);
+
+        // replaying gives the same run, keys included
+        for cycle in 1..=20 {
+            clock.tick();
+            let computer = clock.component::<Computer>(0).unwrap();
+            assert_eq!(computer.memory().keyboard().cycle(), cycle);
+        }
+        assert_eq!(machine(&clock).3, 0);
+    }
+}
@@ -0,0 +1,324 @@
+use crate::alu::{alu, inc_16};
+use crate::gates::{and_gate, mux16_gate, not_gate, or_gate};
+use crate::sequential::{Register, Tick};
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::utils::Bits;
+
+/**
+ * Program counter:
+ * if (reset(t)) out(t+1) = 0
+ * else if (load(t)) out(t+1) = in(t)
+ * else if (inc(t)) out(t+1) = out(t) + 1
+ * else out(t+1) = out(t)
+ * Pins: in[16], load, inc, reset, out[16].
+ */
+#[allow(clippy::upper_case_acronyms)]
+pub struct PC {
+    register: Register,
+}
+
+impl PC {
+    pub fn new() -> Self {
+        PC {
+            register: Register::new(),
+        }
+    }
+
+    pub fn value(&self) -> u16 {
+        self.register.get_state().to_u16().unwrap()
+    }
+
+    // backdoor: jumps without a load or a tick
+    pub fn poke(&mut self, value: u16) {
+        self.register.poke(Bits::from(value));
+    }
+}
+
+impl Default for PC {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tick for PC {
+    fn tick(&mut self) {
+        self.register.tick();
+    }
+
+    fn get_state(&self) -> Bits {
+        self.register.get_state()
+    }
+
+    fn set_state(&mut self, inputs: Bits) {
+        assert_eq!(inputs.len(), 16 + 3);
+
+        let x = inputs.slice_msb(0..16).to_u16().unwrap();
+        let load = inputs.msb(16);
+        let inc = inputs.msb(17);
+        let reset = inputs.msb(18);
+
+        let out = self.value();
+        let next = mux16_gate(out, inc_16(out), inc);
+        let next = mux16_gate(next, x, load);
+        let next = mux16_gate(next, 0, reset);
+
+        // the register loads every cycle, the muxes above hold the value
+        self.register
+            .set_state(Bits::from(next).concat(true.into()));
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        self.register.save(out);
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.register.restore(input)?;
+        Ok(())
+    }
+
+    fn kind(&self) -> &'static str {
+        "PC"
+    }
+}
+
+// combinational part of the CPU for the current inputs and registers
+struct Signals {
+    out_m: u16,
+    write_m: bool,
+    address_m: u16,
+    a_in: u16,
+    load_a: bool,
+    load_d: bool,
+    jump: bool,
+}
+
+/**
+ * The Hack CPU: A and D registers, the ALU and the program counter.
+ * Pins: inM[16], instruction[16], reset,
+ * outM[16], writeM, addressM[15], pc[15].
+ */
+#[allow(clippy::upper_case_acronyms)]
+pub struct CPU {
+    a: Register,
+    d: Register,
+    pc: PC,
+    in_m: u16,
+    instruction: u16,
+    reset: bool,
+}
+
+impl CPU {
+    pub fn new() -> Self {
+        CPU {
+            a: Register::new(),
+            d: Register::new(),
+            pc: PC::new(),
+            in_m: 0,
+            instruction: 0,
+            reset: false,
+        }
+    }
+
+    pub fn a(&self) -> u16 {
+        self.a.get_state().to_u16().unwrap()
+    }
+
+    pub fn d(&self) -> u16 {
+        self.d.get_state().to_u16().unwrap()
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc.value() & 0x7FFF
+    }
+
+    // backdoor: the next instruction is the one at pc
+    pub fn poke_pc(&mut self, pc: u16) {
+        self.pc.poke(pc);
+    }
+
+    fn signals(&self) -> Signals {
+        let i = Bits::from(self.instruction);
+        let is_c = i.msb(0);
+        let (a_bit, dest_a, dest_d, dest_m) = (i.lsb(12), i.lsb(5), i.lsb(4), i.lsb(3));
+        let (jlt, jeq, jgt) = (i.lsb(2), i.lsb(1), i.lsb(0));
+
+        let a = self.a();
+        let y = mux16_gate(a, self.in_m, a_bit);
+        let (out, zr, ng) = alu(
+            self.d(),
+            y,
+            i.lsb(11),
+            i.lsb(10),
+            i.lsb(9),
+            i.lsb(8),
+            i.lsb(7),
+            i.lsb(6),
+        );
+
+        let pos = and_gate(not_gate(zr), not_gate(ng));
+        let jump = or_gate(
+            or_gate(and_gate(jlt, ng), and_gate(jeq, zr)),
+            and_gate(jgt, pos),
+        );
+
+        Signals {
+            out_m: out,
+            write_m: and_gate(is_c, dest_m),
+            address_m: a & 0x7FFF,
+            a_in: mux16_gate(self.instruction, out, is_c),
+            load_a: or_gate(not_gate(is_c), dest_a),
+            load_d: and_gate(is_c, dest_d),
+            jump: and_gate(is_c, jump),
+        }
+    }
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tick for CPU {
+    fn tick(&mut self) {
+        self.a.tick();
+        self.d.tick();
+        self.pc.tick();
+    }
+
+    fn get_state(&self) -> Bits {
+        let signals = self.signals();
+        Bits::from(signals.out_m)
+            .concat(signals.write_m.into())
+            .concat(Bits::new(signals.address_m as u64, 15))
+            .concat(Bits::new(self.pc() as u64, 15))
+    }
+
+    fn set_state(&mut self, inputs: Bits) {
+        assert_eq!(inputs.len(), 16 + 16 + 1);
+
+        self.in_m = inputs.slice_msb(0..16).to_u16().unwrap();
+        self.instruction = inputs.slice_msb(16..32).to_u16().unwrap();
+        self.reset = inputs.msb(32);
+
+        let signals = self.signals();
+        self.a
+            .set_state(Bits::from(signals.a_in).concat(signals.load_a.into()));
+        self.d
+            .set_state(Bits::from(signals.out_m).concat(signals.load_d.into()));
+
+        let pc_input = Bits::from(self.a())
+            .concat(signals.jump.into())
+            .concat(true.into())
+            .concat(self.reset.into());
+        self.pc.set_state(pc_input);
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        self.a.save(out);
+        self.d.save(out);
+        self.pc.save(out);
+        out.u16(self.in_m);
+        out.u16(self.instruction);
+        out.bool(self.reset);
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.a.restore(input)?;
+        self.d.restore(input)?;
+        self.pc.restore(input)?;
+        self.in_m = input.u16()?;
+        self.instruction = input.u16()?;
+        self.reset = input.bool()?;
+        Ok(())
+    }
+
+    fn kind(&self) -> &'static str {
+        "CPU"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pc_input(x: u16, load: bool, inc: bool, reset: bool) -> Bits {
+        Bits::from(x)
+            .concat(load.into())
+            .concat(inc.into())
+            .concat(reset.into())
+    }
+
+    // runs one instruction, returns (outM, writeM, addressM) before the tick
+    fn step(cpu: &mut CPU, in_m: u16, instruction: u16) -> (u16, bool, u16) {
+        let input = Bits::from(in_m)
+            .concat(instruction.into())
+            .concat(false.into());
+        cpu.set_state(input);
+        let out = cpu.get_state();
+        cpu.tick();
+        (
+            out.slice_msb(0..16).to_u16().unwrap(),
+            out.msb(16),
+            out.slice_msb(17..32).to_u16().unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_pc() {
+        let mut pc = PC::new();
+
+        let steps = [
+            (pc_input(0, false, true, false), 1),
+            (pc_input(0, false, true, false), 2),
+            (pc_input(0, false, false, false), 2),
+            (pc_input(0x1234, true, true, false), 0x1234),
+            (pc_input(0x1234, false, true, false), 0x1235),
+            (pc_input(0x1234, true, true, true), 0),
+            (pc_input(0xFFFF, true, false, false), 0xFFFF),
+            (pc_input(0, false, true, false), 0),
+        ];
+        for (input, expected) in steps {
+            pc.set_state(input);
+            pc.tick();
+            assert_eq!(pc.value(), expected);
+        }
+    }
+
+    #[test]
+    fn test_cpu() {
+        let mut cpu = CPU::new();
+
+        // @21
+        step(&mut cpu, 0, 21);
+        assert_eq!((cpu.a(), cpu.pc()), (21, 1));
+
+        // D=A
+        step(&mut cpu, 0, 0b1110_1100_0001_0000);
+        assert_eq!(cpu.d(), 21);
+
+        // M=D+M, with M = 100
+        let (out_m, write_m, address_m) = step(&mut cpu, 100, 0b1111_0000_1000_1000);
+        assert_eq!((out_m, write_m, address_m), (121, true, 21));
+
+        // AD=D-1, M is not written
+        let (_, write_m, _) = step(&mut cpu, 0, 0b1110_0011_1011_0000);
+        assert_eq!((cpu.a(), cpu.d(), write_m), (20, 20, false));
+        assert_eq!(cpu.pc(), 4);
+
+        // @7, D;JLT does not jump, D;JGT does
+        step(&mut cpu, 0, 7);
+        step(&mut cpu, 0, 0b1110_0011_0000_0100);
+        assert_eq!(cpu.pc(), 6);
+        step(&mut cpu, 0, 0b1110_0011_0000_0001);
+        assert_eq!(cpu.pc(), 7);
+
+        // 0;JMP
+        step(&mut cpu, 0, 0b1110_1010_1000_0111);
+        assert_eq!(cpu.pc(), 7);
+
+        cpu.set_state(Bits::from(0u16).concat(0u16.into()).concat(true.into()));
+        cpu.tick();
+        assert_eq!(cpu.pc(), 0);
+    }
+}
@@ -0,0 +1,784 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+
+use crate::asm::{disassemble, SymbolTable};
+use crate::computer::Computer;
+use crate::mem::ROM32K;
+use crate::runner::is_halt;
+use crate::sequential::Clock;
+use crate::srcmap::{Location, SourceMaps};
+
+// `continue` gives up after this many cycles without a reason to stop
+const CONTINUE_LIMIT: u64 = 10_000_000;
+
+// how many cycles `back` can undo
+const HISTORY: usize = 100_000;
+
+// how many frames `backtrace` follows before it takes the stack as broken
+const MAX_FRAMES: usize = 100;
+
+const LCL: u16 = 1;
+const ARG: u16 = 2;
+const THIS: u16 = 3;
+
+pub const HELP: &str = "\
+step                     execute one instruction
+next [N]                 execute N instructions, 1 by default
+continue                 run until a breakpoint, a watch or the halt loop
+back [N]                 undo the last N instructions, 1 by default
+break [ADDR|LABEL]       set a breakpoint on a ROM address, list them without one
+break FILE:LINE          set breakpoints on the code of a source line, e.g. Main.jack:12
+delete ADDR|LABEL        remove a breakpoint, or those of FILE:LINE
+watch RAM[X]             stop when RAM[X] changes, X is an address or a variable
+print A|D|PC|RAM[X]      show a register or a RAM word
+set RAM[X] VALUE         write a RAM word without running the machine
+x/N RAM[X]               show N RAM words from X
+disasm [ADDR|LABEL] [N]  show N instructions, around PC by default
+locals                   show the variables of the current function by name
+backtrace                show the call stack, innermost first
+save FILE                write the whole machine state to FILE
+load FILE                go back to a state written by save
+help                     this text
+quit                     leave the debugger";
+
+/**
+ * Step debugger for Hack programs, driving a Computer on a Clock.
+ * Every command returns the text to show, or an error message.
+ */
+pub struct Debugger {
+    clock: Clock,
+    symbols: SymbolTable,
+    breakpoints: BTreeSet<u16>,
+    watches: BTreeMap<u16, u16>, // address and the value last seen there
+    program_len: usize,
+    sources: Option<SourceMaps>,
+}
+
+impl Debugger {
+    pub fn new(program: &[u16], symbols: SymbolTable) -> Self {
+        let mut clock = Clock::new();
+        clock.register(Box::new(Computer::new(program)));
+        clock.record_history(HISTORY);
+        Debugger {
+            clock,
+            symbols,
+            breakpoints: BTreeSet::new(),
+            watches: BTreeMap::new(),
+            program_len: program.len(),
+            sources: None,
+        }
+    }
+
+    // maps back to the sources, for source lines, locals and the call stack
+    pub fn sources(mut self, sources: SourceMaps) -> Self {
+        self.sources = Some(sources);
+        self
+    }
+
+    pub fn computer(&self) -> &Computer {
+        self.clock.component::<Computer>(0).unwrap()
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.clock.cycles()
+    }
+
+    pub fn execute(&mut self, line: &str) -> Result<String, String> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(String::new());
+        };
+        let args: Vec<&str> = words.collect();
+
+        match (command, args.as_slice()) {
+            ("step" | "s", []) => Ok(self.run(1)),
+            ("next" | "n", []) => Ok(self.run(1)),
+            ("next" | "n", [count]) => {
+                let count = parse_number(count).ok_or("next takes a number of cycles")?;
+                Ok(self.run(count))
+            }
+            ("continue" | "c", []) => Ok(self.run(CONTINUE_LIMIT)),
+            ("back" | "reverse-step", []) => self.back(1),
+            ("back" | "reverse-step", [count]) => {
+                let count = parse_number(count).ok_or("back takes a number of cycles")?;
+                self.back(count)
+            }
+            ("break" | "b", []) => Ok(self.list_breakpoints()),
+            ("break" | "b", [target]) => {
+                let (addrs, name) = self.breakpoint_target(target)?;
+                self.breakpoints.extend(&addrs);
+                Ok(format!("breakpoint at {}", name))
+            }
+            ("delete" | "d", [target]) => {
+                let (addrs, name) = self.breakpoint_target(target)?;
+                let mut deleted = false;
+                for addr in addrs {
+                    deleted |= self.breakpoints.remove(&addr);
+                }
+                if deleted {
+                    Ok(format!("deleted breakpoint at {}", name))
+                } else {
+                    Err(format!("no breakpoint at {}", name))
+                }
+            }
+            ("locals", []) => self.locals(),
+            ("backtrace" | "bt", []) => self.backtrace(),
+            ("watch" | "w", [target]) => {
+                let addr = self.ram_address(target)?;
+                let value = self.computer().ram(addr);
+                self.watches.insert(addr, value);
+                Ok(format!("watching {} = {}", self.describe_ram(addr), value))
+            }
+            ("print" | "p", [target]) => self.print(target),
+            ("set", [target, value]) => {
+                let addr = self.ram_address(target)?;
+                let value = parse_number(value).ok_or("set takes a 16-bit value")?;
+                let computer = self.clock.component_mut::<Computer>(0).unwrap();
+                computer.poke(addr, value);
+                let value = computer.ram(addr);
+                if let Some(last) = self.watches.get_mut(&addr) {
+                    *last = value;
+                }
+                Ok(format!("{} = {}", self.describe_ram(addr), value))
+            }
+            ("disasm", []) => {
+                let pc = self.computer().cpu().pc();
+                Ok(self.disasm(pc.saturating_sub(4), 10))
+            }
+            ("disasm", [target]) => Ok(self.disasm(self.rom_address(target)?, 10)),
+            ("disasm", [target, count]) => {
+                let count = parse_number(count).ok_or("disasm takes a number of instructions")?;
+                Ok(self.disasm(self.rom_address(target)?, count))
+            }
+            ("save", [path]) => {
+                let cycles = self.cycles();
+                self.clock
+                    .save_to(path)
+                    .map_err(|e| format!("{}: {}", path, e))?;
+                Ok(format!("saved the machine at cycle {} to {}", cycles, path))
+            }
+            ("load", [path]) => {
+                self.clock
+                    .restore_from(path)
+                    .map_err(|e| format!("{}: {}", path, e))?;
+                self.refresh_watches();
+                Ok(format!(
+                    "restored the machine at cycle {}\n{}",
+                    self.cycles(),
+                    self.position()
+                ))
+            }
+            ("help" | "h", []) => Ok(HELP.to_string()),
+            (x, [target]) if x == "x" || x.starts_with("x/") => {
+                let count = match x.strip_prefix("x/") {
+                    Some(count) => parse_number(count).ok_or("x/N takes a number of words")?,
+                    None => 1,
+                };
+                Ok(self.examine(self.ram_address(target)?, count))
+            }
+            _ => Err(format!("unknown command '{}', try help", line.trim())),
+        }
+    }
+
+    // ticks up to max_cycles times, stopping early when there is a reason to
+    fn run(&mut self, max_cycles: u64) -> String {
+        let mut out = String::new();
+
+        for i in 0..max_cycles {
+            let computer = self.computer();
+            if i > 0 && is_halt(computer.rom(), computer.cpu().pc()) {
+                let _ = writeln!(out, "halted after {} cycles", self.cycles());
+                break;
+            }
+
+            self.clock.tick();
+            let computer = self.clock.component::<Computer>(0).unwrap();
+
+            let mut stop = false;
+            for (&addr, last) in self.watches.iter_mut() {
+                let value = computer.ram(addr);
+                if value != *last {
+                    let name = describe_ram(&self.symbols, addr);
+                    let _ = writeln!(out, "watch {}: {} -> {}", name, last, value);
+                    *last = value;
+                    stop = true;
+                }
+            }
+
+            let pc = computer.cpu().pc();
+            if self.breakpoints.contains(&pc) {
+                let _ = writeln!(out, "breakpoint at {}", self.describe_rom(pc));
+                stop = true;
+            }
+
+            if stop {
+                break;
+            }
+            if i + 1 == max_cycles && max_cycles == CONTINUE_LIMIT {
+                let _ = writeln!(out, "still running after {} cycles", max_cycles);
+            }
+        }
+
+        out.push_str(&self.position());
+        out
+    }
+
+    // the next instruction, and the source line it is from
+    fn position(&self) -> String {
+        let pc = self.computer().cpu().pc();
+        let mut out = self.disasm(pc, 1);
+        if let Some(line) = self.source_line(pc) {
+            let _ = writeln!(out, "at {}", line);
+        }
+        out
+    }
+
+    // FILE:LINE and its text, of the source the assembly was generated from
+    fn source_line(&self, addr: u16) -> Option<String> {
+        let sources = self.sources.as_ref()?;
+        let chain = sources.locate(addr);
+        if chain.len() < 2 {
+            return None;
+        }
+        let source = chain.last()?;
+        match sources.text(source) {
+            Some(text) => Some(format!("{}: {}", source, text.trim())),
+            None => Some(source.to_string()),
+        }
+    }
+
+    fn loaded_sources(&self) -> Result<&SourceMaps, String> {
+        self.sources
+            .as_ref()
+            .ok_or_else(|| "no source maps loaded".to_string())
+    }
+
+    fn locals(&self) -> Result<String, String> {
+        let sources = self.loaded_sources()?;
+        let computer = self.computer();
+        let function = sources
+            .function(computer.cpu().pc())
+            .ok_or("not in a function of the sources")?;
+        if function.vars.is_empty() {
+            return Ok(format!("no variables known in {}", function.name));
+        }
+
+        let class = function.name.split('.').next().unwrap_or_default();
+        let mut out = String::new();
+        for var in &function.vars {
+            let segment = |pointer: u16| computer.ram(pointer).wrapping_add(var.index);
+            let addr = match var.kind.as_str() {
+                "local" => Some(segment(LCL)),
+                "argument" => Some(segment(ARG)),
+                "field" => Some(segment(THIS)),
+                // statics are assembler variables, only there when used
+                _ => self.symbols.variable(&format!("{}.{}", class, var.index)),
+            };
+            let _ = write!(out, "{} {}: {}", var.kind, var.name, var.ty);
+            match addr {
+                Some(addr) => writeln!(out, " = {}", computer.ram(addr) as i16),
+                None => writeln!(out, " is never used"),
+            }
+            .unwrap();
+        }
+        Ok(out)
+    }
+
+    fn backtrace(&self) -> Result<String, String> {
+        let sources = self.loaded_sources()?;
+        let computer = self.computer();
+        let mut out = String::new();
+
+        let (mut pc, mut frame) = (computer.cpu().pc(), computer.ram(LCL));
+        for depth in 0..MAX_FRAMES {
+            let Some(function) = sources.function(pc) else {
+                break;
+            };
+            let _ = write!(out, "#{} {}", depth, function.name);
+            if let Some(line) = self.source_line(pc) {
+                let _ = write!(out, " at {}", line);
+            }
+            out.push('\n');
+
+            // below the frame $CALL built: the return address and the
+            // caller's LCL; the instruction before the return is the call's
+            pc = computer.ram(frame.wrapping_sub(5)).wrapping_sub(1) & 0x7FFF;
+            frame = computer.ram(frame.wrapping_sub(4));
+        }
+        if out.is_empty() {
+            return Err("not in a function of the sources".to_string());
+        }
+        Ok(out)
+    }
+
+    fn back(&mut self, count: usize) -> Result<String, String> {
+        if self.clock.history_len() == 0 {
+            return Err("no cycles to go back to".to_string());
+        }
+        let undone = self.clock.step_back(count);
+        self.refresh_watches();
+
+        Ok(format!(
+            "undid {} cycles, back at cycle {}\n{}",
+            undone,
+            self.cycles(),
+            self.position()
+        ))
+    }
+
+    // after the machine jumped, watches compare against its new state
+    fn refresh_watches(&mut self) {
+        let computer = self.clock.component::<Computer>(0).unwrap();
+        for (&addr, last) in self.watches.iter_mut() {
+            *last = computer.ram(addr);
+        }
+    }
+
+    fn print(&self, target: &str) -> Result<String, String> {
+        let cpu = self.computer().cpu();
+        let value = match target.to_ascii_uppercase().as_str() {
+            "A" => cpu.a(),
+            "D" => cpu.d(),
+            "PC" => return Ok(format!("PC = {}", self.describe_rom(cpu.pc()))),
+            _ => {
+                let addr = self.ram_address(target)?;
+                let value = self.computer().ram(addr);
+                return Ok(format!("{} = {}", self.describe_ram(addr), value));
+            }
+        };
+        Ok(format!(
+            "{} = {} ({:#06x})",
+            target.to_ascii_uppercase(),
+            value as i16,
+            value
+        ))
+    }
+
+    // eight words per line, signed as the Hack ALU sees them
+    fn examine(&self, start: u16, count: u16) -> String {
+        let computer = self.computer();
+        let mut out = String::new();
+        let end = start.saturating_add(count).min(0x8000);
+
+        for row in (start..end).step_by(8) {
+            let _ = write!(out, "RAM[{}]:", row);
+            for addr in row..end.min(row.saturating_add(8)) {
+                let _ = write!(out, " {:6}", computer.ram(addr) as i16);
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    fn disasm(&self, start: u16, count: u16) -> String {
+        let computer = self.computer();
+        let (rom, pc) = (computer.rom(), computer.cpu().pc());
+        let labels = self.symbols.labels_by_address();
+        let mut out = String::new();
+
+        // the zeroed ROM past the program is not worth listing
+        let program_end = self.program_len.max(pc as usize + 1);
+        let end = (start as usize + count as usize).min(program_end);
+        for addr in start as usize..end {
+            if let Some(names) = labels.get(&(addr as u16)) {
+                for name in names {
+                    let _ = writeln!(out, "({})", name);
+                }
+            }
+
+            let word = rom.peek(addr);
+            let marker = if addr as u16 == pc { "=>" } else { "  " };
+            let text = disassemble(word).unwrap_or_else(|| format!("??? {:#06x}", word));
+            let _ = write!(out, "{} {:5}  {}", marker, addr, text);
+            if let Some(note) = self.annotation(rom, addr) {
+                let _ = write!(out, "  // {}", note);
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    // names an @value by what the next instruction does with it
+    fn annotation(&self, rom: &ROM32K, addr: usize) -> Option<String> {
+        let word = rom.peek(addr);
+        if word & 0x8000 != 0 || addr + 1 >= ROM32K::SIZE {
+            return None;
+        }
+        let next = rom.peek(addr + 1);
+        let is_c = next & 0xE000 == 0xE000;
+        let jumps = next & 0b111 != 0;
+        let uses_m = next & 0x1000 != 0 || next & 0b1000 != 0;
+
+        let labels = self.symbols.labels_by_address();
+        let variables = self.symbols.variables_by_address();
+        if is_c && jumps {
+            labels.get(&word).map(|names| names.join(", "))
+        } else if is_c && uses_m {
+            variables.get(&word).map(|name| name.to_string())
+        } else {
+            None
+        }
+    }
+
+    fn list_breakpoints(&self) -> String {
+        if self.breakpoints.is_empty() {
+            return "no breakpoints".to_string();
+        }
+        let names: Vec<String> = self
+            .breakpoints
+            .iter()
+            .map(|&addr| self.describe_rom(addr))
+            .collect();
+        names.join("\n")
+    }
+
+    fn describe_rom(&self, addr: u16) -> String {
+        match self.symbols.labels_by_address().get(&addr) {
+            Some(names) => format!("{} ({})", addr, names.join(", ")),
+            None => addr.to_string(),
+        }
+    }
+
+    fn describe_ram(&self, addr: u16) -> String {
+        describe_ram(&self.symbols, addr)
+    }
+
+    // the ROM addresses of a breakpoint on ADDR, LABEL or FILE:LINE, and its name
+    fn breakpoint_target(&self, target: &str) -> Result<(Vec<u16>, String), String> {
+        let source = target
+            .rsplit_once(':')
+            .filter(|_| self.symbols.label(target).is_none())
+            .and_then(|(file, line)| Some(Location::new(file, line.parse().ok()?)));
+        let Some(source) = source else {
+            let addr = self.rom_address(target)?;
+            return Ok((vec![addr], self.describe_rom(addr)));
+        };
+
+        let addrs = self.loaded_sources()?.addresses(&source);
+        if addrs.is_empty() {
+            return Err(format!("no code for {}", source));
+        }
+        let list: Vec<String> = addrs.iter().map(|addr| addr.to_string()).collect();
+        Ok((addrs, format!("{} (ROM {})", source, list.join(", "))))
+    }
+
+    fn rom_address(&self, target: &str) -> Result<u16, String> {
+        parse_number(target)
+            .or_else(|| self.symbols.label(target))
+            .filter(|&addr| (addr as usize) < ROM32K::SIZE)
+            .ok_or_else(|| format!("'{}' is not a ROM address or label", target))
+    }
+
+    // X or RAM[X], X being a number, a variable or a predefined symbol
+    fn ram_address(&self, target: &str) -> Result<u16, String> {
+        let inner = target
+            .strip_prefix("RAM[")
+            .and_then(|t| t.strip_suffix(']'))
+            .unwrap_or(target);
+        parse_number(inner)
+            .or_else(|| self.symbols.variable(inner))
+            .filter(|&addr| addr < 0x8000)
+            .ok_or_else(|| format!("'{}' is not a RAM address or variable", target))
+    }
+}
+
+fn describe_ram(symbols: &SymbolTable, addr: u16) -> String {
+    match symbols.variables_by_address().get(&addr) {
+        Some(name) => format!("RAM[{}] ({})", addr, name),
+        None => format!("RAM[{}]", addr),
+    }
+}
+
+// decimal or 0x-prefixed hex
+fn parse_number<T: TryFrom<u64>>(s: &str) -> Option<T> {
+    let value = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => s.parse().ok()?,
+    };
+    T::try_from(value).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::jack::{compile_with_map, parse};
+    use crate::vm::{Translator, VmFile};
+
+    // sums 1..=3 into sum, then halts
+    const SUM: &str = "
+        @i
+        M=1
+        @sum
+        M=0
+    (LOOP)
+        @i
+        D=M
+        @3
+        D=D-A
+        @END
+        D;JGT
+        @i
+        D=M
+        @sum
+        M=D+M
+        @i
+        M=M+1
+        @LOOP
+        0;JMP
+    (END)
+        @END
+        0;JMP
+    ";
+
+    fn debugger() -> Debugger {
+        let program = assemble(SUM).unwrap();
+        Debugger::new(&program.code, program.symbols)
+    }
+
+    #[test]
+    fn test_step_and_print() {
+        let mut dbg = debugger();
+        let out = dbg.execute("step").unwrap();
+        assert_eq!(out, "=>     1  M=1\n");
+
+        dbg.execute("next 3").unwrap();
+        assert_eq!(dbg.execute("print A"), Ok("A = 17 (0x0011)".to_string()));
+        assert_eq!(dbg.execute("p PC"), Ok("PC = 4 (LOOP)".to_string()));
+        assert_eq!(dbg.execute("p RAM[i]"), Ok("RAM[16] (i) = 1".to_string()));
+        assert_eq!(
+            dbg.execute("print sum"),
+            Ok("RAM[17] (sum) = 0".to_string())
+        );
+        assert_eq!(dbg.cycles(), 4);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut dbg = debugger();
+        assert_eq!(
+            dbg.execute("break LOOP"),
+            Ok("breakpoint at 4 (LOOP)".to_string())
+        );
+
+        let out = dbg.execute("continue").unwrap();
+        assert_eq!(out, "breakpoint at 4 (LOOP)\n(LOOP)\n=>     4  @16  // i\n");
+        let out = dbg.execute("c").unwrap();
+        assert!(out.starts_with("breakpoint at 4 (LOOP)\n"));
+        assert_eq!(
+            dbg.execute("x RAM[sum]"),
+            Ok("RAM[17]:      1\n".to_string())
+        );
+
+        dbg.execute("delete 4").unwrap();
+        assert!(dbg.execute("delete LOOP").is_err());
+        let out = dbg.execute("continue").unwrap();
+        assert!(out.starts_with("halted after"), "{}", out);
+        assert!(out.ends_with("(END)\n=>    18  @18  // END\n"), "{}", out);
+        assert_eq!(
+            dbg.execute("p RAM[17]"),
+            Ok("RAM[17] (sum) = 6".to_string())
+        );
+    }
+
+    #[test]
+    fn test_watch() {
+        let mut dbg = debugger();
+        dbg.execute("watch RAM[sum]").unwrap();
+        dbg.execute("next 4").unwrap();
+
+        let out = dbg.execute("continue").unwrap();
+        assert!(out.starts_with("watch RAM[17] (sum): 0 -> 1\n"), "{}", out);
+        let out = dbg.execute("continue").unwrap();
+        assert!(out.starts_with("watch RAM[17] (sum): 1 -> 3\n"), "{}", out);
+    }
+
+    #[test]
+    fn test_examine_and_disasm() {
+        let mut dbg = debugger();
+        dbg.execute("continue").unwrap();
+
+        let out = dbg.execute("x/10 RAM[0]").unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].starts_with("RAM[8]:"));
+        assert_eq!(
+            dbg.execute("x/3 16"),
+            Ok("RAM[16]:      4      6      0\n".to_string())
+        );
+
+        let out = dbg.execute("disasm LOOP 3").unwrap();
+        assert_eq!(
+            out,
+            "(LOOP)\n       4  @16  // i\n       5  D=M\n       6  @3\n"
+        );
+        let out = dbg.execute("disasm").unwrap();
+        assert!(out.contains("=>    18  @18  // END\n"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut dbg = debugger();
+        assert!(dbg.execute("break NOWHERE").is_err());
+        assert!(dbg.execute("watch RAM[nothing]").is_err());
+        assert!(dbg.execute("x/z RAM[0]").is_err());
+        assert!(dbg.execute("frobnicate").is_err());
+        assert_eq!(dbg.execute(""), Ok(String::new()));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("rcpu-debugger-{}.state", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut dbg = debugger();
+        dbg.execute("break LOOP").unwrap();
+        dbg.execute("continue").unwrap();
+        dbg.execute("continue").unwrap();
+        assert!(dbg.execute(&format!("save {}", path)).is_ok());
+        dbg.execute("delete LOOP").unwrap();
+        dbg.execute("continue").unwrap();
+        assert_eq!(dbg.execute("p sum"), Ok("RAM[17] (sum) = 6".to_string()));
+
+        let out = dbg.execute(&format!("load {}", path)).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(
+            out.starts_with("restored the machine at cycle 18\n"),
+            "{}",
+            out
+        );
+        assert_eq!(dbg.execute("p sum"), Ok("RAM[17] (sum) = 1".to_string()));
+        assert_eq!(dbg.execute("p PC"), Ok("PC = 4 (LOOP)".to_string()));
+
+        assert!(dbg.execute("load /nonexistent/state").is_err());
+    }
+
+    #[test]
+    fn test_set() {
+        let mut dbg = debugger();
+        dbg.execute("watch RAM[sum]").unwrap();
+        dbg.execute("next 4").unwrap();
+        assert_eq!(
+            dbg.execute("set sum 0x64"),
+            Ok("RAM[17] (sum) = 100".to_string())
+        );
+        // the watch does not report the write made by set
+        let out = dbg.execute("continue").unwrap();
+        assert!(
+            out.starts_with("watch RAM[17] (sum): 100 -> 101\n"),
+            "{}",
+            out
+        );
+        assert!(dbg.execute("set sum 70000").is_err());
+        assert_eq!(
+            dbg.execute("set RAM[24577] 5"),
+            Ok("RAM[24577] = 0".to_string())
+        );
+    }
+
+    #[test]
+    fn test_back() {
+        let mut dbg = debugger();
+        assert!(dbg.execute("back").is_err());
+
+        dbg.execute("watch RAM[sum]").unwrap();
+        dbg.execute("next 4").unwrap();
+        dbg.execute("continue").unwrap();
+        dbg.execute("continue").unwrap();
+        assert_eq!(dbg.execute("p sum"), Ok("RAM[17] (sum) = 3".to_string()));
+        let cycles = dbg.cycles();
+
+        let out = dbg.execute("back 3").unwrap();
+        assert!(
+            out.starts_with(&format!("undid 3 cycles, back at cycle {}\n", cycles - 3)),
+            "{}",
+            out
+        );
+        assert_eq!(dbg.execute("p sum"), Ok("RAM[17] (sum) = 1".to_string()));
+
+        // the watch sees the write again when it is replayed
+        let out = dbg.execute("continue").unwrap();
+        assert!(out.starts_with("watch RAM[17] (sum): 1 -> 3\n"), "{}", out);
+        assert_eq!(dbg.cycles(), cycles);
+
+        let out = dbg.execute("back 1000").unwrap();
+        assert!(out.starts_with(&format!("undid {} cycles, back at cycle 0\n", cycles)));
+        assert_eq!(dbg.execute("p PC"), Ok("PC = 0".to_string()));
+        assert_eq!(dbg.execute("p sum"), Ok("RAM[17] (sum) = 0".to_string()));
+    }
+
+    const SYS: &str = "\
+class Sys {
+    function void init() {
+        var int x;
+        let x = Sys.sum(3);
+        while (true) {}
+        return;
+    }
+
+    function int sum(int n) {
+        var int i, s;
+        while (i < n) {
+            let i = i + 1;
+            let s = s + i;
+        }
+        return s;
+    }
+}
+";
+
+    // Sys.jack compiled, translated and assembled, with the maps between them
+    fn jack_debugger() -> Debugger {
+        let (vm, vm_map) = compile_with_map(&parse(SYS).unwrap()).unwrap();
+        let (asm, asm_map) = Translator::new()
+            .translate_with_map(&[VmFile::new("Sys", &vm)])
+            .unwrap();
+        let program = assemble(&asm).unwrap();
+
+        let mut sources = SourceMaps::new(program.source_map("Sys.asm"));
+        sources.add_map("Sys.asm", asm_map);
+        sources.add_map("Sys.vm", vm_map);
+        sources.add_text("Sys.jack", SYS);
+        Debugger::new(&program.code, program.symbols).sources(sources)
+    }
+
+    #[test]
+    fn test_sources() {
+        assert!(debugger().execute("locals").is_err());
+
+        let mut dbg = jack_debugger();
+        assert_eq!(
+            dbg.execute("break Sys.jack:8"),
+            Err("no code for Sys.jack:8".to_string())
+        );
+        let out = dbg.execute("break Sys.jack:13").unwrap();
+        assert!(
+            out.starts_with("breakpoint at Sys.jack:13 (ROM "),
+            "{}",
+            out
+        );
+
+        let out = dbg.execute("continue").unwrap();
+        assert!(out.ends_with("at Sys.jack:13: let s = s + i;\n"), "{}", out);
+        assert_eq!(
+            dbg.execute("locals"),
+            Ok("argument n: int = 3\nlocal i: int = 1\nlocal s: int = 0\n".to_string())
+        );
+        assert_eq!(
+            dbg.execute("backtrace"),
+            Ok("#0 Sys.sum at Sys.jack:13: let s = s + i;\n\
+                #1 Sys.init at Sys.jack:4: let x = Sys.sum(3);\n"
+                .to_string())
+        );
+
+        dbg.execute("delete Sys.jack:13").unwrap();
+        let out = dbg.execute("continue").unwrap();
+        assert!(out.starts_with("halted after"), "{}", out);
+        assert!(out.ends_with("at Sys.jack:5: while (true) {}\n"), "{}", out);
+        assert_eq!(dbg.execute("locals"), Ok("local x: int = 6\n".to_string()));
+        assert_eq!(
+            dbg.execute("bt"),
+            Ok("#0 Sys.init at Sys.jack:5: while (true) {}\n".to_string())
+        );
+    }
+}
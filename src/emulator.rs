@@ -0,0 +1,715 @@
+use std::collections::HashMap;
+
+use crate::asm::{assemble, SymbolTable};
+use crate::builtins::{Builtin, Ram};
+use crate::jack::os::{is_bundled, screen_color};
+use crate::keyboard::KEYBOARD_ADDRESS;
+use crate::runner::Runner;
+use crate::vm::{parse, return_label, Command, Segment, Translator, VmError, VmFile};
+
+const SP: usize = 0;
+const LCL: usize = 1;
+const ARG: usize = 2;
+const THIS: usize = 3;
+const THAT: usize = 4;
+const TEMP: usize = 5;
+const FIRST_STATIC: u16 = 16;
+
+// a command with its jumps and names resolved
+#[derive(Debug, Clone)]
+enum Op {
+    Add,
+    Sub,
+    Neg,
+    Eq,
+    Gt,
+    Lt,
+    And,
+    Or,
+    Not,
+    Push(Segment, u16),
+    Pop(Segment, u16),
+    Goto(usize),
+    IfGoto(usize),
+    Function(u16),
+    Call {
+        target: usize,
+        args: u16,
+        site: usize,
+    },
+    Return,
+}
+
+struct Instruction {
+    op: Op,
+    file: usize,
+    line: usize,
+    static_addr: u16, // RAM address of the static a push or pop uses
+}
+
+/**
+ * Runs VM programs directly, on a RAM laid out as the translated program's:
+ * SP, LCL, ARG, THIS and THAT in RAM[0..5], temp in RAM[5..13], statics from
+ * RAM[16] in order of first use, the stack from 256, the screen at 0x4000.
+ * Every command leaves RAM as its translation does, R13..R15 aside, which
+ * the translated code uses as scratch.
+ */
+pub struct VmEmulator {
+    ram: Vec<u16>,
+    program: Vec<Instruction>,
+    files: Vec<String>,
+    pc: usize,
+    steps: u64,
+    halted: bool,
+    // what a call pushes as its return address, per call site, and back
+    return_labels: Vec<String>,
+    return_values: Vec<u16>,
+    return_sites: HashMap<u16, usize>,
+    // the command each call site returns to, None for the bootstrap's
+    site_returns: Vec<Option<usize>>,
+    // Sys.init, called by the first step so use_symbols can come before
+    init: Option<usize>,
+    // the OS functions run natively when accelerated, by function index
+    builtins: HashMap<usize, Builtin>,
+    accelerate: bool,
+    screen_color: Option<u16>,
+}
+
+impl VmEmulator {
+    /**
+     * Loads the files as Translator does: with the bootstrap SP is 256 and
+     * Sys.init is called when it exists, otherwise the first command runs
+     * first and the caller sets up the pointers.
+     */
+    pub fn new(files: &[VmFile], bootstrap: bool) -> Result<Self, VmError> {
+        let mut parsed = Vec::new();
+        for file in files {
+            let commands = parse(&file.source).map_err(|(line, message)| VmError {
+                file: file.name.clone(),
+                line,
+                message,
+            })?;
+            parsed.push(commands);
+        }
+
+        let has_init = parsed
+            .iter()
+            .flatten()
+            .any(|(_, c)| matches!(c, Command::Function(f, _) if f == "Sys.init"));
+        let bootstrap_call = bootstrap && has_init;
+
+        // first pass: where labels and functions are, statics in order of use
+        let mut labels = HashMap::new();
+        let mut functions = HashMap::new();
+        let mut statics: HashMap<String, u16> = HashMap::new();
+        let mut next_static = FIRST_STATIC;
+        let mut index = 0;
+        let mut return_labels = Vec::new();
+        let mut returns = 0;
+        if bootstrap_call {
+            returns += 1;
+            return_labels.push(return_label("Sys.init", returns));
+        }
+
+        for (file, commands) in files.iter().zip(&parsed) {
+            let mut scope = file.name.clone();
+            for (_, command) in commands {
+                match command {
+                    Command::Label(label) => {
+                        labels.insert(format!("{}${}", scope, label), index);
+                        continue;
+                    }
+                    Command::Function(name, _) => {
+                        scope = name.clone();
+                        functions.insert(name.clone(), index);
+                    }
+                    Command::Push(Segment::Static, i) | Command::Pop(Segment::Static, i) => {
+                        statics
+                            .entry(format!("{}.{}", file.name, i))
+                            .or_insert_with(|| {
+                                next_static += 1;
+                                next_static - 1
+                            });
+                    }
+                    Command::Call(..) | Command::Eq | Command::Gt | Command::Lt => {
+                        returns += 1;
+                        if matches!(command, Command::Call(..)) {
+                            return_labels.push(return_label(&scope, returns));
+                        }
+                    }
+                    _ => {}
+                }
+                index += 1;
+            }
+        }
+
+        // second pass: resolve names
+        let mut program = Vec::new();
+        let mut site_returns = match bootstrap_call {
+            true => vec![None],
+            false => Vec::new(),
+        };
+        for (i, (file, commands)) in files.iter().zip(&parsed).enumerate() {
+            let mut scope = file.name.clone();
+            for (line, command) in commands {
+                let error = |message: String| VmError {
+                    file: file.name.clone(),
+                    line: *line,
+                    message,
+                };
+                let label = |label: &str| {
+                    labels
+                        .get(&format!("{}${}", scope, label))
+                        .copied()
+                        .ok_or_else(|| error(format!("unknown label '{}'", label)))
+                };
+
+                let op = match command {
+                    Command::Add => Op::Add,
+                    Command::Sub => Op::Sub,
+                    Command::Neg => Op::Neg,
+                    Command::Eq => Op::Eq,
+                    Command::Gt => Op::Gt,
+                    Command::Lt => Op::Lt,
+                    Command::And => Op::And,
+                    Command::Or => Op::Or,
+                    Command::Not => Op::Not,
+                    Command::Push(segment, n) => Op::Push(*segment, *n),
+                    Command::Pop(segment, n) => Op::Pop(*segment, *n),
+                    Command::Label(_) => continue,
+                    Command::Goto(l) => Op::Goto(label(l)?),
+                    Command::IfGoto(l) => Op::IfGoto(label(l)?),
+                    Command::Function(name, locals) => {
+                        scope = name.clone();
+                        Op::Function(*locals)
+                    }
+                    Command::Call(name, args) => {
+                        let target = *functions
+                            .get(name)
+                            .ok_or_else(|| error(format!("unknown function '{}'", name)))?;
+                        site_returns.push(Some(program.len() + 1));
+                        Op::Call {
+                            target,
+                            args: *args,
+                            site: site_returns.len() - 1,
+                        }
+                    }
+                    Command::Return => Op::Return,
+                };
+
+                let static_addr = match command {
+                    Command::Push(Segment::Static, n) | Command::Pop(Segment::Static, n) => {
+                        statics[&format!("{}.{}", file.name, n)]
+                    }
+                    _ => 0,
+                };
+                program.push(Instruction {
+                    op,
+                    file: i,
+                    line: *line,
+                    static_addr,
+                });
+            }
+        }
+
+        // a program's own versions of the OS functions are not accelerated
+        let builtins = functions
+            .iter()
+            .filter_map(|(name, &index)| Some((name, index, Builtin::from_name(name)?)))
+            .filter(|&(name, index, _)| is_bundled(&files[program[index].file], name))
+            .map(|(_, index, builtin)| (index, builtin))
+            .collect();
+        let mut emulator = VmEmulator {
+            ram: vec![0; 1 << 15],
+            program,
+            files: files.iter().map(|f| f.name.clone()).collect(),
+            pc: 0,
+            steps: 0,
+            halted: false,
+            return_values: Vec::new(),
+            return_sites: HashMap::new(),
+            return_labels,
+            site_returns,
+            init: None,
+            builtins,
+            accelerate: false,
+            screen_color: statics.get(&screen_color()).copied(),
+        };
+        // without an assembled program, a call pushes its site's number
+        let sites: Vec<u16> = (0..emulator.return_labels.len() as u16).collect();
+        emulator.set_return_values(sites);
+
+        if bootstrap {
+            emulator.ram[SP] = 256;
+            if bootstrap_call {
+                emulator.init = Some(functions["Sys.init"]);
+            }
+        }
+        Ok(emulator)
+    }
+
+    /**
+     * Makes calls push the return addresses of the translated program,
+     * found by their labels in the symbols of its assembly. To be called
+     * before running: frames pushed earlier could no longer return.
+     */
+    pub fn use_symbols(&mut self, symbols: &SymbolTable) {
+        let values = self
+            .return_labels
+            .iter()
+            .map(|label| symbols.label(label).unwrap_or(0))
+            .collect();
+        self.set_return_values(values);
+    }
+
+    /**
+     * Runs calls of the builtins of the bundled OS natively, where they
+     * would not end in Sys.error. Off by default: the Jack code runs,
+     * pixel by pixel.
+     */
+    pub fn accelerate(&mut self, on: bool) {
+        self.accelerate = on;
+    }
+
+    fn set_return_values(&mut self, values: Vec<u16>) {
+        self.return_sites = values.iter().enumerate().map(|(i, &v)| (v, i)).collect();
+        self.return_values = values;
+    }
+
+    pub fn peek(&self, addr: u16) -> u16 {
+        match addr as usize & 0x7FFF {
+            addr if addr <= KEYBOARD_ADDRESS as usize => self.ram[addr],
+            _ => 0,
+        }
+    }
+
+    // the key held down, as Keyboard::poke; 0 for none
+    pub fn set_key(&mut self, key: u16) {
+        self.ram[KEYBOARD_ADDRESS as usize] = key;
+    }
+
+    // writes to the keyboard and above are ignored, as by Memory
+    pub fn poke(&mut self, addr: u16, value: u16) {
+        let addr = addr as usize & 0x7FFF;
+        if addr < KEYBOARD_ADDRESS as usize {
+            self.ram[addr] = value;
+        }
+    }
+
+    pub fn ram(&self) -> &[u16] {
+        &self.ram[..KEYBOARD_ADDRESS as usize]
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /**
+     * Ran off the end of the program, returned from the bootstrap's
+     * Sys.init, or is in a goto to itself: the VM form of the halt loop.
+     */
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    // the file and line of the next command
+    pub fn location(&self) -> Option<(&str, usize)> {
+        let instruction = self.program.get(self.pc)?;
+        Some((&self.files[instruction.file], instruction.line))
+    }
+
+    fn error(&self, message: String) -> VmError {
+        let (file, line) = self.location().unwrap_or(("", 0));
+        VmError {
+            file: file.to_string(),
+            line,
+            message,
+        }
+    }
+
+    fn push(&mut self, value: u16) {
+        let sp = self.ram[SP];
+        self.poke(sp, value);
+        self.ram[SP] = sp.wrapping_add(1);
+    }
+
+    fn pop(&mut self) -> u16 {
+        self.ram[SP] = self.ram[SP].wrapping_sub(1);
+        self.peek(self.ram[SP])
+    }
+
+    fn address(&self, segment: Segment, i: u16, static_addr: u16) -> u16 {
+        match segment {
+            Segment::Argument => self.ram[ARG].wrapping_add(i),
+            Segment::Local => self.ram[LCL].wrapping_add(i),
+            Segment::This => self.ram[THIS].wrapping_add(i),
+            Segment::That => self.ram[THAT].wrapping_add(i),
+            Segment::Pointer => (THIS as u16) + i,
+            Segment::Temp => (TEMP as u16) + i,
+            Segment::Static => static_addr,
+            Segment::Constant => unreachable!("constants have no address"),
+        }
+    }
+
+    fn call(&mut self, target: usize, args: u16, site: usize) {
+        self.push(self.return_values[site]);
+        for pointer in [LCL, ARG, THIS, THAT] {
+            self.push(self.ram[pointer]);
+        }
+        self.ram[ARG] = self.ram[SP].wrapping_sub(5 + args);
+        self.ram[LCL] = self.ram[SP];
+        self.pc = target;
+    }
+
+    // binary operation on the two top words, the result replacing them
+    fn binary(&mut self, f: impl Fn(u16, u16) -> u16) {
+        let y = self.pop();
+        let x = self.pop();
+        self.push(f(x, y));
+    }
+
+    /**
+     * Executes one command. Runtime errors, as a return to an address no
+     * call pushed, stop the emulator where they happened.
+     */
+    pub fn step(&mut self) -> Result<(), VmError> {
+        if let Some(init) = self.init.take() {
+            self.call(init, 0, 0);
+        }
+        if self.halted {
+            return Ok(());
+        }
+        let Some(instruction) = self.program.get(self.pc) else {
+            self.halted = true;
+            return Ok(());
+        };
+        let op = instruction.op.clone();
+        let static_addr = instruction.static_addr;
+        let mut next = self.pc + 1;
+
+        // gt and lt compare signed words, as the translation does
+        let truth = |b: bool| if b { 0xFFFF } else { 0 };
+        match op {
+            Op::Add => self.binary(|x, y| x.wrapping_add(y)),
+            Op::Sub => self.binary(|x, y| x.wrapping_sub(y)),
+            Op::And => self.binary(|x, y| x & y),
+            Op::Or => self.binary(|x, y| x | y),
+            Op::Eq => self.binary(|x, y| truth(x == y)),
+            Op::Gt => self.binary(|x, y| truth((x as i16) > (y as i16))),
+            Op::Lt => self.binary(|x, y| truth((x as i16) < (y as i16))),
+            Op::Neg => {
+                let x = self.pop();
+                self.push(x.wrapping_neg());
+            }
+            Op::Not => {
+                let x = self.pop();
+                self.push(!x);
+            }
+            Op::Push(Segment::Constant, i) => self.push(i),
+            Op::Push(segment, i) => {
+                let value = self.peek(self.address(segment, i, static_addr));
+                self.push(value);
+            }
+            Op::Pop(segment, i) => {
+                let addr = self.address(segment, i, static_addr);
+                let value = self.pop();
+                self.poke(addr, value);
+            }
+            Op::Goto(target) => {
+                if target == self.pc {
+                    self.halted = true;
+                    return Ok(());
+                }
+                next = target;
+            }
+            Op::IfGoto(target) => {
+                if self.pop() != 0 {
+                    next = target;
+                }
+            }
+            Op::Function(locals) => {
+                for _ in 0..locals {
+                    self.push(0);
+                }
+            }
+            Op::Call { target, args, .. } if self.accelerate && self.native(target, args) => {}
+            Op::Call { target, args, site } => {
+                self.pc = next;
+                self.call(target, args, site);
+                next = self.pc;
+            }
+            Op::Return => {
+                let frame = self.ram[LCL];
+                let value = self.peek(frame.wrapping_sub(5));
+                let result = self.pop();
+                self.poke(self.ram[ARG], result);
+                self.ram[SP] = self.ram[ARG].wrapping_add(1);
+                self.ram[THAT] = self.peek(frame.wrapping_sub(1));
+                self.ram[THIS] = self.peek(frame.wrapping_sub(2));
+                self.ram[ARG] = self.peek(frame.wrapping_sub(3));
+                self.ram[LCL] = self.peek(frame.wrapping_sub(4));
+
+                let Some(&site) = self.return_sites.get(&value) else {
+                    return Err(self.error(format!("return to unknown address {}", value)));
+                };
+                match self.site_returns[site] {
+                    Some(site_return) => next = site_return,
+                    // Sys.init returned to the bootstrap, which halts
+                    None => {
+                        self.halted = true;
+                        next = self.program.len();
+                    }
+                }
+            }
+        }
+
+        self.pc = next;
+        self.steps += 1;
+        Ok(())
+    }
+
+    // runs an accelerated call and leaves its result on the stack, as its return
+    fn native(&mut self, target: usize, args: u16) -> bool {
+        let Some(&builtin) = self.builtins.get(&target) else {
+            return false;
+        };
+        if builtin.args() != args as usize {
+            return false;
+        }
+        let sp = self.ram[SP];
+        let base = sp.wrapping_sub(args);
+        let values: Vec<u16> = (base..sp).map(|addr| self.peek(addr)).collect();
+        let color = self.screen_color.map(|addr| self.peek(addr));
+        let Some(result) = builtin.call(&values, self, color) else {
+            return false;
+        };
+        self.ram[SP] = base;
+        self.push(result);
+        true
+    }
+
+    // steps until the program halts or max_steps commands were executed
+    pub fn run(&mut self, max_steps: u64) -> Result<(), VmError> {
+        let start = self.steps;
+        while !self.halted && self.steps - start < max_steps {
+            self.step()?;
+        }
+        Ok(())
+    }
+}
+
+impl Ram for VmEmulator {
+    fn peek(&self, addr: u16) -> u16 {
+        VmEmulator::peek(self, addr)
+    }
+
+    fn poke(&mut self, addr: u16, value: u16) {
+        VmEmulator::poke(self, addr, value);
+    }
+}
+
+/**
+ * Runs the files in the emulator and, translated and assembled, on the
+ * Hack computer, both until they halt, and lists the RAM words below the
+ * keyboard that differ as (address, emulator, computer). R13..R15 are
+ * left out, they are scratch registers of the translated code.
+ */
+pub fn cross_check(
+    files: &[VmFile],
+    max_steps: u64,
+    max_cycles: u64,
+) -> Result<Vec<(u16, u16, u16)>, String> {
+    let asm = Translator::new()
+        .translate(files)
+        .map_err(|e| e.to_string())?;
+    let program = assemble(&asm).map_err(|e| e.to_string())?;
+
+    let mut emulator = VmEmulator::new(files, true).map_err(|e| e.to_string())?;
+    emulator.use_symbols(&program.symbols);
+    emulator.run(max_steps).map_err(|e| e.to_string())?;
+    if !emulator.halted() {
+        return Err(format!("the emulator did not halt in {} steps", max_steps));
+    }
+
+    let report = Runner::new()
+        .max_cycles(max_cycles)
+        .dump(0..KEYBOARD_ADDRESS)
+        .run(&program.code);
+    if !report.halted {
+        return Err(format!(
+            "the computer did not halt in {} cycles",
+            max_cycles
+        ));
+    }
+
+    let computer = &report.ram[0].1;
+    Ok(emulator
+        .ram()
+        .iter()
+        .zip(computer)
+        .enumerate()
+        .filter(|&(addr, (a, b))| a != b && !(13..16).contains(&addr))
+        .map(|(addr, (&a, &b))| (addr as u16, a, b))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIN: &str = "
+        function Sys.init 0
+        push constant 6
+        call Main.fib 1
+        pop static 0
+        push constant 2048
+        pop pointer 1
+        push constant 17
+        push constant 3
+        call Main.mul 2
+        pop that 1
+        push constant 16384
+        pop pointer 0
+        push constant 1
+        neg
+        pop this 33
+        call Counter.next 0
+        call Counter.next 0
+        add
+        pop temp 2
+        push constant 30000
+        push constant 30000
+        neg
+        gt
+        push constant 5
+        push constant 5
+        eq
+        label END
+        goto END
+
+        function Main.fib 0
+        push argument 0
+        push constant 2
+        lt
+        if-goto BASE
+        push argument 0
+        push constant 2
+        sub
+        call Main.fib 1
+        push argument 0
+        push constant 1
+        sub
+        call Main.fib 1
+        add
+        return
+        label BASE
+        push argument 0
+        return
+
+        function Main.mul 2
+        label LOOP
+        push argument 1
+        push constant 0
+        eq
+        if-goto DONE
+        push local 0
+        push argument 0
+        add
+        pop local 0
+        push argument 1
+        push constant 1
+        sub
+        pop argument 1
+        goto LOOP
+        label DONE
+        push local 0
+        return
+    ";
+
+    const COUNTER: &str = "
+        function Counter.next 0
+        push static 1
+        push constant 1
+        add
+        pop static 1
+        push static 1
+        return
+    ";
+
+    fn files() -> Vec<VmFile> {
+        vec![VmFile::new("Main", MAIN), VmFile::new("Counter", COUNTER)]
+    }
+
+    #[test]
+    fn test_emulator() {
+        let mut emulator = VmEmulator::new(&files(), true).unwrap();
+        emulator.run(100_000).unwrap();
+        assert!(emulator.halted());
+        assert_eq!(emulator.location(), Some(("Main", 29)));
+
+        // Main.0 is the first static used, Counter.1 the second
+        assert_eq!(emulator.peek(16), 8);
+        assert_eq!(emulator.peek(17), 2);
+        assert_eq!(emulator.peek(2049), 51);
+        assert_eq!(emulator.peek(0x4000 + 33), 0xFFFF);
+        assert_eq!(emulator.peek(7), 3);
+        // 30000 > -30000, though x - y overflows
+        assert_eq!(emulator.peek(261), 0xFFFF);
+        assert_eq!(emulator.peek(262), 0xFFFF);
+        assert_eq!(emulator.peek(0), 263);
+    }
+
+    #[test]
+    fn test_cross_check() {
+        assert_eq!(cross_check(&files(), 100_000, 100_000), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_without_bootstrap() {
+        let source = "
+            push constant 10
+            pop local 0
+            push constant 21
+            pop argument 2
+            push local 0
+            push argument 2
+            add
+        ";
+        let mut emulator = VmEmulator::new(&[VmFile::new("Basic", source)], false).unwrap();
+        for (addr, value) in [(0, 256), (1, 300), (2, 400)] {
+            emulator.poke(addr, value);
+        }
+        emulator.run(100).unwrap();
+        assert!(emulator.halted());
+        assert_eq!(emulator.steps(), 7);
+        assert_eq!((emulator.peek(0), emulator.peek(256)), (257, 31));
+        assert_eq!((emulator.peek(300), emulator.peek(402)), (10, 21));
+    }
+
+    #[test]
+    fn test_errors() {
+        let load = |source: &str| {
+            VmEmulator::new(&[VmFile::new("Main", source)], true)
+                .err()
+                .map(|e| e.to_string())
+        };
+        assert_eq!(
+            load("function Sys.init 0\ncall Main.nothing 0\n"),
+            Some("Main.vm: line 2: unknown function 'Main.nothing'".to_string())
+        );
+        assert_eq!(
+            load("function Main.f 0\nlabel L\nfunction Main.g 0\ngoto L\n"),
+            Some("Main.vm: line 4: unknown label 'L'".to_string())
+        );
+
+        // returning without a frame goes to a return address no call pushed
+        let mut emulator = VmEmulator::new(&[VmFile::new("Main", "return\n")], false).unwrap();
+        emulator.poke(1, 300);
+        emulator.poke(295, 999);
+        let err = emulator.run(10).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Main.vm: line 1: return to unknown address 999"
+        );
+    }
+}
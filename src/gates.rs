@@ -1,3 +1,5 @@
+use crate::bus::{Bus, Bus16, Bus8};
+
 pub fn and_gate(x: bool, y: bool) -> bool {
     x && y
 }
@@ -34,86 +36,110 @@ pub fn dmux_gate(x: bool, sel: bool) -> (bool, bool) {
     (and_gate(x, out1), and_gate(x, sel))
 }
 
-pub fn not16_gate(x: u16) -> u16 {
-    let mut result = 0;
+pub fn not_n_gate<const N: usize>(x: Bus<N>) -> Bus<N> {
+    Bus::from_fn(|i| not_gate(x.bit(i)))
+}
 
-    for i in 0..16 {
-        let bit = (x >> i) & 1 != 0;
-        let res = not_gate(bit);
-        result |= (res as u16) << i;
-    }
+pub fn and_n_gate<const N: usize>(x: Bus<N>, y: Bus<N>) -> Bus<N> {
+    Bus::from_fn(|i| and_gate(x.bit(i), y.bit(i)))
+}
 
-    result
+pub fn or_n_gate<const N: usize>(x: Bus<N>, y: Bus<N>) -> Bus<N> {
+    Bus::from_fn(|i| or_gate(x.bit(i), y.bit(i)))
 }
 
-pub fn and16_gate(x: u16, y: u16) -> u16 {
-    let mut result = 0;
+pub fn mux_n_gate<const N: usize>(x: Bus<N>, y: Bus<N>, sel: bool) -> Bus<N> {
+    Bus::from_fn(|i| mux(x.bit(i), y.bit(i), sel))
+}
 
-    for i in 0..16 {
-        let x_bit = (x >> i) & 1 != 0;
-        let y_bit = (y >> i) & 1 != 0;
-        let res = and_gate(x_bit, y_bit);
-        result |= (res as u16) << i;
+/**
+ * N-way Or:
+ * out = in[0] or in[1] or ... or in[N-1]
+ * Built as a balanced tree of or gates.
+ */
+pub fn or_nway_gate<const N: usize>(x: Bus<N>) -> bool {
+    let mut level: Vec<bool> = (0..N).map(|i| x.bit(i)).collect();
+
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [a, b] => or_gate(*a, *b),
+                [a] => *a,
+                _ => unreachable!(),
+            })
+            .collect();
     }
 
-    result
+    level[0]
 }
 
-pub fn or16_gate(x: u16, y: u16) -> u16 {
-    let mut result = 0;
-
-    for i in 0..16 {
-        let x_bit = (x >> i) & 1 != 0;
-        let y_bit = (y >> i) & 1 != 0;
-        let res = or_gate(x_bit, y_bit);
-        result |= (res as u16) << i;
-    }
-
-    result
+pub fn mux4way_n_gate<const N: usize>(
+    a: Bus<N>,
+    b: Bus<N>,
+    c: Bus<N>,
+    d: Bus<N>,
+    sel: u8,
+) -> Bus<N> {
+    let sel_1 = sel & 1;
+    let sel_2 = (sel >> 1) & 1;
+    let out_1 = mux_n_gate(a, b, sel_1 != 0);
+    let out_2 = mux_n_gate(c, d, sel_1 != 0);
+    mux_n_gate(out_1, out_2, sel_2 != 0)
 }
 
-pub fn mux16_gate(x: u16, y: u16, sel: bool) -> u16 {
-    let mut result = 0;
+#[allow(clippy::too_many_arguments)]
+pub fn mux8way_n_gate<const N: usize>(
+    a: Bus<N>,
+    b: Bus<N>,
+    c: Bus<N>,
+    d: Bus<N>,
+    e: Bus<N>,
+    f: Bus<N>,
+    g: Bus<N>,
+    h: Bus<N>,
+    sel: u8,
+) -> Bus<N> {
+    let sel_1 = sel & 1;
+    let sel_2 = (sel >> 1) & 1;
+    let sel_3 = (sel >> 2) & 1;
 
-    for i in 0..16 {
-        let x_bit = (x >> i) & 1 != 0;
-        let y_bit = (y >> i) & 1 != 0;
-        let res = mux(x_bit, y_bit, sel);
-        result |= (res as u16) << i;
-    }
-    result
-}
+    let out_1 = mux_n_gate(a, b, sel_1 != 0);
+    let out_2 = mux_n_gate(c, d, sel_1 != 0);
+    let out_3 = mux_n_gate(e, f, sel_1 != 0);
+    let out_4 = mux_n_gate(g, h, sel_1 != 0);
 
-pub fn or8way_gate(x: u8) -> bool {
-    let mut arr: [bool; 4] = [false; 4];
+    let out_5 = mux_n_gate(out_1, out_2, sel_2 != 0);
+    let out_6 = mux_n_gate(out_3, out_4, sel_2 != 0);
 
-    let mut index = 0;
-    for _ in 0..4 {
-        let x_bit_1 = (x >> index) & 1 != 0;
-        let x_bit_2 = (x >> (index + 1)) & 1 != 0;
+    mux_n_gate(out_5, out_6, sel_3 != 0)
+}
 
-        let x_res = or_gate(x_bit_1, x_bit_2);
+pub fn not16_gate(x: u16) -> u16 {
+    not_n_gate(Bus16::from(x)).into()
+}
 
-        arr[index] = x_res;
-        index += 1;
-    }
+pub fn and16_gate(x: u16, y: u16) -> u16 {
+    and_n_gate(Bus16::from(x), Bus16::from(y)).into()
+}
 
-    let mut arr2: [bool; 2] = [false; 2];
+pub fn or16_gate(x: u16, y: u16) -> u16 {
+    or_n_gate(Bus16::from(x), Bus16::from(y)).into()
+}
 
-    arr2[0] = or_gate(arr[0], arr[1]);
-    arr2[1] = or_gate(arr[2], arr[3]);
+pub fn mux16_gate(x: u16, y: u16, sel: bool) -> u16 {
+    mux_n_gate(Bus16::from(x), Bus16::from(y), sel).into()
+}
 
-    or_gate(arr2[0], arr2[1])
+pub fn or8way_gate(x: u8) -> bool {
+    or_nway_gate(Bus8::from(x))
 }
 
 pub fn mux4way16_gate(a: u16, b: u16, c: u16, d: u16, sel: u8) -> u16 {
-    let sel_1 = sel & 1;
-    let sel_2 = (sel >> 1) & 1;
-    let out_1 = mux16_gate(a, b, sel_1 != 0);
-    let out_2 = mux16_gate(c, d, sel_1 != 0);
-    mux16_gate(out_1, out_2, sel_2 != 0)
+    mux4way_n_gate(a.into(), b.into(), c.into(), d.into(), sel).into()
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn mux8way16_gate(
     a: u16,
     b: u16,
@@ -125,28 +151,27 @@ pub fn mux8way16_gate(
     h: u16,
     sel: u8,
 ) -> u16 {
-    let sel_1 = sel & 1;
-    let sel_2 = (sel >> 1) & 1;
-    let sel_3 = (sel >> 2) & 1;
-
-    let out_1 = mux16_gate(a, b, sel_1 != 0);
-    let out_2 = mux16_gate(c, d, sel_1 != 0);
-    let out_3 = mux16_gate(e, f, sel_1 != 0);
-    let out_4 = mux16_gate(g, h, sel_1 != 0);
-
-    let out_5 = mux16_gate(out_1, out_2, sel_2 != 0);
-    let out_6 = mux16_gate(out_3, out_4, sel_2 != 0);
-
-    mux16_gate(out_5, out_6, sel_3 != 0)
+    mux8way_n_gate(
+        a.into(),
+        b.into(),
+        c.into(),
+        d.into(),
+        e.into(),
+        f.into(),
+        g.into(),
+        h.into(),
+        sel,
+    )
+    .into()
 }
 
 pub fn dmux4way_gate(inp: bool, sel: u8) -> (bool, bool, bool, bool) {
     let sel_1 = sel & 1;
     let sel_2 = (sel >> 1) & 1;
 
-    let (out_1, out_2) = dmux_gate(inp, sel_1 != 0);
-    let (a, b) = dmux_gate(out_1, sel_2 != 0);
-    let (c, d) = dmux_gate(out_2, sel_2 != 0);
+    let (out_1, out_2) = dmux_gate(inp, sel_2 != 0);
+    let (a, b) = dmux_gate(out_1, sel_1 != 0);
+    let (c, d) = dmux_gate(out_2, sel_1 != 0);
 
     (a, b, c, d)
 }
@@ -213,12 +238,13 @@ pub fn dmux8way_gate(inp: bool, sel: u8) -> u8 {
         | (e_bit << 3)
         | (f_bit << 2)
         | (g_bit << 1)
-        | (h_bit << 0)
+        | h_bit
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::verify::Harness;
 
     #[test]
     fn test_and() {
@@ -324,6 +350,51 @@ mod tests {
         assert_eq!(res, true);
     }
 
+    #[test]
+    fn test_or8way_high_bits() {
+        for i in 0..8 {
+            assert_eq!(or8way_gate(1 << i), true);
+        }
+    }
+
+    #[test]
+    fn test_n_bit_gates() {
+        let x = Bus::<8>::new(0b1100_1010);
+        let y = Bus::<8>::new(0b1010_0110);
+        assert_eq!(not_n_gate(x).value(), 0b0011_0101);
+        assert_eq!(and_n_gate(x, y).value(), 0b1000_0010);
+        assert_eq!(or_n_gate(x, y).value(), 0b1110_1110);
+        assert_eq!(mux_n_gate(x, y, false), x);
+        assert_eq!(mux_n_gate(x, y, true), y);
+
+        let x = Bus::<64>::new(0xDEAD_BEEF_0000_FFFF);
+        assert_eq!(not_n_gate(x).value(), !0xDEAD_BEEF_0000_FFFFu64);
+
+        let x = Bus::<32>::new(0x8000_0000);
+        assert_eq!(or_nway_gate(x), true);
+        assert_eq!(or_nway_gate(Bus::<32>::zero()), false);
+        assert_eq!(or_nway_gate(Bus::<3>::new(0b100)), true);
+        assert_eq!(or_nway_gate(Bus::<1>::new(0)), false);
+    }
+
+    #[test]
+    fn test_n_bit_muxes() {
+        let inputs: Vec<Bus<32>> = (0..8).map(|i| Bus::new(0x1111_1111 * i)).collect();
+
+        for sel in 0..4 {
+            let res = mux4way_n_gate(inputs[0], inputs[1], inputs[2], inputs[3], sel);
+            assert_eq!(res, inputs[sel as usize]);
+        }
+
+        for sel in 0..8 {
+            let res = mux8way_n_gate(
+                inputs[0], inputs[1], inputs[2], inputs[3], inputs[4], inputs[5], inputs[6],
+                inputs[7], sel,
+            );
+            assert_eq!(res, inputs[sel as usize]);
+        }
+    }
+
     #[test]
     fn test_mux4way16() {
         let a: u16 = 0b0001001000110100;
@@ -376,4 +447,129 @@ mod tests {
         let res = dmux8way_gate(inp, sel);
         assert_eq!(res, 0b00000100);
     }
+
+    #[test]
+    fn test_harness_1bit_gates() {
+        let harness = Harness::new();
+
+        harness.assert_equivalent("not", &["in"], |x: bool| not_gate(x), |x| !x);
+        harness.assert_equivalent("and", &["a", "b"], |(x, y)| and_gate(x, y), |(x, y)| x & y);
+        harness.assert_equivalent("or", &["a", "b"], |(x, y)| or_gate(x, y), |(x, y)| x | y);
+        harness.assert_equivalent(
+            "nand",
+            &["a", "b"],
+            |(x, y)| nand_gate(x, y),
+            |(x, y)| !(x & y),
+        );
+        harness.assert_equivalent("xor", &["a", "b"], |(x, y)| xor_gate(x, y), |(x, y)| x ^ y);
+        harness.assert_equivalent(
+            "mux",
+            &["a", "b", "sel"],
+            |(x, y, sel)| mux(x, y, sel),
+            |(x, y, sel)| if sel { y } else { x },
+        );
+        harness.assert_equivalent(
+            "dmux",
+            &["in", "sel"],
+            |(x, sel)| dmux_gate(x, sel),
+            |(x, sel)| if sel { (false, x) } else { (x, false) },
+        );
+    }
+
+    #[test]
+    fn test_harness_16bit_gates() {
+        let harness = Harness::new();
+
+        harness.assert_equivalent("not16", &["in"], not16_gate, |x: u16| !x);
+        harness.assert_equivalent(
+            "and16",
+            &["a", "b"],
+            |(x, y)| and16_gate(x, y),
+            |(x, y): (u16, u16)| x & y,
+        );
+        harness.assert_equivalent(
+            "or16",
+            &["a", "b"],
+            |(x, y)| or16_gate(x, y),
+            |(x, y): (u16, u16)| x | y,
+        );
+        harness.assert_equivalent(
+            "mux16",
+            &["a", "b", "sel"],
+            |(x, y, sel)| mux16_gate(x, y, sel),
+            |(x, y, sel): (u16, u16, bool)| if sel { y } else { x },
+        );
+        harness.assert_equivalent("or8way", &["in"], or8way_gate, |x: u8| x != 0);
+    }
+
+    #[test]
+    fn test_harness_n_bit_gates() {
+        let harness = Harness::new();
+
+        harness.assert_equivalent("not32", &["in"], not_n_gate::<32>, |x| Bus::new(!x.value()));
+        harness.assert_equivalent(
+            "and64",
+            &["a", "b"],
+            |(x, y)| and_n_gate::<64>(x, y),
+            |(x, y)| Bus::new(x.value() & y.value()),
+        );
+        harness.assert_equivalent(
+            "or8",
+            &["a", "b"],
+            |(x, y)| or_n_gate::<8>(x, y),
+            |(x, y)| Bus::new(x.value() | y.value()),
+        );
+        harness.assert_equivalent(
+            "mux32",
+            &["a", "b", "sel"],
+            |(x, y, sel)| mux_n_gate::<32>(x, y, sel),
+            |(x, y, sel)| if sel { y } else { x },
+        );
+        harness.assert_equivalent("or13way", &["in"], or_nway_gate::<13>, |x| x.value() != 0);
+        harness.assert_equivalent("or64way", &["in"], or_nway_gate::<64>, |x| x.value() != 0);
+    }
+
+    #[test]
+    fn test_harness_muxes() {
+        let harness = Harness::new();
+
+        harness.assert_equivalent(
+            "mux4way16",
+            &["a", "b", "c", "d", "sel"],
+            |(a, b, c, d, sel): (u16, u16, u16, u16, Bus<2>)| {
+                mux4way16_gate(a, b, c, d, sel.value() as u8)
+            },
+            |(a, b, c, d, sel)| [a, b, c, d][sel.value() as usize],
+        );
+        harness.assert_equivalent(
+            "mux8way16",
+            &["a", "b", "c", "d", "e", "f", "g", "h", "sel"],
+            |(a, b, c, d, e, f, g, h, sel): (u16, u16, u16, u16, u16, u16, u16, u16, Bus<3>)| {
+                mux8way16_gate(a, b, c, d, e, f, g, h, sel.value() as u8)
+            },
+            |(a, b, c, d, e, f, g, h, sel)| [a, b, c, d, e, f, g, h][sel.value() as usize],
+        );
+    }
+
+    #[test]
+    fn test_harness_dmuxes() {
+        let harness = Harness::new();
+
+        harness.assert_equivalent(
+            "dmux4way",
+            &["in", "sel"],
+            |(x, sel): (bool, Bus<2>)| dmux4way_gate(x, sel.value() as u8),
+            |(x, sel)| {
+                let sel = sel.value();
+                (x && sel == 0, x && sel == 1, x && sel == 2, x && sel == 3)
+            },
+        );
+        harness.assert_equivalent(
+            "dmux8way",
+            &["in", "sel"],
+            |(x, sel): (bool, Bus<3>)| dmux8way_gate(x, sel.value() as u8),
+            // output a is the most significant bit
+            |(x, sel)| (x as u8) << (7 - sel.value()),
+        );
+    }
 }
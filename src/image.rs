@@ -0,0 +1,414 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::mem::{parse_hack, HackError};
+use crate::sequential::Addressable;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+/**
+ * How the words of a memory image are stored:
+ * Hack: one 16 digit binary word per line, as in a .hack file.
+ * IntelHex: Intel HEX records, two bytes per word at byte address 2 * addr.
+ * Binary: raw bytes, two per word.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Hack,
+    IntelHex(Endian),
+    Binary(Endian),
+}
+
+impl Format {
+    // .hack, .hex/.ihex (big endian) or .bin (little endian)
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Format> {
+        match path.as_ref().extension()?.to_str()? {
+            "hack" => Some(Format::Hack),
+            "hex" | "ihex" => Some(Format::IntelHex(Endian::Big)),
+            "bin" => Some(Format::Binary(Endian::Little)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageError {
+    Hack(HackError),
+    Hex { line: usize, message: String },
+    OddLength(usize),
+    OutOfRange { addr: usize, size: usize },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Hack(e) => write!(f, "{}", e),
+            ImageError::Hex { line, message } => write!(f, "line {}: {}", line, message),
+            ImageError::OddLength(len) => {
+                write!(f, "{} bytes do not make whole 16-bit words", len)
+            }
+            ImageError::OutOfRange { addr, size } => {
+                write!(f, "address {} is outside the {} word memory", addr, size)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+impl From<HackError> for ImageError {
+    fn from(e: HackError) -> Self {
+        ImageError::Hack(e)
+    }
+}
+
+fn word_from_bytes(bytes: [u8; 2], endian: Endian) -> u16 {
+    match endian {
+        Endian::Little => u16::from_le_bytes(bytes),
+        Endian::Big => u16::from_be_bytes(bytes),
+    }
+}
+
+fn word_to_bytes(word: u16, endian: Endian) -> [u8; 2] {
+    match endian {
+        Endian::Little => word.to_le_bytes(),
+        Endian::Big => word.to_be_bytes(),
+    }
+}
+
+pub fn parse_binary(bytes: &[u8], endian: Endian) -> Result<Vec<u16>, ImageError> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err(ImageError::OddLength(bytes.len()));
+    }
+    Ok(bytes
+        .chunks_exact(2)
+        .map(|pair| word_from_bytes([pair[0], pair[1]], endian))
+        .collect())
+}
+
+/**
+ * Parses Intel HEX data (00), end of file (01) and extended segment (02)
+ * or linear (04) address records into (word address, word) pairs.
+ * Every word must be given whole, both of its bytes in the file.
+ */
+pub fn parse_ihex(text: &str, endian: Endian) -> Result<Vec<(usize, u16)>, ImageError> {
+    let mut bytes: Vec<(usize, u8, usize)> = Vec::new(); // address, byte, line
+    let mut base = 0;
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: String| ImageError::Hex {
+            line: i + 1,
+            message,
+        };
+
+        let digits = line
+            .strip_prefix(':')
+            .ok_or_else(|| error("record does not start with ':'".to_string()))?;
+        if let Some(c) = digits.chars().find(|c| !c.is_ascii_hexdigit()) {
+            return Err(error(format!("'{}' is not a hex digit", c)));
+        }
+        if !digits.len().is_multiple_of(2) || digits.len() < 10 {
+            return Err(error(format!("record of {} digits", digits.len())));
+        }
+        let record = (0..digits.len())
+            .step_by(2)
+            .map(|j| u8::from_str_radix(&digits[j..j + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|e| error(e.to_string()))?;
+
+        let len = record[0] as usize;
+        if record.len() != len + 5 {
+            return Err(error(format!(
+                "record says {} data bytes but has {}",
+                len,
+                record.len() - 5
+            )));
+        }
+        let sum = record.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        if sum != 0 {
+            return Err(error("bad checksum".to_string()));
+        }
+
+        let offset = u16::from_be_bytes([record[1], record[2]]) as usize;
+        let data = &record[4..4 + len];
+        match (record[3], data) {
+            (0x00, _) => {
+                for (j, &byte) in data.iter().enumerate() {
+                    bytes.push((base + offset + j, byte, i + 1));
+                }
+            }
+            (0x01, _) => break,
+            (0x02, &[high, low]) => base = (u16::from_be_bytes([high, low]) as usize) << 4,
+            (0x04, &[high, low]) => base = (u16::from_be_bytes([high, low]) as usize) << 16,
+            (kind, _) => {
+                return Err(error(format!(
+                    "unsupported record type {:02X} of {} bytes",
+                    kind, len
+                )))
+            }
+        }
+    }
+
+    bytes.sort_by_key(|&(addr, _, _)| addr);
+    let mut words = Vec::new();
+    for pair in bytes.chunks(2) {
+        match *pair {
+            [(a, first, _), (b, second, _)] if a % 2 == 0 && b == a + 1 => {
+                words.push((a / 2, word_from_bytes([first, second], endian)));
+            }
+            [(a, _, line), ..] => {
+                return Err(ImageError::Hex {
+                    line,
+                    message: format!("byte {:#06X} is not part of a whole word", a),
+                })
+            }
+            [] => unreachable!(),
+        }
+    }
+    Ok(words)
+}
+
+/**
+ * Pokes (address, word) pairs into a memory chip, without clocking it
+ * or touching its inputs. Nothing is written if an address is out of range.
+ */
+pub fn load_words<M: Addressable>(
+    memory: &mut M,
+    words: impl IntoIterator<Item = (usize, u16)>,
+) -> Result<(), ImageError> {
+    let size = 1 << M::ADDR_BITS;
+    let words: Vec<(usize, u16)> = words.into_iter().collect();
+    if let Some(&(addr, _)) = words.iter().find(|&&(addr, _)| addr >= size) {
+        return Err(ImageError::OutOfRange { addr, size });
+    }
+
+    for (addr, value) in words {
+        memory.poke(addr, value);
+    }
+    Ok(())
+}
+
+/**
+ * Initializes memory from an image in the given format.
+ * Addresses in the image are relative to start.
+ */
+pub fn load<M: Addressable>(
+    memory: &mut M,
+    start: usize,
+    data: &[u8],
+    format: Format,
+) -> Result<(), ImageError> {
+    let words = match format {
+        Format::Hack => {
+            let text = String::from_utf8_lossy(data);
+            let words = parse_hack(&text)?;
+            words.into_iter().enumerate().collect()
+        }
+        Format::IntelHex(endian) => parse_ihex(&String::from_utf8_lossy(data), endian)?,
+        Format::Binary(endian) => parse_binary(data, endian)?
+            .into_iter()
+            .enumerate()
+            .collect(),
+    };
+    load_words(
+        memory,
+        words.into_iter().map(|(addr, word)| (start + addr, word)),
+    )
+}
+
+pub fn load_file<M: Addressable>(
+    memory: &mut M,
+    start: usize,
+    path: impl AsRef<Path>,
+    format: Format,
+) -> io::Result<()> {
+    let data = fs::read(path)?;
+    load(memory, start, &data, format).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// words in range, read without touching the chip's inputs
+pub fn dump_words<M: Addressable>(memory: &M, range: Range<usize>) -> Vec<u16> {
+    let end = range.end.min(1 << M::ADDR_BITS);
+    (range.start.min(end)..end)
+        .map(|addr| memory.peek(addr))
+        .collect()
+}
+
+/**
+ * The words in range in the given format, clipped to the memory size.
+ * Loading the result at range.start gives the same words back.
+ */
+pub fn dump<M: Addressable>(memory: &M, range: Range<usize>, format: Format) -> Vec<u8> {
+    let words = dump_words(memory, range);
+
+    match format {
+        Format::Hack => words
+            .iter()
+            .map(|word| format!("{:016b}\n", word))
+            .collect::<String>()
+            .into_bytes(),
+        Format::IntelHex(endian) => to_ihex(&words, endian).into_bytes(),
+        Format::Binary(endian) => words
+            .iter()
+            .flat_map(|&word| word_to_bytes(word, endian))
+            .collect(),
+    }
+}
+
+pub fn dump_file<M: Addressable>(
+    memory: &M,
+    range: Range<usize>,
+    path: impl AsRef<Path>,
+    format: Format,
+) -> io::Result<()> {
+    fs::write(path, dump(memory, range, format))
+}
+
+fn ihex_record(kind: u8, offset: u16, data: &[u8]) -> String {
+    let mut record = vec![data.len() as u8];
+    record.extend(offset.to_be_bytes());
+    record.push(kind);
+    record.extend(data);
+    let sum = record.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    record.push(sum.wrapping_neg());
+
+    let digits: String = record.iter().map(|b| format!("{:02X}", b)).collect();
+    format!(":{}\n", digits)
+}
+
+// 16 data bytes per record, relative to byte address 0
+fn to_ihex(words: &[u16], endian: Endian) -> String {
+    let bytes: Vec<u8> = words
+        .iter()
+        .flat_map(|&word| word_to_bytes(word, endian))
+        .collect();
+
+    let mut out = String::new();
+    let mut high = 0;
+    for (i, chunk) in bytes.chunks(16).enumerate() {
+        let addr = i * 16;
+        if addr >> 16 != high {
+            high = addr >> 16;
+            out.push_str(&ihex_record(0x04, 0, &(high as u16).to_be_bytes()));
+        }
+        out.push_str(&ihex_record(0x00, addr as u16, chunk));
+    }
+    out.push_str(&ihex_record(0x01, 0, &[]));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sequential::{RAM512, RAM64};
+
+    fn filled() -> RAM512 {
+        let mut ram = RAM512::new();
+        load_words(&mut ram, (0..512).map(|i| (i, (i * 257) as u16))).unwrap();
+        ram
+    }
+
+    #[test]
+    fn test_round_trips() {
+        let ram = filled();
+        assert_eq!(ram.peek(3), 771);
+        assert_eq!(ram.peek(511), 0x00FF);
+
+        for format in [
+            Format::Hack,
+            Format::IntelHex(Endian::Big),
+            Format::IntelHex(Endian::Little),
+            Format::Binary(Endian::Big),
+            Format::Binary(Endian::Little),
+        ] {
+            let image = dump(&ram, 100..140, format);
+            let mut copy = RAM64::new();
+            load(&mut copy, 8, &image, format).unwrap();
+            assert_eq!(dump_words(&copy, 8..48), dump_words(&ram, 100..140));
+            assert_eq!(copy.peek(7), 0);
+            assert_eq!(copy.peek(48), 0);
+        }
+    }
+
+    #[test]
+    fn test_formats() {
+        let mut ram = RAM64::new();
+        load_words(&mut ram, [(0, 0x1234), (1, 0xABCD)]).unwrap();
+
+        assert_eq!(
+            dump(&ram, 0..2, Format::Binary(Endian::Little)),
+            [0x34, 0x12, 0xCD, 0xAB]
+        );
+        assert_eq!(
+            dump(&ram, 0..2, Format::Binary(Endian::Big)),
+            [0x12, 0x34, 0xAB, 0xCD]
+        );
+        assert_eq!(
+            String::from_utf8(dump(&ram, 1..2, Format::Hack)).unwrap(),
+            "1010101111001101\n"
+        );
+        assert_eq!(
+            String::from_utf8(dump(&ram, 0..1, Format::IntelHex(Endian::Big))).unwrap(),
+            ":020000001234B8\n:00000001FF\n"
+        );
+        // clipped to the chip
+        assert_eq!(dump_words(&ram, 62..100).len(), 2);
+        assert!(dump_words(&ram, 70..100).is_empty());
+    }
+
+    #[test]
+    fn test_ihex_addresses() {
+        // word 0x800 from an extended segment, word 2 split over two records
+        let text = "\
+            :020000020100FB\n\
+            :02000000BEEF51\n\
+            :020000020000FC\n\
+            :0100040012E9\n\
+            :0100050034C6\n\
+            :00000001FF\n\
+            :02000000FFFF00\n";
+        assert_eq!(
+            parse_ihex(text, Endian::Big),
+            Ok(vec![(2, 0x1234), (0x800, 0xBEEF)])
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut ram = RAM64::new();
+        assert_eq!(
+            load(&mut ram, 0, &[1, 2, 3], Format::Binary(Endian::Big)),
+            Err(ImageError::OddLength(3))
+        );
+        assert_eq!(
+            load(&mut ram, 63, &[0; 4], Format::Binary(Endian::Big)),
+            Err(ImageError::OutOfRange { addr: 64, size: 64 })
+        );
+        assert_eq!(
+            parse_ihex(":020000001234B9\n", Endian::Big),
+            Err(ImageError::Hex {
+                line: 1,
+                message: "bad checksum".to_string()
+            })
+        );
+        let err = parse_ihex("\n:0100010012EC\n", Endian::Big).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: byte 0x0001 is not part of a whole word"
+        );
+        let err = parse_ihex(":0100000é0012EC\n", Endian::Big).unwrap_err();
+        assert_eq!(err.to_string(), "line 1: 'é' is not a hex digit");
+        assert!(load(&mut ram, 0, b"0101", Format::Hack).is_err());
+    }
+}
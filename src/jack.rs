@@ -0,0 +1,74 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub mod ast;
+pub mod codegen;
+pub mod os;
+pub mod parser;
+pub mod tokenizer;
+pub mod xml;
+
+pub use codegen::{compile, compile_with_map};
+pub use parser::parse;
+pub use tokenizer::tokenize;
+pub use xml::{class_xml, tokens_xml};
+
+/**
+ * A position in a source file, both counted from 1.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pos {
+    pub line: usize,
+    pub col: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JackError {
+    pub pos: Pos,
+    pub message: String,
+}
+
+impl JackError {
+    pub fn new(pos: Pos, message: &str) -> Self {
+        JackError {
+            pos,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for JackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.pos.line, self.pos.col, self.message)
+    }
+}
+
+impl std::error::Error for JackError {}
+
+/**
+ * The paths of a .jack file, or of every .jack file of a directory in name order.
+ */
+pub fn source_paths(path: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?.path();
+        if entry.extension().is_some_and(|e| e == "jack") {
+            paths.push(entry);
+        }
+    }
+    paths.sort();
+    if paths.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no .jack files in the directory",
+        ));
+    }
+    Ok(paths)
+}
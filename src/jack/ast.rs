@@ -0,0 +1,204 @@
+use std::fmt;
+
+use super::Pos;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    Int,
+    Char,
+    Boolean,
+    Class(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClassVarKind {
+    Static,
+    Field,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubroutineKind {
+    Constructor,
+    Function,
+    Method,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    And,
+    Or,
+    Lt,
+    Gt,
+    Eq,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeywordConst {
+    True,
+    False,
+    Null,
+    This,
+}
+
+/**
+ * One Jack class: a whole .jack file.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Class {
+    pub name: String,
+    pub vars: Vec<ClassVarDec>,
+    pub subroutines: Vec<Subroutine>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClassVarDec {
+    pub pos: Pos,
+    pub kind: ClassVarKind,
+    pub ty: Type,
+    pub names: Vec<String>,
+}
+
+/**
+ * A constructor, function or method. return_type is None for void.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subroutine {
+    pub pos: Pos,
+    pub kind: SubroutineKind,
+    pub return_type: Option<Type>,
+    pub name: String,
+    pub params: Vec<(Type, String)>,
+    pub locals: Vec<VarDec>,
+    pub body: Vec<Statement>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VarDec {
+    pub pos: Pos,
+    pub ty: Type,
+    pub names: Vec<String>,
+}
+
+/**
+ * A statement and the position of its first token.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    pub pos: Pos,
+    pub kind: StatementKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatementKind {
+    Let {
+        name: String,
+        index: Option<Expr>,
+        value: Expr,
+    },
+    If {
+        cond: Expr,
+        then: Vec<Statement>,
+        otherwise: Option<Vec<Statement>>,
+    },
+    While {
+        cond: Expr,
+        body: Vec<Statement>,
+    },
+    Do(Call),
+    Return(Option<Expr>),
+}
+
+/**
+ * An expression as Jack writes it: a term followed by (op, term) pairs,
+ * evaluated left to right with no precedence.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expr {
+    pub first: Term,
+    pub rest: Vec<(BinaryOp, Term)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Term {
+    Int(u16),
+    Str(String),
+    Keyword(KeywordConst),
+    Var(Pos, String),
+    Index(Pos, String, Box<Expr>),
+    Call(Call),
+    Paren(Box<Expr>),
+    Unary(UnaryOp, Box<Term>),
+}
+
+/**
+ * f(args), x.f(args) or Class.f(args): receiver is the part before the dot.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Call {
+    pub pos: Pos,
+    pub receiver: Option<String>,
+    pub name: String,
+    pub args: Vec<Expr>,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Char => write!(f, "char"),
+            Type::Boolean => write!(f, "boolean"),
+            Type::Class(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl BinaryOp {
+    pub fn symbol(self) -> char {
+        match self {
+            BinaryOp::Add => '+',
+            BinaryOp::Sub => '-',
+            BinaryOp::Mul => '*',
+            BinaryOp::Div => '/',
+            BinaryOp::And => '&',
+            BinaryOp::Or => '|',
+            BinaryOp::Lt => '<',
+            BinaryOp::Gt => '>',
+            BinaryOp::Eq => '=',
+        }
+    }
+
+    pub fn from_symbol(c: char) -> Option<Self> {
+        let op = match c {
+            '+' => BinaryOp::Add,
+            '-' => BinaryOp::Sub,
+            '*' => BinaryOp::Mul,
+            '/' => BinaryOp::Div,
+            '&' => BinaryOp::And,
+            '|' => BinaryOp::Or,
+            '<' => BinaryOp::Lt,
+            '>' => BinaryOp::Gt,
+            '=' => BinaryOp::Eq,
+            _ => return None,
+        };
+        Some(op)
+    }
+}
+
+impl UnaryOp {
+    pub fn symbol(self) -> char {
+        match self {
+            UnaryOp::Neg => '-',
+            UnaryOp::Not => '~',
+        }
+    }
+}
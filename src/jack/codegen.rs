@@ -0,0 +1,742 @@
+use std::collections::HashMap;
+
+use super::ast::*;
+use super::{JackError, Pos};
+use crate::srcmap::{Location, SourceMap, Variable};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Static,
+    Field,
+    Argument,
+    Local,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Static => "static",
+            Kind::Field => "field",
+            Kind::Argument => "argument",
+            Kind::Local => "local",
+        }
+    }
+
+    fn segment(self) -> &'static str {
+        match self {
+            Kind::Static => "static",
+            Kind::Field => "this",
+            Kind::Argument => "argument",
+            Kind::Local => "local",
+        }
+    }
+}
+
+struct Symbol {
+    kind: Kind,
+    ty: Type,
+    index: usize,
+}
+
+// class scope (statics and fields) and subroutine scope (arguments and locals)
+#[derive(Default)]
+struct Symbols {
+    class: HashMap<String, Symbol>,
+    subroutine: HashMap<String, Symbol>,
+    counts: HashMap<Kind, usize>,
+}
+
+impl Symbols {
+    fn define(&mut self, name: &str, ty: &Type, kind: Kind) -> Result<(), String> {
+        let scope = match kind {
+            Kind::Static | Kind::Field => &mut self.class,
+            Kind::Argument | Kind::Local => &mut self.subroutine,
+        };
+        if scope.contains_key(name) {
+            return Err(format!("'{}' is declared twice", name));
+        }
+        let count = self.counts.entry(kind).or_insert(0);
+        let symbol = Symbol {
+            kind,
+            ty: ty.clone(),
+            index: *count,
+        };
+        scope.insert(name.to_string(), symbol);
+        *count += 1;
+        Ok(())
+    }
+
+    fn start_subroutine(&mut self) {
+        self.subroutine.clear();
+        self.counts.remove(&Kind::Argument);
+        self.counts.remove(&Kind::Local);
+    }
+
+    fn count(&self, kind: Kind) -> usize {
+        self.counts.get(&kind).copied().unwrap_or(0)
+    }
+
+    // subroutine names hide class names
+    fn get(&self, name: &str) -> Option<&Symbol> {
+        self.subroutine.get(name).or_else(|| self.class.get(name))
+    }
+}
+
+// whether running the statements always ends in a return
+fn returns(statements: &[Statement]) -> bool {
+    match statements.last().map(|s| &s.kind) {
+        Some(StatementKind::Return(_)) => true,
+        Some(StatementKind::If {
+            then,
+            otherwise: Some(otherwise),
+            ..
+        }) => returns(then) && returns(otherwise),
+        _ => false,
+    }
+}
+
+/**
+ * Compiles a class to VM code, its subroutines named Class.name.
+ * Constructors allocate the fields with Memory.alloc, * and / call
+ * Math.multiply and Math.divide, string constants are built with
+ * String.new and String.appendChar, as the Jack OS expects. A subroutine
+ * that can reach its end without a return returns 0 there.
+ */
+pub fn compile(class: &Class) -> Result<String, JackError> {
+    compile_with_map(class).map(|(vm, _)| vm)
+}
+
+/**
+ * compile, with the map of the VM lines to the lines of Class.jack they
+ * were compiled from, and of each function's variables.
+ */
+pub fn compile_with_map(class: &Class) -> Result<(String, SourceMap), JackError> {
+    let mut compiler = Compiler {
+        class,
+        symbols: Symbols::default(),
+        out: String::new(),
+        lines: 0,
+        map: SourceMap::new(),
+        file: format!("{}.jack", class.name),
+        kind: SubroutineKind::Function,
+        void: false,
+        labels: 0,
+        pos: Pos { line: 1, col: 1 },
+    };
+
+    for var in &class.vars {
+        let kind = match var.kind {
+            ClassVarKind::Static => Kind::Static,
+            ClassVarKind::Field => Kind::Field,
+        };
+        for name in &var.names {
+            compiler
+                .symbols
+                .define(name, &var.ty, kind)
+                .map_err(|message| JackError::new(var.pos, &message))?;
+        }
+    }
+    for subroutine in &class.subroutines {
+        compiler.subroutine(subroutine)?;
+    }
+    Ok((compiler.out, compiler.map))
+}
+
+/**
+ * The VM name, Class.n, of a static variable of the class, numbered as
+ * compile numbers them.
+ */
+pub fn static_name(class: &Class, name: &str) -> Option<String> {
+    let mut symbols = Symbols::default();
+    for var in &class.vars {
+        if var.kind == ClassVarKind::Static {
+            for name in &var.names {
+                symbols.define(name, &var.ty, Kind::Static).ok()?;
+            }
+        }
+    }
+    let symbol = symbols.get(name)?;
+    Some(format!("{}.{}", class.name, symbol.index))
+}
+
+struct Compiler<'a> {
+    class: &'a Class,
+    symbols: Symbols,
+    out: String,
+    lines: usize,
+    map: SourceMap,
+    file: String,
+    kind: SubroutineKind,
+    void: bool,
+    labels: usize,
+    // the statement being compiled
+    pos: Pos,
+}
+
+impl Compiler<'_> {
+    // each VM command maps to the statement it is compiled from
+    fn emit(&mut self, command: &str) {
+        self.out.push_str(command);
+        self.out.push('\n');
+        self.lines += 1;
+        self.map
+            .add(self.lines, Location::new(&self.file, self.pos.line));
+    }
+
+    fn label(&mut self) -> usize {
+        self.labels += 1;
+        self.labels - 1
+    }
+
+    fn subroutine(&mut self, subroutine: &Subroutine) -> Result<(), JackError> {
+        let error = |message: String| JackError::new(subroutine.pos, &message);
+
+        self.symbols.start_subroutine();
+        self.kind = subroutine.kind;
+        self.void = subroutine.return_type.is_none();
+        self.labels = 0;
+
+        // a method gets its object as argument 0
+        if subroutine.kind == SubroutineKind::Method {
+            let this = Type::Class(self.class.name.clone());
+            self.symbols
+                .define("this", &this, Kind::Argument)
+                .map_err(error)?;
+        }
+        for (ty, name) in &subroutine.params {
+            self.symbols
+                .define(name, ty, Kind::Argument)
+                .map_err(error)?;
+        }
+        for local in &subroutine.locals {
+            for name in &local.names {
+                self.symbols
+                    .define(name, &local.ty, Kind::Local)
+                    .map_err(|message| JackError::new(local.pos, &message))?;
+            }
+        }
+        let name = format!("{}.{}", self.class.name, subroutine.name);
+        self.map
+            .add_function(self.lines + 1, &name, self.variables());
+        self.pos = subroutine.pos;
+        self.emit(&format!(
+            "function {} {}",
+            name,
+            self.symbols.count(Kind::Local)
+        ));
+        match subroutine.kind {
+            SubroutineKind::Constructor => {
+                let fields = self.symbols.count(Kind::Field);
+                self.emit(&format!("push constant {}", fields));
+                self.emit("call Memory.alloc 1");
+                self.emit("pop pointer 0");
+            }
+            SubroutineKind::Method => {
+                self.emit("push argument 0");
+                self.emit("pop pointer 0");
+            }
+            SubroutineKind::Function => {}
+        }
+        self.statements(&subroutine.body)?;
+        if !returns(&subroutine.body) {
+            self.emit("push constant 0");
+            self.emit("return");
+        }
+        Ok(())
+    }
+
+    fn statements(&mut self, statements: &[Statement]) -> Result<(), JackError> {
+        for statement in statements {
+            self.statement(statement)?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Result<(), JackError> {
+        self.pos = statement.pos;
+        match &statement.kind {
+            StatementKind::Let { name, index, value } => match index {
+                None => {
+                    self.expr(value)?;
+                    let var = self.var(statement.pos, name)?;
+                    self.emit(&format!("pop {}", var));
+                }
+                Some(index) => {
+                    // value may index another array, so pointer 1 is set after it
+                    self.push_var(statement.pos, name)?;
+                    self.expr(index)?;
+                    self.emit("add");
+                    self.expr(value)?;
+                    self.emit("pop temp 0");
+                    self.emit("pop pointer 1");
+                    self.emit("push temp 0");
+                    self.emit("pop that 0");
+                }
+            },
+            StatementKind::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let n = self.label();
+                self.expr(cond)?;
+                self.emit("not");
+                self.emit(&format!("if-goto IF_FALSE{}", n));
+                self.statements(then)?;
+                match otherwise {
+                    Some(otherwise) => {
+                        self.emit(&format!("goto IF_END{}", n));
+                        self.emit(&format!("label IF_FALSE{}", n));
+                        self.statements(otherwise)?;
+                        self.emit(&format!("label IF_END{}", n));
+                    }
+                    None => self.emit(&format!("label IF_FALSE{}", n)),
+                }
+            }
+            StatementKind::While { cond, body } => {
+                let n = self.label();
+                self.emit(&format!("label WHILE_EXP{}", n));
+                // while (true) needs no test, so while (true) {} is a goto to
+                // itself, which the simulators take as the program's end
+                let forever =
+                    cond.rest.is_empty() && cond.first == Term::Keyword(KeywordConst::True);
+                if !forever {
+                    self.expr(cond)?;
+                    self.emit("not");
+                    self.emit(&format!("if-goto WHILE_END{}", n));
+                }
+                self.statements(body)?;
+                self.emit(&format!("goto WHILE_EXP{}", n));
+                self.emit(&format!("label WHILE_END{}", n));
+            }
+            StatementKind::Do(call) => {
+                self.call(call)?;
+                self.emit("pop temp 0");
+            }
+            StatementKind::Return(value) => {
+                match (value, self.void) {
+                    (Some(value), false) => self.expr(value)?,
+                    (None, true) => self.emit("push constant 0"),
+                    (Some(_), true) => {
+                        return Err(JackError::new(
+                            statement.pos,
+                            "a void subroutine returns a value",
+                        ))
+                    }
+                    (None, false) => {
+                        return Err(JackError::new(statement.pos, "missing return value"))
+                    }
+                }
+                self.emit("return");
+            }
+        }
+        Ok(())
+    }
+
+    // the variables in scope, fields only where there is an object
+    fn variables(&self) -> Vec<Variable> {
+        let mut vars: Vec<(&String, &Symbol)> = self
+            .symbols
+            .class
+            .iter()
+            .chain(&self.symbols.subroutine)
+            .filter(|(name, symbol)| std::ptr::eq(self.symbols.get(name).unwrap(), *symbol))
+            .filter(|(_, symbol)| {
+                symbol.kind != Kind::Field || self.kind != SubroutineKind::Function
+            })
+            .collect();
+        vars.sort_by_key(|(_, s)| (s.kind as usize, s.index));
+        vars.into_iter()
+            .map(|(name, symbol)| Variable {
+                kind: symbol.kind.name().to_string(),
+                index: symbol.index as u16,
+                name: name.clone(),
+                ty: symbol.ty.to_string(),
+            })
+            .collect()
+    }
+
+    // segment and index of a variable
+    fn var(&self, pos: Pos, name: &str) -> Result<String, JackError> {
+        let Some(symbol) = self.symbols.get(name) else {
+            return Err(JackError::new(pos, &format!("unknown variable '{}'", name)));
+        };
+        if symbol.kind == Kind::Field && self.kind == SubroutineKind::Function {
+            let message = format!("field '{}' used in a function", name);
+            return Err(JackError::new(pos, &message));
+        }
+        Ok(format!("{} {}", symbol.kind.segment(), symbol.index))
+    }
+
+    fn push_var(&mut self, pos: Pos, name: &str) -> Result<(), JackError> {
+        let var = self.var(pos, name)?;
+        self.emit(&format!("push {}", var));
+        Ok(())
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Result<(), JackError> {
+        self.term(&expr.first)?;
+        for (op, term) in &expr.rest {
+            self.term(term)?;
+            self.emit(match op {
+                BinaryOp::Add => "add",
+                BinaryOp::Sub => "sub",
+                BinaryOp::Mul => "call Math.multiply 2",
+                BinaryOp::Div => "call Math.divide 2",
+                BinaryOp::And => "and",
+                BinaryOp::Or => "or",
+                BinaryOp::Lt => "lt",
+                BinaryOp::Gt => "gt",
+                BinaryOp::Eq => "eq",
+            });
+        }
+        Ok(())
+    }
+
+    fn term(&mut self, term: &Term) -> Result<(), JackError> {
+        match term {
+            Term::Int(n) => self.emit(&format!("push constant {}", n)),
+            Term::Str(s) => {
+                self.emit(&format!("push constant {}", s.chars().count()));
+                self.emit("call String.new 1");
+                for c in s.chars() {
+                    self.emit(&format!("push constant {}", c as u32));
+                    self.emit("call String.appendChar 2");
+                }
+            }
+            Term::Keyword(KeywordConst::True) => {
+                self.emit("push constant 0");
+                self.emit("not");
+            }
+            Term::Keyword(KeywordConst::False | KeywordConst::Null) => self.emit("push constant 0"),
+            Term::Keyword(KeywordConst::This) => {
+                if self.kind == SubroutineKind::Function {
+                    return Err(JackError::new(self.pos, "'this' used in a function"));
+                }
+                self.emit("push pointer 0");
+            }
+            Term::Var(pos, name) => self.push_var(*pos, name)?,
+            Term::Index(pos, name, index) => {
+                self.push_var(*pos, name)?;
+                self.expr(index)?;
+                self.emit("add");
+                self.emit("pop pointer 1");
+                self.emit("push that 0");
+            }
+            Term::Call(call) => self.call(call)?,
+            Term::Paren(expr) => self.expr(expr)?,
+            Term::Unary(op, term) => {
+                self.term(term)?;
+                self.emit(match op {
+                    UnaryOp::Neg => "neg",
+                    UnaryOp::Not => "not",
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, call: &Call) -> Result<(), JackError> {
+        let error = |message: String| Err(JackError::new(call.pos, &message));
+        let this_class = self.class;
+        let class = &this_class.name;
+        let own = |name: &str| this_class.subroutines.iter().find(|s| s.name == name);
+
+        // the class whose subroutine is called, and whether an object is passed
+        let (target, object) = match &call.receiver {
+            None => match own(&call.name) {
+                Some(s) if s.kind == SubroutineKind::Method => {
+                    if self.kind == SubroutineKind::Function {
+                        return error(format!("method '{}' called from a function", call.name));
+                    }
+                    self.emit("push pointer 0");
+                    (class.clone(), true)
+                }
+                Some(_) => (class.clone(), false),
+                None => return error(format!("unknown subroutine '{}'", call.name)),
+            },
+            Some(receiver) => match self.symbols.get(receiver) {
+                Some(symbol) => {
+                    let Type::Class(target) = symbol.ty.clone() else {
+                        return error(format!("'{}' is not an object", receiver));
+                    };
+                    self.push_var(call.pos, receiver)?;
+                    (target, true)
+                }
+                None if receiver == class => match own(&call.name) {
+                    Some(s) if s.kind == SubroutineKind::Method => {
+                        return error(format!("method '{}' called without an object", call.name))
+                    }
+                    Some(_) => (class.clone(), false),
+                    None => return error(format!("unknown subroutine '{}'", call.name)),
+                },
+                None => (receiver.clone(), false),
+            },
+        };
+
+        for arg in &call.args {
+            self.expr(arg)?;
+        }
+        let count = call.args.len() + object as usize;
+        self.emit(&format!("call {}.{} {}", target, call.name, count));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{cross_check, VmEmulator};
+    use crate::jack::parse;
+    use crate::vm::VmFile;
+
+    fn compile_source(source: &str) -> Result<String, String> {
+        let class = parse(source).map_err(|e| e.to_string())?;
+        compile(&class).map_err(|e| e.to_string())
+    }
+
+    // minimal stand-ins for the OS classes the generated code calls
+    const SYS: &str = "
+class Sys {
+    function void init() { do Main.main(); return; }
+}";
+    const MATH: &str = "
+class Math {
+    function int multiply(int x, int y) {
+        var int sum;
+        while (y > 0) { let sum = sum + x; let y = y - 1; }
+        return sum;
+    }
+    function int divide(int x, int y) {
+        var int q;
+        while (~(x < y)) { let x = x - y; let q = q + 1; }
+        return q;
+    }
+}";
+    const MEMORY: &str = "
+class Memory {
+    static int free;
+    function int alloc(int size) {
+        var int block;
+        if (free = 0) { let free = 2048; }
+        let block = free;
+        let free = free + size;
+        return block;
+    }
+}";
+
+    const MAIN: &str = "
+class Main {
+    function void main() {
+        var Array out;
+        var Point p, q;
+        var int i;
+        let out = 8000;
+        let p = Point.new(3, 4);
+        let q = Point.new(10, 20);
+        do p.add(q);
+        let out[0] = p.getX();
+        let out[1] = p.getY() * 3 / 2;
+        let out[2] = Main.fib(10);
+        while (i < 5) {
+            let out[3 + i] = i * i;
+            let i = i + 1;
+        }
+        let out[out[3] + 8] = -(7 - 2);
+        if (p.getX() > q.getX() & ~(Point.count() = 3)) {
+            let out[9] = true;
+        } else {
+            let out[9] = null;
+        }
+        return;
+    }
+
+    function int fib(int n) {
+        if (n < 2) { return n; }
+        return Main.fib(n - 1) + fib(n - 2);
+    }
+}";
+
+    const POINT: &str = "
+class Point {
+    field int x, y;
+    static int count;
+
+    constructor Point new(int ax, int ay) {
+        let x = ax;
+        let y = ay;
+        let count = count + 1;
+        return this;
+    }
+    method int getX() { return x; }
+    method int getY() { return y; }
+    method void add(Point other) {
+        let x = x + other.getX();
+        let y = y + other.getY();
+        do check();
+        return;
+    }
+    method void check() { return; }
+    function int count() { return count; }
+}";
+
+    #[test]
+    fn test_compile() {
+        let vm = compile_source(
+            "class P {\n  field int x;\n  constructor P new() { let x = \"ab\"; return this; }\n  \
+             method boolean f(int a) { if (a) { return true; } else { return x[a]; } }\n}",
+        )
+        .unwrap();
+        let expected = "\
+function P.new 0
+push constant 1
+call Memory.alloc 1
+pop pointer 0
+push constant 2
+call String.new 1
+push constant 97
+call String.appendChar 2
+push constant 98
+call String.appendChar 2
+pop this 0
+push pointer 0
+return
+function P.f 0
+push argument 0
+pop pointer 0
+push argument 1
+not
+if-goto IF_FALSE0
+push constant 0
+not
+return
+goto IF_END0
+label IF_FALSE0
+push this 0
+push argument 1
+add
+pop pointer 1
+push that 0
+return
+label IF_END0
+";
+        assert_eq!(vm, expected);
+    }
+
+    #[test]
+    fn test_run() {
+        let sources = [
+            ("Main", MAIN),
+            ("Point", POINT),
+            ("Sys", SYS),
+            ("Math", MATH),
+            ("Memory", MEMORY),
+        ];
+        let files: Vec<VmFile> = sources
+            .iter()
+            .map(|(name, source)| VmFile::new(name, &compile_source(source).unwrap()))
+            .collect();
+
+        let mut emulator = VmEmulator::new(&files, true).unwrap();
+        emulator.run(1_000_000).unwrap();
+        assert!(emulator.halted());
+
+        let out: Vec<u16> = (8000..8010).map(|addr| emulator.peek(addr)).collect();
+        assert_eq!(out, [13, 36, 55, 0, 1, 4, 9, 16, (-5i16) as u16, 0xFFFF]);
+        // two points of two fields each
+        assert_eq!(emulator.peek(2048), 13);
+        assert_eq!(emulator.peek(2050), 10);
+    }
+
+    #[test]
+    fn test_cross_check() {
+        // Sys.init returns here, the translation must halt as the emulator does
+        let source = "
+class Sys {
+    function void init() {
+        var Array out;
+        let out = 100;
+        let out[0] = Sys.sum(10);
+        return;
+    }
+    function int sum(int n) {
+        if (n = 0) { return 0; }
+        return n + Sys.sum(n - 1);
+    }
+}";
+        let files = [VmFile::new("Sys", &compile_source(source).unwrap())];
+        assert_eq!(cross_check(&files, 10_000, 10_000), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |body: &str| {
+            let source = format!(
+                "class A {{\n  field int f;\n  function void g() {{\n    {}\n  }}\n  method void m() {{ return; }}\n}}",
+                body
+            );
+            compile_source(&source).unwrap_err()
+        };
+        assert_eq!(error("let x = 1; return;"), "4:5: unknown variable 'x'");
+        assert_eq!(
+            error("var int y; return y;"),
+            "4:16: a void subroutine returns a value"
+        );
+        assert_eq!(
+            error("let f = 1; return;"),
+            "4:5: field 'f' used in a function"
+        );
+        assert_eq!(
+            error("do m(); return;"),
+            "4:8: method 'm' called from a function"
+        );
+        assert_eq!(
+            error("do A.m(); return;"),
+            "4:8: method 'm' called without an object"
+        );
+        assert_eq!(error("do h(); return;"), "4:8: unknown subroutine 'h'");
+        assert_eq!(
+            error("var int i; do i.m(); return;"),
+            "4:19: 'i' is not an object"
+        );
+        assert_eq!(
+            error("do Output.println(this); return;"),
+            "4:5: 'this' used in a function"
+        );
+        assert_eq!(error("var int a, a; return;"), "4:5: 'a' is declared twice");
+    }
+
+    #[test]
+    fn test_implicit_return() {
+        // the end is unreachable, or a return is nested before trailing code
+        let vm = compile_source(
+            "class A {\n  function int f() { while (true) { } }\n  \
+             function void g(int x) { if (x) { if (x) { return; } } let x = 1; }\n}",
+        )
+        .unwrap();
+        let expected = "\
+function A.f 0
+label WHILE_EXP0
+goto WHILE_EXP0
+label WHILE_END0
+push constant 0
+return
+function A.g 0
+push argument 0
+not
+if-goto IF_FALSE0
+push argument 0
+not
+if-goto IF_FALSE1
+push constant 0
+return
+label IF_FALSE1
+label IF_FALSE0
+push constant 1
+pop argument 0
+push constant 0
+return
+";
+        assert_eq!(vm, expected);
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+use crate::vm::{self, Command, VmFile};
+
+use super::codegen::static_name;
+use super::{compile, parse};
+
+/**
+ * The Jack OS classes as (name, Jack source). Sys.init starts the others,
+ * runs Main.main and halts.
+ */
+pub const CLASSES: [(&str, &str); 8] = [
+    ("Array", include_str!("os/Array.jack")),
+    ("Keyboard", include_str!("os/Keyboard.jack")),
+    ("Math", include_str!("os/Math.jack")),
+    ("Memory", include_str!("os/Memory.jack")),
+    ("Output", include_str!("os/Output.jack")),
+    ("Screen", include_str!("os/Screen.jack")),
+    ("String", include_str!("os/String.jack")),
+    ("Sys", include_str!("os/Sys.jack")),
+];
+
+/**
+ * The OS classes compiled to VM code.
+ */
+pub fn vm_files() -> Vec<VmFile> {
+    compiled().to_vec()
+}
+
+// compiled once, the classes do not change
+fn compiled() -> &'static [VmFile] {
+    static FILES: OnceLock<Vec<VmFile>> = OnceLock::new();
+    FILES.get_or_init(|| {
+        CLASSES
+            .iter()
+            .map(|(name, source)| {
+                let class = parse(source).unwrap_or_else(|e| panic!("{}.jack:{}", name, e));
+                let vm = compile(&class).unwrap_or_else(|e| panic!("{}.jack:{}", name, e));
+                VmFile::new(name, &vm)
+            })
+            .collect()
+    })
+}
+
+/**
+ * Whether the file has the OS code of the function as link adds it, and
+ * not a program's own version of the function or of its class.
+ */
+pub fn is_bundled(file: &VmFile, function: &str) -> bool {
+    let code = |source| {
+        functions(source)
+            .into_iter()
+            .find(|(name, _)| name == function)
+            .map(|(_, code)| code)
+    };
+    compiled()
+        .iter()
+        .find(|os| os.name == file.name)
+        .and_then(|os| code(&os.source))
+        .is_some_and(|os| code(&file.source) == Some(os))
+}
+
+// the functions of VM code, each from its function command up to the next
+fn functions(source: &str) -> Vec<(String, String)> {
+    let mut functions: Vec<(String, String)> = Vec::new();
+    for line in source.lines() {
+        let mut words = line.split_whitespace();
+        if words.next() == Some("function") {
+            let name = words.next().unwrap_or("").to_string();
+            functions.push((name, String::new()));
+        }
+        if let Some((_, code)) = functions.last_mut() {
+            code.push_str(line);
+            code.push('\n');
+        }
+    }
+    functions
+}
+
+/**
+ * The VM name of Screen's drawing color static, Screen.n as the compiler
+ * numbers Screen.jack's statics.
+ */
+pub fn screen_color() -> String {
+    let (_, source) = CLASSES.iter().find(|(name, _)| *name == "Screen").unwrap();
+    let class = parse(source).unwrap();
+    static_name(&class, "color").unwrap()
+}
+
+/**
+ * Adds the OS functions a program calls but does not define to its VM
+ * files, with those they call in turn, each in a file of its class; and
+ * Sys.init for the bootstrap to call, unless the program has its own. So
+ * a program can replace any of them with its own. A class the program
+ * has a file of is left out whole, as the two would share its statics.
+ */
+pub fn link(files: &mut Vec<VmFile>) {
+    let mut defined = HashSet::new();
+    let mut needed = vec!["Sys.init".to_string()];
+    for file in files.iter() {
+        names(&file.source, &mut defined, &mut needed);
+    }
+    let os: Vec<(&str, Vec<(String, String)>)> = compiled()
+        .iter()
+        .filter(|os| files.iter().all(|file| file.name != os.name))
+        .map(|os| (os.name.as_str(), functions(&os.source)))
+        .collect();
+
+    let mut linked = HashSet::new();
+    while let Some(name) = needed.pop() {
+        if defined.contains(&name) {
+            continue;
+        }
+        let code = os
+            .iter()
+            .flat_map(|(_, functions)| functions)
+            .find(|(function, _)| *function == name);
+        if let Some((_, code)) = code {
+            names(code, &mut defined, &mut needed);
+            linked.insert(name);
+        }
+    }
+    for (class, functions) in os {
+        let source: String = functions
+            .iter()
+            .filter(|(name, _)| linked.contains(name))
+            .map(|(_, code)| code.as_str())
+            .collect();
+        if !source.is_empty() {
+            files.push(VmFile::new(class, &source));
+        }
+    }
+}
+
+// adds the functions VM code defines and those it calls, unparsable code
+// being left to the translator to report
+fn names(source: &str, defined: &mut HashSet<String>, called: &mut Vec<String>) {
+    for (_, command) in vm::parse(source).unwrap_or_default() {
+        match command {
+            Command::Function(name, _) => {
+                defined.insert(name);
+            }
+            Command::Call(name, _) => called.push(name),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::VmEmulator;
+    use crate::vm::Translator;
+
+    fn run(main: &str, steps: u64) -> VmEmulator {
+        let class = parse(main).unwrap();
+        let mut files = vec![VmFile::new("Main", &compile(&class).unwrap())];
+        link(&mut files);
+        let mut emulator = VmEmulator::new(&files, true).unwrap();
+        emulator.run(steps).unwrap();
+        emulator
+    }
+
+    fn pixel(emulator: &VmEmulator, x: u16, y: u16) -> bool {
+        emulator.peek(0x4000 + y * 32 + x / 16) & (1 << (x % 16)) != 0
+    }
+
+    #[test]
+    fn test_link() {
+        let mut files = vec![VmFile::new(
+            "Math",
+            "function Math.init 0\npush constant 0\nreturn",
+        )];
+        link(&mut files);
+        assert_eq!(files.iter().filter(|file| file.name == "Math").count(), 1);
+        assert!(!is_bundled(&files[0], "Math.init"));
+        let sys = files.iter().find(|file| file.name == "Sys").unwrap();
+        assert!(is_bundled(sys, "Sys.init"));
+        assert!(files.iter().any(|file| file.name == "Output"));
+    }
+
+    #[test]
+    fn test_link_called_functions() {
+        // the program's Sys.init, so only what it calls comes from the OS
+        let main = "
+function Sys.init 0
+call Memory.init 0
+pop temp 0
+call Math.init 0
+pop temp 0
+push constant 6
+push constant 7
+call Math.multiply 2
+pop temp 0
+label END
+goto END
+";
+        let mut files = vec![VmFile::new("Main", main)];
+        link(&mut files);
+        let linked = |name: &str| {
+            files
+                .iter()
+                .any(|file| functions(&file.source).iter().any(|(f, _)| f == name))
+        };
+        assert!(linked("Math.multiply") && linked("Memory.alloc") && linked("Sys.error"));
+        assert!(!linked("Screen.init") && !linked("Main.main"));
+        assert!(Translator::new().translate(&files).is_ok());
+
+        let mut emulator = VmEmulator::new(&files, true).unwrap();
+        emulator.run(100_000).unwrap();
+        assert_eq!(emulator.location().unwrap().0, "Main");
+        assert_eq!(emulator.peek(5), 42);
+    }
+
+    #[test]
+    fn test_math_and_strings() {
+        let main = "
+class Main {
+    function void main() {
+        var String s;
+        do Memory.poke(8000, 123 * -45);
+        do Memory.poke(8001, -5535 / 7);
+        do Memory.poke(8002, 32767 / 3);
+        do Memory.poke(8003, Math.sqrt(30000));
+        do Memory.poke(8004, Math.min(-2, 3) + Math.abs(-9));
+        let s = String.new(6);
+        do s.setInt(-32767 - 1);
+        do Memory.poke(8005, s.length());
+        do Memory.poke(8006, s.charAt(5));
+        do Memory.poke(8007, s.intValue() + 1);
+        do s.setInt(409);
+        do Memory.poke(8008, s.intValue());
+        let s = \"\";
+        do Memory.poke(8009, s.length());
+        return;
+    }
+}";
+        let emulator = run(main, 2_000_000);
+        assert!(emulator.halted());
+        assert_eq!(emulator.location().unwrap().0, "Sys");
+        let results: Vec<i16> = (8000..8010).map(|a| emulator.peek(a) as i16).collect();
+        assert_eq!(
+            results,
+            [-5535, -790, 10922, 173, 7, 6, '8' as i16, -32767, 409, 0]
+        );
+    }
+
+    #[test]
+    fn test_divide_min() {
+        let main = "
+class Main {
+    function void main() {
+        var int min;
+        let min = -32767 - 1;
+        do Memory.poke(8000, min / 1);
+        do Memory.poke(8001, min / 2);
+        do Memory.poke(8002, min / -2);
+        do Memory.poke(8003, min / 3);
+        do Memory.poke(8004, min / -3);
+        do Memory.poke(8005, min / min);
+        do Memory.poke(8006, 7 / min);
+        do Memory.poke(8007, min / 32767);
+        return;
+    }
+}";
+        let emulator = run(main, 2_000_000);
+        assert!(emulator.halted());
+        let results: Vec<i16> = (8000..8008).map(|a| emulator.peek(a) as i16).collect();
+        assert_eq!(results, [-32768, -16384, 16384, -10922, 10922, 1, 0, -1]);
+    }
+
+    #[test]
+    fn test_memory() {
+        let main = "
+class Main {
+    function void main() {
+        var Array a, b, c;
+        let a = Array.new(10);
+        let b = Array.new(3);
+        do a.dispose();
+        let c = Array.new(10);
+        do Memory.poke(8000, a);
+        do Memory.poke(8001, b);
+        do Memory.poke(8002, c);
+        return;
+    }
+}";
+        let emulator = run(main, 2_000_000);
+        assert!(emulator.halted());
+        let (a, b, c) = (
+            emulator.peek(8000),
+            emulator.peek(8001),
+            emulator.peek(8002),
+        );
+        // from the top of the heap down, the freed block is used again
+        assert!(a < 16384 && b == a - 4 && c == a);
+    }
+
+    #[test]
+    fn test_output() {
+        let main = "
+class Main {
+    function void main() {
+        do Output.printString(\"Hi\");
+        do Output.println();
+        do Output.printInt(-42);
+        do Output.backSpace();
+        return;
+    }
+}";
+        let emulator = run(main, 2_000_000);
+        assert!(emulator.halted());
+        // 'H' in the low byte, 'i' in the high byte of row 0's words
+        let word =
+            |row: u16, col: u16, line: u16| emulator.peek(0x4000 + row * 352 + line * 32 + col / 2);
+        assert_eq!(word(0, 0, 1), 34 | (8 << 8));
+        assert_eq!(word(0, 0, 4), 62 | (8 << 8));
+        // "-4" is left on row 1, the 2 erased
+        assert_eq!(word(1, 0, 4), 62 | (18 << 8));
+        assert_eq!(word(1, 2, 4), 0);
+    }
+
+    #[test]
+    fn test_screen() {
+        let main = "
+class Main {
+    function void main() {
+        do Screen.drawLine(10, 100, 40, 110);
+        do Screen.drawLine(300, 20, 300, 5);
+        do Screen.drawRectangle(100, 200, 120, 210);
+        do Screen.setColor(false);
+        do Screen.drawPixel(110, 205);
+        do Screen.setColor(true);
+        do Screen.drawCircle(400, 128, 20);
+        return;
+    }
+}";
+        let emulator = run(main, 10_000_000);
+        assert!(emulator.halted());
+        assert!(pixel(&emulator, 10, 100) && pixel(&emulator, 40, 110));
+        assert!(pixel(&emulator, 25, 105) && !pixel(&emulator, 25, 110));
+        assert!((5..=20).all(|y| pixel(&emulator, 300, y)));
+        assert!(!pixel(&emulator, 300, 21));
+        assert!(pixel(&emulator, 100, 200) && pixel(&emulator, 120, 210));
+        assert!(!pixel(&emulator, 110, 205) && !pixel(&emulator, 121, 205));
+        assert!(pixel(&emulator, 400, 108) && pixel(&emulator, 380, 128));
+        assert!(!pixel(&emulator, 384, 112) && !pixel(&emulator, 421, 128));
+
+        // after screen and twoToThe
+        assert_eq!(screen_color(), "Screen.2");
+    }
+
+    #[test]
+    fn test_read_line() {
+        let main = "
+class Main {
+    function void main() {
+        do Memory.poke(8000, Keyboard.readInt(\"n? \"));
+        return;
+    }
+}";
+        let mut emulator = run(main, 1_000_000);
+        assert!(!emulator.halted());
+        // "-129", backspace, "7" and new line, each key pressed then released
+        for key in [45, 49, 50, 57, 129, 55, 128] {
+            emulator.set_key(key);
+            emulator.run(20_000).unwrap();
+            emulator.set_key(0);
+            emulator.run(100_000).unwrap();
+        }
+        assert!(emulator.halted());
+        assert_eq!(emulator.peek(8000) as i16, -127);
+    }
+
+    #[test]
+    fn test_error() {
+        let main = "
+class Main {
+    function void main() {
+        do Memory.poke(8000, 1 / 0);
+        return;
+    }
+}";
+        let emulator = run(main, 2_000_000);
+        assert!(emulator.halted());
+        assert_eq!(emulator.peek(8000), 0);
+        // ERR3 printed on the first row
+        let word = |col: u16| emulator.peek(0x4000 + 32 * 4 + col / 2);
+        assert_eq!(word(0), 30 | (30 << 8));
+        assert_eq!(word(2), 30 | (16 << 8));
+    }
+}
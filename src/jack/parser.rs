@@ -0,0 +1,524 @@
+use super::ast::*;
+use super::tokenizer::{tokenize, Keyword, Token};
+use super::{JackError, Pos};
+
+/**
+ * Parses the source of one .jack file into its class.
+ */
+pub fn parse(source: &str) -> Result<Class, JackError> {
+    let tokens = tokenize(source)?;
+
+    // errors at the end of the file point just past the last character
+    let last_line = source.rsplit('\n').next().unwrap_or("");
+    let end = Pos {
+        line: source.matches('\n').count() + 1,
+        col: last_line.chars().count() + 1,
+    };
+
+    let mut parser = Parser {
+        tokens,
+        next: 0,
+        end,
+    };
+    let class = parser.class()?;
+    if let Some((pos, token)) = parser.tokens.get(parser.next) {
+        let message = format!("expected the end of the file, found {}", token);
+        return Err(JackError::new(*pos, &message));
+    }
+    Ok(class)
+}
+
+struct Parser {
+    tokens: Vec<(Pos, Token)>,
+    next: usize,
+    end: Pos,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, token)| token)
+    }
+
+    fn peek_at(&self, n: usize) -> Option<&Token> {
+        self.tokens.get(self.next + n).map(|(_, token)| token)
+    }
+
+    fn pos(&self) -> Pos {
+        self.tokens.get(self.next).map_or(self.end, |(pos, _)| *pos)
+    }
+
+    fn error<T>(&self, expected: &str) -> Result<T, JackError> {
+        let found = match self.peek() {
+            Some(token) => token.to_string(),
+            None => "the end of the file".to_string(),
+        };
+        let message = format!("expected {}, found {}", expected, found);
+        Err(JackError::new(self.pos(), &message))
+    }
+
+    fn is_symbol(&self, c: char) -> bool {
+        self.peek() == Some(&Token::Symbol(c))
+    }
+
+    fn is_keyword(&self, keyword: Keyword) -> bool {
+        self.peek() == Some(&Token::Keyword(keyword))
+    }
+
+    fn symbol(&mut self, c: char) -> Result<(), JackError> {
+        if !self.is_symbol(c) {
+            return self.error(&format!("'{}'", c));
+        }
+        self.next += 1;
+        Ok(())
+    }
+
+    fn keyword(&mut self, keyword: Keyword) -> Result<(), JackError> {
+        if !self.is_keyword(keyword) {
+            return self.error(&format!("'{}'", keyword));
+        }
+        self.next += 1;
+        Ok(())
+    }
+
+    fn ident(&mut self, what: &str) -> Result<String, JackError> {
+        match self.peek() {
+            Some(Token::Ident(name)) => {
+                let name = name.clone();
+                self.next += 1;
+                Ok(name)
+            }
+            _ => self.error(what),
+        }
+    }
+
+    // 'int' | 'char' | 'boolean' | className
+    fn ty(&mut self) -> Result<Type, JackError> {
+        let ty = match self.peek() {
+            Some(Token::Keyword(Keyword::Int)) => Type::Int,
+            Some(Token::Keyword(Keyword::Char)) => Type::Char,
+            Some(Token::Keyword(Keyword::Boolean)) => Type::Boolean,
+            Some(Token::Ident(name)) => Type::Class(name.clone()),
+            _ => return self.error("a type"),
+        };
+        self.next += 1;
+        Ok(ty)
+    }
+
+    // type varName (',' varName)* ';'
+    fn names(&mut self) -> Result<(Type, Vec<String>), JackError> {
+        let ty = self.ty()?;
+        let mut names = vec![self.ident("a variable name")?];
+        while self.is_symbol(',') {
+            self.next += 1;
+            names.push(self.ident("a variable name")?);
+        }
+        self.symbol(';')?;
+        Ok((ty, names))
+    }
+
+    fn class(&mut self) -> Result<Class, JackError> {
+        self.keyword(Keyword::Class)?;
+        let name = self.ident("a class name")?;
+        self.symbol('{')?;
+
+        let mut vars = Vec::new();
+        loop {
+            let pos = self.pos();
+            let kind = match self.peek() {
+                Some(Token::Keyword(Keyword::Static)) => ClassVarKind::Static,
+                Some(Token::Keyword(Keyword::Field)) => ClassVarKind::Field,
+                _ => break,
+            };
+            self.next += 1;
+            let (ty, names) = self.names()?;
+            vars.push(ClassVarDec {
+                pos,
+                kind,
+                ty,
+                names,
+            });
+        }
+
+        let mut subroutines = Vec::new();
+        while !self.is_symbol('}') {
+            subroutines.push(self.subroutine()?);
+        }
+        self.symbol('}')?;
+
+        Ok(Class {
+            name,
+            vars,
+            subroutines,
+        })
+    }
+
+    fn subroutine(&mut self) -> Result<Subroutine, JackError> {
+        let pos = self.pos();
+        let kind = match self.peek() {
+            Some(Token::Keyword(Keyword::Constructor)) => SubroutineKind::Constructor,
+            Some(Token::Keyword(Keyword::Function)) => SubroutineKind::Function,
+            Some(Token::Keyword(Keyword::Method)) => SubroutineKind::Method,
+            _ => return self.error("a subroutine declaration or '}'"),
+        };
+        self.next += 1;
+
+        let return_type = if self.is_keyword(Keyword::Void) {
+            self.next += 1;
+            None
+        } else {
+            Some(self.ty()?)
+        };
+        let name = self.ident("a subroutine name")?;
+
+        self.symbol('(')?;
+        let mut params = Vec::new();
+        if !self.is_symbol(')') {
+            loop {
+                let ty = self.ty()?;
+                params.push((ty, self.ident("a parameter name")?));
+                if !self.is_symbol(',') {
+                    break;
+                }
+                self.next += 1;
+            }
+        }
+        self.symbol(')')?;
+
+        self.symbol('{')?;
+        let mut locals = Vec::new();
+        while self.is_keyword(Keyword::Var) {
+            let pos = self.pos();
+            self.next += 1;
+            let (ty, names) = self.names()?;
+            locals.push(VarDec { pos, ty, names });
+        }
+        let body = self.statements()?;
+        self.symbol('}')?;
+
+        Ok(Subroutine {
+            pos,
+            kind,
+            return_type,
+            name,
+            params,
+            locals,
+            body,
+        })
+    }
+
+    // statements up to the closing '}', which is left for the caller
+    fn statements(&mut self) -> Result<Vec<Statement>, JackError> {
+        let mut statements = Vec::new();
+        while !self.is_symbol('}') {
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+
+    fn block(&mut self) -> Result<Vec<Statement>, JackError> {
+        self.symbol('{')?;
+        let statements = self.statements()?;
+        self.symbol('}')?;
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> Result<Statement, JackError> {
+        let pos = self.pos();
+        let keyword = match self.peek() {
+            Some(Token::Keyword(keyword)) => *keyword,
+            _ => return self.error("a statement"),
+        };
+
+        let kind = match keyword {
+            Keyword::Let => {
+                self.next += 1;
+                let name = self.ident("a variable name")?;
+                let index = if self.is_symbol('[') {
+                    self.next += 1;
+                    let index = self.expr()?;
+                    self.symbol(']')?;
+                    Some(index)
+                } else {
+                    None
+                };
+                self.symbol('=')?;
+                let value = self.expr()?;
+                self.symbol(';')?;
+                StatementKind::Let { name, index, value }
+            }
+            Keyword::If => {
+                self.next += 1;
+                self.symbol('(')?;
+                let cond = self.expr()?;
+                self.symbol(')')?;
+                let then = self.block()?;
+                let otherwise = if self.is_keyword(Keyword::Else) {
+                    self.next += 1;
+                    Some(self.block()?)
+                } else {
+                    None
+                };
+                StatementKind::If {
+                    cond,
+                    then,
+                    otherwise,
+                }
+            }
+            Keyword::While => {
+                self.next += 1;
+                self.symbol('(')?;
+                let cond = self.expr()?;
+                self.symbol(')')?;
+                let body = self.block()?;
+                StatementKind::While { cond, body }
+            }
+            Keyword::Do => {
+                self.next += 1;
+                let pos = self.pos();
+                let name = self.ident("a subroutine call")?;
+                let call = self.call(pos, name)?;
+                self.symbol(';')?;
+                StatementKind::Do(call)
+            }
+            Keyword::Return => {
+                self.next += 1;
+                let value = if self.is_symbol(';') {
+                    None
+                } else {
+                    Some(self.expr()?)
+                };
+                self.symbol(';')?;
+                StatementKind::Return(value)
+            }
+            _ => return self.error("a statement"),
+        };
+        Ok(Statement { pos, kind })
+    }
+
+    // the rest of a call whose first identifier has been read
+    fn call(&mut self, pos: Pos, first: String) -> Result<Call, JackError> {
+        let (receiver, name) = if self.is_symbol('.') {
+            self.next += 1;
+            (Some(first), self.ident("a subroutine name")?)
+        } else {
+            (None, first)
+        };
+
+        self.symbol('(')?;
+        let mut args = Vec::new();
+        if !self.is_symbol(')') {
+            args.push(self.expr()?);
+            while self.is_symbol(',') {
+                self.next += 1;
+                args.push(self.expr()?);
+            }
+        }
+        self.symbol(')')?;
+
+        Ok(Call {
+            pos,
+            receiver,
+            name,
+            args,
+        })
+    }
+
+    fn expr(&mut self) -> Result<Expr, JackError> {
+        let first = self.term()?;
+        let mut rest = Vec::new();
+        while let Some(&Token::Symbol(c)) = self.peek() {
+            let Some(op) = BinaryOp::from_symbol(c) else {
+                break;
+            };
+            self.next += 1;
+            rest.push((op, self.term()?));
+        }
+        Ok(Expr { first, rest })
+    }
+
+    fn term(&mut self) -> Result<Term, JackError> {
+        let pos = self.pos();
+        let term = match self.peek().cloned() {
+            Some(Token::Int(n)) => Term::Int(n),
+            Some(Token::Str(s)) => Term::Str(s),
+            Some(Token::Keyword(keyword)) => {
+                let constant = match keyword {
+                    Keyword::True => KeywordConst::True,
+                    Keyword::False => KeywordConst::False,
+                    Keyword::Null => KeywordConst::Null,
+                    Keyword::This => KeywordConst::This,
+                    _ => return self.error("an expression"),
+                };
+                Term::Keyword(constant)
+            }
+            Some(Token::Symbol('(')) => {
+                self.next += 1;
+                let expr = self.expr()?;
+                self.symbol(')')?;
+                return Ok(Term::Paren(Box::new(expr)));
+            }
+            Some(Token::Symbol(c @ ('-' | '~'))) => {
+                self.next += 1;
+                let op = if c == '-' { UnaryOp::Neg } else { UnaryOp::Not };
+                return Ok(Term::Unary(op, Box::new(self.term()?)));
+            }
+            Some(Token::Ident(name)) => {
+                self.next += 1;
+                return match self.peek_at(0) {
+                    Some(Token::Symbol('[')) => {
+                        self.next += 1;
+                        let index = self.expr()?;
+                        self.symbol(']')?;
+                        Ok(Term::Index(pos, name, Box::new(index)))
+                    }
+                    Some(Token::Symbol('(' | '.')) => Ok(Term::Call(self.call(pos, name)?)),
+                    _ => Ok(Term::Var(pos, name)),
+                };
+            }
+            _ => return self.error("an expression"),
+        };
+        self.next += 1;
+        Ok(term)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SQUARE: &str = "\
+class Square {
+    field int x, y;
+    static boolean debug;
+
+    constructor Square new(int ax, int ay) {
+        let x = ax;
+        let y = ay;
+        return this;
+    }
+
+    method void move(Array deltas) {
+        var int i;
+        while (i < 2) {
+            if (~(deltas[i] = 0)) {
+                let x = x + (deltas[i] * -1);
+            } else {
+                do Output.printString(\"still\");
+            }
+            let i = i + 1;
+        }
+        do draw();
+        return;
+    }
+}
+";
+
+    #[test]
+    fn test_parse() {
+        let class = parse(SQUARE).unwrap();
+        assert_eq!(class.name, "Square");
+        assert_eq!(
+            class.vars[0],
+            ClassVarDec {
+                pos: Pos { line: 2, col: 5 },
+                kind: ClassVarKind::Field,
+                ty: Type::Int,
+                names: vec!["x".to_string(), "y".to_string()],
+            }
+        );
+        assert_eq!(class.vars[1].kind, ClassVarKind::Static);
+
+        let new = &class.subroutines[0];
+        assert_eq!(new.kind, SubroutineKind::Constructor);
+        assert_eq!(new.return_type, Some(Type::Class("Square".to_string())));
+        assert_eq!(new.params.len(), 2);
+        assert_eq!(new.pos, Pos { line: 5, col: 5 });
+        assert_eq!(
+            new.body[2].kind,
+            StatementKind::Return(Some(Expr {
+                first: Term::Keyword(KeywordConst::This),
+                rest: vec![],
+            }))
+        );
+
+        let moves = &class.subroutines[1];
+        assert_eq!(moves.return_type, None);
+        assert_eq!(moves.locals[0].names, vec!["i".to_string()]);
+        assert_eq!(moves.body.len(), 3);
+        assert_eq!(moves.body[1].pos, Pos { line: 21, col: 9 });
+
+        let StatementKind::While { cond, body } = &moves.body[0].kind else {
+            panic!("expected a while statement");
+        };
+        assert_eq!(cond.rest[0].0, BinaryOp::Lt);
+        let StatementKind::If { otherwise, .. } = &body[0].kind else {
+            panic!("expected an if statement");
+        };
+        let StatementKind::Do(call) = &otherwise.as_ref().unwrap()[0].kind else {
+            panic!("expected a do statement");
+        };
+        assert_eq!(call.receiver.as_deref(), Some("Output"));
+        assert_eq!(call.args[0].first, Term::Str("still".to_string()));
+
+        let StatementKind::Do(call) = &moves.body[1].kind else {
+            panic!("expected a do statement");
+        };
+        assert_eq!(call.receiver, None);
+        assert_eq!(call.name, "draw");
+        assert_eq!(call.pos, Pos { line: 21, col: 12 });
+    }
+
+    #[test]
+    fn test_expressions() {
+        let class = parse("class A { function int f() { return -a[1] + g(2, 3) * ~x; } }").unwrap();
+        let StatementKind::Return(Some(expr)) = &class.subroutines[0].body[0].kind else {
+            panic!("expected a return value");
+        };
+        let index = Term::Index(
+            Pos { line: 1, col: 38 },
+            "a".to_string(),
+            Box::new(Expr {
+                first: Term::Int(1),
+                rest: vec![],
+            }),
+        );
+        assert_eq!(expr.first, Term::Unary(UnaryOp::Neg, Box::new(index)));
+        assert_eq!(expr.rest.len(), 2);
+        let (op, Term::Call(call)) = &expr.rest[0] else {
+            panic!("expected a call");
+        };
+        assert_eq!(*op, BinaryOp::Add);
+        assert_eq!(call.args.len(), 2);
+        assert_eq!(expr.rest[1].0, BinaryOp::Mul);
+        assert!(matches!(expr.rest[1].1, Term::Unary(UnaryOp::Not, _)));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source: &str| parse(source).unwrap_err().to_string();
+        assert_eq!(
+            error("class Main {\n  function void main() {\n    let x = 1\n  }\n}"),
+            "4:3: expected ';', found '}'"
+        );
+        assert_eq!(
+            error("class Main {\n  method int f() { return 1 + ; }\n}"),
+            "2:31: expected an expression, found ';'"
+        );
+        assert_eq!(
+            error("class Main {\n  function void f() { do 1; }"),
+            "2:26: expected a subroutine call, found '1'"
+        );
+        assert_eq!(
+            error("class Main {\n  function void f() { }\n"),
+            "3:1: expected a subroutine declaration or '}', found the end of the file"
+        );
+        assert_eq!(
+            error("class Main { } class B { }"),
+            "1:16: expected the end of the file, found 'class'"
+        );
+        assert_eq!(
+            error("class Main { field x; }"),
+            "1:21: expected a variable name, found ';'"
+        );
+    }
+}
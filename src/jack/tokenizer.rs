@@ -0,0 +1,220 @@
+use std::fmt;
+
+use super::{JackError, Pos};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keyword {
+    Class,
+    Constructor,
+    Function,
+    Method,
+    Field,
+    Static,
+    Var,
+    Int,
+    Char,
+    Boolean,
+    Void,
+    True,
+    False,
+    Null,
+    This,
+    Let,
+    Do,
+    If,
+    Else,
+    While,
+    Return,
+}
+
+const KEYWORDS: [(&str, Keyword); 21] = [
+    ("class", Keyword::Class),
+    ("constructor", Keyword::Constructor),
+    ("function", Keyword::Function),
+    ("method", Keyword::Method),
+    ("field", Keyword::Field),
+    ("static", Keyword::Static),
+    ("var", Keyword::Var),
+    ("int", Keyword::Int),
+    ("char", Keyword::Char),
+    ("boolean", Keyword::Boolean),
+    ("void", Keyword::Void),
+    ("true", Keyword::True),
+    ("false", Keyword::False),
+    ("null", Keyword::Null),
+    ("this", Keyword::This),
+    ("let", Keyword::Let),
+    ("do", Keyword::Do),
+    ("if", Keyword::If),
+    ("else", Keyword::Else),
+    ("while", Keyword::While),
+    ("return", Keyword::Return),
+];
+
+const SYMBOLS: &str = "{}()[].,;+-*/&|<>=~";
+
+impl Keyword {
+    pub fn as_str(self) -> &'static str {
+        KEYWORDS.iter().find(|(_, k)| *k == self).unwrap().0
+    }
+}
+
+impl fmt::Display for Keyword {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Keyword(Keyword),
+    Symbol(char),
+    Int(u16),
+    Str(String),
+    Ident(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Keyword(k) => write!(f, "'{}'", k),
+            Token::Symbol(c) => write!(f, "'{}'", c),
+            Token::Int(n) => write!(f, "'{}'", n),
+            Token::Str(s) => write!(f, "\"{}\"", s),
+            Token::Ident(name) => write!(f, "'{}'", name),
+        }
+    }
+}
+
+/**
+ * Splits Jack source into tokens, each with the line and column it
+ * starts at. Skips whitespace and //, /* */ and /** */ comments.
+ */
+pub fn tokenize(source: &str) -> Result<Vec<(Pos, Token)>, JackError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let (mut i, mut line, mut col) = (0, 1, 1);
+
+    // moves past n characters, keeping track of the position
+    let advance = |i: &mut usize, line: &mut usize, col: &mut usize, n: usize| {
+        for _ in 0..n {
+            if chars[*i] == '\n' {
+                *line += 1;
+                *col = 1;
+            } else {
+                *col += 1;
+            }
+            *i += 1;
+        }
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        let pos = Pos { line, col };
+        let next = chars.get(i + 1).copied();
+
+        if c.is_whitespace() {
+            advance(&mut i, &mut line, &mut col, 1);
+        } else if c == '/' && next == Some('/') {
+            while i < chars.len() && chars[i] != '\n' {
+                advance(&mut i, &mut line, &mut col, 1);
+            }
+        } else if c == '/' && next == Some('*') {
+            let end = (i + 2..chars.len().saturating_sub(1))
+                .find(|&j| chars[j] == '*' && chars[j + 1] == '/')
+                .ok_or_else(|| JackError::new(pos, "unterminated comment"))?;
+            let len = end + 2 - i;
+            advance(&mut i, &mut line, &mut col, len);
+        } else if SYMBOLS.contains(c) {
+            tokens.push((pos, Token::Symbol(c)));
+            advance(&mut i, &mut line, &mut col, 1);
+        } else if c.is_ascii_digit() {
+            let len = chars[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+            let digits: String = chars[i..i + len].iter().collect();
+            match digits.parse::<u16>() {
+                Ok(n) if n <= 0x7FFF => tokens.push((pos, Token::Int(n))),
+                _ => {
+                    let message = format!("integer {} is not in 0..32767", digits);
+                    return Err(JackError::new(pos, &message));
+                }
+            }
+            advance(&mut i, &mut line, &mut col, len);
+        } else if c == '"' {
+            let len = chars[i + 1..]
+                .iter()
+                .take_while(|&&c| c != '"' && c != '\n')
+                .count();
+            if chars.get(i + 1 + len) != Some(&'"') {
+                return Err(JackError::new(pos, "unterminated string"));
+            }
+            // the Hack character set is ASCII with a few keys above it
+            let text = &chars[i + 1..i + 1 + len];
+            if let Some(at) = text.iter().position(|c| !c.is_ascii()) {
+                let pos = Pos {
+                    line,
+                    col: col + 1 + at,
+                };
+                let message = format!("character '{}' in a string is not ASCII", text[at]);
+                return Err(JackError::new(pos, &message));
+            }
+            let text = text.iter().collect();
+            tokens.push((pos, Token::Str(text)));
+            advance(&mut i, &mut line, &mut col, len + 2);
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = chars[i..]
+                .iter()
+                .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+                .count();
+            let word: String = chars[i..i + len].iter().collect();
+            let token = match KEYWORDS.iter().find(|(name, _)| *name == word) {
+                Some(&(_, keyword)) => Token::Keyword(keyword),
+                None => Token::Ident(word),
+            };
+            tokens.push((pos, token));
+            advance(&mut i, &mut line, &mut col, len);
+        } else {
+            return Err(JackError::new(
+                pos,
+                &format!("unexpected character '{}'", c),
+            ));
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        let source = "/** doc\n * comment */\nclass Main { // line\n  let s = \"a /* b\";\n  do f(x[1]-32767); }";
+        let tokens = tokenize(source).unwrap();
+        let texts: Vec<String> = tokens.iter().map(|(_, t)| t.to_string()).collect();
+        assert_eq!(
+            texts.join(" "),
+            "'class' 'Main' '{' 'let' 's' '=' \"a /* b\" ';' \
+             'do' 'f' '(' 'x' '[' '1' ']' '-' '32767' ')' ';' '}'"
+        );
+        assert_eq!(tokens[0].0, Pos { line: 3, col: 1 });
+        assert_eq!(tokens[6].0, Pos { line: 4, col: 11 });
+        assert_eq!(tokens[8].0, Pos { line: 5, col: 3 });
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source: &str| tokenize(source).unwrap_err().to_string();
+        assert_eq!(
+            error("let x = 32768;"),
+            "1:9: integer 32768 is not in 0..32767"
+        );
+        assert_eq!(error("\n  \"abc\n\""), "2:3: unterminated string");
+        assert_eq!(error("x /* y"), "1:3: unterminated comment");
+        assert_eq!(error("a # b"), "1:3: unexpected character '#'");
+        assert_eq!(
+            error("do f(\"caf\u{e9}\");"),
+            "1:10: character '\u{e9}' in a string is not ASCII"
+        );
+    }
+}
@@ -0,0 +1,402 @@
+use super::ast::*;
+use super::tokenizer::{tokenize, Keyword, Token};
+use super::JackError;
+
+fn escape(c: char) -> String {
+    match c {
+        '<' => "&lt;".to_string(),
+        '>' => "&gt;".to_string(),
+        '&' => "&amp;".to_string(),
+        '"' => "&quot;".to_string(),
+        _ => c.to_string(),
+    }
+}
+
+// tag and text of a token, as the course tools write them
+fn token_xml(token: &Token) -> (&'static str, String) {
+    match token {
+        Token::Keyword(k) => ("keyword", k.to_string()),
+        Token::Symbol(c) => ("symbol", escape(*c)),
+        Token::Int(n) => ("integerConstant", n.to_string()),
+        Token::Str(s) => ("stringConstant", s.chars().map(escape).collect()),
+        Token::Ident(name) => ("identifier", name.clone()),
+    }
+}
+
+/**
+ * The token stream of a .jack file in the Nand2Tetris XxxT.xml format.
+ */
+pub fn tokens_xml(source: &str) -> Result<String, JackError> {
+    let mut out = String::from("<tokens>\n");
+    for (_, token) in tokenize(source)? {
+        let (tag, text) = token_xml(&token);
+        out.push_str(&format!("<{0}> {1} </{0}>\n", tag, text));
+    }
+    out.push_str("</tokens>\n");
+    Ok(out)
+}
+
+/**
+ * The parse tree of a class in the Nand2Tetris Xxx.xml format.
+ */
+pub fn class_xml(class: &Class) -> String {
+    let mut xml = Xml::default();
+    xml.class(class);
+    xml.out
+}
+
+#[derive(Default)]
+struct Xml {
+    out: String,
+    depth: usize,
+}
+
+impl Xml {
+    fn open(&mut self, tag: &str) {
+        self.out
+            .push_str(&format!("{}<{}>\n", "  ".repeat(self.depth), tag));
+        self.depth += 1;
+    }
+
+    fn close(&mut self, tag: &str) {
+        self.depth -= 1;
+        self.out
+            .push_str(&format!("{}</{}>\n", "  ".repeat(self.depth), tag));
+    }
+
+    fn token(&mut self, token: Token) {
+        let (tag, text) = token_xml(&token);
+        let indent = "  ".repeat(self.depth);
+        self.out
+            .push_str(&format!("{}<{1}> {2} </{1}>\n", indent, tag, text));
+    }
+
+    fn keyword(&mut self, keyword: Keyword) {
+        self.token(Token::Keyword(keyword));
+    }
+
+    fn symbol(&mut self, c: char) {
+        self.token(Token::Symbol(c));
+    }
+
+    fn ident(&mut self, name: &str) {
+        self.token(Token::Ident(name.to_string()));
+    }
+
+    fn ty(&mut self, ty: &Type) {
+        match ty {
+            Type::Int => self.keyword(Keyword::Int),
+            Type::Char => self.keyword(Keyword::Char),
+            Type::Boolean => self.keyword(Keyword::Boolean),
+            Type::Class(name) => self.ident(name),
+        }
+    }
+
+    // type a, b, c;
+    fn names(&mut self, ty: &Type, names: &[String]) {
+        self.ty(ty);
+        for (i, name) in names.iter().enumerate() {
+            if i > 0 {
+                self.symbol(',');
+            }
+            self.ident(name);
+        }
+        self.symbol(';');
+    }
+
+    fn class(&mut self, class: &Class) {
+        self.open("class");
+        self.keyword(Keyword::Class);
+        self.ident(&class.name);
+        self.symbol('{');
+        for var in &class.vars {
+            self.open("classVarDec");
+            self.keyword(match var.kind {
+                ClassVarKind::Static => Keyword::Static,
+                ClassVarKind::Field => Keyword::Field,
+            });
+            self.names(&var.ty, &var.names);
+            self.close("classVarDec");
+        }
+        for subroutine in &class.subroutines {
+            self.subroutine(subroutine);
+        }
+        self.symbol('}');
+        self.close("class");
+    }
+
+    fn subroutine(&mut self, subroutine: &Subroutine) {
+        self.open("subroutineDec");
+        self.keyword(match subroutine.kind {
+            SubroutineKind::Constructor => Keyword::Constructor,
+            SubroutineKind::Function => Keyword::Function,
+            SubroutineKind::Method => Keyword::Method,
+        });
+        match &subroutine.return_type {
+            Some(ty) => self.ty(ty),
+            None => self.keyword(Keyword::Void),
+        }
+        self.ident(&subroutine.name);
+
+        self.symbol('(');
+        self.open("parameterList");
+        for (i, (ty, name)) in subroutine.params.iter().enumerate() {
+            if i > 0 {
+                self.symbol(',');
+            }
+            self.ty(ty);
+            self.ident(name);
+        }
+        self.close("parameterList");
+        self.symbol(')');
+
+        self.open("subroutineBody");
+        self.symbol('{');
+        for local in &subroutine.locals {
+            self.open("varDec");
+            self.keyword(Keyword::Var);
+            self.names(&local.ty, &local.names);
+            self.close("varDec");
+        }
+        self.statements(&subroutine.body);
+        self.symbol('}');
+        self.close("subroutineBody");
+        self.close("subroutineDec");
+    }
+
+    fn statements(&mut self, statements: &[Statement]) {
+        self.open("statements");
+        for statement in statements {
+            self.statement(statement);
+        }
+        self.close("statements");
+    }
+
+    fn block(&mut self, statements: &[Statement]) {
+        self.symbol('{');
+        self.statements(statements);
+        self.symbol('}');
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match &statement.kind {
+            StatementKind::Let { name, index, value } => {
+                self.open("letStatement");
+                self.keyword(Keyword::Let);
+                self.ident(name);
+                if let Some(index) = index {
+                    self.symbol('[');
+                    self.expr(index);
+                    self.symbol(']');
+                }
+                self.symbol('=');
+                self.expr(value);
+                self.symbol(';');
+                self.close("letStatement");
+            }
+            StatementKind::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                self.open("ifStatement");
+                self.keyword(Keyword::If);
+                self.symbol('(');
+                self.expr(cond);
+                self.symbol(')');
+                self.block(then);
+                if let Some(otherwise) = otherwise {
+                    self.keyword(Keyword::Else);
+                    self.block(otherwise);
+                }
+                self.close("ifStatement");
+            }
+            StatementKind::While { cond, body } => {
+                self.open("whileStatement");
+                self.keyword(Keyword::While);
+                self.symbol('(');
+                self.expr(cond);
+                self.symbol(')');
+                self.block(body);
+                self.close("whileStatement");
+            }
+            StatementKind::Do(call) => {
+                self.open("doStatement");
+                self.keyword(Keyword::Do);
+                self.call(call);
+                self.symbol(';');
+                self.close("doStatement");
+            }
+            StatementKind::Return(value) => {
+                self.open("returnStatement");
+                self.keyword(Keyword::Return);
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+                self.symbol(';');
+                self.close("returnStatement");
+            }
+        }
+    }
+
+    // a call is written inline, without a term of its own
+    fn call(&mut self, call: &Call) {
+        if let Some(receiver) = &call.receiver {
+            self.ident(receiver);
+            self.symbol('.');
+        }
+        self.ident(&call.name);
+        self.symbol('(');
+        self.open("expressionList");
+        for (i, arg) in call.args.iter().enumerate() {
+            if i > 0 {
+                self.symbol(',');
+            }
+            self.expr(arg);
+        }
+        self.close("expressionList");
+        self.symbol(')');
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        self.open("expression");
+        self.term(&expr.first);
+        for (op, term) in &expr.rest {
+            self.symbol(op.symbol());
+            self.term(term);
+        }
+        self.close("expression");
+    }
+
+    fn term(&mut self, term: &Term) {
+        self.open("term");
+        match term {
+            Term::Int(n) => self.token(Token::Int(*n)),
+            Term::Str(s) => self.token(Token::Str(s.clone())),
+            Term::Keyword(constant) => self.keyword(match constant {
+                KeywordConst::True => Keyword::True,
+                KeywordConst::False => Keyword::False,
+                KeywordConst::Null => Keyword::Null,
+                KeywordConst::This => Keyword::This,
+            }),
+            Term::Var(_, name) => self.ident(name),
+            Term::Index(_, name, index) => {
+                self.ident(name);
+                self.symbol('[');
+                self.expr(index);
+                self.symbol(']');
+            }
+            Term::Call(call) => self.call(call),
+            Term::Paren(expr) => {
+                self.symbol('(');
+                self.expr(expr);
+                self.symbol(')');
+            }
+            Term::Unary(op, term) => {
+                self.symbol(op.symbol());
+                self.term(term);
+            }
+        }
+        self.close("term");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jack::parse;
+
+    #[test]
+    fn test_tokens_xml() {
+        let xml = tokens_xml("if (x < 1) { let s = \"a&b\"; }").unwrap();
+        assert_eq!(
+            xml,
+            "<tokens>\n\
+             <keyword> if </keyword>\n\
+             <symbol> ( </symbol>\n\
+             <identifier> x </identifier>\n\
+             <symbol> &lt; </symbol>\n\
+             <integerConstant> 1 </integerConstant>\n\
+             <symbol> ) </symbol>\n\
+             <symbol> { </symbol>\n\
+             <keyword> let </keyword>\n\
+             <identifier> s </identifier>\n\
+             <symbol> = </symbol>\n\
+             <stringConstant> a&amp;b </stringConstant>\n\
+             <symbol> ; </symbol>\n\
+             <symbol> } </symbol>\n\
+             </tokens>\n"
+        );
+    }
+
+    #[test]
+    fn test_class_xml() {
+        let class = parse("class Main {\n  function void main() {\n    do Output.printInt(-1);\n    return;\n  }\n}\n").unwrap();
+        let expected = "\
+<class>
+  <keyword> class </keyword>
+  <identifier> Main </identifier>
+  <symbol> { </symbol>
+  <subroutineDec>
+    <keyword> function </keyword>
+    <keyword> void </keyword>
+    <identifier> main </identifier>
+    <symbol> ( </symbol>
+    <parameterList>
+    </parameterList>
+    <symbol> ) </symbol>
+    <subroutineBody>
+      <symbol> { </symbol>
+      <statements>
+        <doStatement>
+          <keyword> do </keyword>
+          <identifier> Output </identifier>
+          <symbol> . </symbol>
+          <identifier> printInt </identifier>
+          <symbol> ( </symbol>
+          <expressionList>
+            <expression>
+              <term>
+                <symbol> - </symbol>
+                <term>
+                  <integerConstant> 1 </integerConstant>
+                </term>
+              </term>
+            </expression>
+          </expressionList>
+          <symbol> ) </symbol>
+          <symbol> ; </symbol>
+        </doStatement>
+        <returnStatement>
+          <keyword> return </keyword>
+          <symbol> ; </symbol>
+        </returnStatement>
+      </statements>
+      <symbol> } </symbol>
+    </subroutineBody>
+  </subroutineDec>
+  <symbol> } </symbol>
+</class>
+";
+        assert_eq!(class_xml(&class), expected);
+    }
+
+    #[test]
+    fn test_xml_keeps_every_token() {
+        // the parse tree lists the same tokens, in order, as the tokenizer
+        let source = "class A { field int x; static Array a, b;\n\
+            method boolean f(int n, char c) { var String s; \
+            let a[n] = (x * 2) | ~c; \
+            if (n > 0) { return true; } else { while (false) { do A.g(); } } \
+            return (n = null) & this; } }";
+        let tree = class_xml(&parse(source).unwrap());
+        let leaves: Vec<&str> = tree
+            .lines()
+            .map(str::trim)
+            .filter(|line| line.contains("> "))
+            .collect();
+        let tokens = tokens_xml(source).unwrap();
+        let expected: Vec<&str> = tokens.lines().filter(|line| line.contains("> ")).collect();
+        assert_eq!(leaves, expected);
+    }
+}
@@ -0,0 +1,421 @@
+use std::io::{self, Read};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::sequential::Tick;
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::utils::Bits;
+
+pub const KEYBOARD_ADDRESS: u16 = 0x6000;
+
+// Hack key codes of the keys that are not printable ASCII
+pub const NEWLINE: u16 = 128;
+pub const BACKSPACE: u16 = 129;
+pub const LEFT: u16 = 130;
+pub const UP: u16 = 131;
+pub const RIGHT: u16 = 132;
+pub const DOWN: u16 = 133;
+pub const HOME: u16 = 134;
+pub const END: u16 = 135;
+pub const PAGE_UP: u16 = 136;
+pub const PAGE_DOWN: u16 = 137;
+pub const INSERT: u16 = 138;
+pub const DELETE: u16 = 139;
+pub const ESCAPE: u16 = 140;
+pub const F1: u16 = 141; // F1 .. F12 are 141 .. 152
+
+/**
+ * From `cycle` on, the keyboard reads `key`; 0 releases the key.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub cycle: u64,
+    pub key: u16,
+}
+
+enum Source {
+    Idle,
+    Script { events: Vec<KeyEvent>, next: usize },
+    Stdin(StdinKeys),
+}
+
+/**
+ * Keyboard memory map, a single read only word at 0x6000.
+ * Outputs the Hack code of the key currently pressed, 0 if none.
+ * Has no inputs: set_state takes an empty bit vector.
+ */
+pub struct Keyboard {
+    key: u16,
+    cycle: u64,
+    source: Source,
+}
+
+impl Keyboard {
+    pub fn new() -> Self {
+        Keyboard {
+            key: 0,
+            cycle: 0,
+            source: Source::Idle,
+        }
+    }
+
+    // deterministic key presses, sorted by cycle
+    pub fn scripted(events: impl IntoIterator<Item = KeyEvent>) -> Self {
+        let mut events: Vec<KeyEvent> = events.into_iter().collect();
+        events.sort_by_key(|e| e.cycle);
+
+        let mut keyboard = Keyboard {
+            key: 0,
+            cycle: 0,
+            source: Source::Script { events, next: 0 },
+        };
+        keyboard.poll();
+        keyboard
+    }
+
+    // keys typed in the terminal; puts it in raw mode until dropped
+    pub fn stdin(hold_cycles: u64) -> io::Result<Self> {
+        Ok(Keyboard {
+            key: 0,
+            cycle: 0,
+            source: Source::Stdin(StdinKeys::new(hold_cycles)?),
+        })
+    }
+
+    pub fn key(&self) -> u16 {
+        self.key
+    }
+
+    // backdoor: presses key until the source presses another one
+    pub fn poke(&mut self, key: u16) {
+        self.key = key;
+    }
+
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    fn poll(&mut self) {
+        match &mut self.source {
+            Source::Idle => {}
+            Source::Script { events, next } => {
+                while let Some(event) = events.get(*next).filter(|e| e.cycle <= self.cycle) {
+                    self.key = event.key;
+                    *next += 1;
+                }
+            }
+            Source::Stdin(stdin) => self.key = stdin.poll(self.cycle),
+        }
+    }
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tick for Keyboard {
+    fn tick(&mut self) {
+        self.cycle += 1;
+        self.poll();
+    }
+
+    fn get_state(&self) -> Bits {
+        self.key.into()
+    }
+
+    fn set_state(&mut self, inputs: Bits) {
+        assert!(inputs.is_empty(), "the keyboard has no inputs");
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        out.u16(self.key);
+        out.u64(self.cycle);
+        match &self.source {
+            Source::Idle => out.u8(0),
+            Source::Script { events, next } => {
+                out.u8(1);
+                out.u64((events.len() - next) as u64);
+                for event in &events[*next..] {
+                    out.u64(event.cycle);
+                    out.u16(event.key);
+                }
+            }
+            Source::Stdin(_) => out.u8(2),
+        }
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.key = input.u16()?;
+        self.cycle = input.u64()?;
+        match input.u8()? {
+            0 => self.source = Source::Idle,
+            1 => {
+                let len = input.u64()?;
+                let mut events = Vec::new();
+                for _ in 0..len {
+                    let cycle = input.u64()?;
+                    let key = input.u16()?;
+                    events.push(KeyEvent { cycle, key });
+                }
+                self.source = Source::Script { events, next: 0 };
+            }
+            // a terminal cannot be restored, keep reading the current one
+            2 => {
+                if !matches!(self.source, Source::Stdin(_)) {
+                    self.source = Source::Idle;
+                }
+            }
+            source => return Err(StateError::Invalid(format!("keyboard source {}", source))),
+        }
+        Ok(())
+    }
+
+    // the script itself does not change, only how far it has been read
+    fn tick_recorded(&mut self, undo: &mut StateWriter) {
+        undo.u16(self.key);
+        undo.u64(self.cycle);
+        if let Source::Script { next, .. } = self.source {
+            undo.u64(next as u64);
+        }
+        self.tick();
+    }
+
+    // keys typed in a terminal stay typed
+    fn undo(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.key = input.u16()?;
+        self.cycle = input.u64()?;
+        if let Source::Script { events, next } = &mut self.source {
+            let read = input.u64()? as usize;
+            if read > events.len() {
+                return Err(StateError::Invalid(format!("{} keys read", read)));
+            }
+            *next = read;
+        }
+        Ok(())
+    }
+
+    fn kind(&self) -> &'static str {
+        "Keyboard"
+    }
+}
+
+/*
+ * A terminal only reports key presses, never releases, so a key read from
+ * stdin is held for hold_cycles cycles after its last repeat.
+ */
+struct StdinKeys {
+    keys: Receiver<Vec<u8>>,
+    saved_mode: Option<String>,
+    hold_cycles: u64,
+    key: u16,
+    pressed_at: u64,
+}
+
+impl StdinKeys {
+    fn new(hold_cycles: u64) -> io::Result<Self> {
+        let saved_mode = stty(&["-g"]).ok().map(|mode| mode.trim().to_string());
+        if saved_mode.is_some() {
+            // unbuffered and silent, but keep ^C working
+            stty(&["-icanon", "-echo", "min", "1"])?;
+        }
+
+        let (tx, keys) = mpsc::channel();
+        thread::spawn(move || {
+            let mut stdin = io::stdin();
+            let mut buf = [0u8; 64];
+            while let Ok(n) = stdin.read(&mut buf) {
+                if n == 0 || tx.send(buf[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(StdinKeys {
+            keys,
+            saved_mode,
+            hold_cycles,
+            key: 0,
+            pressed_at: 0,
+        })
+    }
+
+    fn poll(&mut self, cycle: u64) -> u16 {
+        while let Ok(bytes) = self.keys.try_recv() {
+            if let Some(&key) = decode_keys(&bytes).last() {
+                self.key = key;
+                self.pressed_at = cycle;
+            }
+        }
+
+        if cycle.saturating_sub(self.pressed_at) >= self.hold_cycles {
+            self.key = 0;
+        }
+        self.key
+    }
+}
+
+impl Drop for StdinKeys {
+    fn drop(&mut self) {
+        if let Some(mode) = &self.saved_mode {
+            let _ = stty(&[mode]);
+        }
+    }
+}
+
+fn stty(args: &[&str]) -> io::Result<String> {
+    let out = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()?;
+    if !out.status.success() {
+        return Err(io::Error::other("stdin is not a terminal"));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/**
+ * Translates bytes read from a raw mode terminal into Hack key codes.
+ * Printable ASCII maps to itself, ANSI escape sequences to the special keys.
+ */
+pub fn decode_keys(bytes: &[u8]) -> Vec<u16> {
+    let mut keys = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let byte = bytes[i];
+        i += 1;
+
+        let key = match byte {
+            b'\r' | b'\n' => NEWLINE,
+            0x08 | 0x7F => BACKSPACE,
+            0x20..=0x7E => byte as u16,
+            0x1B => {
+                let (key, len) = decode_escape(&bytes[i..]);
+                i += len;
+                key
+            }
+            _ => continue,
+        };
+        keys.push(key);
+    }
+
+    keys
+}
+
+// key after an ESC byte and the number of bytes of the sequence it used
+fn decode_escape(rest: &[u8]) -> (u16, usize) {
+    let Some(&kind) = rest.first().filter(|&&b| b == b'[' || b == b'O') else {
+        return (ESCAPE, 0);
+    };
+
+    // CSI: ESC [ <digits> <final byte>, or SS3: ESC O <final byte>
+    let params: Vec<u8> = rest[1..]
+        .iter()
+        .copied()
+        .take_while(|b| b.is_ascii_digit() || *b == b';')
+        .collect();
+    let Some(&last) = rest.get(1 + params.len()) else {
+        return (ESCAPE, 0);
+    };
+    let len = 2 + params.len();
+
+    let number: u16 = String::from_utf8_lossy(&params)
+        .split(';')
+        .next()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0);
+
+    let key = match (kind, last) {
+        (_, b'A') => UP,
+        (_, b'B') => DOWN,
+        (_, b'C') => RIGHT,
+        (_, b'D') => LEFT,
+        (_, b'H') => HOME,
+        (_, b'F') => END,
+        (b'O', b'P'..=b'S') => F1 + (last - b'P') as u16,
+        (b'[', b'~') => match number {
+            1 | 7 => HOME,
+            2 => INSERT,
+            3 => DELETE,
+            4 | 8 => END,
+            5 => PAGE_UP,
+            6 => PAGE_DOWN,
+            11..=15 => F1 + number - 11,
+            17..=21 => F1 + 5 + number - 17,
+            23 | 24 => F1 + 10 + number - 23,
+            _ => return (ESCAPE, 0),
+        },
+        _ => return (ESCAPE, 0),
+    };
+
+    (key, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scripted_keyboard() {
+        let events = [
+            KeyEvent { cycle: 3, key: 0 },
+            KeyEvent {
+                cycle: 1,
+                key: b'A' as u16,
+            },
+            KeyEvent {
+                cycle: 5,
+                key: NEWLINE,
+            },
+        ];
+        let mut keyboard = Keyboard::scripted(events);
+
+        let mut seen = vec![keyboard.get_state().to_u16().unwrap()];
+        for _ in 0..6 {
+            keyboard.tick();
+            seen.push(keyboard.get_state().to_u16().unwrap());
+        }
+
+        assert_eq!(seen, [0, 65, 65, 0, 0, 128, 128]);
+        assert_eq!(keyboard.cycle(), 6);
+    }
+
+    #[test]
+    fn test_scripted_keyboard_same_cycle() {
+        // the last event of a cycle wins, events at cycle 0 apply at once
+        let keyboard = Keyboard::scripted([
+            KeyEvent { cycle: 0, key: 1 },
+            KeyEvent {
+                cycle: 0,
+                key: ESCAPE,
+            },
+        ]);
+        assert_eq!(keyboard.key(), ESCAPE);
+    }
+
+    #[test]
+    fn test_decode_plain_keys() {
+        assert_eq!(decode_keys(b"hi!"), [104, 105, 33]);
+        assert_eq!(decode_keys(b"\r\n\x7f\x08"), [128, 128, 129, 129]);
+        assert_eq!(decode_keys(b"\x01\x03"), []);
+    }
+
+    #[test]
+    fn test_decode_escape_sequences() {
+        assert_eq!(
+            decode_keys(b"\x1b[D\x1b[A\x1b[C\x1b[B"),
+            [LEFT, UP, RIGHT, DOWN]
+        );
+        assert_eq!(
+            decode_keys(b"\x1b[H\x1b[4~\x1b[5~\x1b[6~\x1b[2~\x1b[3~"),
+            [HOME, END, PAGE_UP, PAGE_DOWN, INSERT, DELETE]
+        );
+        assert_eq!(decode_keys(b"\x1bOP\x1b[15~\x1b[24~"), [141, 145, 152]);
+        assert_eq!(decode_keys(b"\x1b"), [ESCAPE]);
+        assert_eq!(decode_keys(b"\x1bx"), [ESCAPE, b'x' as u16]);
+        assert_eq!(decode_keys(b"\x1b[1;5C"), [RIGHT]);
+    }
+}
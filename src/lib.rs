@@ -0,0 +1,28 @@
+// chip tests compare against truth table literals, e.g. assert_eq!(res, false)
+#![cfg_attr(test, allow(clippy::bool_assert_comparison))]
+
+pub mod alu;
+pub mod analyze;
+pub mod asm;
+pub mod builtins;
+pub mod bus;
+pub mod circuit;
+pub mod computer;
+pub mod cpu;
+pub mod debugger;
+pub mod emulator;
+pub mod gates;
+pub mod image;
+pub mod jack;
+pub mod keyboard;
+pub mod mem;
+pub mod regfile;
+pub mod runner;
+pub mod screen;
+pub mod sequential;
+pub mod srcmap;
+pub mod state;
+pub mod truth;
+pub mod utils;
+pub mod verify;
+pub mod vm;
@@ -1,27 +1,686 @@
-mod alu;
-mod gates;
-mod mem;
-mod sequential;
-mod utils;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process;
 
-use sequential::{Clock, DFF};
+use rcpu::analyze::analyze;
+use rcpu::asm::{assemble, verify_optimized, AsmError, Assembler, Optimizer, Program, SymbolTable};
+use rcpu::circuit::Library;
+use rcpu::debugger::Debugger;
+use rcpu::jack;
+use rcpu::keyboard::KeyEvent;
+use rcpu::mem::parse_hack;
+use rcpu::runner::Runner;
+use rcpu::srcmap::{SourceMap, SourceMaps};
+use rcpu::truth;
+use rcpu::vm::{self, Command, Translator};
+
+fn truth_table(args: &[String]) {
+    let Some(chip) = args.first() else {
+        eprintln!("usage: main truth <chip>");
+        eprintln!("chips: {}", truth::CHIPS.join(", "));
+        process::exit(2);
+    };
+
+    match truth::chip_truth_table(chip) {
+        Some(table) => print!("{}", truth::render(&table)),
+        None => {
+            eprintln!("unknown chip '{}'", chip);
+            eprintln!("chips: {}", truth::CHIPS.join(", "));
+            process::exit(2);
+        }
+    }
+}
+
+fn dot(args: &[String]) {
+    let library = Library::new();
+    let flatten = args.iter().any(|a| a == "--flatten");
+    let chip = args.iter().find(|a| !a.starts_with("--"));
+
+    let Some(chip) = chip else {
+        eprintln!("usage: main dot <chip> [--flatten]");
+        eprintln!("chips: {}", library.names().join(", "));
+        process::exit(2);
+    };
+
+    let dot = if flatten {
+        library.to_dot_flat(chip)
+    } else {
+        library.to_dot(chip)
+    };
+
+    match dot {
+        Some(dot) => print!("{}", dot),
+        None => {
+            eprintln!("unknown chip '{}'", chip);
+            eprintln!("chips: {}", library.names().join(", "));
+            process::exit(2);
+        }
+    }
+}
+
+// decimal or 0x-prefixed hex
+fn parse_number<T: TryFrom<u64>>(s: &str) -> Option<T> {
+    let value = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => s.parse().ok()?,
+    };
+    T::try_from(value).ok()
+}
+
+// START..END (END excluded) or a single ADDR
+fn parse_range(s: &str) -> Option<Range<u16>> {
+    match s.split_once("..") {
+        Some((start, end)) => Some(parse_number(start)?..parse_number(end)?),
+        None => {
+            let addr: u16 = parse_number(s)?;
+            Some(addr..addr.checked_add(1)?)
+        }
+    }
+}
+
+fn parse_key(s: &str) -> Option<KeyEvent> {
+    let (cycle, key) = s.split_once(':')?;
+    Some(KeyEvent {
+        cycle: parse_number(cycle)?,
+        key: parse_number(key)?,
+    })
+}
+
+// the Hack assembly of a .vm file or a directory of them
+fn translate_vm(path: &str) -> Result<String, String> {
+    translate_vm_with_map(path).map(|(asm, _)| asm)
+}
+
+// the OS is linked in as by jackc, but only for programs that call into it
+fn translate_vm_with_map(path: &str) -> Result<(String, SourceMap), String> {
+    let mut files = vm::read_files(path).map_err(|e| e.to_string())?;
+    let commands: Vec<Command> = files
+        .iter()
+        .filter_map(|file| vm::parse(&file.source).ok())
+        .flatten()
+        .map(|(_, command)| command)
+        .collect();
+    let defined = |name: &String| {
+        commands
+            .iter()
+            .any(|c| matches!(c, Command::Function(f, _) if f == name))
+    };
+    let calls_os = commands
+        .iter()
+        .any(|c| matches!(c, Command::Call(f, _) if !defined(f)));
+    if calls_os {
+        jack::os::link(&mut files);
+    }
+    Translator::new()
+        .translate_with_map(&files)
+        .map_err(|e| e.to_string())
+}
+
+// .include finds files next to the one including them
+fn assemble_file(path: &str, source: &str) -> Result<Program, AsmError> {
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    Assembler::new().include_dir(dir).assemble(source)
+}
+
+// FILE.map, next to FILE
+fn map_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".map");
+    PathBuf::from(name)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+// .vm files and directories are translated, .asm assembled, the rest read as .hack
+fn load_program(path: &str) -> (Vec<u16>, SymbolTable) {
+    if path.ends_with(".asm") {
+        let (program, _) = load_asm(path);
+        return (program.code, program.symbols);
+    }
+    let is_vm = path.ends_with(".vm") || Path::new(path).is_dir();
+    let program = if is_vm {
+        translate_vm(path)
+    } else {
+        fs::read_to_string(path).map_err(|e| e.to_string())
+    }
+    .map_err(|e| format!("{}: {}", path, e))
+    .and_then(|text| {
+        if is_vm {
+            let program = assemble(&text).map_err(|e| format!("{}: {}", path, e))?;
+            Ok((program.code, program.symbols))
+        } else {
+            let code = parse_hack(&text).map_err(|e| format!("{}: {}", path, e))?;
+            Ok((code, SymbolTable::new()))
+        }
+    });
+
+    match program {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// a .asm file assembled, with its text
+fn load_asm(path: &str) -> (Program, String) {
+    let source = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    });
+    match assemble_file(path, &source) {
+        Ok(program) => (program, source),
+        Err(e) => {
+            eprintln!("{}", e.report(path, &source));
+            process::exit(1);
+        }
+    }
+}
+
+fn asm(args: &[String]) {
+    const USAGE: &str = "usage: main asm <file.asm> [-o <file.hack>] [--listing <file.lst>]";
+    let usage = || -> ! {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    };
+
+    let mut path = None;
+    let mut out = None;
+    let mut listing = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => out = Some(args.next().unwrap_or_else(|| usage())),
+            "--listing" => listing = Some(args.next().unwrap_or_else(|| usage())),
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let Some(path) = path else { usage() };
+
+    // the map and the listing are of the .asm, VM code is assembled without
+    let (code, assembled) = if path.ends_with(".asm") {
+        let (program, source) = load_asm(path);
+        (program.code.clone(), Some((program, source)))
+    } else {
+        if listing.is_some() {
+            eprintln!("{}: --listing needs a .asm file", path);
+            process::exit(2);
+        }
+        (load_program(path).0, None)
+    };
+    let hack: String = code.iter().map(|word| format!("{:016b}\n", word)).collect();
+    match out {
+        None => print!("{}", hack),
+        Some(out) => write_file(Path::new(out), hack),
+    }
+
+    let Some((program, source)) = assembled else {
+        return;
+    };
+    if let Some(out) = out {
+        // the ROM addresses to the lines of the .asm, for the debugger
+        let map = program.source_map(&file_name(Path::new(path)));
+        write_file(&map_path(Path::new(out)), map.to_text());
+    }
+    if let Some(listing) = listing {
+        write_file(Path::new(listing), program.listing(&source));
+    }
+}
+
+// Prog/ goes to Prog/Prog.asm, Prog.vm to Prog.asm
+fn default_asm_path(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    if path.is_dir() {
+        let name = path
+            .canonicalize()
+            .ok()
+            .and_then(|p| p.file_name().map(|n| n.to_owned()))
+            .unwrap_or_else(|| "out".into());
+        path.join(name).with_extension("asm")
+    } else {
+        path.with_extension("asm")
+    }
+}
+
+fn vm_translate(args: &[String]) {
+    const USAGE: &str = "usage: main vm <dir|file.vm> [-o <file.asm>]";
+    let (path, out) = match args {
+        [path] => (path, default_asm_path(path)),
+        [path, flag, out] if flag == "-o" => (path, PathBuf::from(out)),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    let (asm, map) = match translate_vm_with_map(path) {
+        Ok(translated) => translated,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    write_file(&out, asm);
+    write_file(&map_path(&out), map.to_text());
+    println!("wrote {}", out.display());
+}
+
+fn jack_paths(path: &str) -> Vec<PathBuf> {
+    jack::source_paths(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    })
+}
+
+fn parse_jack(path: &Path) -> (String, jack::ast::Class) {
+    let parsed = fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|source| match jack::parse(&source) {
+            Ok(class) => Ok((source, class)),
+            Err(e) => Err(format!(":{}", e)),
+        });
+    parsed.unwrap_or_else(|e| {
+        // syntax errors start with their line:col, I/O errors do not
+        let separator = if e.starts_with(':') { "" } else { ": " };
+        eprintln!("{}{}{}", path.display(), separator, e);
+        process::exit(1);
+    })
+}
+
+fn write_file(path: &Path, contents: String) {
+    if let Err(e) = fs::write(path, contents) {
+        eprintln!("{}: {}", path.display(), e);
+        process::exit(1);
+    }
+}
+
+fn jack_xml(args: &[String]) {
+    let [path] = args else {
+        eprintln!("usage: main jack <dir|file.jack>");
+        process::exit(2);
+    };
+
+    // XxxT.xml and Xxx.xml next to each Xxx.jack, as the course tools expect
+    for path in jack_paths(path) {
+        let (source, class) = parse_jack(&path);
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let tokens_path = path.with_file_name(format!("{}T.xml", stem));
+        let tree_path = path.with_extension("xml");
+        write_file(&tokens_path, jack::tokens_xml(&source).unwrap());
+        write_file(&tree_path, jack::class_xml(&class));
+        println!(
+            "wrote {} and {}",
+            tokens_path.display(),
+            tree_path.display()
+        );
+    }
+}
+
+fn jackc(args: &[String]) {
+    let [path] = args else {
+        eprintln!("usage: main jackc <dir|file.jack>");
+        process::exit(2);
+    };
+
+    // Xxx.vm next to each Xxx.jack, then the program with the OS as Hack assembly
+    let mut vm_path = PathBuf::new();
+    for path in jack_paths(path) {
+        let (_, class) = parse_jack(&path);
+        let (vm, map) = jack::compile_with_map(&class).unwrap_or_else(|e| {
+            eprintln!("{}:{}", path.display(), e);
+            process::exit(1);
+        });
+        vm_path = path.with_extension("vm");
+        write_file(&vm_path, vm);
+        write_file(&map_path(&vm_path), map.to_text());
+        println!("wrote {}", vm_path.display());
+    }
+
+    let program = if Path::new(path).is_dir() {
+        path.clone()
+    } else {
+        vm_path.to_string_lossy().into_owned()
+    };
+    // the OS functions the program calls but does not define are linked in
+    let (asm, map) = vm::read_files(&program)
+        .map_err(|e| e.to_string())
+        .and_then(|mut files| {
+            jack::os::link(&mut files);
+            Translator::new()
+                .translate_with_map(&files)
+                .map_err(|e| e.to_string())
+        })
+        .unwrap_or_else(|e| {
+            eprintln!("{}: {}", program, e);
+            process::exit(1);
+        });
+    let out = default_asm_path(&program);
+    write_file(&out, asm);
+    write_file(&map_path(&out), map.to_text());
+    println!("wrote {}", out.display());
+}
+
+/*
+ * The maps from the program's ROM back to its sources: the lines of the
+ * .asm, assembled or in FILE.hack.map, then the FILE.map files written
+ * with what they were generated from, e.g. by jackc, in the same directory.
+ */
+fn load_sources(path: &str) -> Option<SourceMaps> {
+    let is_vm = path.ends_with(".vm") || Path::new(path).is_dir();
+    let dir = match Path::new(path).is_dir() {
+        true => Path::new(path),
+        false => Path::new(path).parent()?,
+    };
+
+    let mut sources = if is_vm {
+        // translated here, as by vm, into a Prog.asm that is not written
+        let (asm, map) = translate_vm_with_map(path).ok()?;
+        let name = file_name(&default_asm_path(path));
+        let program = assemble(&asm).ok()?;
+        let mut sources = SourceMaps::new(program.source_map(&name));
+        sources.add_map(&name, map);
+        sources.add_text(&name, &asm);
+        sources
+    } else if path.ends_with(".asm") {
+        let program = assemble_file(path, &fs::read_to_string(path).ok()?).ok()?;
+        SourceMaps::new(program.source_map(&file_name(Path::new(path))))
+    } else {
+        let text = fs::read_to_string(map_path(Path::new(path))).ok()?;
+        match SourceMap::parse(&text) {
+            Ok(map) => SourceMaps::new(map),
+            Err((line, e)) => {
+                eprintln!("{}.map:{}: {}", path, line, e);
+                return None;
+            }
+        }
+    };
+    if let Err(e) = sources.load_from(dir) {
+        eprintln!("{}", e);
+    }
+    Some(sources)
+}
+
+fn opt(args: &[String]) {
+    const USAGE: &str = "usage: main opt <file.asm> [-o <file.asm>] [--vm] [--verify] [--cycles N]";
+    let usage = || -> ! {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    };
+
+    let mut path = None;
+    let mut out = None;
+    let mut verify = false;
+    let mut vm_stack = false;
+    let mut cycles = 10_000_000;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => out = Some(args.next().unwrap_or_else(|| usage())),
+            "--verify" => verify = true,
+            "--vm" => vm_stack = true,
+            "--cycles" => match args.next().and_then(|n| parse_number(n)) {
+                Some(n) => cycles = n,
+                None => usage(),
+            },
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let Some(path) = path else { usage() };
+
+    let source = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    });
+    let original = assemble_file(path, &source).unwrap_or_else(|e| {
+        eprintln!("{}", e.report(path, &source));
+        process::exit(1);
+    });
+    // the stack rewrites only hold for what the VM translator writes
+    let (optimized_asm, map) = Optimizer::new()
+        .vm_stack(vm_stack)
+        .optimize_with_map(&source, &file_name(Path::new(path)));
+    let optimized = assemble_file(path, &optimized_asm).unwrap_or_else(|e| {
+        eprintln!("{}: the optimized program: {}", path, e);
+        process::exit(1);
+    });
+
+    match out {
+        None => print!("{}", optimized_asm),
+        Some(out) => {
+            // its lines to those of the original, which has its own map
+            write_file(Path::new(out), optimized_asm);
+            write_file(&map_path(Path::new(out)), map.to_text());
+        }
+    }
+    eprintln!(
+        "{}: {} instructions, {} optimized",
+        path,
+        original.code.len(),
+        optimized.code.len()
+    );
+
+    if verify {
+        let verification = verify_optimized(&original, &optimized, cycles).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        });
+        eprintln!(
+            "{}: {} cycles, {} optimized",
+            path, verification.original_cycles, verification.optimized_cycles
+        );
+        for (addr, before, after) in verification.differences.iter().take(10) {
+            eprintln!("RAM[{}] is {}, optimized {}", addr, before, after);
+        }
+        if !verification.differences.is_empty() {
+            eprintln!("{} words of RAM differ", verification.differences.len());
+            process::exit(1);
+        }
+        eprintln!("the final RAM is the same");
+    }
+}
+
+fn check(args: &[String]) {
+    const USAGE: &str = "usage: main check <file.asm|file.hack|dir|file.vm> [--cfg <file.dot>]";
+    let (path, cfg) = match args {
+        [path] => (path, None),
+        [path, flag, cfg] if flag == "--cfg" => (path, Some(cfg)),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    let (code, symbols) = load_program(path);
+    let analysis = analyze(&code, &symbols);
+    if let Some(cfg) = cfg {
+        write_file(Path::new(cfg), analysis.to_dot(&symbols));
+    }
+
+    // each warning at its source lines when there are maps, else its address
+    let sources = load_sources(path);
+    for warning in &analysis.warnings {
+        let addr = warning.addr();
+        let locations = sources
+            .as_ref()
+            .map(|sources| sources.locate(addr))
+            .unwrap_or_default();
+        let at = match locations.last() {
+            Some(source) => format!("{}: ROM {} ({})", path, addr, source),
+            None => format!("{}: ROM {}", path, addr),
+        };
+        println!("{}: warning: {}", at, warning);
+    }
+    println!(
+        "{} instructions, {} blocks, {} warnings",
+        code.len(),
+        analysis.blocks.len(),
+        analysis.warnings.len()
+    );
+    if !analysis.warnings.is_empty() {
+        process::exit(1);
+    }
+}
+
+fn debug(args: &[String]) {
+    let [path] = args else {
+        eprintln!("usage: main debug <file.asm|file.hack>");
+        process::exit(2);
+    };
+
+    let (code, symbols) = load_program(path);
+    let mut debugger = Debugger::new(&code, symbols);
+    if let Some(sources) = load_sources(path) {
+        debugger = debugger.sources(sources);
+    }
+    println!(
+        "{} instructions loaded, type help for the commands",
+        code.len()
+    );
+
+    // an empty line repeats the last command, as in gdb
+    let mut last = String::new();
+    let mut line = String::new();
+    loop {
+        print!("(rcpu) ");
+        let _ = io::stdout().flush();
+
+        line.clear();
+        match io::stdin().lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let command = match line.trim() {
+            "" => last.clone(),
+            command => command.to_string(),
+        };
+        if command == "quit" || command == "q" {
+            break;
+        }
+
+        match debugger.execute(&command) {
+            Ok(out) if out.is_empty() || out.ends_with('\n') => print!("{}", out),
+            Ok(out) => println!("{}", out),
+            Err(e) => eprintln!("{}", e),
+        }
+        last = command;
+    }
+}
+
+fn run(args: &[String]) {
+    const USAGE: &str = "usage: main run <file.hack|file.asm> [--cycles N] [--ram START..END]... \
+                         [--key CYCLE:CODE]... [--no-halt] [--accel] \
+                         [--screen FILE.png|FILE.pbm] [--load-state FILE] [--save-state FILE]";
+
+    let usage = |message: &str| -> ! {
+        eprintln!("{}", message);
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    };
+
+    let mut runner = Runner::new();
+    let mut path = None;
+    let mut screen = None;
+    let mut load_state = None;
+    let mut save_state = None;
+    let mut accelerate = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| match args.next() {
+            Some(value) => value.clone(),
+            None => usage(&format!("{} needs a value", flag)),
+        };
+
+        match arg.as_str() {
+            "--cycles" => match parse_number(&value(arg)) {
+                Some(cycles) => runner = runner.max_cycles(cycles),
+                None => usage("--cycles takes a number"),
+            },
+            "--ram" => match parse_range(&value(arg)) {
+                Some(range) => runner = runner.dump(range),
+                None => usage("--ram takes START..END or ADDR"),
+            },
+            "--key" => match parse_key(&value(arg)) {
+                Some(key) => runner = runner.keys([key]),
+                None => usage("--key takes CYCLE:CODE"),
+            },
+            "--no-halt" => runner = runner.stop_at_halt(false),
+            "--accel" => accelerate = true,
+            "--screen" => screen = Some(value(arg)),
+            "--load-state" => load_state = Some(value(arg)),
+            "--save-state" => save_state = Some(value(arg)),
+            flag if flag.starts_with("--") => usage(&format!("unknown option {}", flag)),
+            file => path = Some(file.to_string()),
+        }
+    }
+
+    // a saved state holds the ROM too, so it needs no program
+    let (program, symbols) = match (&path, &load_state) {
+        (Some(path), _) => load_program(path),
+        (None, Some(_)) => (Vec::new(), SymbolTable::new()),
+        (None, None) => usage("no program given"),
+    };
+    // the OS builtins run natively when asked for, the run is no longer cycle exact
+    if accelerate {
+        runner = runner.accelerate(&program, &symbols);
+    }
+
+    let mut clock = runner.machine(&program);
+    if let Some(state) = load_state {
+        if let Err(e) = clock.restore_from(&state) {
+            eprintln!("{}: {}", state, e);
+            process::exit(1);
+        }
+    }
+
+    let report = runner.run_on(&mut clock);
+    println!("{}", report.to_json());
+
+    if let Some(screen) = screen {
+        if let Err(e) = report.screen.save(&screen) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+    if let Some(state) = save_state {
+        if let Err(e) = clock.save_to(&state) {
+            eprintln!("{}: {}", state, e);
+            process::exit(1);
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: main <command> [args]");
+    eprintln!("commands: truth, dot, run, asm, opt, check, debug, vm, jack, jackc");
+    process::exit(2);
+}
 
 fn main() {
-    let mut clock = Clock::new();
-
-    let dff1 = DFF::new();
-
-    clock.register(Box::new(dff1));
-    clock.set_state_of_component(0, vec![true]);
-    println!("State: {:#?}", clock.get_state_of_component(0));
-
-    //clock.start_clock();
-    clock.tick();
-    println!("State: {:#?}", clock.get_state_of_component(0));
-    clock.tick();
-    println!("State: {:#?}", clock.get_state_of_component(0));
-    clock.set_state_of_component(0, vec![false]);
-    clock.tick();
-    println!("State: {:#?}", clock.get_state_of_component(0));
-    clock.tick();
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        Some("truth") => truth_table(&args[1..]),
+        Some("dot") => dot(&args[1..]),
+        Some("run") => run(&args[1..]),
+        Some("asm") => asm(&args[1..]),
+        Some("opt") => opt(&args[1..]),
+        Some("check") => check(&args[1..]),
+        Some("debug") => debug(&args[1..]),
+        Some("vm") => vm_translate(&args[1..]),
+        Some("jack") => jack_xml(&args[1..]),
+        Some("jackc") => jackc(&args[1..]),
+        _ => usage(),
+    }
 }
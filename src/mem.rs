@@ -1 +1,393 @@
+use std::fmt;
 
+use crate::gates::{dmux4way_gate, mux4way16_gate};
+use crate::keyboard::{Keyboard, KEYBOARD_ADDRESS};
+use crate::screen::{Screen, SCREEN_ADDRESS};
+use crate::sequential::{Addressable, Tick, RAM16K};
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::utils::Bits;
+
+/**
+ * The data memory of the Hack computer:
+ * 0x0000 - 0x3FFF RAM16K, 0x4000 - 0x5FFF Screen, 0x6000 Keyboard.
+ * Reading above 0x6000 gives 0 and writes there are ignored.
+ * Pins: in[16], load, address[15], out[16].
+ */
+pub struct Memory {
+    ram: RAM16K,
+    screen: Screen,
+    keyboard: Keyboard,
+    addr: u16, // 15 bits
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Self::with_keyboard(Keyboard::new())
+    }
+
+    pub fn with_keyboard(keyboard: Keyboard) -> Self {
+        Memory {
+            ram: RAM16K::new(),
+            screen: Screen::new(),
+            keyboard,
+            addr: 0,
+        }
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    pub fn keyboard(&self) -> &Keyboard {
+        &self.keyboard
+    }
+
+    pub fn set_keyboard(&mut self, keyboard: Keyboard) {
+        self.keyboard = keyboard;
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Addressable for Memory {
+    const ADDR_BITS: usize = 15;
+
+    fn peek(&self, addr: usize) -> u16 {
+        match addr {
+            0..0x4000 => self.ram.peek(addr),
+            0x4000..0x6000 => self.screen.peek(addr - SCREEN_ADDRESS as usize),
+            0x6000 => self.keyboard.key(),
+            _ => 0,
+        }
+    }
+
+    // a poked key is held until the keyboard's source changes it
+    fn poke(&mut self, addr: usize, value: u16) {
+        match addr {
+            0..0x4000 => self.ram.poke(addr, value),
+            0x4000..0x6000 => self.screen.poke(addr - SCREEN_ADDRESS as usize, value),
+            0x6000 => self.keyboard.poke(value),
+            _ => {}
+        }
+    }
+}
+
+impl Tick for Memory {
+    fn tick(&mut self) {
+        self.ram.tick();
+        self.screen.tick();
+        self.keyboard.tick();
+    }
+
+    fn get_state(&self) -> Bits {
+        let ram = self.ram.get_state().to_u16().unwrap();
+        let screen = self.screen.get_state().to_u16().unwrap();
+        let keyboard = match self.addr {
+            KEYBOARD_ADDRESS => self.keyboard.get_state().to_u16().unwrap(),
+            _ => 0,
+        };
+
+        let sel = (self.addr >> 13) as u8;
+        mux4way16_gate(ram, ram, screen, keyboard, sel).into()
+    }
+
+    fn set_state(&mut self, inputs: Bits) {
+        assert_eq!(inputs.len(), 16 + 1 + 15);
+
+        let data = inputs.slice_msb(0..16);
+        let load = inputs.msb(16);
+        let addr = inputs.slice_msb(17..32);
+        self.addr = addr.to_u16().unwrap();
+
+        // address bits 14 and 13 pick the device, the keyboard is read only
+        let (ram_low, ram_high, screen_load, _) = dmux4way_gate(load, (self.addr >> 13) as u8);
+
+        let ram_input = data.concat((ram_low || ram_high).into());
+        self.ram.set_state(ram_input.concat(addr.slice_msb(1..15)));
+
+        let screen_input = data.concat(screen_load.into());
+        self.screen
+            .set_state(screen_input.concat(addr.slice_msb(2..15)));
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        self.ram.save(out);
+        self.screen.save(out);
+        self.keyboard.save(out);
+        out.u16(self.addr);
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.ram.restore(input)?;
+        self.screen.restore(input)?;
+        self.keyboard.restore(input)?;
+        self.addr = input.u16()? & 0x7FFF;
+        Ok(())
+    }
+
+    // a tick writes at most the addressed word, so its old value is enough
+    fn tick_recorded(&mut self, undo: &mut StateWriter) {
+        undo.u16(self.addr);
+        undo.u16(self.peek(self.addr as usize));
+        self.ram.tick();
+        self.screen.tick();
+        self.keyboard.tick_recorded(undo);
+    }
+
+    fn undo(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        let addr = input.u16()? & 0x7FFF;
+        let old = input.u16()?;
+        self.keyboard.undo(input)?;
+
+        // the keyboard was undone above, and load is dropped so the next
+        // tick does not write the undone value again
+        if addr < KEYBOARD_ADDRESS {
+            self.poke(addr as usize, old);
+        }
+        self.set_state(
+            Bits::from(old)
+                .concat(false.into())
+                .concat(Bits::new(addr as u64, 15)),
+        );
+        Ok(())
+    }
+
+    fn kind(&self) -> &'static str {
+        "Memory"
+    }
+}
+
+/**
+ * Instruction memory, 32K read only words.
+ * Pins: address[15], out[16].
+ */
+#[allow(clippy::upper_case_acronyms)]
+pub struct ROM32K {
+    words: Vec<u16>,
+    addr: u16,
+}
+
+impl ROM32K {
+    pub const SIZE: usize = 1 << 15;
+
+    pub fn new() -> Self {
+        ROM32K {
+            words: vec![0; Self::SIZE],
+            addr: 0,
+        }
+    }
+
+    // program starting at address 0, the rest of the ROM is zeroed
+    pub fn load(&mut self, program: &[u16]) {
+        assert!(
+            program.len() <= Self::SIZE,
+            "program has {} instructions, the ROM holds {}",
+            program.len(),
+            Self::SIZE
+        );
+        self.words.fill(0);
+        self.words[..program.len()].copy_from_slice(program);
+    }
+
+    // backdoor access, the address latch is left alone
+    pub fn peek(&self, addr: usize) -> u16 {
+        self.words[addr]
+    }
+
+    pub fn poke(&mut self, addr: usize, value: u16) {
+        self.words[addr] = value;
+    }
+}
+
+impl Default for ROM32K {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tick for ROM32K {
+    fn tick(&mut self) {}
+
+    fn get_state(&self) -> Bits {
+        self.words[self.addr as usize].into()
+    }
+
+    fn set_state(&mut self, inputs: Bits) {
+        assert_eq!(inputs.len(), 15);
+        self.addr = inputs.to_u16().unwrap();
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        // the zeroed tail of the ROM is left out
+        let len = self
+            .words
+            .iter()
+            .rposition(|&w| w != 0)
+            .map_or(0, |i| i + 1);
+        out.u64(len as u64);
+        for &word in &self.words[..len] {
+            out.u16(word);
+        }
+        out.u16(self.addr);
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        let len = input.u64()? as usize;
+        if len > Self::SIZE {
+            return Err(StateError::Invalid(format!("{} ROM words", len)));
+        }
+        self.words.fill(0);
+        for word in self.words[..len].iter_mut() {
+            *word = input.u16()?;
+        }
+        self.addr = input.u16()? & 0x7FFF;
+        Ok(())
+    }
+
+    fn kind(&self) -> &'static str {
+        "ROM32K"
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HackError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for HackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for HackError {}
+
+/**
+ * Parses a .hack file: one 16 character binary instruction per line.
+ * Blank lines are skipped.
+ */
+pub fn parse_hack(text: &str) -> Result<Vec<u16>, HackError> {
+    let mut program = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let error = |message: String| HackError {
+            line: i + 1,
+            message,
+        };
+        if line.len() != 16 {
+            return Err(error(format!(
+                "expected 16 binary digits, got {} characters",
+                line.len()
+            )));
+        }
+        let word = Bits::parse_bin(line).map_err(|e| error(e.to_string()))?;
+        program.push(word.to_u16().unwrap());
+    }
+
+    if program.len() > ROM32K::SIZE {
+        return Err(HackError {
+            line: text.lines().count(),
+            message: format!("program does not fit in {} words of ROM", ROM32K::SIZE),
+        });
+    }
+
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::KeyEvent;
+
+    fn memory_input(data: u16, load: bool, addr: u16) -> Bits {
+        Bits::from(data)
+            .concat(load.into())
+            .concat(Bits::new(addr as u64, 15))
+    }
+
+    fn read(memory: &mut Memory, addr: u16) -> u16 {
+        memory.set_state(memory_input(0, false, addr));
+        memory.get_state().to_u16().unwrap()
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let mut memory =
+            Memory::with_keyboard(Keyboard::scripted([KeyEvent { cycle: 1, key: 75 }]));
+
+        for (addr, data) in [(0x0000, 1u16), (0x3FFF, 2), (0x4000, 3), (0x5FFF, 4)] {
+            memory.set_state(memory_input(data, true, addr));
+            memory.tick();
+        }
+
+        assert_eq!(read(&mut memory, 0x0000), 1);
+        assert_eq!(read(&mut memory, 0x3FFF), 2);
+        assert_eq!(read(&mut memory, 0x4000), 3);
+        assert_eq!(read(&mut memory, 0x5FFF), 4);
+        assert_eq!(read(&mut memory, 0x6000), 75);
+        assert_eq!(memory.screen().peek(0), 3);
+        assert_eq!(memory.screen().peek(0x1FFF), 4);
+    }
+
+    #[test]
+    fn test_memory_ignores_writes_past_keyboard() {
+        let mut memory = Memory::new();
+
+        for addr in [0x6000, 0x6001, 0x7FFF] {
+            memory.set_state(memory_input(0xFFFF, true, addr));
+            memory.tick();
+            assert_eq!(read(&mut memory, addr), 0);
+        }
+
+        // the high RAM4Ks and the screen halves are not aliases of them
+        assert_eq!(read(&mut memory, 0x2001), 0);
+        assert_eq!(read(&mut memory, 0x4001), 0);
+        assert_eq!(read(&mut memory, 0x5FFF), 0);
+    }
+
+    #[test]
+    fn test_memory_poke() {
+        let mut memory = Memory::new();
+        for (addr, data) in [(0x0005, 1u16), (0x4005, 2), (0x6000, 3), (0x6001, 4)] {
+            memory.poke(addr, data);
+        }
+        assert_eq!(memory.peek(0x0005), 1);
+        assert_eq!(memory.screen().peek(5), 2);
+        assert_eq!(memory.keyboard().key(), 3);
+        assert_eq!(memory.peek(0x6001), 0);
+        // the keyboard was not clocked
+        assert_eq!(memory.keyboard().cycle(), 0);
+    }
+
+    #[test]
+    fn test_rom() {
+        let mut rom = ROM32K::new();
+        rom.load(&[7, 8, 9]);
+        rom.set_state(Bits::new(2, 15));
+        assert_eq!(rom.get_state().to_u16(), Ok(9));
+        rom.set_state(Bits::new(0x7FFF, 15));
+        assert_eq!(rom.get_state().to_u16(), Ok(0));
+    }
+
+    #[test]
+    fn test_parse_hack() {
+        let program = parse_hack("0000000000000010\n\n1110110000010000\r\n").unwrap();
+        assert_eq!(program, [2, 0xEC10]);
+
+        let err = parse_hack("0000000000000010\n111011000001000\n").unwrap_err();
+        assert_eq!(err.line, 2);
+
+        let err = parse_hack("000000000000001x").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: invalid digit 'x'");
+    }
+}
@@ -0,0 +1,295 @@
+use crate::gates::{dmux_gate, mux16_gate};
+use crate::sequential::{Register, Tick};
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::utils::Bits;
+
+// mux16 tree: sel picks one of words, its high bit splitting the halves
+fn mux_tree(words: &[u16], sel: usize) -> u16 {
+    if words.len() == 1 {
+        return words[0];
+    }
+    let half = words.len() / 2;
+    let low = mux_tree(&words[..half], sel & (half - 1));
+    let high = mux_tree(&words[half..], sel & (half - 1));
+    mux16_gate(low, high, sel & half != 0)
+}
+
+// dmux tree: x routed to output sel of count, the others are 0
+fn dmux_tree(x: bool, sel: usize, count: usize) -> Vec<bool> {
+    if count == 1 {
+        return vec![x];
+    }
+    let half = count / 2;
+    let (low, high) = dmux_gate(x, sel & half != 0);
+    let mut outs = dmux_tree(low, sel & (half - 1), half);
+    outs.extend(dmux_tree(high, sel & (half - 1), half));
+    outs
+}
+
+/**
+ * 2^K 16-bit registers with one write port and two read ports
+ * (8 registers unless stated otherwise).
+ * If load is asserted, the register selected by write is set to in
+ * on the next tick. out_a and out_b emit the registers selected by
+ * read_a and read_b; a register being written still reads its old
+ * value until the tick.
+ * Pins: in[16], load, write[K], read_a[K], read_b[K], out_a[16], out_b[16].
+ */
+pub struct RegisterFile<const K: usize = 3> {
+    registers: Vec<Register>,
+    read: [usize; 2],
+}
+
+impl<const K: usize> RegisterFile<K> {
+    pub fn new() -> Self {
+        RegisterFile {
+            registers: (0..1 << K).map(|_| Register::new()).collect(),
+            read: [0, 0],
+        }
+    }
+
+    // backdoor access, as Addressable::peek and poke
+    pub fn peek(&self, addr: usize) -> u16 {
+        self.registers[addr].get_state().to_u16().unwrap()
+    }
+
+    pub fn poke(&mut self, addr: usize, value: u16) {
+        self.registers[addr].poke(value.into());
+    }
+}
+
+impl<const K: usize> Default for RegisterFile<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const K: usize> Tick for RegisterFile<K> {
+    fn tick(&mut self) {
+        for register in self.registers.iter_mut() {
+            register.tick();
+        }
+    }
+
+    fn get_state(&self) -> Bits {
+        let words: Vec<u16> = (0..1 << K).map(|i| self.peek(i)).collect();
+        let out_a = mux_tree(&words, self.read[0]);
+        let out_b = mux_tree(&words, self.read[1]);
+        Bits::from(out_a).concat(out_b.into())
+    }
+
+    fn set_state(&mut self, inputs: Bits) {
+        assert_eq!(inputs.len(), 16 + 1 + 3 * K);
+
+        let data = inputs.slice_msb(0..16);
+        let load = inputs.msb(16);
+        let address = |i: usize| {
+            let start = 17 + i * K;
+            inputs.slice_msb(start..start + K).to_u64().unwrap() as usize
+        };
+
+        let loads = dmux_tree(load, address(0), 1 << K);
+        self.read = [address(1), address(2)];
+
+        for (register, load) in self.registers.iter_mut().zip(loads) {
+            register.set_state(data.concat(load.into()));
+        }
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        for register in &self.registers {
+            register.save(out);
+        }
+        for &addr in &self.read {
+            out.u16(addr as u16);
+        }
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        for register in self.registers.iter_mut() {
+            register.restore(input)?;
+        }
+        for addr in self.read.iter_mut() {
+            *addr = input.u16()? as usize;
+            if *addr >= 1 << K {
+                return Err(StateError::Invalid(format!(
+                    "read address {} of {}",
+                    addr,
+                    1 << K
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn kind(&self) -> &'static str {
+        "RegisterFile"
+    }
+}
+
+/**
+ * RAM of 2^K 16-bit registers with a read/write port and a read only port.
+ * Port A works as the single port of the RAM chips: if load is asserted,
+ * the register selected by address is set to in on the next tick, and out
+ * emits it. out_b emits the register selected by address_b. Reading the
+ * register being written, on either port, gives its old value until the tick.
+ * Pins: in[16], load, address[K], address_b[K], out[16], out_b[16].
+ */
+pub struct DualPortRAM<const K: usize> {
+    file: RegisterFile<K>,
+}
+
+pub type DualPortRAM8 = DualPortRAM<3>;
+pub type DualPortRAM64 = DualPortRAM<6>;
+
+impl<const K: usize> DualPortRAM<K> {
+    pub fn new() -> Self {
+        DualPortRAM {
+            file: RegisterFile::new(),
+        }
+    }
+
+    pub fn peek(&self, addr: usize) -> u16 {
+        self.file.peek(addr)
+    }
+
+    pub fn poke(&mut self, addr: usize, value: u16) {
+        self.file.poke(addr, value);
+    }
+}
+
+impl<const K: usize> Default for DualPortRAM<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// a register file whose first read port shares the write address
+impl<const K: usize> Tick for DualPortRAM<K> {
+    fn tick(&mut self) {
+        self.file.tick();
+    }
+
+    fn get_state(&self) -> Bits {
+        self.file.get_state()
+    }
+
+    fn set_state(&mut self, inputs: Bits) {
+        assert_eq!(inputs.len(), 16 + 1 + 2 * K);
+
+        let address = inputs.slice_msb(17..17 + K);
+        let file_input = inputs.slice_msb(0..17 + K).concat(address);
+        self.file
+            .set_state(file_input.concat(inputs.slice_msb(17 + K..17 + 2 * K)));
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        self.file.save(out);
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.file.restore(input)
+    }
+
+    fn kind(&self) -> &'static str {
+        "DualPortRAM"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_input(data: u16, load: bool, write: u64, read_a: u64, read_b: u64) -> Bits {
+        Bits::from(data)
+            .concat(load.into())
+            .concat(Bits::new(write, 3))
+            .concat(Bits::new(read_a, 3))
+            .concat(Bits::new(read_b, 3))
+    }
+
+    fn outs(chip: &impl Tick) -> (u16, u16) {
+        let out = chip.get_state();
+        (
+            out.slice_msb(0..16).to_u16().unwrap(),
+            out.slice_msb(16..32).to_u16().unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_mux_and_dmux_trees() {
+        let words: Vec<u16> = (0..16).map(|i| i * 3).collect();
+        for sel in 0..16 {
+            assert_eq!(mux_tree(&words, sel), sel as u16 * 3);
+
+            let outs = dmux_tree(true, sel, 16);
+            assert_eq!(outs.iter().filter(|&&x| x).count(), 1);
+            assert!(outs[sel]);
+            assert!(dmux_tree(false, sel, 16).iter().all(|&x| !x));
+        }
+    }
+
+    #[test]
+    fn test_register_file() {
+        let mut file = RegisterFile::<3>::new();
+        for i in 0..8 {
+            file.set_state(file_input(100 + i as u16, true, i, 0, 0));
+            file.tick();
+        }
+
+        // two different registers read at once, nothing written
+        file.set_state(file_input(0xFFFF, false, 2, 5, 7));
+        assert_eq!(outs(&file), (105, 107));
+        file.tick();
+        assert_eq!(outs(&file), (105, 107));
+        assert_eq!(file.peek(2), 102);
+
+        file.set_state(file_input(9, true, 6, 1, 1));
+        assert_eq!(outs(&file), (101, 101));
+    }
+
+    #[test]
+    fn test_register_file_read_during_write() {
+        let mut file = RegisterFile::<3>::new();
+        file.poke(4, 0x1111);
+
+        // both ports read the register being written: the old value
+        // until the tick, the new one after it
+        file.set_state(file_input(0x2222, true, 4, 4, 4));
+        assert_eq!(outs(&file), (0x1111, 0x1111));
+        file.tick();
+        assert_eq!(outs(&file), (0x2222, 0x2222));
+
+        // a read port on another register is not disturbed by the write
+        file.set_state(file_input(0x3333, true, 4, 4, 0));
+        assert_eq!(outs(&file), (0x2222, 0));
+        file.tick();
+        assert_eq!(outs(&file), (0x3333, 0));
+    }
+
+    #[test]
+    fn test_dual_port_ram() {
+        let input = |data: u16, load: bool, addr: u64, addr_b: u64| {
+            Bits::from(data)
+                .concat(load.into())
+                .concat(Bits::new(addr, 6))
+                .concat(Bits::new(addr_b, 6))
+        };
+
+        let mut ram = DualPortRAM64::new();
+        ram.set_state(input(0xAAAA, true, 42, 9));
+        ram.tick();
+        ram.set_state(input(0xBBBB, true, 9, 42));
+        // port B reads 42 while port A writes 9
+        assert_eq!(outs(&ram), (0, 0xAAAA));
+        ram.tick();
+        assert_eq!(outs(&ram), (0xBBBB, 0xAAAA));
+
+        // read during write on port B sees the old value until the tick
+        ram.set_state(input(0xCCCC, true, 42, 42));
+        assert_eq!(outs(&ram), (0xAAAA, 0xAAAA));
+        ram.tick();
+        assert_eq!(outs(&ram), (0xCCCC, 0xCCCC));
+        assert_eq!(ram.peek(9), 0xBBBB);
+    }
+}
@@ -0,0 +1,313 @@
+use std::fmt::Write as _;
+use std::ops::Range;
+
+use crate::asm::SymbolTable;
+use crate::builtins::Accelerator;
+use crate::computer::Computer;
+use crate::keyboard::{KeyEvent, Keyboard};
+use crate::mem::ROM32K;
+use crate::screen::Framebuffer;
+use crate::sequential::Clock;
+
+/**
+ * Runs a program headless for a fixed cycle budget, stopping early at the
+ * `@x / 0;JMP` halt idiom, and reports the final machine state.
+ */
+#[derive(Debug, Clone)]
+pub struct Runner {
+    max_cycles: u64,
+    stop_at_halt: bool,
+    dumps: Vec<Range<u16>>,
+    keys: Vec<KeyEvent>,
+    accelerator: Option<Accelerator>,
+}
+
+impl Runner {
+    pub fn new() -> Self {
+        Runner {
+            max_cycles: 1_000_000,
+            stop_at_halt: true,
+            dumps: Vec::new(),
+            keys: Vec::new(),
+            accelerator: None,
+        }
+    }
+
+    pub fn max_cycles(mut self, cycles: u64) -> Self {
+        self.max_cycles = cycles;
+        self
+    }
+
+    pub fn stop_at_halt(mut self, stop: bool) -> Self {
+        self.stop_at_halt = stop;
+        self
+    }
+
+    // RAM words to include in the report
+    pub fn dump(mut self, range: Range<u16>) -> Self {
+        self.dumps.push(range);
+        self
+    }
+
+    pub fn keys(mut self, keys: impl IntoIterator<Item = KeyEvent>) -> Self {
+        self.keys.extend(keys);
+        self
+    }
+
+    // runs the builtins of the bundled OS the program's symbols locate natively
+    pub fn accelerate(mut self, program: &[u16], symbols: &SymbolTable) -> Self {
+        self.accelerator = Some(Accelerator::new(program, symbols));
+        self
+    }
+
+    pub fn run(&self, program: &[u16]) -> RunReport {
+        let mut clock = self.machine(program);
+        self.run_on(&mut clock)
+    }
+
+    // a clock with the computer as its only component, as run_on expects
+    pub fn machine(&self, program: &[u16]) -> Clock {
+        let keyboard = Keyboard::scripted(self.keys.iter().copied());
+        let mut clock = Clock::new();
+        clock.register(Box::new(Computer::with_keyboard(program, keyboard)));
+        clock
+    }
+
+    // continues a machine, e.g. one restored from a snapshot, for max_cycles more
+    pub fn run_on(&self, clock: &mut Clock) -> RunReport {
+        let start = clock.cycles();
+        let mut halted = false;
+        while clock.cycles() - start < self.max_cycles {
+            let computer = clock.component::<Computer>(0).unwrap();
+            if self.stop_at_halt && is_halt(computer.rom(), computer.cpu().pc()) {
+                halted = true;
+                break;
+            }
+            if let Some(accelerator) = &self.accelerator {
+                let computer = clock.component_mut::<Computer>(0).unwrap();
+                if accelerator.intercept(computer) {
+                    continue;
+                }
+            }
+            clock.tick();
+        }
+
+        let computer = clock.component::<Computer>(0).unwrap();
+        let cpu = computer.cpu();
+        let ram = self
+            .dumps
+            .iter()
+            .map(|range| {
+                let words = range.clone().map(|addr| computer.ram(addr)).collect();
+                (range.start, words)
+            })
+            .collect();
+
+        RunReport {
+            cycles: clock.cycles(),
+            halted,
+            a: cpu.a(),
+            d: cpu.d(),
+            pc: cpu.pc(),
+            ram,
+            screen: computer.memory().screen().framebuffer(),
+        }
+    }
+}
+
+impl Default for Runner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/**
+ * Whether the instruction at pc starts an `@pc / <comp>;JMP` loop,
+ * the usual way a Hack program ends.
+ */
+pub fn is_halt(rom: &ROM32K, pc: u16) -> bool {
+    let pc = pc as usize;
+    if pc + 1 >= ROM32K::SIZE {
+        return false;
+    }
+
+    let (a_instruction, jump) = (rom.peek(pc), rom.peek(pc + 1));
+    let is_c = jump & 0xE000 == 0xE000;
+    a_instruction as usize == pc && is_c && jump & 0b111 == 0b111
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunReport {
+    pub cycles: u64, // since the machine was started
+    pub halted: bool,
+    pub a: u16,
+    pub d: u16,
+    pub pc: u16,
+    pub ram: Vec<(u16, Vec<u16>)>, // start address and the words from there
+    pub screen: Framebuffer,
+}
+
+impl RunReport {
+    // 64-bit FNV-1a over the screen words, little endian
+    pub fn screen_hash(&self) -> u64 {
+        self.screen
+            .words()
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3)
+            })
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        let _ = writeln!(json, "{{");
+        let _ = writeln!(json, "  \"cycles\": {},", self.cycles);
+        let _ = writeln!(json, "  \"halted\": {},", self.halted);
+        let _ = writeln!(json, "  \"a\": {},", self.a);
+        let _ = writeln!(json, "  \"d\": {},", self.d);
+        let _ = writeln!(json, "  \"pc\": {},", self.pc);
+
+        let ranges: Vec<String> = self
+            .ram
+            .iter()
+            .map(|(start, words)| {
+                let words: Vec<String> = words.iter().map(u16::to_string).collect();
+                format!(
+                    "    {{ \"start\": {}, \"words\": [{}] }}",
+                    start,
+                    words.join(", ")
+                )
+            })
+            .collect();
+        if ranges.is_empty() {
+            let _ = writeln!(json, "  \"ram\": [],");
+        } else {
+            let _ = writeln!(json, "  \"ram\": [\n{}\n  ],", ranges.join(",\n"));
+        }
+
+        // as a string, JSON numbers lose precision past 2^53
+        let _ = writeln!(json, "  \"screen_hash\": \"{:016x}\"", self.screen_hash());
+        json.push('}');
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::parse_hack;
+
+    // Max.hack: RAM[2] = max(RAM[0], RAM[1]), with RAM[0..2] set up first
+    const MAX: &str = "
+        0000000000000111
+        1110110000010000
+        0000000000000000
+        1110001100001000
+        0000000000001001
+        1110110000010000
+        0000000000000001
+        1110001100001000
+        0000000000000000
+        1111110000010000
+        0000000000000001
+        1111010011010000
+        0000000000010100
+        1110001100000001
+        0000000000000001
+        1111110000010000
+        0000000000000010
+        1110001100001000
+        0000000000011000
+        1110101010000111
+        0000000000000000
+        1111110000010000
+        0000000000000010
+        1110001100001000
+        0000000000011000
+        1110101010000111
+    ";
+
+    #[test]
+    fn test_run_until_halt() {
+        let report = Runner::new().dump(0..3).run(&parse_hack(MAX).unwrap());
+
+        assert!(report.halted);
+        assert_eq!(report.pc, 24);
+        assert_eq!(report.ram, [(0, vec![7, 9, 9])]);
+        assert_eq!(report.cycles, 20);
+    }
+
+    #[test]
+    fn test_run_exact_cycles() {
+        let program = parse_hack(MAX).unwrap();
+        let report = Runner::new().max_cycles(4).run(&program);
+        assert!(!report.halted);
+        assert_eq!(report.cycles, 4);
+        assert_eq!((report.a, report.d, report.pc), (0, 7, 4));
+
+        // without halt detection the budget is always used up
+        let report = Runner::new()
+            .stop_at_halt(false)
+            .max_cycles(50)
+            .run(&program);
+        assert!(!report.halted);
+        assert_eq!((report.cycles, report.pc), (50, 24));
+    }
+
+    #[test]
+    fn test_halt_idiom() {
+        let mut rom = ROM32K::new();
+        // @2, 0;JMP at 2, D;JMP at 4 but @5 is not its own address
+        rom.load(&[0, 0, 2, 0xEA87, 5, 0xE307]);
+        assert!(is_halt(&rom, 2));
+        assert!(!is_halt(&rom, 3));
+        assert!(!is_halt(&rom, 4));
+        assert!(!is_halt(&rom, 0x7FFF));
+    }
+
+    #[test]
+    fn test_json_report() {
+        let report = Runner::new()
+            .dump(0..3)
+            .dump(0x4000..0x4001)
+            .run(&parse_hack(MAX).unwrap());
+
+        let json = report.to_json();
+        assert!(json.starts_with("{\n  \"cycles\": 20,\n  \"halted\": true,\n"));
+        assert!(json.contains("  \"a\": 24,\n  \"d\": 9,\n  \"pc\": 24,\n"));
+        assert!(json.contains(
+            "  \"ram\": [\n    { \"start\": 0, \"words\": [7, 9, 9] },\n    { \"start\": 16384, \"words\": [0] }\n  ],\n"
+        ));
+        // hash of a blank screen
+        assert!(json.ends_with("  \"screen_hash\": \"9c1bda7f8c872325\"\n}"));
+    }
+
+    #[test]
+    fn test_screen_hash_changes() {
+        // @SCREEN, M=1, then halt
+        let program = [0x4000, 0xEFC8, 2, 0xEA87];
+        let report = Runner::new().run(&program);
+        assert!(report.halted);
+        assert!(report.screen.pixel(0, 0));
+
+        let blank = Runner::new().run(&[0, 0xEA87]);
+        assert_ne!(report.screen_hash(), blank.screen_hash());
+    }
+
+    #[test]
+    fn test_run_from_snapshot() {
+        let program = parse_hack(MAX).unwrap();
+        let runner = Runner::new().dump(0..3);
+
+        let mut clock = runner.machine(&program);
+        let partial = Runner::new().max_cycles(9).run_on(&mut clock);
+        assert_eq!(partial.cycles, 9);
+        let snapshot = clock.snapshot();
+
+        // a fresh machine for an empty program, overwritten by the snapshot
+        let mut restored = runner.machine(&[]);
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(runner.run_on(&mut restored), runner.run(&program));
+    }
+}
@@ -0,0 +1,421 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::gates::{dmux_gate, mux16_gate};
+use crate::sequential::{Addressable, Tick, RAM4K};
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::utils::Bits;
+
+pub const SCREEN_ADDRESS: u16 = 0x4000;
+
+pub const WIDTH: usize = 512;
+pub const HEIGHT: usize = 256;
+pub const WORDS: usize = WIDTH * HEIGHT / 16;
+
+/**
+ * Screen memory map: 8K words backing a 512 x 256 black and white display.
+ * Pixel (x, y) is bit x % 16 of word y * 32 + x / 16, the least significant
+ * bit being the leftmost pixel; 1 is black.
+ * Same pins as the RAM chips: in[16], load, address[13], out[16].
+ */
+pub struct Screen {
+    halves: [RAM4K; 2],
+    high: bool, // address bit 12
+}
+
+impl Screen {
+    pub fn new() -> Self {
+        Screen {
+            halves: [RAM4K::new(), RAM4K::new()],
+            high: false,
+        }
+    }
+
+    pub fn framebuffer(&self) -> Framebuffer {
+        let words = (0..WORDS).map(|addr| self.peek(addr)).collect();
+        Framebuffer { words }
+    }
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Addressable for Screen {
+    const ADDR_BITS: usize = 13;
+
+    fn peek(&self, addr: usize) -> u16 {
+        let half = addr >> RAM4K::ADDR_BITS;
+        self.halves[half].peek(addr & (WORDS / 2 - 1))
+    }
+
+    fn poke(&mut self, addr: usize, value: u16) {
+        let half = addr >> RAM4K::ADDR_BITS;
+        self.halves[half].poke(addr & (WORDS / 2 - 1), value);
+    }
+}
+
+// as with RAMBank, the half that is not addressed gets load = 0 and holds
+impl Tick for Screen {
+    fn tick(&mut self) {
+        self.halves[self.high as usize].tick();
+    }
+
+    fn get_state(&self) -> Bits {
+        let out = self.halves[self.high as usize]
+            .get_state()
+            .to_u16()
+            .unwrap();
+        let (low, high) = if self.high { (0, out) } else { (out, 0) };
+        mux16_gate(low, high, self.high).into()
+    }
+
+    fn set_state(&mut self, inputs: Bits) {
+        assert_eq!(inputs.len(), 16 + 1 + 13);
+
+        let data = inputs.slice_msb(0..16);
+        let load = inputs.msb(16);
+        let addr = inputs.slice_msb(17..30);
+
+        self.high = addr.msb(0);
+        let (load_low, load_high) = dmux_gate(load, self.high);
+        let load = if self.high { load_high } else { load_low };
+
+        let input = data.concat(load.into()).concat(addr.slice_msb(1..13));
+        self.halves[self.high as usize].set_state(input);
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        for half in &self.halves {
+            half.save(out);
+        }
+        out.bool(self.high);
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        for half in self.halves.iter_mut() {
+            half.restore(input)?;
+        }
+        self.high = input.bool()?;
+        Ok(())
+    }
+
+    fn kind(&self) -> &'static str {
+        "Screen"
+    }
+}
+
+/**
+ * Snapshot of the screen contents, in the screen's memory layout.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+    words: Vec<u16>,
+}
+
+impl Framebuffer {
+    pub fn new() -> Self {
+        Framebuffer {
+            words: vec![0; WORDS],
+        }
+    }
+
+    pub fn from_words(words: &[u16]) -> Self {
+        assert_eq!(words.len(), WORDS, "a framebuffer is {} words", WORDS);
+        Framebuffer {
+            words: words.to_vec(),
+        }
+    }
+
+    pub fn words(&self) -> &[u16] {
+        &self.words
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        assert!(
+            x < WIDTH && y < HEIGHT,
+            "pixel ({}, {}) is off screen",
+            x,
+            y
+        );
+        self.words[y * WIDTH / 16 + x / 16] & (1 << (x % 16)) != 0
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, black: bool) {
+        assert!(
+            x < WIDTH && y < HEIGHT,
+            "pixel ({}, {}) is off screen",
+            x,
+            y
+        );
+        let word = &mut self.words[y * WIDTH / 16 + x / 16];
+        if black {
+            *word |= 1 << (x % 16);
+        } else {
+            *word &= !(1 << (x % 16));
+        }
+    }
+
+    // one byte per 8 pixels, leftmost pixel in the most significant bit
+    fn packed_row(&self, y: usize, black: bool) -> impl Iterator<Item = u8> + '_ {
+        (0..WIDTH / 8).map(move |byte| {
+            (0..8).fold(0u8, |acc, i| {
+                let bit = self.pixel(byte * 8 + i, y) == black;
+                (acc << 1) | bit as u8
+            })
+        })
+    }
+
+    // binary PBM (P4), where 1 is black like on the Hack screen
+    pub fn to_pbm(&self) -> Vec<u8> {
+        let mut res = format!("P4\n{} {}\n", WIDTH, HEIGHT).into_bytes();
+        for y in 0..HEIGHT {
+            res.extend(self.packed_row(y, true));
+        }
+        res
+    }
+
+    // 1-bit grayscale PNG, stored without compression
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut ihdr = Vec::new();
+        ihdr.extend((WIDTH as u32).to_be_bytes());
+        ihdr.extend((HEIGHT as u32).to_be_bytes());
+        // bit depth 1, grayscale, deflate, no filtering, no interlace
+        ihdr.extend([1, 0, 0, 0, 0]);
+
+        // in grayscale 1 is white, so the bits are inverted
+        let mut scanlines = Vec::with_capacity(HEIGHT * (WIDTH / 8 + 1));
+        for y in 0..HEIGHT {
+            scanlines.push(0); // filter type: none
+            scanlines.extend(self.packed_row(y, false));
+        }
+
+        let mut png = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+        png_chunk(&mut png, b"IHDR", &ihdr);
+        png_chunk(&mut png, b"IDAT", &zlib_stored(&scanlines));
+        png_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    // two by two pixels per character, drawn with Unicode quadrant blocks
+    pub fn to_terminal(&self) -> String {
+        const QUADRANTS: [char; 16] = [
+            ' ', '▘', '▝', '▀', '▖', '▌', '▞', '▛', '▗', '▚', '▐', '▜', '▄', '▙', '▟', '█',
+        ];
+
+        let mut res = String::with_capacity(HEIGHT / 2 * (WIDTH / 2 * 3 + 1));
+        for y in (0..HEIGHT).step_by(2) {
+            for x in (0..WIDTH).step_by(2) {
+                let index = self.pixel(x, y) as usize
+                    | (self.pixel(x + 1, y) as usize) << 1
+                    | (self.pixel(x, y + 1) as usize) << 2
+                    | (self.pixel(x + 1, y + 1) as usize) << 3;
+                res.push(QUADRANTS[index]);
+            }
+            res.push('\n');
+        }
+        res
+    }
+
+    // writes a .pbm or .png file, picked by the extension of path
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let extension = path.extension().and_then(|e| e.to_str());
+        let bytes = match extension.map(|e| e.to_ascii_lowercase()).as_deref() {
+            Some("pbm") => self.to_pbm(),
+            Some("png") => self.to_png(),
+            _ => {
+                let msg = format!("{}: expected a .pbm or .png file", path.display());
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        };
+        fs::write(path, bytes)
+    }
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend(kind);
+    png.extend(data);
+    let crc = crc32(&png[start..]);
+    png.extend(crc.to_be_bytes());
+}
+
+// zlib stream made of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut res = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        res.extend([1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        res.push(last as u8);
+        res.extend(len.to_le_bytes());
+        res.extend((!len).to_le_bytes());
+        res.extend(block);
+    }
+    res.extend(adler32(data).to_be_bytes());
+    res
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen_input(data: u16, load: bool, addr: u64) -> Bits {
+        Bits::from(data)
+            .concat(load.into())
+            .concat(Bits::new(addr, 13))
+    }
+
+    #[test]
+    fn test_screen() {
+        let mut screen = Screen::new();
+
+        for (addr, data) in [
+            (0, 0x0001u16),
+            (4095, 0x8000),
+            (4096, 0xF0F0),
+            (8191, 0xFFFF),
+        ] {
+            screen.set_state(screen_input(data, true, addr));
+            screen.tick();
+            assert_eq!(screen.get_state().to_u16(), Ok(data));
+        }
+
+        screen.set_state(screen_input(0x1234, false, 4096));
+        screen.tick();
+        assert_eq!(screen.get_state().to_u16(), Ok(0xF0F0));
+
+        // the two halves do not alias
+        assert_eq!(screen.peek(0), 0x0001);
+        assert_eq!(screen.peek(4096), 0xF0F0);
+        assert_eq!(screen.peek(1), 0);
+    }
+
+    #[test]
+    fn test_framebuffer_layout() {
+        let mut screen = Screen::new();
+        // top left pixel, and the rightmost pixel of the last row
+        screen.set_state(screen_input(0x0001, true, 0));
+        screen.tick();
+        screen.set_state(screen_input(0x8000, true, 8191));
+        screen.tick();
+
+        let fb = screen.framebuffer();
+        assert!(fb.pixel(0, 0));
+        assert!(!fb.pixel(1, 0));
+        assert!(fb.pixel(511, 255));
+        assert!(!fb.pixel(510, 255));
+
+        let mut fb = Framebuffer::new();
+        fb.set_pixel(17, 1, true);
+        assert_eq!(fb.words()[32 + 1], 0b10);
+        fb.set_pixel(17, 1, false);
+        assert_eq!(fb, Framebuffer::new());
+    }
+
+    #[test]
+    fn test_pbm() {
+        let mut fb = Framebuffer::new();
+        fb.set_pixel(0, 0, true);
+        fb.set_pixel(9, 1, true);
+
+        let pbm = fb.to_pbm();
+        let header = b"P4\n512 256\n";
+        assert_eq!(&pbm[..header.len()], header);
+
+        let pixels = &pbm[header.len()..];
+        assert_eq!(pixels.len(), WIDTH * HEIGHT / 8);
+        assert_eq!(pixels[0], 0b1000_0000);
+        assert_eq!(pixels[64 + 1], 0b0100_0000);
+        assert_eq!(pixels.iter().filter(|&&b| b != 0).count(), 2);
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn test_png() {
+        let mut fb = Framebuffer::new();
+        fb.set_pixel(0, 0, true);
+
+        let png = fb.to_png();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..29], &[0, 0, 2, 0, 0, 0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xAE\x42\x60\x82");
+
+        // one stored deflate block holding every scanline
+        let idat_len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let zlib = &png[41..41 + idat_len];
+        let raw_len = HEIGHT * (WIDTH / 8 + 1);
+        assert_eq!(zlib[2], 1);
+        assert_eq!(u16::from_le_bytes([zlib[3], zlib[4]]) as usize, raw_len);
+
+        let raw = &zlib[7..7 + raw_len];
+        assert_eq!(&raw[..3], &[0, 0b0111_1111, 0xFF]);
+        assert!(raw[65..].iter().skip(1).take(64).all(|&b| b == 0xFF));
+        assert_eq!(zlib[7 + raw_len..], adler32(raw).to_be_bytes());
+    }
+
+    #[test]
+    fn test_terminal() {
+        let mut fb = Framebuffer::new();
+        fb.set_pixel(0, 0, true);
+        fb.set_pixel(3, 0, true);
+        fb.set_pixel(3, 1, true);
+        fb.set_pixel(5, 3, true);
+
+        let text = fb.to_terminal();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), HEIGHT / 2);
+        assert!(lines.iter().all(|l| l.chars().count() == WIDTH / 2));
+        assert!(lines[0].starts_with("▘▐ "));
+        assert!(lines[1].starts_with("  ▗ "));
+    }
+
+    #[test]
+    fn test_save_rejects_unknown_format() {
+        let err = Framebuffer::new().save("screen.bmp").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
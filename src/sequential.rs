@@ -1,75 +1,115 @@
 use core::panic;
-use std::env;
-use std::thread;
-use std::time::Duration;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::Path;
 
-use crate::gates::{dmux8way_gate, mux, mux8way16_gate};
-use crate::utils::{
-    u16_to_vec_bool, u8_to_vec_bool, vec_bool_to_u16, vec_bool_to_u32, vec_bool_to_u8,
-};
+use crate::gates::{dmux4way_gate, dmux8way_gate, mux, mux4way16_gate, mux8way16_gate};
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::utils::Bits;
+
+const STATE_MAGIC: &[u8; 4] = b"RCPU";
+const STATE_VERSION: u8 = 2;
 
 pub struct Clock {
     is_tick: bool,
+    cycles: u64,
     components: Vec<Box<dyn Tick>>,
+    history: VecDeque<Vec<u8>>, // undo records of the last cycles, oldest first
+    history_limit: usize,
 }
 
 impl Clock {
     pub fn new() -> Self {
         Clock {
             is_tick: false,
+            cycles: 0,
             components: Vec::new(),
+            history: VecDeque::new(),
+            history_limit: 0,
         }
     }
 
-    pub fn start_clock(&self) {
-        let mut i = false;
-        loop {
-            if i {
-                println!("Tick");
-            } else {
-                println!("Tock");
+    pub fn tick(&mut self) {
+        if self.history_limit == 0 {
+            for component in &mut self.components {
+                component.tick();
+            }
+        } else {
+            let mut record = StateWriter::new();
+            record.bool(self.is_tick);
+            for component in &mut self.components {
+                let mut block = StateWriter::new();
+                component.tick_recorded(&mut block);
+                record.bytes(&block.finish());
             }
 
-            let skip_sleep = env::var("SKIP_CLOCK_SLEEP").unwrap_or_else(|_| "false".to_string());
-            let sleep_duration = if skip_sleep == "true" {
-                Duration::from_secs(3)
-            } else {
-                Duration::from_secs(0)
-            };
-            thread::sleep(sleep_duration);
-
-            i = !i;
+            if self.history.len() == self.history_limit {
+                self.history.pop_front();
+            }
+            self.history.push_back(record.finish());
         }
+
+        self.is_tick = !self.is_tick;
+        self.cycles += 1;
     }
 
-    // temporary func to manually tick
-    pub fn tick(&mut self) {
-        if self.is_tick {
-            println!("Tick");
-        } else {
-            println!("Tock");
+    /**
+     * Keeps what is needed to undo the last `cycles` ticks, in a ring
+     * buffer that drops the oldest cycle when full. 0 turns it off.
+     */
+    pub fn record_history(&mut self, cycles: usize) {
+        self.history_limit = cycles;
+        while self.history.len() > cycles {
+            self.history.pop_front();
         }
+    }
 
-        let skip_sleep = env::var("SKIP_CLOCK_SLEEP").unwrap_or_else(|_| "false".to_string());
-        let sleep_duration = if skip_sleep == "true" {
-            Duration::from_secs(1)
-        } else {
-            Duration::from_secs(0)
-        };
-        thread::sleep(sleep_duration);
+    // how many cycles step_back can undo
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /**
+     * Undoes the last n ticks, newest first, as far as the history goes.
+     * Returns how many were undone.
+     */
+    pub fn step_back(&mut self, n: usize) -> usize {
+        for undone in 0..n {
+            let Some(record) = self.history.pop_back() else {
+                return undone;
+            };
+
+            let mut input = StateReader::new(&record);
+            // the records were written by these components, they always fit
+            let is_tick = input.bool().expect("undo record");
+            let blocks: Vec<&[u8]> = self
+                .components
+                .iter()
+                .map(|_| input.bytes().expect("undo record"))
+                .collect();
+            for (component, block) in self.components.iter_mut().zip(blocks).rev() {
+                component
+                    .undo(&mut StateReader::new(block))
+                    .expect("undo record");
+            }
 
-        for component in &mut self.components {
-            component.tick();
+            self.is_tick = is_tick;
+            self.cycles -= 1;
         }
+        n
+    }
 
-        self.is_tick = !self.is_tick;
+    pub fn cycles(&self) -> u64 {
+        self.cycles
     }
 
     pub fn register(&mut self, component: Box<dyn Tick>) {
         self.components.push(component);
     }
 
-    pub fn get_state_of_component(&self, index: usize) -> Vec<bool> {
+    pub fn get_state_of_component(&self, index: usize) -> Bits {
         if let Some(val) = self
             .components
             .get(index)
@@ -81,26 +121,185 @@ impl Clock {
         }
     }
 
-    pub fn set_state_of_component(&mut self, index: usize, inputs: Vec<bool>) {
+    pub fn set_state_of_component(&mut self, index: usize, inputs: Bits) {
         if let Some(component) = self.components.get_mut(index) {
             component.set_state(inputs);
         } else {
             panic!("Invalid index passed");
         }
     }
+
+    /**
+     * Everything the clock and its components hold: the phase, the cycle
+     * count and each component's registers, memory and latched inputs.
+     */
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut out = StateWriter::new();
+        for &byte in STATE_MAGIC {
+            out.u8(byte);
+        }
+        out.u8(STATE_VERSION);
+        out.bool(self.is_tick);
+        out.u64(self.cycles);
+        out.u64(self.components.len() as u64);
+
+        // each component in its own block, so a mismatch is caught early
+        for component in &self.components {
+            out.bytes(component.kind().as_bytes());
+            let mut block = StateWriter::new();
+            component.save(&mut block);
+            out.bytes(&block.finish());
+        }
+        out.finish()
+    }
+
+    /**
+     * The registered components must be of the same kinds as when saved.
+     * The whole snapshot is checked before anything changes, and a
+     * component that rejects its block rolls the others back, so a failed
+     * restore leaves the machine as it was.
+     */
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), StateError> {
+        let mut input = StateReader::new(snapshot);
+        for &byte in STATE_MAGIC {
+            if input.u8()? != byte {
+                return Err(StateError::BadMagic);
+            }
+        }
+        match input.u8()? {
+            STATE_VERSION => {}
+            version => return Err(StateError::UnsupportedVersion(version)),
+        }
+
+        let is_tick = input.bool()?;
+        let cycles = input.u64()?;
+        let found = input.u64()? as usize;
+        if found != self.components.len() {
+            return Err(StateError::ComponentCount {
+                expected: self.components.len(),
+                found,
+            });
+        }
+
+        let mut blocks = Vec::with_capacity(found);
+        for (index, component) in self.components.iter().enumerate() {
+            let kind = String::from_utf8_lossy(input.bytes()?);
+            if kind != component.kind() {
+                return Err(StateError::ComponentKind {
+                    index,
+                    expected: component.kind().to_string(),
+                    found: kind.into_owned(),
+                });
+            }
+            blocks.push(input.bytes()?);
+        }
+
+        let mut saved = Vec::with_capacity(found);
+        for component in &self.components {
+            let mut block = StateWriter::new();
+            component.save(&mut block);
+            saved.push(block.finish());
+        }
+        for (index, block) in blocks.into_iter().enumerate() {
+            let mut block = StateReader::new(block);
+            let restored = self.components[index].restore(&mut block);
+            let result = match restored {
+                Ok(()) if !block.is_at_end() => Err(StateError::ComponentSize { index }),
+                result => result,
+            };
+            if let Err(e) = result {
+                // what a component saved it can always restore
+                for (component, state) in self.components.iter_mut().zip(&saved) {
+                    component
+                        .restore(&mut StateReader::new(state))
+                        .expect("restoring the previous state");
+                }
+                return Err(e);
+            }
+        }
+
+        // the recorded cycles led to another state
+        self.history.clear();
+        self.is_tick = is_tick;
+        self.cycles = cycles;
+        Ok(())
+    }
+
+    pub fn save_to(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.snapshot())
+    }
+
+    pub fn restore_from(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let snapshot = fs::read(path)?;
+        self.restore(&snapshot)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // the component at index, if it is a T
+    pub fn component<T: Tick>(&self, index: usize) -> Option<&T> {
+        let component: &dyn Any = self.components.get(index)?.as_ref();
+        component.downcast_ref()
+    }
+
+    pub fn component_mut<T: Tick>(&mut self, index: usize) -> Option<&mut T> {
+        let component: &mut dyn Any = self.components.get_mut(index)?.as_mut();
+        component.downcast_mut()
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct DFF {
     state: bool,
     next_state: bool, // updated on assignment but state gets updated on next tick only
 }
 
-pub trait Tick {
+/**
+ * A clocked chip. Inputs and outputs are bit vectors laid out most
+ * significant bit first, in the order the chip's pins are listed.
+ */
+pub trait Tick: Any {
     fn tick(&mut self);
-    fn get_state(&self) -> Vec<bool>;
-    //fn set_state(&mut self, state: bool);
-    fn set_state(&mut self, inputs: Vec<bool>); // TODO pass u64 and convert to Vec<bool> of size
-                                                // required!
+    fn get_state(&self) -> Bits;
+    fn set_state(&mut self, inputs: Bits);
+
+    // everything the component holds, including latched inputs
+    fn save(&self, out: &mut StateWriter);
+    fn restore(&mut self, input: &mut StateReader) -> Result<(), StateError>;
+
+    /**
+     * tick, writing what undo needs to take it back. By default that is
+     * the whole saved state, chips with a lot of memory record less.
+     */
+    fn tick_recorded(&mut self, undo: &mut StateWriter) {
+        self.save(undo);
+        self.tick();
+    }
+
+    fn undo(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.restore(input)
+    }
+
+    // recorded with the saved state, so it is not restored into another
+    // chip; part of the file format, so it must not change
+    fn kind(&self) -> &'static str;
+}
+
+// a select latch of a memory chip, which must address one of its parts
+fn restore_select(input: &mut StateReader, parts: u8) -> Result<u8, StateError> {
+    let addr = input.u8()?;
+    if addr >= parts {
+        return Err(StateError::Invalid(format!(
+            "address {} of {}",
+            addr, parts
+        )));
+    }
+    Ok(addr)
 }
 
 impl DFF {
@@ -114,6 +313,17 @@ impl DFF {
     pub fn update_state(&mut self) {
         self.state = self.next_state;
     }
+
+    // backdoor: overwrites the output, the latched input stays as it is
+    pub fn poke(&mut self, value: bool) {
+        self.state = value;
+    }
+}
+
+impl Default for DFF {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Tick for DFF {
@@ -121,13 +331,28 @@ impl Tick for DFF {
         self.update_state();
     }
 
-    fn get_state(&self) -> Vec<bool> {
-        vec![self.state]
+    fn get_state(&self) -> Bits {
+        self.state.into()
     }
 
-    fn set_state(&mut self, inputs: Vec<bool>) {
+    fn set_state(&mut self, inputs: Bits) {
         assert_eq!(inputs.len(), 1);
-        self.next_state = inputs[0];
+        self.next_state = inputs.msb(0);
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        out.bool(self.state);
+        out.bool(self.next_state);
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.state = input.bool()?;
+        self.next_state = input.bool()?;
+        Ok(())
+    }
+
+    fn kind(&self) -> &'static str {
+        "DFF"
     }
 }
 
@@ -137,7 +362,7 @@ impl Tick for DFF {
  * Otherwise, the register maintains its current value:
  * if (load(t)) out(t+1) = in(t), else out(t+1) = out(t)
  */
-struct Bit {
+pub struct Bit {
     dff: DFF,
     load: bool,
     state: bool,
@@ -151,6 +376,16 @@ impl Bit {
             state: false,
         }
     }
+
+    pub fn poke(&mut self, value: bool) {
+        self.dff.poke(value);
+    }
+}
+
+impl Default for Bit {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Tick for Bit {
@@ -159,74 +394,119 @@ impl Tick for Bit {
 
         assert_eq!(dff_out.len(), 1);
 
-        let out_mux = mux(dff_out[0], self.state, self.load);
-        self.dff.set_state(vec![out_mux]);
+        let out_mux = mux(dff_out.msb(0), self.state, self.load);
+        self.dff.set_state(out_mux.into());
         self.dff.tick();
     }
 
-    fn get_state(&self) -> Vec<bool> {
+    fn get_state(&self) -> Bits {
         let dff_out = self.dff.get_state();
         assert_eq!(dff_out.len(), 1);
         dff_out
     }
 
-    fn set_state(&mut self, inputs: Vec<bool>) {
+    fn set_state(&mut self, inputs: Bits) {
         assert_eq!(inputs.len(), 2);
-        self.state = inputs[0];
-        self.load = inputs[1];
+        self.state = inputs.msb(0);
+        self.load = inputs.msb(1);
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        self.dff.save(out);
+        out.bool(self.load);
+        out.bool(self.state);
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.dff.restore(input)?;
+        self.load = input.bool()?;
+        self.state = input.bool()?;
+        Ok(())
+    }
+
+    fn kind(&self) -> &'static str {
+        "Bit"
     }
 }
 
 /**
- * 16-bit register:
+ * N-bit register (16-bit unless stated otherwise):
  * If load is asserted, the register's value is set to in;
  * Otherwise, the register maintains its current value:
  * if (load(t)) out(t+1) = int(t), else out(t+1) = out(t)
  */
-struct Register {
-    bits: [Bit; 16],
+pub struct Register<const N: usize = 16> {
+    bits: [Bit; N],
 }
 
-impl Register {
+impl<const N: usize> Register<N> {
     pub fn new() -> Self {
-        let bits = [(); 16].map(|_| Bit::new());
+        let bits = [(); N].map(|_| Bit::new());
         Register { bits }
     }
+
+    // backdoor: sets the stored value without a load or a tick
+    pub fn poke(&mut self, value: Bits) {
+        assert_eq!(value.len(), N);
+        for (i, bit) in self.bits.iter_mut().enumerate() {
+            bit.poke(value.msb(i));
+        }
+    }
 }
 
-impl Tick for Register {
+impl<const N: usize> Default for Register<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Tick for Register<N> {
     fn tick(&mut self) {
-        for i in 0..16 {
-            self.bits[i].tick()
-        }
-    }
-
-    fn get_state(&self) -> Vec<bool> {
-        self.bits
-            .iter()
-            .map(|bit| {
-                let bit_out = bit.get_state();
-                assert_eq!(
-                    bit_out.len(),
-                    1,
-                    "bit_out should have exactly one element but has {}",
-                    bit_out.len()
-                );
-                bit_out[0]
-            })
-            .collect()
+        for bit in self.bits.iter_mut() {
+            bit.tick()
+        }
+    }
+
+    fn get_state(&self) -> Bits {
+        Bits::from_msb_iter(self.bits.iter().map(|bit| {
+            let bit_out = bit.get_state();
+            assert_eq!(
+                bit_out.len(),
+                1,
+                "bit_out should have exactly one element but has {}",
+                bit_out.len()
+            );
+            bit_out.msb(0)
+        }))
     }
 
-    fn set_state(&mut self, inputs: Vec<bool>) {
-        assert_eq!(inputs.len(), 16 + 1); // first 16-> 16bit input, next is the load bit
+    fn set_state(&mut self, inputs: Bits) {
+        assert_eq!(inputs.len(), N + 1); // first N -> N bit input, next is the load bit
 
-        let load = inputs[16];
+        let load = inputs.lsb(0);
 
-        // 0 - 15
-        for i in 0..16 {
-            self.bits[i].set_state(vec![inputs[i], load]);
+        // 0 - (N-1), bits[0] is the most significant
+        for (i, bit) in self.bits.iter_mut().enumerate() {
+            bit.set_state(Bits::from(inputs.msb(i)).concat(load.into()));
         }
     }
+
+    fn save(&self, out: &mut StateWriter) {
+        for bit in &self.bits {
+            bit.save(out);
+        }
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        for bit in self.bits.iter_mut() {
+            bit.restore(input)?;
+        }
+        Ok(())
+    }
+
+    fn kind(&self) -> &'static str {
+        "Register"
+    }
 }
 
 /**
@@ -235,7 +515,7 @@ impl Tick for Register {
  * address is set to in; Otherwise, the value does not change.
  * The value of the selected register is emitted by out.
  */
-struct RAM8 {
+pub struct RAM8 {
     registers: [Register; 8],
     addr: u8, // 3 bits
 }
@@ -247,155 +527,302 @@ impl RAM8 {
     }
 }
 
+impl Default for RAM8 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Tick for RAM8 {
     fn tick(&mut self) {
-        for i in 0..8 {
-            self.registers[i].tick();
+        for register in self.registers.iter_mut() {
+            register.tick();
         }
     }
 
-    fn get_state(&self) -> Vec<bool> {
-        println!("get_state: self.addr: {}", self.addr);
+    fn get_state(&self) -> Bits {
         let res = mux8way16_gate(
-            vec_bool_to_u16(self.registers[0].get_state()),
-            vec_bool_to_u16(self.registers[1].get_state()),
-            vec_bool_to_u16(self.registers[2].get_state()),
-            vec_bool_to_u16(self.registers[3].get_state()),
-            vec_bool_to_u16(self.registers[4].get_state()),
-            vec_bool_to_u16(self.registers[5].get_state()),
-            vec_bool_to_u16(self.registers[6].get_state()),
-            vec_bool_to_u16(self.registers[7].get_state()),
+            self.registers[0].get_state().to_u16().unwrap(),
+            self.registers[1].get_state().to_u16().unwrap(),
+            self.registers[2].get_state().to_u16().unwrap(),
+            self.registers[3].get_state().to_u16().unwrap(),
+            self.registers[4].get_state().to_u16().unwrap(),
+            self.registers[5].get_state().to_u16().unwrap(),
+            self.registers[6].get_state().to_u16().unwrap(),
+            self.registers[7].get_state().to_u16().unwrap(),
             self.addr,
         );
 
-        u16_to_vec_bool(res)
+        res.into()
     }
 
-    fn set_state(&mut self, inputs: Vec<bool>) {
+    fn set_state(&mut self, inputs: Bits) {
         // 16 + 1 + 3
         assert_eq!(inputs.len(), 16 + 1 + 3);
 
-        let load = inputs[16];
-        let mut addr: Vec<bool> = vec![false; 3];
+        let data = inputs.slice_msb(0..16);
+        let load = inputs.msb(16);
+        let addr = inputs.slice_msb(17..20);
+
+        self.addr = addr.to_u8().unwrap();
 
-        for i in 0..3 {
-            addr[i] = inputs[i + 17];
+        // register i gets bit 7 - i of the demultiplexed load
+        let dmux_out = Bits::from(dmux8way_gate(load, self.addr));
+
+        for (i, register) in self.registers.iter_mut().enumerate() {
+            register.set_state(data.concat(dmux_out.msb(i).into()));
         }
+    }
 
-        self.addr = vec_bool_to_u8(addr.clone());
+    fn save(&self, out: &mut StateWriter) {
+        for register in &self.registers {
+            register.save(out);
+        }
+        out.u8(self.addr);
+    }
 
-        println!(
-            "RAM8 -  load: {}, addr: {:#?}, self.addr: {:#?}",
-            load, addr, self.addr
-        );
+    fn restore(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        for register in self.registers.iter_mut() {
+            register.restore(input)?;
+        }
+        self.addr = restore_select(input, 8)?;
+        Ok(())
+    }
 
-        let dmux_out: Vec<bool> = u8_to_vec_bool(dmux8way_gate(load, vec_bool_to_u8(addr)));
+    fn kind(&self) -> &'static str {
+        "RAM8"
+    }
+}
 
-        /*
-         * Input is vector of bool. Here converting to u32
-         *  - Then right shift 4 and take the 16 LSB bits as input data
-         */
-        let input_bit: u32 = vec_bool_to_u32(inputs.clone()) >> 4;
-        let inp_bool = u16_to_vec_bool((input_bit & 0xFFFF) as u16);
+/**
+ * A memory chip with inputs in[16], load, address[ADDR_BITS]
+ * and a 16-bit output.
+ * peek and poke are a backdoor for debuggers and test setup: they go
+ * straight to the stored words, bypassing the clock, and leave the
+ * address latch and every latched input as they were.
+ */
+pub trait Addressable: Tick {
+    const ADDR_BITS: usize;
 
-        assert_eq!(inp_bool.len(), 16);
+    fn peek(&self, addr: usize) -> u16;
+    fn poke(&mut self, addr: usize, value: u16);
+}
 
-        for i in 0..8 {
-            let mut input = inp_bool.clone();
-            input.extend([dmux_out[i]]);
-            self.registers[i].set_state(input);
-        }
+impl Addressable for RAM8 {
+    const ADDR_BITS: usize = 3;
+
+    fn peek(&self, addr: usize) -> u16 {
+        self.registers[addr].get_state().to_u16().unwrap()
+    }
+
+    fn poke(&mut self, addr: usize, value: u16) {
+        self.registers[addr].poke(value.into());
     }
 }
 
 /**
- * Memory of sixty four 16-bit registers.
+ * Memory of eight smaller memory chips.
+ * The high 3 address bits pick the chip, the remaining bits are
+ * passed on as the address inside it.
  * If load is asserted, the value of the register selected by
  * address is set to in; Otherwise, the value does not change.
  * The value of the selected register is emitted by out.
  */
-struct RAM64 {
-    ram8s: [RAM8; 8],
-    addr: u8, // 6 bit size
+pub struct RAMBank<C> {
+    chips: Vec<C>,
+    addr: u8, // 3 bits
 }
 
-impl RAM64 {
+pub type RAM64 = RAMBank<RAM8>;
+pub type RAM512 = RAMBank<RAM64>;
+pub type RAM4K = RAMBank<RAM512>;
+
+impl<C: Addressable + Default> RAMBank<C> {
     pub fn new() -> Self {
-        let ram8s = [(); 8].map(|_| RAM8::new());
-        RAM64 { ram8s, addr: 0 }
+        let chips = (0..8).map(|_| C::default()).collect();
+        RAMBank { chips, addr: 0 }
+    }
+}
+
+impl<C: Addressable + Default> Default for RAMBank<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Addressable> Addressable for RAMBank<C> {
+    const ADDR_BITS: usize = C::ADDR_BITS + 3;
+
+    fn peek(&self, addr: usize) -> u16 {
+        let inner = addr & ((1 << C::ADDR_BITS) - 1);
+        self.chips[addr >> C::ADDR_BITS].peek(inner)
+    }
+
+    fn poke(&mut self, addr: usize, value: u16) {
+        let inner = addr & ((1 << C::ADDR_BITS) - 1);
+        self.chips[addr >> C::ADDR_BITS].poke(inner, value);
     }
 }
 
-impl Tick for RAM64 {
+/*
+ * The chips that are not addressed get load = 0 from the dmux and hold
+ * their value through the tick, and the mux never lets their output
+ * through. So only the addressed chip is driven, clocked and read; this
+ * keeps the big memories usable without changing their behaviour.
+ */
+impl<C: Addressable> Tick for RAMBank<C> {
     fn tick(&mut self) {
-        for i in 0..8 {
-            self.ram8s[i].tick();
-        }
+        self.chips[self.addr as usize].tick();
     }
 
-    fn get_state(&self) -> Vec<bool> {
-        println!("RAM64 get_state: self.addr: {}", self.addr);
+    fn get_state(&self) -> Bits {
+        let mut outs = [0u16; 8];
+        outs[self.addr as usize] = self.chips[self.addr as usize].get_state().to_u16().unwrap();
+
         let res = mux8way16_gate(
-            vec_bool_to_u16(self.ram8s[0].get_state()),
-            vec_bool_to_u16(self.ram8s[1].get_state()),
-            vec_bool_to_u16(self.ram8s[2].get_state()),
-            vec_bool_to_u16(self.ram8s[3].get_state()),
-            vec_bool_to_u16(self.ram8s[4].get_state()),
-            vec_bool_to_u16(self.ram8s[5].get_state()),
-            vec_bool_to_u16(self.ram8s[6].get_state()),
-            vec_bool_to_u16(self.ram8s[7].get_state()),
-            self.addr,
+            outs[0], outs[1], outs[2], outs[3], outs[4], outs[5], outs[6], outs[7], self.addr,
         );
 
-        u16_to_vec_bool(res)
+        res.into()
     }
 
-    fn set_state(&mut self, inputs: Vec<bool>) {
-        // 16 + 1 + 6
-        assert_eq!(inputs.len(), 16 + 1 + 6);
+    fn set_state(&mut self, inputs: Bits) {
+        let addr_bits = Self::ADDR_BITS;
+        assert_eq!(inputs.len(), 16 + 1 + addr_bits);
+
+        let data = inputs.slice_msb(0..16);
+        let load = inputs.msb(16);
+        let addr = inputs.slice_msb(17..17 + addr_bits);
+
+        // high 3 address bits pick the chip, the rest is the address inside it
+        self.addr = addr.slice_msb(0..3).to_u8().unwrap();
+        let inner_addr = addr.slice_msb(3..addr_bits);
 
-        let load = inputs[16];
-        let mut addr: Vec<bool> = vec![false; 6];
+        let dmux_out = Bits::from(dmux8way_gate(load, self.addr));
 
-        for i in 0..6 {
-            addr[i] = inputs[i + 17];
+        let i = self.addr as usize;
+        let input = data.concat(dmux_out.msb(i).into()).concat(inner_addr);
+        self.chips[i].set_state(input);
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        for chip in &self.chips {
+            chip.save(out);
         }
+        out.u8(self.addr);
+    }
 
-        self.addr = vec_bool_to_u8(addr[0..3].to_vec());
+    fn restore(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        for chip in self.chips.iter_mut() {
+            chip.restore(input)?;
+        }
+        self.addr = restore_select(input, 8)?;
+        Ok(())
+    }
 
-        println!(
-            "RAM64 -  load: {}, addr: {:#?}, self.addr: {:#?}",
-            load, addr, self.addr
-        );
+    fn kind(&self) -> &'static str {
+        match Self::ADDR_BITS {
+            6 => "RAM64",
+            9 => "RAM512",
+            12 => "RAM4K",
+            _ => "RAMBank",
+        }
+    }
+}
+
+/**
+ * Memory of 16K 16-bit registers, four RAM4K chips.
+ * Same pins as the other RAM chips with a 14-bit address, the high two
+ * bits picking the RAM4K.
+ */
+pub struct RAM16K {
+    chips: Vec<RAM4K>,
+    addr: u8, // 2 bits
+}
 
-        let dmux_out: Vec<bool> =
-            u8_to_vec_bool(dmux8way_gate(load, vec_bool_to_u8(addr[3..6].to_vec())));
+impl RAM16K {
+    pub fn new() -> Self {
+        let chips = (0..4).map(|_| RAM4K::new()).collect();
+        RAM16K { chips, addr: 0 }
+    }
+}
 
-        /*
-         * Input is vector of bool. Here converting to u32
-         *  - Then right shift 7 and take the 16 LSB bits as input data
-         */
-        let input_bit: u32 = vec_bool_to_u32(inputs.clone()) >> 7;
-        let inp_bool: Vec<bool> = u16_to_vec_bool((input_bit & 0xFFFF) as u16);
+impl Default for RAM16K {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Addressable for RAM16K {
+    const ADDR_BITS: usize = 14;
+
+    fn peek(&self, addr: usize) -> u16 {
+        self.chips[addr >> RAM4K::ADDR_BITS].peek(addr & 0xFFF)
+    }
+
+    fn poke(&mut self, addr: usize, value: u16) {
+        self.chips[addr >> RAM4K::ADDR_BITS].poke(addr & 0xFFF, value);
+    }
+}
+
+// only the addressed chip is driven, as in RAMBank
+impl Tick for RAM16K {
+    fn tick(&mut self) {
+        self.chips[self.addr as usize].tick();
+    }
 
-        assert_eq!(inp_bool.len(), 16);
+    fn get_state(&self) -> Bits {
+        let mut outs = [0u16; 4];
+        outs[self.addr as usize] = self.chips[self.addr as usize].get_state().to_u16().unwrap();
 
-        for i in 0..8 {
-            let mut input = inp_bool.clone();
-            input.extend([dmux_out[i]]);
-            input.extend(&addr[0..3]);
-            println!("RAM64 - For i: {}, setting input: {:#?}", i, input);
-            self.ram8s[i].set_state(input);
+        mux4way16_gate(outs[0], outs[1], outs[2], outs[3], self.addr).into()
+    }
+
+    fn set_state(&mut self, inputs: Bits) {
+        assert_eq!(inputs.len(), 16 + 1 + 14);
+
+        let data = inputs.slice_msb(0..16);
+        let load = inputs.msb(16);
+        let addr = inputs.slice_msb(17..31);
+
+        self.addr = addr.slice_msb(0..2).to_u8().unwrap();
+        let (a, b, c, d) = dmux4way_gate(load, self.addr);
+        let load = [a, b, c, d][self.addr as usize];
+
+        let input = data.concat(load.into()).concat(addr.slice_msb(2..14));
+        self.chips[self.addr as usize].set_state(input);
+    }
+
+    fn save(&self, out: &mut StateWriter) {
+        for chip in &self.chips {
+            chip.save(out);
         }
+        out.u8(self.addr);
+    }
+
+    fn restore(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        for chip in self.chips.iter_mut() {
+            chip.restore(input)?;
+        }
+        self.addr = restore_select(input, 4)?;
+        Ok(())
+    }
+
+    fn kind(&self) -> &'static str {
+        "RAM16K"
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::utils::u32_to_vec_bool;
-
     use super::*;
 
+    fn ram_input(data: u16, load: bool, addr: u64, addr_width: usize) -> Bits {
+        Bits::from(data)
+            .concat(load.into())
+            .concat(Bits::new(addr, addr_width))
+    }
+
     #[test]
     fn test_dff() {
         let mut clock = Clock::new();
@@ -403,25 +830,25 @@ mod tests {
         let dff1 = DFF::new();
 
         clock.register(Box::new(dff1));
-        clock.set_state_of_component(0, vec![true]);
+        clock.set_state_of_component(0, true.into());
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 1);
-        assert_eq!(out[0], false);
+        assert_eq!(out.msb(0), false);
 
         clock.tick();
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 1);
-        assert_eq!(out[0], true);
+        assert_eq!(out.msb(0), true);
 
-        clock.set_state_of_component(0, vec![false]);
+        clock.set_state_of_component(0, false.into());
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 1);
-        assert_eq!(out[0], true);
+        assert_eq!(out.msb(0), true);
 
         clock.tick();
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 1);
-        assert_eq!(out[0], false);
+        assert_eq!(out.msb(0), false);
     }
 
     #[test]
@@ -433,67 +860,85 @@ mod tests {
         clock.register(Box::new(bit1));
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 1);
-        assert_eq!(out[0], false);
+        assert_eq!(out.msb(0), false);
 
-        clock.set_state_of_component(0, vec![false, false]);
+        clock.set_state_of_component(0, Bits::parse_bin("00").unwrap());
 
         clock.tick();
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 1);
-        assert_eq!(out[0], false);
+        assert_eq!(out.msb(0), false);
 
-        clock.set_state_of_component(0, vec![true, false]);
+        clock.set_state_of_component(0, Bits::parse_bin("10").unwrap());
 
         clock.tick();
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 1);
-        assert_eq!(out[0], false);
+        assert_eq!(out.msb(0), false);
 
-        clock.set_state_of_component(0, vec![true, true]);
+        clock.set_state_of_component(0, Bits::parse_bin("11").unwrap());
 
         clock.tick();
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 1);
-        assert_eq!(out[0], true);
+        assert_eq!(out.msb(0), true);
 
-        clock.set_state_of_component(0, vec![false, false]);
+        clock.set_state_of_component(0, Bits::parse_bin("00").unwrap());
 
         clock.tick();
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 1);
-        assert_eq!(out[0], true);
+        assert_eq!(out.msb(0), true);
     }
 
     #[test]
     fn test_register() {
         let mut clock = Clock::new();
 
-        let reg1 = Register::new();
+        let reg1 = Register::<16>::new();
 
         clock.register(Box::new(reg1));
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 16);
-        assert_eq!(out, [false; 16]);
+        assert_eq!(out, Bits::zero(16));
 
         let inp_bool: u16 = 0b1000000010000000;
-        let mut inp = u16_to_vec_bool(inp_bool);
-        inp.extend([true]);
+        let inp = Bits::from(inp_bool).concat(true.into());
         clock.set_state_of_component(0, inp);
         clock.tick();
 
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 16);
-        assert_eq!(vec_bool_to_u16(out), inp_bool);
+        assert_eq!(out.to_u16(), Ok(inp_bool));
 
         let inp_bool2: u16 = 0b1000000010000001;
-        let mut inp2 = u16_to_vec_bool(inp_bool2);
-        inp2.extend([false]);
+        let inp2 = Bits::from(inp_bool2).concat(false.into());
         clock.set_state_of_component(0, inp2);
         clock.tick();
 
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 16);
-        assert_eq!(vec_bool_to_u16(out), inp_bool);
+        assert_eq!(out.to_u16(), Ok(inp_bool));
+    }
+
+    #[test]
+    fn test_register_n() {
+        let mut clock = Clock::new();
+
+        clock.register(Box::new(Register::<8>::new()));
+        clock.register(Box::new(Register::<32>::new()));
+        assert_eq!(clock.get_state_of_component(0).len(), 8);
+        assert_eq!(clock.get_state_of_component(1).len(), 32);
+
+        let inp = Bits::from(0b1010_0101u8).concat(true.into());
+        clock.set_state_of_component(0, inp);
+
+        let inp = Bits::from(0xCAFE_F00Du32).concat(true.into());
+        clock.set_state_of_component(1, inp);
+        clock.tick();
+
+        assert_eq!(clock.get_state_of_component(0).to_u8(), Ok(0b1010_0101));
+        assert_eq!(clock.get_state_of_component(1).to_u32(), Ok(0xCAFE_F00D));
     }
 
     #[test]
@@ -505,57 +950,38 @@ mod tests {
         clock.register(Box::new(ram8));
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 16);
-        assert_eq!(out, [false; 16]);
+        assert_eq!(out, Bits::zero(16));
 
         /*
-         * Test 1: Set and get register at addr 010
+         * Test 1: Set and get register at addr 101
          */
-        let input: u32 = 0b1000000011111111;
-        let load = 1;
-        let addr = 0b101;
-        let mut final_input: u32 = input;
-        final_input = (final_input << 1) | load;
-        final_input = (final_input << 3) | addr;
-
-        let inp = u32_to_vec_bool(final_input)[12..].to_vec();
-        clock.set_state_of_component(0, inp);
+        let input: u16 = 0b1000000011111111;
+        clock.set_state_of_component(0, ram_input(input, true, 0b101, 3));
         clock.tick();
 
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 16);
-        assert_eq!(vec_bool_to_u16(out), (input & 0xFFFF) as u16);
+        assert_eq!(out.to_u16(), Ok(input));
 
         /*
          * Test 2: Read it after another tick, load ==0 at same addr
          */
-        let input: u32 = 0;
-        let load = 0;
-        let addr = 0b101;
-        let final_input: u32 = (((input << 1) | load) << 3) | addr;
-
-        let inp = u32_to_vec_bool(final_input)[12..].to_vec();
-        clock.set_state_of_component(0, inp);
+        clock.set_state_of_component(0, ram_input(0, false, 0b101, 3));
         clock.tick();
 
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 16);
-        assert_eq!(vec_bool_to_u16(out), 0b1000000011111111);
+        assert_eq!(out.to_u16(), Ok(0b1000000011111111));
 
         /*
          * Test 3: Read a different addr
          */
-        let input: u32 = 0;
-        let load = 0;
-        let addr = 0b100;
-        let final_input: u32 = (((input << 1) | load) << 3) | addr;
-
-        let inp = u32_to_vec_bool(final_input)[12..].to_vec();
-        clock.set_state_of_component(0, inp);
+        clock.set_state_of_component(0, ram_input(0, false, 0b100, 3));
         clock.tick();
 
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 16);
-        assert_eq!(vec_bool_to_u16(out), 0);
+        assert_eq!(out.to_u16(), Ok(0));
     }
 
     #[test]
@@ -570,77 +996,274 @@ mod tests {
         clock.register(Box::new(ram64));
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 16);
-        assert_eq!(vec_bool_to_u16(out), 0);
+        assert_eq!(out.to_u16(), Ok(0));
 
         /*
-         * Test 1: Set and get register at addr 010
+         * Test 2: Set and get register at addr 000000
          */
-        let input: u32 = 1;
-        let load = 1;
-        let addr = 0;
-        let mut final_input: u32 = input;
-        final_input = (final_input << 1) | load;
-        final_input = (final_input << 6) | addr;
-
-        let inp = u32_to_vec_bool(final_input)[9..].to_vec();
-        clock.set_state_of_component(0, inp);
+        let input: u16 = 1;
+        clock.set_state_of_component(0, ram_input(input, true, 0, 6));
         clock.tick();
 
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 16);
-        assert_eq!(vec_bool_to_u16(out), (input & 0xFFFF) as u16);
+        assert_eq!(out.to_u16(), Ok(input));
 
         /*
-         * Test 2: Set and get register at addr 010
+         * Test 3: Set and get register at addr 101101
          */
-        let input_1: u32 = 0b1000000011111111;
-        let load_1 = 1;
-        let addr_1 = 0b101101;
-        let mut final_input_1: u32 = input_1;
-        final_input_1 = (final_input_1 << 1) | load_1;
-        final_input_1 = (final_input_1 << 6) | addr_1;
-
-        let inp = u32_to_vec_bool(final_input_1)[9..].to_vec();
-        clock.set_state_of_component(0, inp);
+        let input_1: u16 = 0b1000000011111111;
+        clock.set_state_of_component(0, ram_input(input_1, true, 0b101101, 6));
         clock.tick();
 
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 16);
-        assert_eq!(vec_bool_to_u16(out), (input_1 & 0xFFFF) as u16);
+        assert_eq!(out.to_u16(), Ok(input_1));
 
         /*
-         * Test 3: Set at 010 and get register at addr 011
+         * Test 4: Another tick keeps the value
          */
         clock.tick();
 
         let out = clock.get_state_of_component(0);
         assert_eq!(out.len(), 16);
-        assert_eq!(vec_bool_to_u16(out), (input_1 & 0xFFFF) as u16);
+        assert_eq!(out.to_u16(), Ok(input_1));
 
         /*
-         * Test 4
+         * Test 5: Addresses whose high and low halves differ
          */
-//        | 4   |   1313 |  1 |   13  |   1313 |
-
-        //let input_4: u32 = 0b10100100001;
-        //let load_4 = 1;
-        //let addr_4 = 0b001101;
-        //let mut final_input_4: u32 = input_4;
-        //final_input_4 = (final_input_4 << 1) | load_4;
-        //final_input_4 = (final_input_4 << 6) | addr_4;
-
-        //let inp = u32_to_vec_bool(final_input_4)[9..].to_vec();
-        //println!("ANANT Final input: {:#?}", inp);
-        //clock.set_state_of_component(0, inp);
-        //clock.tick();
-
-        //let out = clock.get_state_of_component(0);
-        //assert_eq!(out.len(), 16);
-        //assert_eq!(vec_bool_to_u16(out), (input_4 & 0xFFFF) as u16);
-
-        // now just run the original test suite
-        // TODO open file
-        // TODO parse file
-        // TODO run tests
+        let input_4: u16 = 0b10100100001;
+        clock.set_state_of_component(0, ram_input(input_4, true, 0b001101, 6));
+        clock.tick();
+
+        let out = clock.get_state_of_component(0);
+        assert_eq!(out.to_u16(), Ok(input_4));
+
+        clock.set_state_of_component(0, ram_input(0, false, 0b101001, 6));
+        let out = clock.get_state_of_component(0);
+        assert_eq!(out.to_u16(), Ok(0));
+
+        clock.set_state_of_component(0, ram_input(0, false, 0b101101, 6));
+        let out = clock.get_state_of_component(0);
+        assert_eq!(out.to_u16(), Ok(input_1));
+
+        clock.set_state_of_component(0, ram_input(0, false, 0b001101, 6));
+        let out = clock.get_state_of_component(0);
+        assert_eq!(out.to_u16(), Ok(input_4));
+    }
+
+    #[test]
+    fn test_ram4k() {
+        let mut clock = Clock::new();
+
+        clock.register(Box::new(RAM4K::new()));
+        assert_eq!(RAM4K::ADDR_BITS, 12);
+
+        let writes = [
+            (0u64, 0x1111u16),
+            (0o7777, 0xFFFF),
+            (0o1234, 0xBEEF),
+            (0o4321, 0x0F0F),
+        ];
+        for (addr, data) in writes {
+            clock.set_state_of_component(0, ram_input(data, true, addr, 12));
+            clock.tick();
+        }
+
+        for (addr, data) in writes {
+            clock.set_state_of_component(0, ram_input(0, false, addr, 12));
+            clock.tick();
+            assert_eq!(clock.get_state_of_component(0).to_u16(), Ok(data));
+        }
+
+        // neighbours of written words are untouched
+        for addr in [1, 0o7776, 0o1235, 0o1233, 0o4320] {
+            clock.set_state_of_component(0, ram_input(0, false, addr, 12));
+            assert_eq!(clock.get_state_of_component(0).to_u16(), Ok(0));
+        }
+    }
+
+    #[test]
+    fn test_ram_bank_load_latch_is_per_address() {
+        let mut ram = RAM512::new();
+
+        // a write that is re-addressed before the clock edge must not land
+        ram.set_state(ram_input(0xAAAA, true, 0o100, 9));
+        ram.set_state(ram_input(0, false, 0o200, 9));
+        ram.tick();
+
+        ram.set_state(ram_input(0, false, 0o100, 9));
+        ram.tick();
+        assert_eq!(ram.get_state().to_u16(), Ok(0));
+        assert_eq!(ram.peek(0o200), 0);
+
+        ram.set_state(ram_input(0x5555, true, 0o477, 9));
+        ram.tick();
+        assert_eq!(ram.peek(0o477), 0x5555);
+        assert_eq!(ram.peek(0o077), 0);
+    }
+
+    #[test]
+    fn test_ram16k() {
+        let mut clock = Clock::new();
+        clock.register(Box::new(RAM16K::new()));
+
+        for (addr, data) in [(0x0000, 0x0101u16), (0x1000, 0x2020), (0x3FFF, 0x7777)] {
+            clock.set_state_of_component(0, ram_input(data, true, addr, 14));
+            clock.tick();
+        }
+
+        clock.set_state_of_component(0, ram_input(0, false, 0x1000, 14));
+        assert_eq!(clock.get_state_of_component(0).to_u16(), Ok(0x2020));
+        assert_eq!(clock.cycles(), 3);
+
+        let ram = clock.component::<RAM16K>(0).unwrap();
+        assert_eq!(ram.peek(0x0000), 0x0101);
+        assert_eq!(ram.peek(0x3FFF), 0x7777);
+        assert_eq!(ram.peek(0x2FFF), 0);
+        assert!(clock.component::<RAM4K>(0).is_none());
+        assert!(clock.component::<RAM16K>(1).is_none());
+    }
+
+    #[test]
+    fn test_peek_poke() {
+        let mut ram = RAM4K::new();
+        ram.set_state(ram_input(0x1111, true, 0o7001, 12));
+
+        // the latched write and address survive the backdoor
+        ram.poke(0o7001, 0x2222);
+        ram.poke(0o0017, 0x3333);
+        assert_eq!(ram.peek(0o7001), 0x2222);
+        assert_eq!(ram.get_state().to_u16(), Ok(0x2222));
+
+        ram.tick();
+        assert_eq!(ram.get_state().to_u16(), Ok(0x1111));
+        assert_eq!(ram.peek(0o0017), 0x3333);
+
+        let mut register = Register::<4>::new();
+        register.poke(Bits::new(0b1010, 4));
+        assert_eq!(register.get_state(), Bits::new(0b1010, 4));
+        register.tick();
+        assert_eq!(register.get_state(), Bits::new(0b1010, 4));
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut clock = Clock::new();
+        clock.register(Box::new(DFF::new()));
+        clock.register(Box::new(RAM512::new()));
+
+        clock.set_state_of_component(0, true.into());
+        clock.set_state_of_component(1, ram_input(0xCAFE, true, 0o123, 9));
+        clock.tick();
+        // latched but not yet clocked in
+        clock.set_state_of_component(0, false.into());
+        clock.set_state_of_component(1, ram_input(0xF00D, true, 0o321, 9));
+
+        let snapshot = clock.snapshot();
+
+        let mut restored = Clock::new();
+        restored.register(Box::new(DFF::new()));
+        restored.register(Box::new(RAM512::new()));
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.cycles(), 1);
+        assert_eq!(restored.snapshot(), snapshot);
+
+        for clock in [&mut clock, &mut restored] {
+            assert_eq!(clock.get_state_of_component(0).msb(0), true);
+            clock.tick();
+            assert_eq!(clock.get_state_of_component(0).msb(0), false);
+            assert_eq!(clock.component::<RAM512>(1).unwrap().peek(0o123), 0xCAFE);
+            assert_eq!(clock.component::<RAM512>(1).unwrap().peek(0o321), 0xF00D);
+        }
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut clock = Clock::new();
+        clock.register(Box::new(DFF::new()));
+        clock.register(Box::new(RAM64::new()));
+        clock.record_history(3);
+
+        let mut snapshots = Vec::new();
+        for i in 0..5u16 {
+            clock.set_state_of_component(0, (i % 2 == 0).into());
+            clock.set_state_of_component(1, ram_input(i + 1, true, i as u64 * 9, 6));
+            snapshots.push(clock.snapshot());
+            clock.tick();
+        }
+        assert_eq!(clock.history_len(), 3);
+
+        // back to just before each tick, latched inputs included
+        assert_eq!(clock.step_back(1), 1);
+        assert_eq!(clock.snapshot(), snapshots[4]);
+        assert_eq!(clock.component::<RAM64>(1).unwrap().peek(36), 0);
+        assert_eq!(clock.component::<RAM64>(1).unwrap().peek(27), 4);
+
+        // the ring only kept the last three cycles
+        assert_eq!(clock.step_back(5), 2);
+        assert_eq!(clock.snapshot(), snapshots[2]);
+        assert_eq!(clock.cycles(), 2);
+        assert_eq!(clock.step_back(1), 0);
+
+        clock.tick();
+        assert_eq!(clock.step_back(1), 1);
+        assert_eq!(clock.snapshot(), snapshots[2]);
+    }
+
+    #[test]
+    fn test_restore_rejects_other_machines() {
+        let mut clock = Clock::new();
+        clock.register(Box::new(DFF::new()));
+        let snapshot = clock.snapshot();
+
+        let mut other = Clock::new();
+        assert_eq!(
+            other.restore(&snapshot),
+            Err(StateError::ComponentCount {
+                expected: 0,
+                found: 1
+            })
+        );
+
+        other.register(Box::new(Bit::new()));
+        assert_eq!(
+            other.restore(&snapshot),
+            Err(StateError::ComponentKind {
+                index: 0,
+                expected: "Bit".to_string(),
+                found: "DFF".to_string()
+            })
+        );
+
+        assert_eq!(clock.restore(b"nope"), Err(StateError::BadMagic));
+        assert_eq!(
+            clock.restore(&snapshot[..6]),
+            Err(StateError::UnexpectedEnd)
+        );
+    }
+
+    #[test]
+    fn test_failed_restore_changes_nothing() {
+        let mut clock = Clock::new();
+        clock.register(Box::new(DFF::new()));
+        clock.register(Box::new(Register::<16>::new()));
+        clock.record_history(4);
+        clock.set_state_of_component(0, true.into());
+        clock.set_state_of_component(1, Bits::from(0xBEEFu16).concat(true.into()));
+        clock.tick();
+        clock.tick();
+        let before = clock.snapshot();
+
+        // same kinds, but the register is narrower
+        let mut other = Clock::new();
+        other.register(Box::new(DFF::new()));
+        other.register(Box::new(Register::<8>::new()));
+        assert_eq!(
+            clock.restore(&other.snapshot()),
+            Err(StateError::UnexpectedEnd)
+        );
+        assert_eq!(clock.snapshot(), before);
+        assert_eq!(clock.history_len(), 2);
     }
 }
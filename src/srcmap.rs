@@ -0,0 +1,343 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/**
+ * A line of a source file, the line counted from 1.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+}
+
+impl Location {
+    pub fn new(file: &str, line: usize) -> Self {
+        Location {
+            file: file.to_string(),
+            line,
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/**
+ * A variable of a function: its Jack kind (static, field, argument or
+ * local), its index in the matching VM segment, its name and its type.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variable {
+    pub kind: String,
+    pub index: u16,
+    pub name: String,
+    pub ty: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub name: String,
+    pub start: usize,
+    pub vars: Vec<Variable>,
+}
+
+/**
+ * Where the lines of a generated file come from: for each of its lines,
+ * or ROM addresses for a program, the source location it was generated
+ * from, and the functions starting at which of its lines. As text:
+ *
+ * ```text
+ * 12 Main.vm:3
+ * function 10 Main.main
+ * var local 0 sum int
+ * ```
+ *
+ * a var belonging to the function before it.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    lines: BTreeMap<usize, Location>,
+    functions: Vec<Function>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap::default()
+    }
+
+    pub fn add(&mut self, at: usize, source: Location) {
+        self.lines.insert(at, source);
+    }
+
+    // functions are added in the order of their start lines
+    pub fn add_function(&mut self, at: usize, name: &str, vars: Vec<Variable>) {
+        self.functions.push(Function {
+            name: name.to_string(),
+            start: at,
+            vars,
+        });
+    }
+
+    pub fn get(&self, at: usize) -> Option<&Location> {
+        self.lines.get(&at)
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = (usize, &Location)> {
+        self.lines.iter().map(|(&at, source)| (at, source))
+    }
+
+    // the function whose code the line is in, for lines with a source
+    pub fn function(&self, at: usize) -> Option<&Function> {
+        self.get(at)?;
+        self.functions.iter().rev().find(|f| f.start <= at)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        let mut functions = self.functions.iter().peekable();
+        for (&at, source) in &self.lines {
+            while let Some(function) = functions.next_if(|f| f.start <= at) {
+                write_function(&mut out, function);
+            }
+            let _ = writeln!(out, "{} {}", at, source);
+        }
+        for function in functions {
+            write_function(&mut out, function);
+        }
+        out
+    }
+
+    /**
+     * Reads the text of to_text. Errors give the line they are on.
+     */
+    pub fn parse(text: &str) -> Result<Self, (usize, String)> {
+        let mut map = SourceMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: &str| (i + 1, message.to_string());
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => {}
+                ["function", at, name] => {
+                    let at = at.parse().map_err(|_| error("invalid line number"))?;
+                    map.add_function(at, name, Vec::new());
+                }
+                ["var", kind, index, name, ty] => {
+                    let function = map
+                        .functions
+                        .last_mut()
+                        .ok_or_else(|| error("var outside a function"))?;
+                    function.vars.push(Variable {
+                        kind: kind.to_string(),
+                        index: index.parse().map_err(|_| error("invalid index"))?,
+                        name: name.to_string(),
+                        ty: ty.to_string(),
+                    });
+                }
+                [at, source] => {
+                    let at = at.parse().map_err(|_| error("invalid line number"))?;
+                    let (file, line) = source
+                        .rsplit_once(':')
+                        .and_then(|(file, line)| Some((file, line.parse().ok()?)))
+                        .ok_or_else(|| error("expected FILE:LINE"))?;
+                    map.add(at, Location::new(file, line));
+                }
+                _ => return Err(error("expected 'AT FILE:LINE', 'function' or 'var'")),
+            }
+        }
+        Ok(map)
+    }
+}
+
+fn write_function(out: &mut String, function: &Function) {
+    let _ = writeln!(out, "function {} {}", function.start, function.name);
+    for var in &function.vars {
+        let _ = writeln!(
+            out,
+            "var {} {} {} {}",
+            var.kind, var.index, var.name, var.ty
+        );
+    }
+}
+
+/**
+ * The maps from a program's ROM back to the sources it was built from,
+ * e.g. ROM address to Prog.asm line to Main.vm line to Main.jack line,
+ * each map found by the name of the file it maps the lines of.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct SourceMaps {
+    rom: SourceMap,
+    maps: HashMap<String, SourceMap>,
+    texts: HashMap<String, Vec<String>>,
+}
+
+impl SourceMaps {
+    pub fn new(rom: SourceMap) -> Self {
+        SourceMaps {
+            rom,
+            ..SourceMaps::default()
+        }
+    }
+
+    pub fn add_map(&mut self, file: &str, map: SourceMap) {
+        self.maps.insert(file.to_string(), map);
+    }
+
+    // the text of a source file, to show its lines
+    pub fn add_text(&mut self, file: &str, text: &str) {
+        let lines = text.lines().map(|line| line.to_string()).collect();
+        self.texts.insert(file.to_string(), lines);
+    }
+
+    /**
+     * Adds FILE.map and the text of FILE from dir for every FILE the maps
+     * refer to, and so on down to the original sources. Files that are
+     * not there are left out.
+     */
+    pub fn load_from(&mut self, dir: &Path) -> io::Result<()> {
+        let mut seen = HashSet::new();
+        let mut pending: Vec<String> = self.files(&self.rom);
+        while let Some(file) = pending.pop() {
+            if !seen.insert(file.clone()) {
+                continue;
+            }
+            if !self.texts.contains_key(&file) {
+                if let Ok(text) = fs::read_to_string(dir.join(&file)) {
+                    self.add_text(&file, &text);
+                }
+            }
+            if !self.maps.contains_key(&file) {
+                let path = dir.join(format!("{}.map", file));
+                let Ok(text) = fs::read_to_string(&path) else {
+                    continue;
+                };
+                let map = SourceMap::parse(&text).map_err(|(line, message)| {
+                    let message = format!("{}:{}: {}", path.display(), line, message);
+                    io::Error::new(io::ErrorKind::InvalidData, message)
+                })?;
+                self.maps.insert(file.clone(), map);
+            }
+            pending.extend(self.files(&self.maps[&file]));
+        }
+        Ok(())
+    }
+
+    fn files(&self, map: &SourceMap) -> Vec<String> {
+        let files: HashSet<&String> = map.lines.values().map(|source| &source.file).collect();
+        files.into_iter().cloned().collect()
+    }
+
+    /**
+     * Where the instruction at addr comes from, from the assembly line
+     * down to the original source, empty for code with no source.
+     */
+    pub fn locate(&self, addr: u16) -> Vec<Location> {
+        let mut chain: Vec<Location> = Vec::new();
+        let mut next = self.rom.get(addr as usize);
+        while let Some(source) = next {
+            // a map of its own lines would never end
+            if chain.contains(source) {
+                break;
+            }
+            chain.push(source.clone());
+            next = self.maps.get(&source.file).and_then(|m| m.get(source.line));
+        }
+        chain
+    }
+
+    /**
+     * The function the instruction at addr is in, as the map closest to
+     * the original source knows it.
+     */
+    pub fn function(&self, addr: u16) -> Option<&Function> {
+        self.locate(addr)
+            .iter()
+            .filter_map(|source| self.maps.get(&source.file)?.function(source.line))
+            .next_back()
+    }
+
+    // the ROM addresses starting the code of a source line
+    pub fn addresses(&self, source: &Location) -> Vec<u16> {
+        let mut res = Vec::new();
+        let mut previous = false;
+        for (addr, _) in self.rom.lines() {
+            let Ok(addr) = u16::try_from(addr) else {
+                break;
+            };
+            let here = self.locate(addr).contains(source);
+            if here && !previous {
+                res.push(addr);
+            }
+            previous = here;
+        }
+        res
+    }
+
+    pub fn text(&self, source: &Location) -> Option<&str> {
+        let lines = self.texts.get(&source.file)?;
+        Some(lines.get(source.line.checked_sub(1)?)?.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(text: &str) -> SourceMap {
+        SourceMap::parse(text).unwrap()
+    }
+
+    #[test]
+    fn test_text() {
+        let text = "\
+function 1 Main.main
+var argument 0 n int
+var local 0 a Array
+1 Main.jack:3
+2 Main.jack:3
+function 3 Main.f
+3 Main.jack:9
+";
+        let parsed = map(text);
+        assert_eq!(parsed.to_text(), text);
+        assert_eq!(parsed.get(2), Some(&Location::new("Main.jack", 3)));
+        assert_eq!(parsed.function(2).unwrap().vars[1].name, "a");
+        assert_eq!(parsed.function(3).unwrap().name, "Main.f");
+        assert_eq!(parsed.function(4), None);
+
+        assert_eq!(SourceMap::parse("1 Main.jack").unwrap_err().0, 1);
+        assert_eq!(SourceMap::parse("\nvar local 0 a int").unwrap_err().0, 2);
+        assert!(SourceMap::parse("x Main.jack:1").is_err());
+    }
+
+    #[test]
+    fn test_chain() {
+        let rom = map("0 Prog.asm:1\n1 Prog.asm:2\n2 Prog.asm:3\n3 Prog.asm:5\n");
+        let mut maps = SourceMaps::new(rom);
+        maps.add_map(
+            "Prog.asm",
+            map("function 1 Main.main\n1 Main.vm:2\n2 Main.vm:2\n3 Main.vm:3\n5 Main.vm:4\n"),
+        );
+        maps.add_map(
+            "Main.vm",
+            map("function 1 Main.main\nvar local 0 x int\n2 Main.jack:4\n3 Main.jack:4\n4 Main.jack:5\n"),
+        );
+        maps.add_text("Main.jack", "\n\n\n    let x = 1;\n    return;\n");
+
+        assert_eq!(maps.locate(0).len(), 3);
+        assert_eq!(maps.locate(3)[2], Location::new("Main.jack", 5));
+        assert_eq!(maps.text(&maps.locate(3)[2]), Some("    return;"));
+        assert_eq!(maps.function(1).unwrap().vars.len(), 1);
+        assert_eq!(maps.locate(9), []);
+        assert_eq!(maps.addresses(&Location::new("Main.jack", 4)), [0]);
+        assert_eq!(maps.addresses(&Location::new("Main.vm", 3)), [2]);
+        assert_eq!(maps.addresses(&Location::new("Main.jack", 6)), []);
+    }
+}
@@ -0,0 +1,238 @@
+use std::fmt;
+
+/**
+ * Serialized machine state: a flat stream written by Tick::save and read
+ * back in the same order by Tick::restore.
+ * Booleans are packed eight to a byte, everything else is little endian.
+ */
+pub struct StateWriter {
+    bytes: Vec<u8>,
+    bits: u8,
+    bit_count: u8,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter {
+            bytes: Vec::new(),
+            bits: 0,
+            bit_count: 0,
+        }
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.bits |= (value as u8) << self.bit_count;
+        self.bit_count += 1;
+        if self.bit_count == 8 {
+            self.flush_bits();
+        }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.flush_bits();
+        self.bytes.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.flush_bits();
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.flush_bits();
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u64(bytes.len() as u64);
+        self.bytes.extend(bytes);
+    }
+
+    fn flush_bits(&mut self) {
+        if self.bit_count > 0 {
+            self.bytes.push(self.bits);
+            self.bits = 0;
+            self.bit_count = 0;
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.flush_bits();
+        self.bytes
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnexpectedEnd,
+    ComponentCount {
+        expected: usize,
+        found: usize,
+    },
+    ComponentKind {
+        index: usize,
+        expected: String,
+        found: String,
+    },
+    ComponentSize {
+        index: usize,
+    },
+    Invalid(String),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a machine state file"),
+            StateError::UnsupportedVersion(v) => write!(f, "unsupported state version {}", v),
+            StateError::UnexpectedEnd => write!(f, "state ends unexpectedly"),
+            StateError::ComponentCount { expected, found } => write!(
+                f,
+                "state has {} components but the clock has {}",
+                found, expected
+            ),
+            StateError::ComponentKind {
+                index,
+                expected,
+                found,
+            } => write!(
+                f,
+                "component {} is a {} but the state is of a {}",
+                index, expected, found
+            ),
+            StateError::ComponentSize { index } => {
+                write!(f, "state of component {} does not match it", index)
+            }
+            StateError::Invalid(message) => write!(f, "invalid state: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    bits: u8,
+    bit_count: u8,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        StateReader {
+            bytes,
+            pos: 0,
+            bits: 0,
+            bit_count: 0,
+        }
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        if self.bit_count == 0 {
+            self.bits = self.take(1)?[0];
+            self.bit_count = 8;
+        }
+        let value = self.bits & 1 != 0;
+        self.bits >>= 1;
+        self.bit_count -= 1;
+        Ok(value)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        self.bit_count = 0;
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        self.bit_count = 0;
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        self.bit_count = 0;
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.u64()?;
+        let len = usize::try_from(len).map_err(|_| StateError::UnexpectedEnd)?;
+        self.take(len)
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or(StateError::UnexpectedEnd)?;
+        let res = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut w = StateWriter::new();
+        w.bool(true);
+        w.bool(false);
+        w.bool(true);
+        w.u16(0xBEEF);
+        for i in 0..9 {
+            w.bool(i % 3 == 0);
+        }
+        w.u64(u64::MAX - 1);
+        w.bytes(b"abc");
+        w.u8(7);
+        w.bool(true);
+        let bytes = w.finish();
+        // 1 + 2 + 2 + 8 + 8 + 3 + 1 + 1
+        assert_eq!(bytes.len(), 26);
+
+        let mut r = StateReader::new(&bytes);
+        assert_eq!(
+            (r.bool(), r.bool(), r.bool()),
+            (Ok(true), Ok(false), Ok(true))
+        );
+        assert_eq!(r.u16(), Ok(0xBEEF));
+        for i in 0..9 {
+            assert_eq!(r.bool(), Ok(i % 3 == 0));
+        }
+        assert_eq!(r.u64(), Ok(u64::MAX - 1));
+        assert_eq!(r.bytes(), Ok(&b"abc"[..]));
+        assert_eq!(r.u8(), Ok(7));
+        assert_eq!(r.bool(), Ok(true));
+        assert!(r.is_at_end());
+        assert_eq!(r.u8(), Err(StateError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut r = StateReader::new(&[1, 2, 3]);
+        assert_eq!(r.u64(), Err(StateError::UnexpectedEnd));
+
+        let mut w = StateWriter::new();
+        w.bytes(&[0; 10]);
+        let bytes = w.finish();
+        assert_eq!(
+            StateReader::new(&bytes[..12]).bytes(),
+            Err(StateError::UnexpectedEnd)
+        );
+    }
+}
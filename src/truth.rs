@@ -0,0 +1,384 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::alu::{full_adder, half_adder};
+use crate::gates::{
+    and_gate, dmux4way_gate, dmux8way_gate, dmux_gate, mux, nand_gate, not_gate, or8way_gate,
+    or_gate, xor_gate,
+};
+
+/**
+ * Truth table of a chip with 1-bit outputs.
+ * Row r assigns input i the value of bit (n - 1 - i) of r, so the first
+ * input changes slowest, as in the Nand2Tetris compare files.
+ */
+pub struct TruthTable {
+    pub chip: String,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    // rows[r][j] is output j for input row r
+    pub rows: Vec<Vec<bool>>,
+}
+
+/**
+ * Product term over the chip inputs. Bits set in `mask` are inputs the
+ * term does not depend on; the other bits must equal `value`.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Implicant {
+    pub value: u64,
+    pub mask: u64,
+}
+
+impl Implicant {
+    fn covers(&self, minterm: u64) -> bool {
+        (minterm & !self.mask) == self.value
+    }
+
+    fn literals(&self, n: usize) -> usize {
+        n - self.mask.count_ones() as usize
+    }
+}
+
+impl TruthTable {
+    pub fn from_fn(
+        chip: &str,
+        inputs: &[&str],
+        outputs: &[&str],
+        f: impl Fn(&[bool]) -> Vec<bool>,
+    ) -> Self {
+        let n = inputs.len();
+        assert!(n <= 16, "{} inputs is too many for a truth table", n);
+
+        let rows = (0..1u64 << n)
+            .map(|row| {
+                let values: Vec<bool> = (0..n).map(|i| (row >> (n - 1 - i)) & 1 != 0).collect();
+                let out = f(&values);
+                assert_eq!(out.len(), outputs.len());
+                out
+            })
+            .collect();
+
+        TruthTable {
+            chip: chip.to_string(),
+            inputs: inputs.iter().map(|s| s.to_string()).collect(),
+            outputs: outputs.iter().map(|s| s.to_string()).collect(),
+            rows,
+        }
+    }
+
+    pub fn minterms(&self, output: usize) -> Vec<u64> {
+        (0..self.rows.len() as u64)
+            .filter(|row| self.rows[*row as usize][output])
+            .collect()
+    }
+
+    // canonical sum of products, one term per true row
+    pub fn sum_of_products(&self, output: usize) -> String {
+        let terms: Vec<Implicant> = self
+            .minterms(output)
+            .into_iter()
+            .map(|value| Implicant { value, mask: 0 })
+            .collect();
+        self.format_terms(&terms)
+    }
+
+    // sum of products minimized with Quine-McCluskey
+    pub fn minimized(&self, output: usize) -> String {
+        let n = self.inputs.len();
+        let minterms = self.minterms(output);
+        let primes = prime_implicants(&minterms);
+        let cover = select_cover(&primes, &minterms, n);
+        self.format_terms(&cover)
+    }
+
+    fn format_terms(&self, terms: &[Implicant]) -> String {
+        let n = self.inputs.len();
+
+        if terms.is_empty() {
+            return "0".to_string();
+        }
+        if terms.iter().any(|t| t.literals(n) == 0) {
+            return "1".to_string();
+        }
+
+        let formatted: Vec<String> = terms
+            .iter()
+            .map(|term| {
+                let literals: Vec<String> = (0..n)
+                    .filter(|i| (term.mask >> (n - 1 - i)) & 1 == 0)
+                    .map(|i| {
+                        if (term.value >> (n - 1 - i)) & 1 != 0 {
+                            self.inputs[i].clone()
+                        } else {
+                            format!("!{}", self.inputs[i])
+                        }
+                    })
+                    .collect();
+
+                if literals.len() > 1 && terms.len() > 1 {
+                    format!("({})", literals.join(" & "))
+                } else {
+                    literals.join(" & ")
+                }
+            })
+            .collect();
+
+        formatted.join(" | ")
+    }
+}
+
+impl fmt::Display for TruthTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let n = self.inputs.len();
+        let names: Vec<&String> = self.inputs.iter().chain(&self.outputs).collect();
+
+        write!(f, "|")?;
+        for name in &names {
+            write!(f, " {} |", name)?;
+        }
+        writeln!(f)?;
+
+        for (row, outputs) in self.rows.iter().enumerate() {
+            write!(f, "|")?;
+            let inputs = (0..n).map(|i| (row >> (n - 1 - i)) & 1 != 0);
+            for (name, value) in names.iter().zip(inputs.chain(outputs.iter().copied())) {
+                write!(f, " {:^width$} |", value as u8, width = name.len())?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn prime_implicants(minterms: &[u64]) -> Vec<Implicant> {
+    let mut current: BTreeSet<Implicant> = minterms
+        .iter()
+        .map(|value| Implicant {
+            value: *value,
+            mask: 0,
+        })
+        .collect();
+    let mut primes = BTreeSet::new();
+
+    while !current.is_empty() {
+        let mut next = BTreeSet::new();
+        let mut combined = BTreeSet::new();
+
+        for a in &current {
+            for b in &current {
+                let diff = a.value ^ b.value;
+                if a.mask == b.mask && a.value < b.value && diff.count_ones() == 1 {
+                    next.insert(Implicant {
+                        value: a.value & !diff,
+                        mask: a.mask | diff,
+                    });
+                    combined.insert(*a);
+                    combined.insert(*b);
+                }
+            }
+        }
+
+        primes.extend(current.difference(&combined).copied());
+        current = next;
+    }
+
+    primes.into_iter().collect()
+}
+
+// essential prime implicants first, then greedily the prime covering the
+// most remaining minterms with the fewest literals
+fn select_cover(primes: &[Implicant], minterms: &[u64], n: usize) -> Vec<Implicant> {
+    let mut remaining: BTreeSet<u64> = minterms.iter().copied().collect();
+    let mut cover: Vec<Implicant> = Vec::new();
+
+    for minterm in minterms {
+        let covering: Vec<&Implicant> = primes.iter().filter(|p| p.covers(*minterm)).collect();
+        if covering.len() == 1 && !cover.contains(covering[0]) {
+            cover.push(*covering[0]);
+        }
+    }
+    remaining.retain(|m| !cover.iter().any(|p| p.covers(*m)));
+
+    while !remaining.is_empty() {
+        let best = primes
+            .iter()
+            .filter(|p| !cover.contains(p))
+            .max_by_key(|p| {
+                let covered = remaining.iter().filter(|m| p.covers(**m)).count();
+                (covered, usize::MAX - p.literals(n))
+            })
+            .expect("prime implicants always cover every minterm");
+        cover.push(*best);
+        remaining.retain(|m| !best.covers(*m));
+    }
+
+    cover.sort_by_key(|p| std::cmp::Reverse(*p));
+    cover
+}
+
+pub const CHIPS: [&str; 12] = [
+    "not",
+    "and",
+    "or",
+    "nand",
+    "xor",
+    "mux",
+    "dmux",
+    "dmux4way",
+    "dmux8way",
+    "or8way",
+    "half_adder",
+    "full_adder",
+];
+
+pub fn chip_truth_table(chip: &str) -> Option<TruthTable> {
+    let table = match chip {
+        "not" => TruthTable::from_fn(chip, &["in"], &["out"], |i| vec![not_gate(i[0])]),
+        "and" => TruthTable::from_fn(chip, &["a", "b"], &["out"], |i| vec![and_gate(i[0], i[1])]),
+        "or" => TruthTable::from_fn(chip, &["a", "b"], &["out"], |i| vec![or_gate(i[0], i[1])]),
+        "nand" => TruthTable::from_fn(chip, &["a", "b"], &["out"], |i| vec![nand_gate(i[0], i[1])]),
+        "xor" => TruthTable::from_fn(chip, &["a", "b"], &["out"], |i| vec![xor_gate(i[0], i[1])]),
+        "mux" => TruthTable::from_fn(chip, &["a", "b", "sel"], &["out"], |i| {
+            vec![mux(i[0], i[1], i[2])]
+        }),
+        "dmux" => TruthTable::from_fn(chip, &["in", "sel"], &["a", "b"], |i| {
+            let (a, b) = dmux_gate(i[0], i[1]);
+            vec![a, b]
+        }),
+        "dmux4way" => {
+            TruthTable::from_fn(chip, &["in", "sel1", "sel0"], &["a", "b", "c", "d"], |i| {
+                let (a, b, c, d) = dmux4way_gate(i[0], ((i[1] as u8) << 1) | i[2] as u8);
+                vec![a, b, c, d]
+            })
+        }
+        "dmux8way" => TruthTable::from_fn(
+            chip,
+            &["in", "sel2", "sel1", "sel0"],
+            &["a", "b", "c", "d", "e", "f", "g", "h"],
+            |i| {
+                let sel = ((i[1] as u8) << 2) | ((i[2] as u8) << 1) | i[3] as u8;
+                let out = dmux8way_gate(i[0], sel);
+                (0..8).map(|j| (out >> (7 - j)) & 1 != 0).collect()
+            },
+        ),
+        "or8way" => TruthTable::from_fn(
+            chip,
+            &["in7", "in6", "in5", "in4", "in3", "in2", "in1", "in0"],
+            &["out"],
+            |i| {
+                let x = i.iter().fold(0u8, |acc, bit| (acc << 1) | *bit as u8);
+                vec![or8way_gate(x)]
+            },
+        ),
+        "half_adder" => TruthTable::from_fn(chip, &["a", "b"], &["sum", "carry"], |i| {
+            let (sum, carry) = half_adder(i[0], i[1]);
+            vec![sum, carry]
+        }),
+        "full_adder" => TruthTable::from_fn(chip, &["a", "b", "c"], &["sum", "carry"], |i| {
+            let (sum, carry) = full_adder(i[0], i[1], i[2]);
+            vec![sum, carry]
+        }),
+        _ => return None,
+    };
+
+    Some(table)
+}
+
+// table followed by the canonical and minimized expression of every output
+pub fn render(table: &TruthTable) -> String {
+    let mut res = format!("Chip {}\n\n{}\n", table.chip, table);
+
+    for (j, output) in table.outputs.iter().enumerate() {
+        res.push_str(&format!("{} = {}\n", output, table.sum_of_products(j)));
+        res.push_str(&format!(
+            "{} = {}  (minimized)\n",
+            output,
+            table.minimized(j)
+        ));
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xor_table() {
+        let table = chip_truth_table("xor").unwrap();
+        let outputs: Vec<bool> = table.rows.iter().map(|r| r[0]).collect();
+        assert_eq!(outputs, [false, true, true, false]);
+        assert_eq!(table.minterms(0), [0b01, 0b10]);
+
+        let rendered = table.to_string();
+        assert_eq!(
+            rendered,
+            "| a | b | out |\n\
+             | 0 | 0 |  0  |\n\
+             | 0 | 1 |  1  |\n\
+             | 1 | 0 |  1  |\n\
+             | 1 | 1 |  0  |\n"
+        );
+    }
+
+    #[test]
+    fn test_expressions() {
+        let xor = chip_truth_table("xor").unwrap();
+        assert_eq!(xor.sum_of_products(0), "(!a & b) | (a & !b)");
+        assert_eq!(xor.minimized(0), "(a & !b) | (!a & b)");
+
+        let mux = chip_truth_table("mux").unwrap();
+        assert_eq!(
+            mux.sum_of_products(0),
+            "(!a & b & sel) | (a & !b & !sel) | (a & b & !sel) | (a & b & sel)"
+        );
+        assert_eq!(mux.minimized(0), "(a & !sel) | (b & sel)");
+
+        let dmux = chip_truth_table("dmux").unwrap();
+        assert_eq!(dmux.minimized(0), "in & !sel");
+        assert_eq!(dmux.minimized(1), "in & sel");
+
+        let or8way = chip_truth_table("or8way").unwrap();
+        assert_eq!(
+            or8way.minimized(0),
+            "in7 | in6 | in5 | in4 | in3 | in2 | in1 | in0"
+        );
+
+        let not = chip_truth_table("not").unwrap();
+        assert_eq!(not.minimized(0), "!in");
+    }
+
+    #[test]
+    fn test_constant_outputs() {
+        let zero = TruthTable::from_fn("zero", &["a", "b"], &["out"], |_| vec![false]);
+        assert_eq!(zero.minimized(0), "0");
+
+        let one = TruthTable::from_fn("one", &["a", "b"], &["out"], |_| vec![true]);
+        assert_eq!(
+            one.sum_of_products(0),
+            "(!a & !b) | (!a & b) | (a & !b) | (a & b)"
+        );
+        assert_eq!(one.minimized(0), "1");
+    }
+
+    #[test]
+    fn test_minimized_matches_table() {
+        // evaluate the minimized cover back against every row
+        for chip in CHIPS {
+            let table = chip_truth_table(chip).unwrap();
+            let n = table.inputs.len();
+            for j in 0..table.outputs.len() {
+                let minterms = table.minterms(j);
+                let primes = prime_implicants(&minterms);
+                let cover = select_cover(&primes, &minterms, n);
+                for row in 0..table.rows.len() {
+                    let covered = cover.iter().any(|p| p.covers(row as u64));
+                    assert_eq!(covered, table.rows[row][j], "{} row {}", chip, row);
+                }
+            }
+        }
+    }
+}
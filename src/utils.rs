@@ -1,151 +1,451 @@
-pub fn u16_to_vec_bool(value: u16) -> Vec<bool> {
-    let mut res: Vec<bool> = (0..16).map(|i| (value & (1 << i)) != 0).collect();
-    res.reverse();
-    res
-}
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::bus::Bus;
 
-pub fn vec_bool_to_u16(mut value: Vec<bool>) -> u16 {
-    let mut res: u16 = 0;
-    value.reverse();
-    for i in 0..value.len() {
-        let bit = match value[i] {
-            false => 0,
-            true => 1,
-        };
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BitsError {
+    TooWide { width: usize, max: usize },
+    Empty,
+    InvalidDigit(char),
+}
 
-        res |= bit << i;
+impl fmt::Display for BitsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitsError::TooWide { width, max } => {
+                write!(f, "{} bits do not fit in {} bits", width, max)
+            }
+            BitsError::Empty => write!(f, "no digits to parse"),
+            BitsError::InvalidDigit(c) => write!(f, "invalid digit '{}'", c),
+        }
     }
+}
 
-    res
+impl std::error::Error for BitsError {}
+
+/**
+ * Fixed-width bit vector of up to 64 bits, stored packed in a u64.
+ * The width is part of the value: two vectors holding the same number
+ * with different widths are different signals.
+ *
+ * Bits can be addressed from either end:
+ *   msb(0) is the most significant bit (first wire of a chip input),
+ *   lsb(0) is the least significant bit.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Bits {
+    width: usize,
+    value: u64,
 }
 
-pub fn vec_bool_to_u8(mut value: Vec<bool>) -> u8 {
-    let mut res: u8 = 0;
+impl Bits {
+    pub const MAX_WIDTH: usize = 64;
 
-    value.reverse();
+    fn mask(width: usize) -> u64 {
+        if width == 64 {
+            u64::MAX
+        } else {
+            (1 << width) - 1
+        }
+    }
 
-    for i in 0..value.len() {
-        let bit = match value[i] {
-            false => 0,
-            true => 1,
-        };
+    // panics if value needs more than width bits
+    pub fn new(value: u64, width: usize) -> Self {
+        Self::try_new(value, width).unwrap_or_else(|e| panic!("{}", e))
+    }
 
-        res |= bit << i;
+    pub fn try_new(value: u64, width: usize) -> Result<Self, BitsError> {
+        if width > Self::MAX_WIDTH {
+            return Err(BitsError::TooWide {
+                width,
+                max: Self::MAX_WIDTH,
+            });
+        }
+
+        let needed = (u64::BITS - value.leading_zeros()) as usize;
+        if needed > width {
+            return Err(BitsError::TooWide {
+                width: needed,
+                max: width,
+            });
+        }
+
+        Ok(Bits { width, value })
     }
 
-    res
-}
+    pub fn zero(width: usize) -> Self {
+        Bits::new(0, width)
+    }
 
-pub fn u8_to_vec_bool(value: u8) -> Vec<bool> {
-    let mut res: Vec<bool> = (0..8).map(|i| (value & (1 << i)) != 0).collect();
-    res.reverse();
-    res
-}
+    pub fn from_msb_iter(bits: impl IntoIterator<Item = bool>) -> Self {
+        bits.into_iter()
+            .fold(Bits::zero(0), |acc, bit| acc.concat(bit.into()))
+    }
 
-pub fn u32_to_vec_bool(value: u32) -> Vec<bool> {
-    let mut res: Vec<bool> = (0..32).map(|i| (value & (1 << i)) != 0).collect();
-    res.reverse();
-    res
-}
+    pub fn width(&self) -> usize {
+        self.width
+    }
 
-pub fn vec_bool_to_u32(mut value: Vec<bool>) -> u32 {
-    let mut res: u32 = 0;
+    pub fn len(&self) -> usize {
+        self.width
+    }
 
-    value.reverse();
+    pub fn is_empty(&self) -> bool {
+        self.width == 0
+    }
 
-    for i in 0..value.len() {
-        let bit = match value[i] {
-            false => 0,
-            true => 1,
-        };
+    pub fn value(&self) -> u64 {
+        self.value
+    }
 
-        res |= bit << i;
+    pub fn lsb(&self, i: usize) -> bool {
+        assert!(
+            i < self.width,
+            "bit {} out of range for width {}",
+            i,
+            self.width
+        );
+        (self.value >> i) & 1 != 0
     }
 
-    res
-}
+    pub fn msb(&self, i: usize) -> bool {
+        assert!(
+            i < self.width,
+            "bit {} out of range for width {}",
+            i,
+            self.width
+        );
+        self.lsb(self.width - 1 - i)
+    }
 
-#[cfg(test)]
-mod tests {
-    use crate::utils::{
-        u16_to_vec_bool, u32_to_vec_bool, vec_bool_to_u16, vec_bool_to_u32, vec_bool_to_u8,
-    };
+    pub fn set_lsb(&mut self, i: usize, bit: bool) {
+        assert!(
+            i < self.width,
+            "bit {} out of range for width {}",
+            i,
+            self.width
+        );
+        self.value = (self.value & !(1 << i)) | ((bit as u64) << i);
+    }
 
-    #[test]
-    fn test_u16_to_vec_bool() {
-        let inp: u16 = 0b1;
-        let mut exp_out = vec![false; 16];
-        exp_out[15] = true;
+    pub fn set_msb(&mut self, i: usize, bit: bool) {
+        assert!(
+            i < self.width,
+            "bit {} out of range for width {}",
+            i,
+            self.width
+        );
+        self.set_lsb(self.width - 1 - i, bit);
+    }
 
-        assert_eq!(u16_to_vec_bool(inp), exp_out);
+    // bits range.start..range.end counted from the least significant end
+    pub fn slice_lsb(&self, range: Range<usize>) -> Bits {
+        assert!(
+            range.start <= range.end && range.end <= self.width,
+            "slice {:?} out of range for width {}",
+            range,
+            self.width
+        );
+        let width = range.end - range.start;
+        if width == 0 {
+            return Bits::zero(0);
+        }
+        Bits {
+            width,
+            value: (self.value >> range.start) & Self::mask(width),
+        }
+    }
 
-        let inp: u16 = 0b1111111111111111;
-        let exp_out = vec![true; 16];
+    // bits range.start..range.end counted from the most significant end
+    pub fn slice_msb(&self, range: Range<usize>) -> Bits {
+        assert!(
+            range.start <= range.end && range.end <= self.width,
+            "slice {:?} out of range for width {}",
+            range,
+            self.width
+        );
+        self.slice_lsb(self.width - range.end..self.width - range.start)
+    }
 
-        assert_eq!(u16_to_vec_bool(inp), exp_out);
+    // self ends up in the most significant bits, other in the least significant
+    pub fn concat(self, other: Bits) -> Bits {
+        let width = self.width + other.width;
+        assert!(
+            width <= Self::MAX_WIDTH,
+            "concatenation is {} bits wide, max is {}",
+            width,
+            Self::MAX_WIDTH
+        );
+        if self.width == 0 {
+            return other;
+        }
+        Bits {
+            width,
+            value: (self.value << other.width) | other.value,
+        }
     }
 
-    #[test]
-    fn test_vec_bool_to_u16() {
-        let inp = vec![true];
-        let exp_out: u16 = 0b1;
+    // most significant bit first
+    pub fn iter(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..self.width).map(move |i| self.msb(i))
+    }
 
-        assert_eq!(vec_bool_to_u16(inp), exp_out);
+    pub fn to_u8(&self) -> Result<u8, BitsError> {
+        self.fit(8).map(|v| v as u8)
+    }
 
-        let inp = vec![true, false, true, false];
-        let exp_out: u16 = 0b1010;
+    pub fn to_u16(&self) -> Result<u16, BitsError> {
+        self.fit(16).map(|v| v as u16)
+    }
 
-        assert_eq!(vec_bool_to_u16(inp), exp_out);
+    pub fn to_u32(&self) -> Result<u32, BitsError> {
+        self.fit(32).map(|v| v as u32)
     }
 
-    #[test]
-    fn test_vec_bool_to_u8() {
-        let inp = vec![true];
-        let exp_out: u8 = 0b1;
+    pub fn to_u64(&self) -> Result<u64, BitsError> {
+        self.fit(64)
+    }
 
-        assert_eq!(vec_bool_to_u8(inp), exp_out);
+    fn fit(&self, max: usize) -> Result<u64, BitsError> {
+        if self.width > max {
+            Err(BitsError::TooWide {
+                width: self.width,
+                max,
+            })
+        } else {
+            Ok(self.value)
+        }
+    }
 
-        let inp = vec![true, false, false, false, true];
-        let exp_out: u8 = 0b10001;
+    /**
+     * Parses a string of binary digits, most significant first.
+     * The width is the number of digits; '_' separators are ignored.
+     */
+    pub fn parse_bin(s: &str) -> Result<Bits, BitsError> {
+        Self::parse_radix(s, 1)
+    }
 
-        assert_eq!(vec_bool_to_u8(inp), exp_out);
+    /**
+     * Parses a string of hex digits, most significant first.
+     * Each digit contributes four bits to the width.
+     */
+    pub fn parse_hex(s: &str) -> Result<Bits, BitsError> {
+        Self::parse_radix(s, 4)
+    }
 
-        let inp = vec![true, false, false, true, true];
-        let exp_out: u8 = 0b10011;
+    fn parse_radix(s: &str, bits_per_digit: usize) -> Result<Bits, BitsError> {
+        let radix = 1 << bits_per_digit;
+        let mut res = Bits::zero(0);
+
+        for c in s.chars().filter(|c| *c != '_') {
+            let digit = c.to_digit(radix).ok_or(BitsError::InvalidDigit(c))?;
+            if res.width + bits_per_digit > Self::MAX_WIDTH {
+                return Err(BitsError::TooWide {
+                    width: res.width + bits_per_digit,
+                    max: Self::MAX_WIDTH,
+                });
+            }
+            res = res.concat(Bits::new(digit as u64, bits_per_digit));
+        }
+
+        if res.is_empty() {
+            return Err(BitsError::Empty);
+        }
+
+        Ok(res)
+    }
+}
 
-        assert_eq!(vec_bool_to_u8(inp), exp_out);
+/**
+ * Accepts "0b" prefixed binary, "0x" prefixed hex, or bare binary digits.
+ */
+impl FromStr for Bits {
+    type Err = BitsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(hex) = s.strip_prefix("0x") {
+            Bits::parse_hex(hex)
+        } else if let Some(bin) = s.strip_prefix("0b") {
+            Bits::parse_bin(bin)
+        } else {
+            Bits::parse_bin(s)
+        }
+    }
+}
 
-        let inp = vec![true, false, true, true];
-        let exp_out: u8 = 0b1011;
+impl fmt::Display for Bits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Binary::fmt(self, f)
+    }
+}
 
-        assert_eq!(vec_bool_to_u8(inp), exp_out);
+impl fmt::Debug for Bits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Bits({}'b{:b})", self.width, self)
     }
+}
 
-    #[test]
-    fn test_u32_to_vec_bool() {
-        let inp: u32 = 0b1;
-        let mut exp_out = vec![false; 32];
-        exp_out[31] = true;
+impl fmt::Binary for Bits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            write!(f, "0b")?;
+        }
+        for bit in self.iter() {
+            write!(f, "{}", bit as u8)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::LowerHex for Bits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = self.width.div_ceil(4);
+        if f.alternate() {
+            write!(f, "0x")?;
+        }
+        write!(f, "{:0width$x}", self.value, width = digits)
+    }
+}
+
+impl fmt::UpperHex for Bits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = self.width.div_ceil(4);
+        if f.alternate() {
+            write!(f, "0x")?;
+        }
+        write!(f, "{:0width$X}", self.value, width = digits)
+    }
+}
+
+impl From<bool> for Bits {
+    fn from(value: bool) -> Self {
+        Bits::new(value as u64, 1)
+    }
+}
+
+impl From<u8> for Bits {
+    fn from(value: u8) -> Self {
+        Bits::new(value as u64, 8)
+    }
+}
 
-        assert_eq!(u32_to_vec_bool(inp), exp_out);
+impl From<u16> for Bits {
+    fn from(value: u16) -> Self {
+        Bits::new(value as u64, 16)
+    }
+}
 
-        let mut inp: u32 = 0b1111111111111111;
-        inp = inp << 16 | 0b1111111111111111;
-        let exp_out = vec![true; 32];
+impl From<u32> for Bits {
+    fn from(value: u32) -> Self {
+        Bits::new(value as u64, 32)
+    }
+}
 
-        assert_eq!(u32_to_vec_bool(inp), exp_out);
+impl From<u64> for Bits {
+    fn from(value: u64) -> Self {
+        Bits::new(value, 64)
     }
+}
+
+impl<const N: usize> From<Bus<N>> for Bits {
+    fn from(value: Bus<N>) -> Self {
+        Bits::new(value.value(), N)
+    }
+}
+
+impl<const N: usize> TryFrom<Bits> for Bus<N> {
+    type Error = BitsError;
+
+    fn try_from(value: Bits) -> Result<Self, Self::Error> {
+        value.fit(N).map(Bus::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
     #[test]
-    fn test_vec_bool_to_u32() {
-        let inp = vec![true];
-        let exp_out: u32 = 0b1;
+    fn test_indexing() {
+        let bits = Bits::parse_bin("1000000000000010").unwrap();
+        assert_eq!(bits.width(), 16);
+        assert_eq!(bits.msb(0), true);
+        assert_eq!(bits.lsb(15), true);
+        assert_eq!(bits.lsb(1), true);
+        assert_eq!(bits.msb(14), true);
+        assert_eq!(bits.lsb(0), false);
+
+        let mut bits = Bits::zero(4);
+        bits.set_msb(0, true);
+        bits.set_lsb(0, true);
+        assert_eq!(bits.value(), 0b1001);
+        assert_eq!(bits.iter().collect::<Vec<_>>(), [true, false, false, true]);
+    }
 
-        assert_eq!(vec_bool_to_u32(inp), exp_out);
+    #[test]
+    fn test_slice_and_concat() {
+        let data = Bits::from(0b1010_0000_1111_0101u16);
+        let load = Bits::from(true);
+        let addr = Bits::new(0b011, 3);
+
+        let inputs = data.concat(load).concat(addr);
+        assert_eq!(inputs.width(), 20);
+        assert_eq!(inputs.slice_msb(0..16), data);
+        assert_eq!(inputs.slice_msb(16..17), load);
+        assert_eq!(inputs.slice_lsb(0..3), addr);
+        assert_eq!(inputs.slice_lsb(4..20), data);
+        assert_eq!(inputs.slice_msb(0..4).value(), 0b1010);
+        assert_eq!(inputs.slice_msb(3..3).width(), 0);
+
+        let bits = Bits::from_msb_iter([true, true, false]);
+        assert_eq!(bits, Bits::new(0b110, 3));
+
+        let full = Bits::from(u32::MAX).concat(Bits::from(u32::MAX));
+        assert_eq!(full.value(), u64::MAX);
+    }
 
-        let inp = vec![true, false, false, false, true];
-        let exp_out: u32 = 0b10001;
+    #[test]
+    fn test_parse_and_format() {
+        let bits: Bits = "0x1F".parse().unwrap();
+        assert_eq!(bits, Bits::new(0x1F, 8));
+        assert_eq!(format!("{:x}", bits), "1f");
+        assert_eq!(format!("{:#X}", bits), "0x1F");
+        assert_eq!(format!("{}", bits), "00011111");
+
+        let bits: Bits = "0b1_0001".parse().unwrap();
+        assert_eq!(bits, Bits::new(0b10001, 5));
+        assert_eq!(format!("{:#b}", bits), "0b10001");
+        assert_eq!(format!("{:x}", bits), "11");
+
+        assert_eq!("101".parse::<Bits>().unwrap(), Bits::new(0b101, 3));
+        assert_eq!("0b12".parse::<Bits>(), Err(BitsError::InvalidDigit('2')));
+        assert_eq!("0x".parse::<Bits>(), Err(BitsError::Empty));
+        assert!(Bits::parse_hex(&"f".repeat(17)).is_err());
+    }
 
-        assert_eq!(vec_bool_to_u32(inp), exp_out);
+    #[test]
+    fn test_checked_conversions() {
+        let bits = Bits::new(0b10011, 5);
+        assert_eq!(bits.to_u8(), Ok(0b10011));
+        assert_eq!(bits.to_u16(), Ok(0b10011));
+
+        let wide = Bits::new(0b1_0000_0001, 9);
+        assert_eq!(wide.to_u8(), Err(BitsError::TooWide { width: 9, max: 8 }));
+        assert_eq!(wide.to_u32(), Ok(0b1_0000_0001));
+
+        assert_eq!(Bits::from(u32::MAX).to_u32(), Ok(u32::MAX));
+        assert_eq!(Bits::from(u64::MAX).to_u64(), Ok(u64::MAX));
+        assert!(Bits::try_new(0b100, 2).is_err());
+    }
+
+    #[test]
+    fn test_bus_conversions() {
+        let bus = Bus::<12>::new(0xABC);
+        let bits = Bits::from(bus);
+        assert_eq!(bits.width(), 12);
+        assert_eq!(Bus::<12>::try_from(bits), Ok(bus));
+        assert!(Bus::<8>::try_from(bits).is_err());
     }
 }
@@ -0,0 +1,390 @@
+use std::fmt::{self, Debug};
+
+use crate::bus::Bus;
+
+/**
+ * xorshift64* pseudo random generator.
+ * Deterministic for a given seed so a reported counterexample can be
+ * reproduced by re-running with the same seed.
+ */
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // the all-zero state is a fixed point of xorshift
+        Rng(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    // random value with the given number of low bits, biased towards the
+    // values that usually break arithmetic: 0, all ones, sign bit only, ...
+    pub fn next_bits(&mut self, bits: u32) -> u64 {
+        let mask = if bits >= 64 {
+            u64::MAX
+        } else {
+            (1 << bits) - 1
+        };
+
+        let edge_cases = [0, 1, mask, mask >> 1, (mask >> 1) + 1];
+        let roll = self.next_u64();
+        if roll.is_multiple_of(8) {
+            edge_cases[(roll >> 8) as usize % edge_cases.len()] & mask
+        } else {
+            self.next_u64() & mask
+        }
+    }
+}
+
+/**
+ * A value that can be fed into a chip under test.
+ * BITS is log2 of the number of distinct values; small domains are
+ * enumerated with nth(0..2^BITS), large ones are sampled.
+ */
+pub trait Input: Copy + Debug {
+    const BITS: u32;
+
+    fn nth(index: u64) -> Self;
+    fn random(rng: &mut Rng) -> Self;
+
+    // one entry per chip pin, used when printing a counterexample
+    fn pins(&self) -> Vec<String> {
+        vec![format!("{:?}", self)]
+    }
+}
+
+impl Input for bool {
+    const BITS: u32 = 1;
+
+    fn nth(index: u64) -> Self {
+        index & 1 != 0
+    }
+
+    fn random(rng: &mut Rng) -> Self {
+        rng.next_u64() & 1 != 0
+    }
+}
+
+impl Input for u8 {
+    const BITS: u32 = 8;
+
+    fn nth(index: u64) -> Self {
+        index as u8
+    }
+
+    fn random(rng: &mut Rng) -> Self {
+        rng.next_bits(8) as u8
+    }
+
+    fn pins(&self) -> Vec<String> {
+        vec![format!("{:#010b}", self)]
+    }
+}
+
+impl Input for u16 {
+    const BITS: u32 = 16;
+
+    fn nth(index: u64) -> Self {
+        index as u16
+    }
+
+    fn random(rng: &mut Rng) -> Self {
+        rng.next_bits(16) as u16
+    }
+
+    fn pins(&self) -> Vec<String> {
+        vec![format!("{:#018b}", self)]
+    }
+}
+
+impl<const N: usize> Input for Bus<N> {
+    const BITS: u32 = N as u32;
+
+    fn nth(index: u64) -> Self {
+        Bus::new(index)
+    }
+
+    fn random(rng: &mut Rng) -> Self {
+        Bus::new(rng.next_bits(N as u32))
+    }
+
+    fn pins(&self) -> Vec<String> {
+        vec![format!("{:#0width$b}", self.value(), width = N + 2)]
+    }
+}
+
+// tuples of inputs: the first element takes the lowest bits of the index
+macro_rules! tuple_input {
+    ($($name:ident),+) => {
+        impl<$($name: Input),+> Input for ($($name,)+) {
+            const BITS: u32 = 0 $(+ $name::BITS)+;
+
+            #[allow(unused_assignments)]
+            fn nth(mut index: u64) -> Self {
+                ($({
+                    let value = $name::nth(index);
+                    index = index.checked_shr($name::BITS).unwrap_or(0);
+                    value
+                },)+)
+            }
+
+            fn random(rng: &mut Rng) -> Self {
+                ($($name::random(rng),)+)
+            }
+
+            #[allow(non_snake_case)]
+            fn pins(&self) -> Vec<String> {
+                let ($($name,)+) = self;
+                let mut res = Vec::new();
+                $(res.extend($name.pins());)+
+                res
+            }
+        }
+    };
+}
+
+tuple_input!(A);
+tuple_input!(A, B);
+tuple_input!(A, B, C);
+tuple_input!(A, B, C, D);
+tuple_input!(A, B, C, D, E);
+tuple_input!(A, B, C, D, E, F);
+tuple_input!(A, B, C, D, E, F, G);
+tuple_input!(A, B, C, D, E, F, G, H);
+tuple_input!(A, B, C, D, E, F, G, H, I);
+
+/**
+ * First input on which a chip and its reference model disagree.
+ */
+pub struct Counterexample<I, O> {
+    pub chip: String,
+    pub pin_names: Vec<String>,
+    pub input: I,
+    pub got: O,
+    pub expected: O,
+    pub case: u64,
+    pub seed: Option<u64>,
+}
+
+impl<I: Input, O: Debug> fmt::Display for Counterexample<I, O> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "chip `{}` disagrees with its model", self.chip)?;
+        match self.seed {
+            Some(seed) => writeln!(f, "  random case #{} (seed {:#x})", self.case, seed)?,
+            None => writeln!(f, "  exhaustive case #{}", self.case)?,
+        }
+        for (i, pin) in self.input.pins().iter().enumerate() {
+            let default_name = format!("in{}", i);
+            let name = self.pin_names.get(i).unwrap_or(&default_name);
+            writeln!(f, "  {} = {}", name, pin)?;
+        }
+        writeln!(f, "  got      {:?}", self.got)?;
+        write!(f, "  expected {:?}", self.expected)
+    }
+}
+
+impl<I: Input, O: Debug> Debug for Counterexample<I, O> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    pub cases: u64,
+    pub exhaustive: bool,
+}
+
+/**
+ * Checks a chip against a reference model.
+ * Input spaces of at most exhaustive_limit bits are enumerated completely,
+ * larger ones are covered with `samples` random vectors.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Harness {
+    exhaustive_limit: u32,
+    samples: u64,
+    seed: u64,
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        Harness {
+            exhaustive_limit: 16,
+            samples: 2000,
+            seed: 0x5EED_CAFE,
+        }
+    }
+
+    pub fn exhaustive_limit(mut self, bits: u32) -> Self {
+        self.exhaustive_limit = bits;
+        self
+    }
+
+    pub fn samples(mut self, samples: u64) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn check<I, O>(
+        &self,
+        chip: &str,
+        pin_names: &[&str],
+        chip_fn: impl Fn(I) -> O,
+        model: impl Fn(I) -> O,
+    ) -> Result<Report, Box<Counterexample<I, O>>>
+    where
+        I: Input,
+        O: PartialEq + Debug,
+    {
+        let counterexample = |input: I, got: O, expected: O, case: u64, seed: Option<u64>| {
+            Box::new(Counterexample {
+                chip: chip.to_string(),
+                pin_names: pin_names.iter().map(|s| s.to_string()).collect(),
+                input,
+                got,
+                expected,
+                case,
+                seed,
+            })
+        };
+
+        if I::BITS <= self.exhaustive_limit {
+            let cases = 1u64 << I::BITS;
+            for case in 0..cases {
+                let input = I::nth(case);
+                let (got, expected) = (chip_fn(input), model(input));
+                if got != expected {
+                    return Err(counterexample(input, got, expected, case, None));
+                }
+            }
+            return Ok(Report {
+                cases,
+                exhaustive: true,
+            });
+        }
+
+        let mut rng = Rng::new(self.seed);
+        for case in 0..self.samples {
+            let input = I::random(&mut rng);
+            let (got, expected) = (chip_fn(input), model(input));
+            if got != expected {
+                return Err(counterexample(input, got, expected, case, Some(self.seed)));
+            }
+        }
+
+        Ok(Report {
+            cases: self.samples,
+            exhaustive: false,
+        })
+    }
+
+    // like check, but panics with the counterexample; meant for tests
+    pub fn assert_equivalent<I, O>(
+        &self,
+        chip: &str,
+        pin_names: &[&str],
+        chip_fn: impl Fn(I) -> O,
+        model: impl Fn(I) -> O,
+    ) -> Report
+    where
+        I: Input,
+        O: PartialEq + Debug,
+    {
+        match self.check(chip, pin_names, chip_fn, model) {
+            Ok(report) => report,
+            Err(counterexample) => panic!("{}", counterexample),
+        }
+    }
+}
+
+impl Default for Harness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exhaustive_enumerates_all() {
+        let report = Harness::new().assert_equivalent(
+            "and",
+            &["a", "b"],
+            |(a, b): (bool, bool)| a && b,
+            |(a, b)| !(!a || !b),
+        );
+        assert_eq!(
+            report,
+            Report {
+                cases: 4,
+                exhaustive: true
+            }
+        );
+
+        let report =
+            Harness::new().assert_equivalent("id", &["x", "sel"], |x: (u8, Bus<3>)| x, |x| x);
+        assert_eq!(report.cases, 1 << 11);
+    }
+
+    #[test]
+    fn test_sampling_large_domains() {
+        let report = Harness::new().samples(50).assert_equivalent(
+            "add",
+            &["a", "b"],
+            |(a, b): (u16, u16)| a.wrapping_add(b),
+            |(a, b)| b.wrapping_add(a),
+        );
+        assert_eq!(
+            report,
+            Report {
+                cases: 50,
+                exhaustive: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_reports_first_counterexample() {
+        let res = Harness::new().check(
+            "broken_or",
+            &["a", "b"],
+            |(a, b): (bool, bool)| a ^ b,
+            |(a, b)| a || b,
+        );
+        let counterexample = res.unwrap_err();
+        assert_eq!(counterexample.input, (true, true));
+        assert_eq!(counterexample.case, 3);
+
+        let message = counterexample.to_string();
+        assert!(message.contains("broken_or"));
+        assert!(message.contains("a = true"));
+        assert!(message.contains("b = true"));
+    }
+
+    #[test]
+    fn test_sampling_is_reproducible() {
+        let find = |seed| {
+            Harness::new()
+                .seed(seed)
+                .check("lt", &["x"], |x: u16| x < 40000, |_| true)
+                .unwrap_err()
+                .input
+        };
+        assert_eq!(find(7), find(7));
+    }
+}
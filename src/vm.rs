@@ -0,0 +1,1029 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::srcmap::{Location, SourceMap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    Argument,
+    Local,
+    Static,
+    Constant,
+    This,
+    That,
+    Pointer,
+    Temp,
+}
+
+impl Segment {
+    fn parse(name: &str) -> Option<Segment> {
+        Some(match name {
+            "argument" => Segment::Argument,
+            "local" => Segment::Local,
+            "static" => Segment::Static,
+            "constant" => Segment::Constant,
+            "this" => Segment::This,
+            "that" => Segment::That,
+            "pointer" => Segment::Pointer,
+            "temp" => Segment::Temp,
+            _ => return None,
+        })
+    }
+
+    // the base pointer of the segments that live in the stack frame or heap
+    fn base(self) -> Option<&'static str> {
+        match self {
+            Segment::Argument => Some("ARG"),
+            Segment::Local => Some("LCL"),
+            Segment::This => Some("THIS"),
+            Segment::That => Some("THAT"),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Add,
+    Sub,
+    Neg,
+    Eq,
+    Gt,
+    Lt,
+    And,
+    Or,
+    Not,
+    Push(Segment, u16),
+    Pop(Segment, u16),
+    Label(String),
+    Goto(String),
+    IfGoto(String),
+    Function(String, u16),
+    Call(String, u16),
+    Return,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.vm: line {}: {}", self.file, self.line, self.message)
+    }
+}
+
+impl std::error::Error for VmError {}
+
+// letters, digits, '_', '.' and ':', not starting with a digit
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    let valid = |c: char| c.is_ascii_alphanumeric() || "_.:".contains(c);
+    matches!(chars.next(), Some(c) if valid(c) && !c.is_ascii_digit()) && chars.all(valid)
+}
+
+/**
+ * Parses one command per line; // starts a comment.
+ * Returns each command with its line number.
+ */
+pub fn parse(source: &str) -> Result<Vec<(usize, Command)>, (usize, String)> {
+    let mut commands = Vec::new();
+
+    for (i, text) in source.lines().enumerate() {
+        let text = text.split("//").next().unwrap();
+        let words: Vec<&str> = text.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        let error = |message: String| (i + 1, message);
+
+        let index = |value: &str| -> Result<u16, (usize, String)> {
+            match value.parse::<u16>() {
+                Ok(n) if n <= 0x7FFF => Ok(n),
+                _ => Err(error(format!("'{}' is not in 0..32767", value))),
+            }
+        };
+        let name = |value: &str| -> Result<String, (usize, String)> {
+            if is_identifier(value) {
+                Ok(value.to_string())
+            } else {
+                Err(error(format!("invalid name '{}'", value)))
+            }
+        };
+
+        let command = match words.as_slice() {
+            ["add"] => Command::Add,
+            ["sub"] => Command::Sub,
+            ["neg"] => Command::Neg,
+            ["eq"] => Command::Eq,
+            ["gt"] => Command::Gt,
+            ["lt"] => Command::Lt,
+            ["and"] => Command::And,
+            ["or"] => Command::Or,
+            ["not"] => Command::Not,
+            [op @ ("push" | "pop"), segment, i] => {
+                let segment = Segment::parse(segment)
+                    .ok_or_else(|| error(format!("unknown segment '{}'", segment)))?;
+                let i = index(i)?;
+                let limit = match segment {
+                    Segment::Pointer => Some(2),
+                    Segment::Temp => Some(8),
+                    _ => None,
+                };
+                if limit.is_some_and(|limit| i >= limit) {
+                    return Err(error(format!("{} {} is out of range", words[1], i)));
+                }
+                if *op == "push" {
+                    Command::Push(segment, i)
+                } else if segment == Segment::Constant {
+                    return Err(error("cannot pop to constant".to_string()));
+                } else {
+                    Command::Pop(segment, i)
+                }
+            }
+            ["label", label] => Command::Label(name(label)?),
+            ["goto", label] => Command::Goto(name(label)?),
+            ["if-goto", label] => Command::IfGoto(name(label)?),
+            ["function", f, n] => Command::Function(name(f)?, index(n)?),
+            ["call", f, n] => Command::Call(name(f)?, index(n)?),
+            ["return"] => Command::Return,
+            _ => return Err(error(format!("invalid command '{}'", text.trim()))),
+        };
+        commands.push((i + 1, command));
+    }
+
+    Ok(commands)
+}
+
+/**
+ * A .vm file: its name without the extension, which prefixes its
+ * static variables, and its text.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmFile {
+    pub name: String,
+    pub source: String,
+}
+
+impl VmFile {
+    pub fn new(name: &str, source: &str) -> Self {
+        VmFile {
+            name: name.to_string(),
+            source: source.to_string(),
+        }
+    }
+}
+
+/**
+ * Reads a .vm file, or every .vm file of a directory in name order.
+ */
+pub fn read_files(path: impl AsRef<Path>) -> io::Result<Vec<VmFile>> {
+    let path = path.as_ref();
+    let mut paths = Vec::new();
+    if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            let entry = entry?.path();
+            if entry.extension().is_some_and(|e| e == "vm") {
+                paths.push(entry);
+            }
+        }
+        paths.sort();
+        if paths.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no .vm files in the directory",
+            ));
+        }
+    } else {
+        paths.push(path.to_path_buf());
+    }
+
+    paths
+        .iter()
+        .map(|path| {
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            Ok(VmFile::new(&name, &fs::read_to_string(path)?))
+        })
+        .collect()
+}
+
+/*
+ * Calls, returns and comparisons jump to shared routines after the
+ * program instead of being expanded in place, which keeps programs
+ * the size of the Jack OS inside the 32K ROM. Their labels have a '$',
+ * which VM names cannot.
+ * $CALL: R13 = function, R14 = number of arguments, D = return address.
+ * $RETURN: no arguments.
+ * $EQ, $GT, $LT: D = return address, compare the two words on the stack.
+ * Only the routines a program uses are added, the others would be dead code.
+ */
+const CALL: &str = "\
+($CALL)
+@SP
+AM=M+1
+A=A-1
+M=D
+@LCL
+D=M
+@SP
+AM=M+1
+A=A-1
+M=D
+@ARG
+D=M
+@SP
+AM=M+1
+A=A-1
+M=D
+@THIS
+D=M
+@SP
+AM=M+1
+A=A-1
+M=D
+@THAT
+D=M
+@SP
+AM=M+1
+A=A-1
+M=D
+@R14
+D=M
+@5
+D=D+A
+@SP
+D=M-D
+@ARG
+M=D
+@SP
+D=M
+@LCL
+M=D
+@R13
+A=M
+0;JMP
+";
+
+const RETURN: &str = "\
+($RETURN)
+@5
+D=A
+@LCL
+A=M-D
+D=M
+@R14
+M=D
+@SP
+AM=M-1
+D=M
+@ARG
+A=M
+M=D
+@ARG
+D=M+1
+@SP
+M=D
+@LCL
+AM=M-1
+D=M
+@THAT
+M=D
+@LCL
+AM=M-1
+D=M
+@THIS
+M=D
+@LCL
+AM=M-1
+D=M
+@ARG
+M=D
+@LCL
+A=M-1
+D=M
+@LCL
+M=D
+@R14
+A=M
+0;JMP
+";
+
+/*
+ * gt and lt, with the jump taken when x - y is greater or less than 0.
+ * x - y overflows when x and y have different signs, so then the sign
+ * of x alone decides.
+ */
+const ORDERINGS: [(&str, &str); 2] = [("GT", "JGT"), ("LT", "JLT")];
+
+/**
+ * Translates VM files to one Hack assembly program.
+ * With the bootstrap (the default) the program starts by setting SP to
+ * 256 and calling Sys.init, when one of the files defines it; without,
+ * the code starts at ROM address 0 and the caller sets up the pointers.
+ * Either way the files are followed by a halt loop.
+ */
+pub struct Translator {
+    bootstrap: bool,
+}
+
+impl Translator {
+    pub fn new() -> Self {
+        Translator { bootstrap: true }
+    }
+
+    pub fn bootstrap(mut self, bootstrap: bool) -> Self {
+        self.bootstrap = bootstrap;
+        self
+    }
+
+    pub fn translate(&self, files: &[VmFile]) -> Result<String, VmError> {
+        self.translate_with_map(files).map(|(asm, _)| asm)
+    }
+
+    /**
+     * translate, with the map of the assembly lines to the lines of the
+     * Xxx.vm files they were translated from, and where the functions start.
+     */
+    pub fn translate_with_map(&self, files: &[VmFile]) -> Result<(String, SourceMap), VmError> {
+        let mut parsed = Vec::new();
+        for file in files {
+            let commands = parse(&file.source).map_err(|(line, message)| VmError {
+                file: file.name.clone(),
+                line,
+                message,
+            })?;
+            parsed.push((file, commands));
+        }
+
+        let error = |file: &VmFile, line: usize, message: String| VmError {
+            file: file.name.clone(),
+            line,
+            message,
+        };
+        // an unknown name would be taken for a variable and jumped to
+        let mut functions = HashSet::new();
+        for (file, commands) in &parsed {
+            for (line, command) in commands {
+                if let Command::Function(name, _) = command {
+                    if !functions.insert(name.as_str()) {
+                        let message = format!("function '{}' is already defined", name);
+                        return Err(error(file, *line, message));
+                    }
+                }
+            }
+        }
+        for (file, commands) in &parsed {
+            // labels are local to their function, or the file before any
+            let mut labels = HashSet::new();
+            let mut scope = file.name.as_str();
+            for (_, command) in commands {
+                match command {
+                    Command::Function(name, _) => scope = name,
+                    Command::Label(label) => {
+                        labels.insert((scope, label.as_str()));
+                    }
+                    _ => {}
+                }
+            }
+            let mut scope = file.name.as_str();
+            for (line, command) in commands {
+                match command {
+                    Command::Function(name, _) => scope = name,
+                    Command::Goto(label) | Command::IfGoto(label)
+                        if !labels.contains(&(scope, label.as_str())) =>
+                    {
+                        let message = format!("undefined label '{}'", label);
+                        return Err(error(file, *line, message));
+                    }
+                    Command::Call(name, _) if !functions.contains(name.as_str()) => {
+                        let message = format!("undefined function '{}'", name);
+                        return Err(error(file, *line, message));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut out = Output::default();
+        if self.bootstrap {
+            out.line("// bootstrap");
+            out.lines(&["@256", "D=A", "@SP", "M=D"]);
+            let has_init = parsed.iter().any(|(_, commands)| {
+                commands
+                    .iter()
+                    .any(|(_, c)| matches!(c, Command::Function(f, _) if f == "Sys.init"))
+            });
+            if has_init {
+                out.scope = "Sys.init".to_string();
+                out.call("Sys.init", 0);
+                // Sys.init should not return, halt if it does
+                out.lines(&["@$HALT", "0;JMP"]);
+            }
+        }
+
+        for (file, commands) in &parsed {
+            out.file = file.name.clone();
+            out.scope = file.name.clone();
+            for (line, command) in commands {
+                out.source = Some(Location::new(&format!("{}.vm", file.name), *line));
+                if let Command::Function(name, _) = command {
+                    out.map.add_function(out.lines + 1, name, Vec::new());
+                }
+                out.line(&format!(
+                    "// {}.vm:{} {}",
+                    file.name,
+                    line,
+                    describe(command)
+                ));
+                out.command(command);
+            }
+            out.source = None;
+        }
+
+        out.line("// halt");
+        out.lines(&["($HALT)", "@$HALT", "0;JMP"]);
+        if !out.routines.is_empty() {
+            out.line("// runtime: call, return and comparisons");
+        }
+        if out.routines.contains("CALL") {
+            out.text.push_str(CALL);
+        }
+        if out.routines.contains("RETURN") {
+            out.text.push_str(RETURN);
+        }
+        if out.routines.contains("EQ") {
+            out.lines(&[
+                "($EQ)",
+                "@R15",
+                "M=D",
+                "@SP",
+                "AM=M-1",
+                "D=M",
+                "A=A-1",
+                "D=M-D",
+                "M=-1",
+                "@$EQ.TRUE",
+                "D;JEQ",
+                "@SP",
+                "A=M-1",
+                "M=0",
+                "($EQ.TRUE)",
+                "@R15",
+                "A=M",
+                "0;JMP",
+            ]);
+        }
+        for (name, jump) in ORDERINGS {
+            if !out.routines.contains(name) {
+                continue;
+            }
+            // x < 0 <= y makes gt false and lt true, y < 0 <= x the reverse
+            let (x_negative, y_negative) = match name {
+                "GT" => ("FALSE", "TRUE"),
+                _ => ("TRUE", "FALSE"),
+            };
+            let label = |part: &str| format!("${}.{}", name, part);
+            out.lines(&[
+                &format!("(${})", name),
+                "@R15",
+                "M=D",
+                "@SP",
+                "A=M-1",
+                "D=M",
+                &format!("@{}", label("YNEG")),
+                "D;JLT",
+                "@SP",
+                "A=M-1",
+                "A=A-1",
+                "D=M",
+                &format!("@{}", label(x_negative)),
+                "D;JLT",
+                &format!("@{}", label("SAME")),
+                "0;JMP",
+                &format!("({})", label("YNEG")),
+                "@SP",
+                "A=M-1",
+                "A=A-1",
+                "D=M",
+                &format!("@{}", label(y_negative)),
+                "D;JGE",
+                &format!("({})", label("SAME")),
+                "@SP",
+                "A=M-1",
+                "D=D-M",
+                &format!("@{}", label("TRUE")),
+                &format!("D;{}", jump),
+                &format!("({})", label("FALSE")),
+                "D=0",
+                &format!("@{}", label("END")),
+                "0;JMP",
+                &format!("({})", label("TRUE")),
+                "D=-1",
+                &format!("({})", label("END")),
+                "@SP",
+                "AM=M-1",
+                "A=A-1",
+                "M=D",
+                "@R15",
+                "A=M",
+                "0;JMP",
+            ]);
+        }
+
+        Ok((out.text, out.map))
+    }
+}
+
+impl Default for Translator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/*
+ * Label of the n-th call or comparison of the program, counting from 1
+ * in program order with the bootstrap's call first.
+ */
+pub(crate) fn return_label(scope: &str, n: usize) -> String {
+    format!("{}$ret.{}", scope, n)
+}
+
+fn describe(command: &Command) -> String {
+    let segment = |s: &Segment| format!("{:?}", s).to_lowercase();
+    match command {
+        Command::Push(s, i) => format!("push {} {}", segment(s), i),
+        Command::Pop(s, i) => format!("pop {} {}", segment(s), i),
+        Command::Label(l) => format!("label {}", l),
+        Command::Goto(l) => format!("goto {}", l),
+        Command::IfGoto(l) => format!("if-goto {}", l),
+        Command::Function(f, n) => format!("function {} {}", f, n),
+        Command::Call(f, n) => format!("call {} {}", f, n),
+        Command::Return => "return".to_string(),
+        other => format!("{:?}", other).to_lowercase(),
+    }
+}
+
+#[derive(Default)]
+struct Output {
+    text: String,
+    file: String,  // prefix of the static variables
+    scope: String, // prefix of the labels: the function, or the file before any
+    returns: usize,
+    // the runtime routines jumped to
+    routines: HashSet<&'static str>,
+    lines: usize,
+    // the command being translated, for the map
+    source: Option<Location>,
+    map: SourceMap,
+}
+
+impl Output {
+    fn line(&mut self, line: &str) {
+        let _ = writeln!(self.text, "{}", line);
+        self.lines += 1;
+        if let Some(source) = &self.source {
+            self.map.add(self.lines, source.clone());
+        }
+    }
+
+    fn lines(&mut self, lines: &[&str]) {
+        for line in lines {
+            self.line(line);
+        }
+    }
+
+    fn push_d(&mut self) {
+        self.lines(&["@SP", "M=M+1", "A=M-1", "M=D"]);
+    }
+
+    fn pop_d(&mut self) {
+        self.lines(&["@SP", "AM=M-1", "D=M"]);
+    }
+
+    // a return label, unique in the program
+    fn return_label(&mut self) -> String {
+        self.returns += 1;
+        return_label(&self.scope, self.returns)
+    }
+
+    // the RAM address of a fixed segment entry
+    fn fixed(&self, segment: Segment, i: u16) -> String {
+        match segment {
+            Segment::Static => format!("@{}.{}", self.file, i),
+            Segment::Temp => format!("@R{}", 5 + i),
+            Segment::Pointer if i == 0 => "@THIS".to_string(),
+            _ => "@THAT".to_string(),
+        }
+    }
+
+    // A = base + i
+    fn address(&mut self, base: &str, i: u16) {
+        match i {
+            0 => self.lines(&[&format!("@{}", base), "A=M"]),
+            1 => self.lines(&[&format!("@{}", base), "A=M+1"]),
+            _ => self.lines(&[&format!("@{}", i), "D=A", &format!("@{}", base), "A=D+M"]),
+        }
+    }
+
+    fn call(&mut self, function: &str, args: u16) {
+        self.routines.insert("CALL");
+        match args {
+            0 | 1 => self.lines(&["@R14", &format!("M={}", args)]),
+            _ => self.lines(&[&format!("@{}", args), "D=A", "@R14", "M=D"]),
+        }
+        let ret = self.return_label();
+        self.lines(&[
+            &format!("@{}", function),
+            "D=A",
+            "@R13",
+            "M=D",
+            &format!("@{}", ret),
+            "D=A",
+            "@$CALL",
+            "0;JMP",
+            &format!("({})", ret),
+        ]);
+    }
+
+    fn command(&mut self, command: &Command) {
+        match command {
+            Command::Add => self.lines(&["@SP", "AM=M-1", "D=M", "A=A-1", "M=D+M"]),
+            Command::Sub => self.lines(&["@SP", "AM=M-1", "D=M", "A=A-1", "M=M-D"]),
+            Command::And => self.lines(&["@SP", "AM=M-1", "D=M", "A=A-1", "M=D&M"]),
+            Command::Or => self.lines(&["@SP", "AM=M-1", "D=M", "A=A-1", "M=D|M"]),
+            Command::Neg => self.lines(&["@SP", "A=M-1", "M=-M"]),
+            Command::Not => self.lines(&["@SP", "A=M-1", "M=!M"]),
+            Command::Eq | Command::Gt | Command::Lt => {
+                let routine = match command {
+                    Command::Eq => "EQ",
+                    Command::Gt => "GT",
+                    _ => "LT",
+                };
+                self.routines.insert(routine);
+                let ret = self.return_label();
+                self.lines(&[
+                    &format!("@{}", ret),
+                    "D=A",
+                    &format!("@${}", routine),
+                    "0;JMP",
+                    &format!("({})", ret),
+                ]);
+            }
+            Command::Push(Segment::Constant, i) => {
+                match i {
+                    0 | 1 => {
+                        self.lines(&["@SP", "M=M+1", "A=M-1", &format!("M={}", i)]);
+                        return;
+                    }
+                    _ => self.lines(&[&format!("@{}", i), "D=A"]),
+                }
+                self.push_d();
+            }
+            Command::Push(segment, i) => {
+                match segment.base() {
+                    Some(base) => self.address(base, *i),
+                    None => self.line(&self.fixed(*segment, *i)),
+                }
+                self.line("D=M");
+                self.push_d();
+            }
+            Command::Pop(segment, i) => match segment.base() {
+                Some(base) if *i <= 1 => {
+                    self.pop_d();
+                    self.address(base, *i);
+                    self.line("M=D");
+                }
+                Some(base) => {
+                    self.lines(&[&format!("@{}", i), "D=A", &format!("@{}", base)]);
+                    self.lines(&["D=D+M", "@R13", "M=D"]);
+                    self.pop_d();
+                    self.lines(&["@R13", "A=M", "M=D"]);
+                }
+                None => {
+                    self.pop_d();
+                    let address = self.fixed(*segment, *i);
+                    self.lines(&[&address, "M=D"]);
+                }
+            },
+            Command::Label(label) => self.line(&format!("({}${})", self.scope, label)),
+            Command::Goto(label) => {
+                self.lines(&[&format!("@{}${}", self.scope, label), "0;JMP"]);
+            }
+            Command::IfGoto(label) => {
+                self.pop_d();
+                self.lines(&[&format!("@{}${}", self.scope, label), "D;JNE"]);
+            }
+            Command::Function(function, locals) => {
+                self.scope = function.clone();
+                self.line(&format!("({})", function));
+                if *locals > 0 {
+                    self.lines(&["@SP", "A=M"]);
+                    for _ in 0..*locals {
+                        self.lines(&["M=0", "A=A+1"]);
+                    }
+                    self.lines(&["D=A", "@SP", "M=D"]);
+                }
+            }
+            Command::Call(function, args) => self.call(function, *args),
+            Command::Return => {
+                self.routines.insert("RETURN");
+                self.lines(&["@$RETURN", "0;JMP"]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::runner::{RunReport, Runner};
+
+    fn run(files: &[VmFile], translator: Translator, setup: &[(u16, u16)]) -> RunReport {
+        // the pointers a Nand2Tetris test script would set
+        let mut asm = String::new();
+        for (addr, value) in setup {
+            let _ = write!(asm, "@{}\nD=A\n@{}\nM=D\n", value, addr);
+        }
+        asm.push_str(&translator.translate(files).unwrap());
+        let program = assemble(&asm).unwrap();
+
+        Runner::new()
+            .max_cycles(20_000)
+            .dump(0..16)
+            .dump(256..320)
+            .dump(3000..3020)
+            .run(&program.code)
+    }
+
+    fn ram(report: &RunReport, addr: u16) -> u16 {
+        let (start, words) = report
+            .ram
+            .iter()
+            .find(|(start, words)| (*start..*start + words.len() as u16).contains(&addr))
+            .unwrap();
+        words[(addr - start) as usize]
+    }
+
+    #[test]
+    fn test_parse() {
+        let commands =
+            parse("// comment\npush constant 7 // seven\n\n  add\nlabel L.1_x:y\n").unwrap();
+        assert_eq!(
+            commands,
+            [
+                (2, Command::Push(Segment::Constant, 7)),
+                (4, Command::Add),
+                (5, Command::Label("L.1_x:y".to_string())),
+            ]
+        );
+
+        let errors = [
+            ("push constant 32768", "'32768' is not in 0..32767"),
+            ("pop constant 1", "cannot pop to constant"),
+            ("push temp 8", "temp 8 is out of range"),
+            ("pop pointer 2", "pointer 2 is out of range"),
+            ("push heap 1", "unknown segment 'heap'"),
+            ("goto 1abc", "invalid name '1abc'"),
+            ("call f$x 0", "invalid name 'f$x'"),
+            ("add 1", "invalid command 'add 1'"),
+        ];
+        for (source, message) in errors {
+            assert_eq!(parse(source), Err((1, message.to_string())), "{}", source);
+        }
+
+        let err = Translator::new()
+            .translate(&[VmFile::new("Main", "push constant 1\nfoo\n")])
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Main.vm: line 2: invalid command 'foo'");
+    }
+
+    #[test]
+    fn test_undefined_and_duplicate_names() {
+        let error = |files: &[(&str, &str)]| {
+            let files: Vec<VmFile> = files
+                .iter()
+                .map(|&(name, source)| VmFile::new(name, source))
+                .collect();
+            Translator::new().translate(&files).unwrap_err().to_string()
+        };
+        assert_eq!(
+            error(&[("Sys", "function Sys.init 0\ngoto NOPE\n")]),
+            "Sys.vm: line 2: undefined label 'NOPE'"
+        );
+        // a label belongs to the function it is in
+        assert_eq!(
+            error(&[(
+                "Sys",
+                "function Sys.init 0\nlabel L\nfunction Sys.f 0\nif-goto L\n"
+            )]),
+            "Sys.vm: line 4: undefined label 'L'"
+        );
+        assert_eq!(
+            error(&[("Sys", "function Sys.init 0\ncall Sys.f 0\n")]),
+            "Sys.vm: line 2: undefined function 'Sys.f'"
+        );
+        assert_eq!(
+            error(&[
+                ("Main", "function Main.f 0\npush constant 0\nreturn\n"),
+                ("Dup", "\nfunction Main.f 0\npush constant 1\nreturn\n"),
+            ]),
+            "Dup.vm: line 2: function 'Main.f' is already defined"
+        );
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let source = "
+            push constant 17
+            push constant 17
+            eq
+            push constant 892
+            push constant 891
+            lt
+            push constant 32767
+            push constant 32766
+            gt
+            push constant 57
+            push constant 31
+            push constant 53
+            add
+            push constant 112
+            sub
+            neg
+            and
+            push constant 82
+            or
+            not
+        ";
+        let report = run(&[VmFile::new("StackTest", source)], Translator::new(), &[]);
+        assert!(report.halted);
+        assert_eq!(ram(&report, 0), 260);
+        let stack: Vec<u16> = (256..260).map(|addr| ram(&report, addr)).collect();
+        assert_eq!(stack, [0xFFFF, 0, 0xFFFF, 0xFFA5]);
+    }
+
+    #[test]
+    fn test_comparisons_with_opposite_signs() {
+        // x - y overflows for all but the last two
+        let pairs = [
+            ("push constant 30000", "push constant 30000\nneg"),
+            ("push constant 30000\nneg", "push constant 30000"),
+            (
+                "push constant 32767\nneg\npush constant 1\nsub",
+                "push constant 32767",
+            ),
+            (
+                "push constant 32767",
+                "push constant 32767\nneg\npush constant 1\nsub",
+            ),
+            ("push constant 2\nneg", "push constant 5\nneg"),
+            ("push constant 5", "push constant 5"),
+        ];
+        let mut source = String::new();
+        for (x, y) in pairs {
+            for op in ["gt", "lt"] {
+                let _ = writeln!(source, "{}\n{}\n{}", x, y, op);
+            }
+        }
+        let report = run(&[VmFile::new("Compare", &source)], Translator::new(), &[]);
+        assert!(report.halted);
+        assert_eq!(ram(&report, 0), 268);
+        let stack: Vec<u16> = (256..268).map(|addr| ram(&report, addr)).collect();
+        assert_eq!(
+            stack,
+            [0xFFFF, 0, 0, 0xFFFF, 0, 0xFFFF, 0xFFFF, 0, 0xFFFF, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_memory_segments() {
+        // BasicTest and PointerTest from the course, with their pointers
+        let source = "
+            push constant 10
+            pop local 0
+            push constant 21
+            push constant 22
+            pop argument 2
+            pop argument 1
+            push constant 36
+            pop this 6
+            push constant 42
+            push constant 45
+            pop that 5
+            pop that 2
+            push constant 510
+            pop temp 6
+            push local 0
+            push that 5
+            add
+            push argument 1
+            sub
+            push this 6
+            push this 6
+            add
+            sub
+            push temp 6
+            add
+            push constant 3000
+            pop pointer 0
+            push constant 3010
+            pop pointer 1
+            push constant 7
+            pop static 3
+            push pointer 0
+            push pointer 1
+            add
+            push static 3
+            add
+        ";
+        let setup = [(0, 256), (1, 300), (2, 400), (3, 3000), (4, 3010)];
+        let report = run(
+            &[VmFile::new("BasicTest", source)],
+            Translator::new().bootstrap(false),
+            &setup,
+        );
+        assert_eq!(ram(&report, 256), 472);
+        assert_eq!(ram(&report, 257), 6017);
+        assert_eq!(ram(&report, 3), 3000);
+        assert_eq!(ram(&report, 4), 3010);
+        assert_eq!(ram(&report, 11), 510);
+        assert_eq!(ram(&report, 3006), 36);
+        assert_eq!(ram(&report, 3012), 42);
+        assert_eq!(ram(&report, 3015), 45);
+        assert_eq!(ram(&report, 300), 10);
+        assert_eq!(ram(&report, 0), 258);
+    }
+
+    #[test]
+    fn test_functions_and_statics() {
+        // Main.fib computes recursively, Counter keeps a static per file
+        let main = "
+            function Sys.init 0
+            push constant 6
+            call Main.fib 1
+            pop static 0
+            call Counter.next 0
+            pop temp 0
+            call Counter.next 0
+            pop temp 1
+            label END
+            goto END
+
+            function Main.fib 0
+            push argument 0
+            push constant 2
+            lt
+            if-goto BASE
+            push argument 0
+            push constant 2
+            sub
+            call Main.fib 1
+            push argument 0
+            push constant 1
+            sub
+            call Main.fib 1
+            add
+            return
+            label BASE
+            push argument 0
+            return
+        ";
+        let counter = "
+            function Counter.next 1
+            push static 0
+            push constant 1
+            add
+            pop local 0
+            push local 0
+            pop static 0
+            push local 0
+            return
+        ";
+        let asm = Translator::new()
+            .translate(&[VmFile::new("Main", main), VmFile::new("Counter", counter)])
+            .unwrap();
+        let program = assemble(&asm).unwrap();
+        let main_0 = program.symbols.variable("Main.0").unwrap();
+        let counter_0 = program.symbols.variable("Counter.0").unwrap();
+
+        let report = Runner::new()
+            .max_cycles(20_000)
+            .dump(0..32)
+            .run(&program.code);
+        assert!(report.halted);
+        assert_eq!(ram(&report, main_0), 8);
+        assert_eq!(ram(&report, counter_0), 2);
+        assert_eq!((ram(&report, 5), ram(&report, 6)), (1, 2));
+        // Sys.init's frame sits right after the bootstrap's call frame
+        assert_eq!(ram(&report, 0), 261);
+        assert_eq!(ram(&report, 1), 261);
+    }
+}